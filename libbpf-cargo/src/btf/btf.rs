@@ -1,5 +1,5 @@
 use std::cmp::{max, min};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::ffi::{c_void, CStr, CString};
 use std::fmt::Write;
@@ -11,6 +11,7 @@ use scroll::Pread;
 
 use crate::btf::c_types::*;
 use crate::btf::*;
+use crate::gen::escape_ident;
 
 pub struct Btf<'a> {
     types: Vec<BtfType<'a>>,
@@ -181,7 +182,7 @@ impl<'a> Btf<'a> {
         let ty = self.type_by_id(stripped_type_id)?;
 
         Ok(match ty {
-            BtfType::Void => "std::ffi::c_void".to_string(),
+            BtfType::Void => "core::ffi::c_void".to_string(),
             BtfType::Int(t) => {
                 let width = match (t.bits + 7) / 8 {
                     1 => "8",
@@ -208,14 +209,14 @@ impl<'a> Btf<'a> {
 
                 format!("[{}; {}]", val_ty, t.nelems)
             }
-            BtfType::Struct(t) | BtfType::Union(t) => t.name.to_string(),
-            BtfType::Enum(t) => t.name.to_string(),
+            BtfType::Struct(t) | BtfType::Union(t) => Self::type_name(stripped_type_id, t.name),
+            BtfType::Enum(t) => Self::type_name(stripped_type_id, t.name),
             // The only way a variable references a function is through a function pointer.
             // Return c_void here so the final def will look like `*mut c_void`.
             //
             // It's not like rust code can call a function inside a bpf prog either so we don't
             // really need a full definition. `void *` is totally sufficient for sharing a pointer.
-            BtfType::Func(_) => "std::ffi::c_void".to_string(),
+            BtfType::Func(_) => "core::ffi::c_void".to_string(),
             BtfType::Var(t) => self.type_declaration(t.type_id)?,
             BtfType::Fwd(_)
             | BtfType::FuncProto(_)
@@ -229,6 +230,129 @@ impl<'a> Btf<'a> {
         })
     }
 
+    /// Returns the rust name of a struct, union, or enum. Anonymous types are named after their
+    /// type ID so that the name is stable across runs.
+    fn type_name(type_id: u32, name: &str) -> String {
+        if name.is_empty() {
+            format!("__anon_{}", type_id)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn is_bitfield(m: &BtfMember) -> bool {
+        m.bit_size != 0 || m.bit_offset % 8 != 0
+    }
+
+    /// Returns the size of bitfield `m` in bits.
+    fn bitfield_size(&self, m: &BtfMember) -> Result<u32> {
+        // Newer compilers encode the bitfield size in the member (`kind_flag` set). Older ones
+        // encode it in the int type.
+        if m.bit_size != 0 {
+            return Ok(m.bit_size.into());
+        }
+
+        match self.type_by_id(self.skip_mods_and_typedefs(m.type_id)?)? {
+            BtfType::Int(t) => Ok(t.bits.into()),
+            BtfType::Enum(t) => Ok(t.size * 8),
+            ty => bail!("Invalid bitfield type: {}", ty),
+        }
+    }
+
+    /// Writes getters and setters for bitfields stored in byte arrays. Bool bitfields are
+    /// accessed as `bool`, enum bitfields as the enum's underlying integer type, since not every
+    /// value the bits can hold need be a variant.
+    ///
+    /// Each entry in `bitfields` is `(storage_field_name, bit_offset_into_storage, member)`.
+    ///
+    /// Note the generated code assumes a little endian target.
+    fn write_bitfield_accessors(
+        &self,
+        def: &mut String,
+        struct_name: &str,
+        bitfields: &[(String, u32, &BtfMember)],
+    ) -> Result<()> {
+        writeln!(def, r#"impl {} {{"#, struct_name)?;
+
+        for (storage, start, member) in bitfields {
+            let bits = self.bitfield_size(member)?;
+            let field_ty_id = self.skip_mods_and_typedefs(member.type_id)?;
+            let (field_ty, signed) = match self.type_by_id(field_ty_id)? {
+                BtfType::Int(t) if t.encoding == BtfIntEncoding::Bool => {
+                    ("bool".to_string(), false)
+                }
+                BtfType::Int(t) => (
+                    self.type_declaration(field_ty_id)?,
+                    t.encoding == BtfIntEncoding::Signed,
+                ),
+                BtfType::Enum(t) => {
+                    // Same as the enum's repr
+                    let signed = t.values.iter().any(|v| v.value < 0);
+                    let prefix = if signed { "i" } else { "u" };
+                    (format!("{}{}", prefix, t.size * 8), signed)
+                }
+                ty => bail!("Unsupported bitfield type: {}", ty),
+            };
+
+            writeln!(
+                def,
+                r#"    pub fn {name}(&self) -> {ty} {{
+        let mut val: u64 = 0;
+        for i in 0..{bits} {{
+            let bit = {start} + i;
+            if self.{storage}[bit / 8] & (1 << (bit % 8)) != 0 {{
+                val |= 1 << i;
+            }}
+        }}"#,
+                name = escape_ident(member.name),
+                ty = field_ty,
+                bits = bits,
+                start = start,
+                storage = storage,
+            )?;
+            if signed && bits < 64 {
+                // Sign extend
+                writeln!(
+                    def,
+                    r#"        if val & (1 << {sign_bit}) != 0 {{
+            val |= !0 << {bits};
+        }}"#,
+                    sign_bit = bits - 1,
+                    bits = bits,
+                )?;
+            }
+            if field_ty == "bool" {
+                writeln!(def, r#"        val != 0"#)?;
+            } else {
+                writeln!(def, r#"        val as {ty}"#, ty = field_ty)?;
+            }
+            writeln!(
+                def,
+                r#"    }}
+    pub fn set_{name}(&mut self, val: {ty}) {{
+        let val = val as u64;
+        for i in 0..{bits} {{
+            let bit = {start} + i;
+            if val & (1 << i) != 0 {{
+                self.{storage}[bit / 8] |= 1 << (bit % 8);
+            }} else {{
+                self.{storage}[bit / 8] &= !(1 << (bit % 8));
+            }}
+        }}
+    }}"#,
+                name = member.name,
+                ty = field_ty,
+                bits = bits,
+                start = start,
+                storage = storage,
+            )?;
+        }
+
+        writeln!(def, "}}")?;
+
+        Ok(())
+    }
+
     fn is_struct_packed(&self, struct_type_id: u32, t: &BtfComposite) -> Result<bool> {
         if !t.is_struct {
             return Ok(false);
@@ -255,7 +379,7 @@ impl<'a> Btf<'a> {
                 m.type_id
             );
 
-            if m.bit_size == 0 && m.bit_offset % (align * 8) != 0 {
+            if !Self::is_bitfield(m) && m.bit_offset % (align * 8) != 0 {
                 return Ok(true);
             }
         }
@@ -306,10 +430,64 @@ impl<'a> Btf<'a> {
         }
     }
 
+    /// Returns the C spelling of the type `type_id`, eg. `struct event *` or `char[16]`.
+    ///
+    /// Anonymous types are spelled `struct <anon>`, function prototypes `<func_proto>`.
+    pub fn c_type_name(&self, type_id: u32) -> Result<String> {
+        let or_anon = |name: &str| {
+            if name.is_empty() {
+                "<anon>".to_string()
+            } else {
+                name.to_string()
+            }
+        };
+
+        Ok(match self.type_by_id(type_id)? {
+            BtfType::Void => "void".to_string(),
+            BtfType::Int(t) => t.name.to_string(),
+            BtfType::Ptr(t) => format!("{} *", self.c_type_name(t.pointee_type)?),
+            BtfType::Array(t) => format!("{}[{}]", self.c_type_name(t.val_type_id)?, t.nelems),
+            BtfType::Struct(t) => format!("struct {}", or_anon(t.name)),
+            BtfType::Union(t) => format!("union {}", or_anon(t.name)),
+            BtfType::Enum(t) => format!("enum {}", or_anon(t.name)),
+            BtfType::Fwd(t) => match t.kind {
+                BtfFwdKind::Struct => format!("struct {}", t.name),
+                BtfFwdKind::Union => format!("union {}", t.name),
+            },
+            BtfType::Typedef(t) => t.name.to_string(),
+            BtfType::Volatile(t) => format!("volatile {}", self.c_type_name(t.type_id)?),
+            BtfType::Const(t) => format!("const {}", self.c_type_name(t.type_id)?),
+            BtfType::Restrict(t) => format!("{} restrict", self.c_type_name(t.type_id)?),
+            BtfType::Func(t) => t.name.to_string(),
+            BtfType::FuncProto(_) => "<func_proto>".to_string(),
+            BtfType::Var(t) => self.c_type_name(t.type_id)?,
+            BtfType::Datasec(t) => t.name.to_string(),
+        })
+    }
+
+    /// Returns the type ID of the struct, union, or enum named `name`, if one exists.
+    pub fn find_named_type(&self, name: &str) -> Option<u32> {
+        self.types
+            .iter()
+            .position(|ty| match ty {
+                BtfType::Struct(t) | BtfType::Union(t) => t.name == name,
+                BtfType::Enum(t) => t.name == name,
+                _ => false,
+            })
+            .map(|idx| idx as u32)
+    }
+
     /// Returns rust type definition of `ty` in string format, including dependent types.
     ///
     /// `ty` must be a struct, union, enum, or datasec type.
     pub fn type_definition(&self, type_id: u32) -> Result<String> {
+        self.type_definitions(&[type_id])
+    }
+
+    /// Same as [`Btf::type_definition`] except definitions for all of `type_ids` are returned.
+    ///
+    /// Dependent types shared between multiple `type_ids` are only defined once.
+    pub fn type_definitions(&self, type_ids: &[u32]) -> Result<String> {
         let is_terminal = |id| -> Result<bool> {
             match self.type_by_id(id)?.kind() {
                 BtfKind::Struct | BtfKind::Union | BtfKind::Enum | BtfKind::Datasec => Ok(false),
@@ -317,17 +495,19 @@ impl<'a> Btf<'a> {
             }
         };
 
-        ensure!(
-            !is_terminal(type_id)?,
-            "Tried to print type definition for terminal type"
-        );
+        for type_id in type_ids {
+            ensure!(
+                !is_terminal(*type_id)?,
+                "Tried to print type definition for terminal type"
+            );
+        }
 
         // Process dependent types until there are none left.
         //
         // When we hit a terminal, we write out some stuff. A non-terminal adds more types to
         // the queue.
         let mut def = String::new();
-        let mut dependent_types = vec![type_id];
+        let mut dependent_types = type_ids.to_vec();
         let mut processed = BTreeSet::new();
         while !dependent_types.is_empty() {
             let type_id = dependent_types.remove(0);
@@ -342,6 +522,7 @@ impl<'a> Btf<'a> {
             match ty {
                 BtfType::Struct(t) | BtfType::Union(t) => {
                     let packed = self.is_struct_packed(type_id, t)?;
+                    let name = Self::type_name(type_id, t.name);
 
                     let aggregate_type = if t.is_struct { "struct" } else { "union" };
                     let packed_repr = if packed { ", packed" } else { "" };
@@ -349,7 +530,8 @@ impl<'a> Btf<'a> {
                     if t.is_struct {
                         writeln!(def, r#"#[derive(Debug, Default, Copy, Clone)]"#)?;
                     } else {
-                        writeln!(def, r#"#[derive(Debug, Copy, Clone)]"#)?;
+                        // Debug and Default cannot be derived for unions
+                        writeln!(def, r#"#[derive(Copy, Clone)]"#)?;
                     }
 
                     writeln!(def, r#"#[repr(C{})]"#, packed_repr)?;
@@ -357,15 +539,75 @@ impl<'a> Btf<'a> {
                         def,
                         r#"pub {agg_type} {name} {{"#,
                         agg_type = aggregate_type,
-                        name = t.name,
+                        name = name,
                     )?;
 
+                    let mut bitfields = Vec::new();
+                    // `(field_name, byte_offset)` of each struct field other than bitfields and
+                    // padding
+                    let mut field_offsets = Vec::new();
+                    let mut has_bitfields = false;
                     let mut offset = 0; // In bytes
-                    for member in &t.members {
-                        ensure!(
-                            member.bit_size == 0 && member.bit_offset % 8 == 0,
-                            "Struct bitfields not supported"
-                        );
+                    let mut members = t.members.iter().enumerate().peekable();
+                    while let Some((idx, member)) = members.next() {
+                        if Self::is_bitfield(member) {
+                            ensure!(t.is_struct, "Union bitfields not supported");
+                            has_bitfields = true;
+
+                            // Adjacent bitfields share a single byte array for storage
+                            let storage_start = member.bit_offset / 8;
+                            let mut storage_end = member.bit_offset + self.bitfield_size(member)?;
+                            let mut run = vec![member];
+                            while let Some((_, next)) = members.peek() {
+                                if !Self::is_bitfield(next) {
+                                    break;
+                                }
+
+                                storage_end =
+                                    max(storage_end, next.bit_offset + self.bitfield_size(next)?);
+                                run.push(next);
+                                members.next();
+                            }
+                            let storage_len = (storage_end + 7) / 8 - storage_start;
+
+                            let padding = self.required_padding(
+                                offset,
+                                storage_start as usize,
+                                member.type_id,
+                                true,
+                            )?;
+                            if padding != 0 {
+                                writeln!(
+                                    def,
+                                    r#"    __pad_{offset}: [u8; {padding}],"#,
+                                    offset = offset,
+                                    padding = padding,
+                                )?;
+                            }
+
+                            let storage = format!("__bitfield_{}", storage_start);
+                            writeln!(
+                                def,
+                                r#"    {storage}: [u8; {len}],"#,
+                                storage = storage,
+                                len = storage_len,
+                            )?;
+
+                            offset = (storage_start + storage_len) as usize;
+
+                            for m in run {
+                                // Unnamed bitfields are only there for padding
+                                if !m.name.is_empty() {
+                                    bitfields.push((
+                                        storage.clone(),
+                                        m.bit_offset - storage_start * 8,
+                                        m,
+                                    ));
+                                }
+                            }
+
+                            continue;
+                        }
 
                         let field_ty_id = self.skip_mods_and_typedefs(member.type_id)?;
                         if !is_terminal(field_ty_id)? {
@@ -394,17 +636,94 @@ impl<'a> Btf<'a> {
                         // Set `offset` to end of current var
                         offset = ((member.bit_offset / 8) + self.size_of(field_ty_id)?) as usize;
 
+                        // Anonymous nested structs and unions do not have a member name
+                        let field_name = if member.name.is_empty() {
+                            format!("__anon_{}", idx)
+                        } else {
+                            escape_ident(member.name)
+                        };
+
                         writeln!(
                             def,
                             r#"    pub {field_name}: {field_ty_str},"#,
-                            field_name = member.name,
+                            field_name = field_name,
                             field_ty_str = self.type_declaration(field_ty_id)?,
                         )?;
+                        // Union fields all start at 0
+                        if t.is_struct {
+                            field_offsets.push((field_name, member.bit_offset / 8));
+                        }
+                    }
+
+                    // Add trailing padding as necessary. Bitfield storage is only byte aligned
+                    // so we cannot rely on the compiler to add trailing padding for us.
+                    if t.is_struct {
+                        let padding = self.required_padding(
+                            offset,
+                            t.size as usize,
+                            type_id,
+                            packed || has_bitfields,
+                        )?;
+                        if padding != 0 {
+                            writeln!(
+                                def,
+                                r#"    __pad_{offset}: [u8; {padding}],"#,
+                                offset = offset,
+                                padding = padding,
+                            )?;
+                        }
                     }
 
                     writeln!(def, "}}")?;
+
+                    if !t.is_struct {
+                        writeln!(
+                            def,
+                            r#"impl core::fmt::Debug for {name} {{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {{
+        write!(f, "(???)")
+    }}
+}}
+impl Default for {name} {{
+    fn default() -> Self {{
+        unsafe {{ core::mem::zeroed() }}
+    }}
+}}"#,
+                            name = name,
+                        )?;
+                    }
+
+                    if !bitfields.is_empty() {
+                        self.write_bitfield_accessors(&mut def, &name, &bitfields)?;
+                    }
+
+                    // Fail compilation if our layout does not match what BTF describes
+                    writeln!(
+                        def,
+                        r#"const _: [(); {size}] = [(); core::mem::size_of::<{name}>()];"#,
+                        size = t.size,
+                        name = name,
+                    )?;
+                    // `core::mem::offset_of!()` needs Rust 1.77, this works from libbpf-rs's
+                    // rust-version
+                    for (field_name, field_offset) in field_offsets {
+                        writeln!(
+                            def,
+                            r#"const _: [(); {offset}] = [(); unsafe {{
+    let base = core::mem::MaybeUninit::<{name}>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).{field}) as *const u8).offset_from(base as *const u8) as usize
+}}];"#,
+                            offset = field_offset,
+                            name = name,
+                            field = field_name,
+                        )?;
+                    }
                 }
                 BtfType::Enum(t) => {
+                    let name = Self::type_name(type_id, t.name);
+                    ensure!(!t.values.is_empty(), "Empty enum {} not supported", name);
+
                     let repr_size = match t.size {
                         1 => "8",
                         2 => "16",
@@ -429,9 +748,19 @@ impl<'a> Btf<'a> {
                         signed = signed,
                         repr_size = repr_size,
                     )?;
-                    writeln!(def, r#"pub enum {name} {{"#, name = t.name,)?;
+                    writeln!(def, r#"pub enum {name} {{"#, name = name)?;
 
+                    // Rust enums cannot have duplicate discriminants, so duplicates become
+                    // associated consts instead
+                    let mut variants = BTreeMap::new();
+                    let mut aliases = Vec::new();
                     for value in &t.values {
+                        if let Some(variant) = variants.get(&value.value) {
+                            aliases.push((value.name, *variant));
+                            continue;
+                        }
+                        variants.insert(value.value, value.name);
+
                         writeln!(
                             def,
                             r#"    {name} = {value},"#,
@@ -441,6 +770,31 @@ impl<'a> Btf<'a> {
                     }
 
                     writeln!(def, "}}")?;
+
+                    if !aliases.is_empty() {
+                        writeln!(def, r#"impl {} {{"#, name)?;
+                        for (alias, variant) in aliases {
+                            writeln!(
+                                def,
+                                r#"    pub const {alias}: {name} = {name}::{variant};"#,
+                                alias = alias,
+                                name = name,
+                                variant = variant,
+                            )?;
+                        }
+                        writeln!(def, "}}")?;
+                    }
+
+                    writeln!(
+                        def,
+                        r#"impl Default for {name} {{
+    fn default() -> Self {{
+        {name}::{variant}
+    }}
+}}"#,
+                        name = name,
+                        variant = t.values[0].name,
+                    )?;
                 }
                 BtfType::Datasec(t) => {
                     let mut sec_name = t.name.to_string();
@@ -453,8 +807,13 @@ impl<'a> Btf<'a> {
                     writeln!(def, r#"#[repr(C)]"#)?;
                     writeln!(def, r#"pub struct {} {{"#, sec_name,)?;
 
+                    // libbpf lays out some sections (eg `.kconfig`) itself, so vars are not
+                    // guaranteed to be in offset order
+                    let mut vars: Vec<_> = t.vars.iter().collect();
+                    vars.sort_by_key(|v| v.offset);
+
                     let mut offset: u32 = 0;
-                    for datasec_var in &t.vars {
+                    for datasec_var in vars {
                         let var = match self.type_by_id(datasec_var.type_id)? {
                             BtfType::Var(v) => {
                                 let stripped_var_type_id =
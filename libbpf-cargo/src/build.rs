@@ -1,14 +1,38 @@
+use std::cmp;
 use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
-use anyhow::{anyhow, bail, Result};
-use regex::Regex;
-use semver::Version;
+use anyhow::{anyhow, bail, Context, Result};
 
+use crate::clang;
 use crate::metadata;
 use crate::metadata::UnprocessedObj;
+use crate::report::{Diagnostic, Reporter};
+
+/// Returns the clang that all of `objs` agree on through their package metadata, if any
+fn metadata_clang(objs: &[UnprocessedObj]) -> Result<Option<&Path>> {
+    let mut clangs: Vec<&Path> = objs.iter().filter_map(|obj| obj.clang.as_deref()).collect();
+    clangs.sort();
+    clangs.dedup();
+
+    match clangs.as_slice() {
+        [] => Ok(None),
+        [clang] => Ok(Some(*clang)),
+        _ => bail!(
+            "Packages disagree on package.metadata.libbpf.clang: {}",
+            clangs
+                .iter()
+                .map(|c| c.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
 
 fn check_progs(objs: &[UnprocessedObj]) -> Result<()> {
     let mut set = HashSet::with_capacity(objs.len());
@@ -29,174 +53,261 @@ fn check_progs(objs: &[UnprocessedObj]) -> Result<()> {
     Ok(())
 }
 
-fn extract_version(output: &str) -> Result<&str> {
-    let re = Regex::new(r"clang\s+version\s+(?P<version_str>\d+\.\d+\.\d+)")?;
-    let captures = re
-        .captures(output)
-        .ok_or_else(|| anyhow!("Failed to run regex on version string"))?;
+/// Returns the prerequisites listed in a make style dependency file, as generated by
+/// `clang -MD`
+fn parse_dep_file(contents: &str) -> Vec<PathBuf> {
+    // Placeholder for escaped spaces so they survive splitting on whitespace
+    const SPACE: char = '\u{0}';
 
-    captures.name("version_str").map_or_else(
-        || Err(anyhow!("Failed to find version capture group")),
-        |v| Ok(v.as_str()),
-    )
+    let contents = contents
+        .replace("\\\n", " ")
+        .replace("\\ ", &SPACE.to_string())
+        .replace("$$", "$");
+
+    // Everything before the first ": " is the target
+    let prereqs = match contents.find(": ") {
+        Some(idx) => &contents[idx + 2..],
+        None => return Vec::new(),
+    };
+
+    prereqs
+        .split_whitespace()
+        .map(|p| PathBuf::from(p.replace(SPACE, " ")))
+        .collect()
 }
 
-fn check_clang(debug: bool, clang: &Path, skip_version_checks: bool) -> Result<()> {
-    let output = Command::new(clang.as_os_str()).arg("--version").output()?;
+/// Returns true if `dest` is newer than everything `dep_file` says it was built from
+fn is_fresh(dest: &Path, dep_file: &Path) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+
+    let dest_modified = match modified(dest) {
+        Some(m) => m,
+        None => return false,
+    };
+    let deps = match fs::read_to_string(dep_file) {
+        Ok(c) => parse_dep_file(&c),
+        Err(_) => return false,
+    };
+
+    !deps.is_empty()
+        && deps
+            .iter()
+            .all(|dep| modified(dep).map_or(false, |m| m <= dest_modified))
+}
+
+/// Drop DWARF debug info from `obj` in place. BTF is not debug info as far as `llvm-strip` is
+/// concerned, so it is kept, along with everything else libbpf needs.
+fn strip(obj: &Path, llvm_strip: &Path) -> Result<()> {
+    let output = Command::new(llvm_strip.as_os_str())
+        .arg("-g")
+        .arg(obj.as_os_str())
+        .output()
+        .map_err(|e| {
+            anyhow!(
+                "Failed to run {}: {}. Use --no-strip to skip stripping",
+                llvm_strip.display(),
+                e
+            )
+        })?;
 
     if !output.status.success() {
-        bail!("Failed to execute clang binary");
+        bail!(
+            "Failed to strip obj={} with status={}\n \
+            stderr=\n \
+            {}\n",
+            obj.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
     }
 
-    if skip_version_checks {
+    Ok(())
+}
+
+/// We're essentially going to run:
+///
+///     clang -g -O2 -target bpf -c -D__TARGET_ARCH_$(ARCH) runqslower.bpf.c -o runqslower.bpf.o \
+///         -MD -MF runqslower.bpf.d
+///     llvm-strip -g runqslower.bpf.o
+///
+/// if the object is older than its source or any header it includes.
+fn compile_one(
+    reporter: &dyn Reporter,
+    obj: &UnprocessedObj,
+    clang: &Path,
+    arch: &str,
+    llvm_strip: Option<&Path>,
+) -> Result<()> {
+    let dest_name = if let Some(f) = obj.path.as_path().file_stem() {
+        let mut stem = f.to_os_string();
+        stem.push(".o");
+        stem
+    } else {
+        bail!(
+            "Could not calculate destination name for obj={}",
+            obj.path.as_path().display()
+        );
+    };
+    let mut dest_path = obj.out.clone();
+    dest_path.push(&dest_name);
+    let dep_path = dest_path.with_extension("d");
+
+    if is_fresh(&dest_path, &dep_path) {
+        reporter.report(Diagnostic::debug(format!(
+            "{} is up to date",
+            obj.path.display()
+        )));
         return Ok(());
     }
 
-    // Example output:
-    //
-    //     clang version 10.0.0
-    //     Target: x86_64-pc-linux-gnu
-    //     Thread model: posix
-    //     InstalledDir: /bin
-    //
-    let output = String::from_utf8_lossy(&output.stdout);
-    let version_str = extract_version(&output)?;
-    let version = Version::parse(version_str)?;
-    if debug {
-        println!("{} is version {}", clang.display(), version);
-    }
+    fs::create_dir_all(obj.out.as_path())?;
+
+    reporter.report(Diagnostic::debug(format!(
+        "Building {}",
+        obj.path.display()
+    )));
 
-    if version < Version::parse("10.0.0").unwrap() {
+    let output = Command::new(clang.as_os_str())
+        .arg("-g")
+        .arg("-O2")
+        .arg("-target")
+        .arg("bpf")
+        .arg("-c")
+        .arg(format!("-D__TARGET_ARCH_{}", arch))
+        .arg(obj.path.as_path().as_os_str())
+        .arg("-o")
+        .arg(&dest_path)
+        .arg("-MD")
+        .arg("-MF")
+        .arg(dep_path)
+        .output()?;
+
+    if !output.status.success() {
         bail!(
-            "version {} is too old. Use --skip-clang-version-checks to skip version check",
-            version
-        );
+            "Failed to compile obj={} with status={}\n \
+            stdout=\n \
+            {}\n \
+            stderr=\n \
+            {}\n",
+            dest_name.to_string_lossy(),
+            output.status,
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap()
+        )
+    }
+
+    if let Some(llvm_strip) = llvm_strip {
+        strip(&dest_path, llvm_strip)?;
     }
 
     Ok(())
 }
 
-/// We're essentially going to run:
-///
-///     clang -g -O2 -target bpf -c -D__TARGET_ARCH_$(ARCH) runqslower.bpf.c -o runqslower.bpf.o
-///
-/// for each prog.
-fn compile(debug: bool, objs: &[UnprocessedObj], clang: &Path) -> Result<()> {
+/// Compile `objs` on up to `jobs` threads. Every object is attempted even if some fail, and
+/// all failures are reported together.
+pub(crate) fn compile(
+    reporter: &dyn Reporter,
+    objs: &[UnprocessedObj],
+    clang: &Path,
+    jobs: usize,
+    llvm_strip: Option<&Path>,
+) -> Result<()> {
     let arch = if std::env::consts::ARCH == "x86_64" {
         "x86"
     } else {
         std::env::consts::ARCH
     };
 
-    for obj in objs {
-        let dest_name = if let Some(f) = obj.path.as_path().file_stem() {
-            let mut stem = f.to_os_string();
-            stem.push(".o");
-            stem
-        } else {
-            bail!(
-                "Could not calculate destination name for obj={}",
-                obj.path.as_path().display()
-            );
-        };
-        let mut dest_path = obj.out.clone();
-        dest_path.push(&dest_name);
+    let next = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
 
-        fs::create_dir_all(obj.out.as_path())?;
+    thread::scope(|s| {
+        for _ in 0..cmp::min(cmp::max(jobs, 1), objs.len()) {
+            s.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let obj = match objs.get(idx) {
+                    Some(obj) => obj,
+                    None => break,
+                };
 
-        if debug {
-            println!("Building {}", obj.path.display());
+                if let Err(e) = compile_one(reporter, obj, clang, arch, llvm_strip) {
+                    failures.lock().unwrap().push((idx, e));
+                }
+            });
         }
+    });
 
-        let output = Command::new(clang.as_os_str())
-            .arg("-g")
-            .arg("-O2")
-            .arg("-target")
-            .arg("bpf")
-            .arg("-c")
-            .arg(format!("-D__TARGET_ARCH_{}", arch))
-            .arg(obj.path.as_path().as_os_str())
-            .arg("-o")
-            .arg(dest_path)
-            .output()?;
-
-        if !output.status.success() {
-            bail!(
-                "Failed to compile obj={} with status={}\n \
-                stdout=\n \
-                {}\n \
-                stderr=\n \
-                {}\n",
-                dest_name.to_string_lossy(),
-                output.status,
-                String::from_utf8(output.stdout).unwrap(),
-                String::from_utf8(output.stderr).unwrap()
-            )
-        }
+    let mut failures = failures.into_inner().unwrap();
+    if failures.is_empty() {
+        return Ok(());
     }
 
-    Ok(())
+    // Report in a stable order regardless of which thread finished first
+    failures.sort_by_key(|(idx, _)| *idx);
+    bail!(
+        "{} of {} objects failed:\n{}",
+        failures.len(),
+        objs.len(),
+        failures
+            .iter()
+            .map(|(_, e)| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
 }
 
+/// Compile the BPF programs of the project at `manifest_path`, as `cargo libbpf build` does.
+///
+/// `clang` takes precedence over the other ways of picking clang documented for `cargo libbpf
+/// build`. Objects are stripped with `llvm_strip` if set.
 pub fn build(
-    debug: bool,
+    reporter: &dyn Reporter,
     manifest_path: Option<&PathBuf>,
-    clang: &Path,
+    clang: Option<&Path>,
     skip_clang_version_checks: bool,
-) -> i32 {
-    let to_compile = match metadata::get(debug, manifest_path) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("{}", e);
-            return 1;
-        }
-    };
-
-    if debug && !to_compile.is_empty() {
-        println!("Found bpf progs to compile:");
-        for obj in &to_compile {
-            println!("\t{:?}", obj);
-        }
-    } else if to_compile.is_empty() {
-        eprintln!("Did not find any bpf progs to compile");
-        return 1;
+    jobs: Option<usize>,
+    llvm_strip: Option<&Path>,
+) -> Result<()> {
+    let to_compile = metadata::get(reporter, manifest_path)?;
+    if to_compile.is_empty() {
+        bail!("Did not find any bpf progs to compile");
     }
+    reporter.report(Diagnostic::debug(format!(
+        "Found bpf progs to compile:\n{}",
+        to_compile
+            .iter()
+            .map(|obj| format!("\t{:?}", obj))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )));
 
-    if let Err(e) = check_progs(&to_compile) {
-        eprintln!("{}", e);
-        return 1;
-    }
+    check_progs(&to_compile)?;
 
-    if let Err(e) = check_clang(debug, clang, skip_clang_version_checks) {
-        eprintln!("{} is invalid: {}", clang.display(), e);
-        return 1;
-    }
+    let clang = clang::find(
+        reporter,
+        clang,
+        metadata_clang(&to_compile)?,
+        skip_clang_version_checks,
+    )?;
 
-    match compile(debug, &to_compile, clang) {
-        Ok(_) => 0,
-        Err(e) => {
-            eprintln!("Failed to compile progs: {}", e);
-            1
-        }
-    }
+    // Default to one job per core, like cargo
+    let jobs = jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+
+    compile(reporter, &to_compile, &clang, jobs, llvm_strip).context("Failed to compile progs")
 }
 
 #[test]
-fn test_extract_version() {
-    let upstream_format = r"clang version 10.0.0
-Target: x86_64-pc-linux-gnu
-Thread model: posix
-InstalledDir: /bin
-";
-    assert_eq!(extract_version(upstream_format).unwrap(), "10.0.0");
-
-    let ubuntu_format = r"Ubuntu clang version 11.0.1-++20201121072624+973b95e0a84-1~exp1~20201121063303.19
-Target: x86_64-pc-linux-gnu
-Thread model: posix
-InstalledDir: /bin
-";
-    assert_eq!(extract_version(ubuntu_format).unwrap(), "11.0.1");
-
-    assert!(extract_version("askldfjwe").is_err());
-    assert!(extract_version("my clang version 1.5").is_err());
+fn test_parse_dep_file() {
+    let deps = "/tmp/target/bpf/prog.bpf.o: /tmp/src/bpf/prog.bpf.c \\
+  /tmp/src/bpf/vmlinux.h /tmp/src/bpf/my\\ dir/bpf_helpers.h\n";
+    assert_eq!(
+        parse_dep_file(deps),
+        vec![
+            PathBuf::from("/tmp/src/bpf/prog.bpf.c"),
+            PathBuf::from("/tmp/src/bpf/vmlinux.h"),
+            PathBuf::from("/tmp/src/bpf/my dir/bpf_helpers.h"),
+        ]
+    );
+
+    assert!(parse_dep_file("").is_empty());
 }
@@ -0,0 +1,431 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use goblin::elf::{header, section_header, Elf};
+use memmap::Mmap;
+
+use crate::gen;
+use crate::report::{Diagnostic, Level, Reporter};
+
+/// Local storage map types the bundled libbpf-sys predates
+const BPF_MAP_TYPE_INODE_STORAGE: u32 = 28;
+const BPF_MAP_TYPE_TASK_STORAGE: u32 = 29;
+const BPF_MAP_TYPE_CGRP_STORAGE: u32 = 32;
+
+/// Map types that may be declared without `max_entries`, because the kernel does not use it or
+/// libbpf fills it in
+const NO_MAX_ENTRIES_MAP_TYPES: &[u32] = &[
+    libbpf_sys::BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+    libbpf_sys::BPF_MAP_TYPE_CGROUP_STORAGE,
+    libbpf_sys::BPF_MAP_TYPE_PERCPU_CGROUP_STORAGE,
+    libbpf_sys::BPF_MAP_TYPE_SK_STORAGE,
+    BPF_MAP_TYPE_INODE_STORAGE,
+    BPF_MAP_TYPE_TASK_STORAGE,
+    BPF_MAP_TYPE_CGRP_STORAGE,
+    libbpf_sys::BPF_MAP_TYPE_STRUCT_OPS,
+];
+
+/// Map types indexed by a `u32`
+const ARRAY_MAP_TYPES: &[u32] = &[
+    libbpf_sys::BPF_MAP_TYPE_ARRAY,
+    libbpf_sys::BPF_MAP_TYPE_PERCPU_ARRAY,
+    libbpf_sys::BPF_MAP_TYPE_PROG_ARRAY,
+    libbpf_sys::BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+    libbpf_sys::BPF_MAP_TYPE_CGROUP_ARRAY,
+    libbpf_sys::BPF_MAP_TYPE_ARRAY_OF_MAPS,
+];
+
+/// How bad a [`Problem`] is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The object loads, but probably not as intended
+    Warning,
+    /// The object will fail to open or load
+    Error,
+}
+
+/// A mistake found in an object by [`check_object()`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Problem {
+    fn error<T: Into<String>>(message: T) -> Self {
+        Problem {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+
+    fn warning<T: Into<String>>(message: T) -> Self {
+        Problem {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Returns the name of every section of `elf` and its header
+fn sections<'a>(elf: &'a Elf) -> Vec<(&'a str, &'a section_header::SectionHeader)> {
+    elf.section_headers
+        .iter()
+        .filter_map(|sh| match elf.shdr_strtab.get(sh.sh_name) {
+            Some(Ok(name)) => Some((name, sh)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Checks the ELF layout of `object`: that it is a relocatable BPF object for this host, and that
+/// its sections are named and laid out the way libbpf expects
+fn check_elf(object: &[u8]) -> Vec<Problem> {
+    let elf = match Elf::parse(object) {
+        Ok(elf) => elf,
+        Err(e) => return vec![Problem::error(format!("not an ELF file: {}", e))],
+    };
+
+    if elf.header.e_machine != header::EM_BPF {
+        return vec![Problem::error(format!(
+            "not a BPF object, machine is {}",
+            header::machine_to_str(elf.header.e_machine)
+        ))];
+    }
+
+    let mut problems = Vec::new();
+    if elf.header.e_type != header::ET_REL {
+        problems.push(Problem::error(
+            "not a relocatable object, BPF objects must not be linked",
+        ));
+    }
+    if !elf.is_64 {
+        problems.push(Problem::error("not a 64-bit object"));
+    }
+    if elf.little_endian != cfg!(target_endian = "little") {
+        let (obj, host) = if elf.little_endian {
+            ("little", "big")
+        } else {
+            ("big", "little")
+        };
+        problems.push(Problem::warning(format!(
+            "object is {}-endian but this host is {}-endian, so it can't be loaded here",
+            obj, host
+        )));
+    }
+
+    let sections = sections(&elf);
+    let has_section = |name: &str| sections.iter().any(|(n, _)| *n == name);
+
+    if !has_section(".BTF") {
+        if has_section(".maps") {
+            problems.push(Problem::error(
+                "maps in .maps are defined through BTF, but the object has no .BTF section (built without -g?)",
+            ));
+        } else {
+            problems.push(Problem::warning(
+                "no .BTF section, so CO-RE, BTF-defined maps and skeleton datasec and type definitions are unavailable (built without -g?)",
+            ));
+        }
+    }
+
+    match sections.iter().find(|(n, _)| *n == "license") {
+        Some((_, sh)) => {
+            let start = sh.sh_offset as usize;
+            let data = object.get(start..start + sh.sh_size as usize);
+            match data.and_then(|d| d.iter().position(|&b| b == 0)) {
+                Some(0) => problems.push(Problem::error("license is empty")),
+                Some(_) => (),
+                None => problems.push(Problem::error(
+                    "license section is not a NUL-terminated string",
+                )),
+            }
+        }
+        None => problems.push(Problem::warning(
+            "no license section, so programs can't use GPL-only helpers. Declare one with `char LICENSE[] SEC(\"license\") = \"GPL\";`",
+        )),
+    }
+
+    for (name, sh) in &sections {
+        let is_prog = sh.sh_flags & u64::from(section_header::SHF_EXECINSTR) != 0;
+        // `.text` holds subprograms, which take the type of the programs calling them
+        if !is_prog || sh.sh_size == 0 || *name == ".text" {
+            continue;
+        }
+
+        let cname = match CString::new(*name) {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let mut prog_type = 0;
+        let mut attach_type = 0;
+        let ret = unsafe {
+            libbpf_sys::libbpf_prog_type_by_name(cname.as_ptr(), &mut prog_type, &mut attach_type)
+        };
+        // Loading such a section is up to a handler the application registers
+        if ret != 0 {
+            problems.push(Problem::warning(format!(
+                "section {} does not name a program type this libbpf knows, eg. kprobe/<FUNC> or tracepoint/<CATEGORY>/<NAME>, so loading it needs a handler registered with `ObjectBuilder::section_handler()`",
+                name
+            )));
+        }
+    }
+
+    problems
+}
+
+/// Checks a map definition as libbpf parsed it against what the kernel accepts
+fn check_map_def(name: &str, def: &libbpf_sys::bpf_map_def) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    if def.type_ == libbpf_sys::BPF_MAP_TYPE_UNSPEC {
+        problems.push(Problem::error(format!("map {} has no type", name)));
+        return problems;
+    }
+    if def.type_ > gen::BPF_MAP_TYPE_ARENA {
+        // Up to the kernel to decide, libbpf-rs may just be out of date
+        problems.push(Problem::warning(format!(
+            "map {} has type {}, which libbpf-rs does not know",
+            name, def.type_
+        )));
+        return problems;
+    }
+
+    if def.max_entries == 0 && !NO_MAX_ENTRIES_MAP_TYPES.contains(&def.type_) {
+        problems.push(Problem::error(format!("map {} has no max_entries", name)));
+    }
+
+    if ARRAY_MAP_TYPES.contains(&def.type_) && def.key_size != 4 {
+        problems.push(Problem::error(format!(
+            "map {} is an array, so its key must be 4 bytes, not {}",
+            name, def.key_size
+        )));
+    }
+
+    match def.type_ {
+        libbpf_sys::BPF_MAP_TYPE_RINGBUF => {
+            if def.key_size != 0 || def.value_size != 0 {
+                problems.push(Problem::error(format!(
+                    "map {} is a ringbuf, so it must not have a key or value",
+                    name
+                )));
+            }
+            if def.max_entries != 0
+                && (!def.max_entries.is_power_of_two() || def.max_entries < 4096)
+            {
+                problems.push(Problem::error(format!(
+                    "map {} is a ringbuf, so max_entries must be a power of 2 multiple of the page size, not {}",
+                    name, def.max_entries
+                )));
+            }
+        }
+        gen::BPF_MAP_TYPE_ARENA => {
+            if def.key_size != 0 || def.value_size != 0 {
+                problems.push(Problem::error(format!(
+                    "map {} is an arena, so it must not have a key or value",
+                    name
+                )));
+            }
+            if def.map_flags & libbpf_sys::BPF_F_MMAPABLE == 0 {
+                problems.push(Problem::error(format!(
+                    "map {} is an arena, so it must have the BPF_F_MMAPABLE flag",
+                    name
+                )));
+            }
+        }
+        libbpf_sys::BPF_MAP_TYPE_QUEUE | libbpf_sys::BPF_MAP_TYPE_STACK => {
+            if def.key_size != 0 {
+                problems.push(Problem::error(format!(
+                    "map {} is a queue or stack, so it must not have a key",
+                    name
+                )));
+            }
+        }
+        _ => (),
+    }
+
+    problems
+}
+
+/// Checks the object `obj_file` named `name` for mistakes that would otherwise only show when it
+/// is opened or loaded, eg. on a target machine.
+///
+/// Checks that `obj_file` is a relocatable 64-bit BPF ELF object of this host's endianness, that
+/// it has BTF and a license, that its program sections name program types known to libbpf, and
+/// that libbpf can parse its map definitions and they are valid for their map types.
+pub fn check_object(name: &str, obj_file: &Path) -> Result<Vec<Problem>> {
+    let file = File::open(obj_file)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+
+    let mut problems = check_elf(&*mmap);
+    if problems.iter().any(|p| p.severity == Severity::Error) {
+        // libbpf would only repeat the errors, if it doesn't crash on them
+        return Ok(problems);
+    }
+
+    let object = match gen::open_bpf_object(&format!("{}_bpf", name), &*mmap) {
+        Ok(object) => object,
+        Err(e) => {
+            problems.push(Problem::error(format!("libbpf can't open object: {}", e)));
+            return Ok(problems);
+        }
+    };
+
+    for map in gen::MapIter::new(object) {
+        if unsafe { libbpf_sys::bpf_map__is_internal(map) } {
+            continue;
+        }
+
+        let map_name = gen::get_raw_map_name(map)?;
+        let def = unsafe { &*libbpf_sys::bpf_map__def(map) };
+        problems.append(&mut check_map_def(&map_name, def));
+    }
+    unsafe { libbpf_sys::bpf_object__close(object) };
+
+    Ok(problems)
+}
+
+/// Checks each object in `objects`, or in the project at `manifest_path` if `objects` is empty,
+/// as `cargo libbpf check` does, and reports the problems found. Fails if any object has errors,
+/// or warnings if `deny_warnings` is set.
+pub fn check(
+    reporter: &dyn Reporter,
+    manifest_path: Option<&PathBuf>,
+    objects: &[PathBuf],
+    deny_warnings: bool,
+) -> Result<()> {
+    let objs = gen::named_objects(reporter, manifest_path, objects)?;
+    if objs.is_empty() {
+        bail!("Did not find any bpf objects to check");
+    }
+
+    let mut failed = 0;
+    for (name, obj_file) in &objs {
+        let problems = match check_object(name, obj_file) {
+            Ok(problems) => problems,
+            Err(e) => {
+                reporter.report(Diagnostic::error(format!(
+                    "Failed to check {}: {}",
+                    obj_file.display(),
+                    e
+                )));
+                failed += 1;
+                continue;
+            }
+        };
+
+        if problems.is_empty() {
+            reporter.report(Diagnostic::debug(format!("{}: ok", obj_file.display())));
+        }
+        let mut obj_failed = false;
+        for problem in &problems {
+            let level = match problem.severity {
+                Severity::Warning => Level::Warning,
+                Severity::Error => Level::Error,
+            };
+            reporter.report(Diagnostic::new(
+                level,
+                format!("{}: {}", obj_file.display(), problem.message),
+            ));
+            obj_failed |= problem.severity == Severity::Error || deny_warnings;
+        }
+        if obj_failed {
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of {} objects failed checks", failed, objs.len());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_elf_not_bpf() {
+    let problems = check_elf(b"not an object");
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].severity, Severity::Error);
+
+    // The test binary is an ELF file, but not a BPF one
+    let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+    let problems = check_elf(&exe);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].message.starts_with("not a BPF object"));
+}
+
+#[test]
+fn test_check_map_def() {
+    let def = |type_, key_size, value_size, max_entries| libbpf_sys::bpf_map_def {
+        type_,
+        key_size,
+        value_size,
+        max_entries,
+        map_flags: 0,
+    };
+    let arena = |max_entries| libbpf_sys::bpf_map_def {
+        map_flags: libbpf_sys::BPF_F_MMAPABLE,
+        ..def(gen::BPF_MAP_TYPE_ARENA, 0, 0, max_entries)
+    };
+
+    assert!(check_map_def("m", &def(libbpf_sys::BPF_MAP_TYPE_HASH, 4, 8, 64)).is_empty());
+    assert!(check_map_def(
+        "m",
+        &def(libbpf_sys::BPF_MAP_TYPE_PERF_EVENT_ARRAY, 4, 4, 0)
+    )
+    .is_empty());
+    assert!(check_map_def("m", &def(libbpf_sys::BPF_MAP_TYPE_RINGBUF, 0, 0, 1 << 16)).is_empty());
+    assert!(check_map_def("m", &arena(16)).is_empty());
+    for type_ in &[
+        libbpf_sys::BPF_MAP_TYPE_SK_STORAGE,
+        BPF_MAP_TYPE_INODE_STORAGE,
+        BPF_MAP_TYPE_TASK_STORAGE,
+        BPF_MAP_TYPE_CGRP_STORAGE,
+    ] {
+        assert!(check_map_def("m", &def(*type_, 4, 8, 0)).is_empty());
+    }
+
+    let severities = |def| {
+        check_map_def("m", &def)
+            .into_iter()
+            .map(|p| p.severity)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        severities(def(libbpf_sys::BPF_MAP_TYPE_UNSPEC, 4, 8, 64)),
+        vec![Severity::Error]
+    );
+    assert_eq!(
+        severities(def(libbpf_sys::BPF_MAP_TYPE_HASH, 4, 8, 0)),
+        vec![Severity::Error]
+    );
+    assert_eq!(
+        severities(def(libbpf_sys::BPF_MAP_TYPE_ARRAY, 8, 8, 64)),
+        vec![Severity::Error]
+    );
+    assert_eq!(
+        severities(def(libbpf_sys::BPF_MAP_TYPE_RINGBUF, 0, 0, 1000)),
+        vec![Severity::Error]
+    );
+    assert_eq!(severities(arena(0)), vec![Severity::Error]);
+    assert_eq!(
+        severities(def(gen::BPF_MAP_TYPE_ARENA, 0, 0, 16)),
+        vec![Severity::Error]
+    );
+    assert_eq!(
+        severities(def(libbpf_sys::BPF_MAP_TYPE_RINGBUF + 100, 4, 8, 64)),
+        vec![Severity::Warning]
+    );
+}
@@ -0,0 +1,253 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Result};
+use regex::Regex;
+use semver::Version;
+
+use crate::report::{Diagnostic, Reporter};
+
+/// Environment variable overriding which clang to use
+const CLANG_ENV: &str = "CLANG";
+
+/// Directories distros install versioned LLVM toolchains into, outside of `PATH`
+const LLVM_GLOBS: &[&str] = &[
+    "/usr/lib/llvm-*/bin/clang",
+    "/usr/lib/llvm/*/bin/clang",
+    "/usr/lib64/llvm*/bin/clang",
+];
+
+fn extract_version(output: &str) -> Result<&str> {
+    let re = Regex::new(r"clang\s+version\s+(?P<version_str>\d+\.\d+\.\d+)")?;
+    let captures = re
+        .captures(output)
+        .ok_or_else(|| anyhow!("Failed to run regex on version string"))?;
+
+    captures.name("version_str").map_or_else(
+        || Err(anyhow!("Failed to find version capture group")),
+        |v| Ok(v.as_str()),
+    )
+}
+
+/// Check that `clang` runs, is recent enough and can compile for `-target bpf`
+fn check_clang(reporter: &dyn Reporter, clang: &Path, skip_version_checks: bool) -> Result<()> {
+    let output = Command::new(clang.as_os_str())
+        .arg("--version")
+        .output()
+        .map_err(|e| anyhow!("failed to execute: {}", e))?;
+
+    if !output.status.success() {
+        bail!("Failed to execute clang binary");
+    }
+
+    if !skip_version_checks {
+        // Example output:
+        //
+        //     clang version 10.0.0
+        //     Target: x86_64-pc-linux-gnu
+        //     Thread model: posix
+        //     InstalledDir: /bin
+        //
+        let output = String::from_utf8_lossy(&output.stdout);
+        let version_str = extract_version(&output)?;
+        let version = Version::parse(version_str)?;
+        reporter.report(Diagnostic::debug(format!(
+            "{} is version {}",
+            clang.display(),
+            version
+        )));
+
+        if version < Version::parse("10.0.0").unwrap() {
+            bail!(
+                "version {} is too old. Use --skip-clang-version-checks to skip version check",
+                version
+            );
+        }
+    }
+
+    // Some distro builds leave out the BPF backend
+    let status = Command::new(clang.as_os_str())
+        .arg("-target")
+        .arg("bpf")
+        .arg("-x")
+        .arg("c")
+        .arg("-c")
+        .arg("/dev/null")
+        .arg("-o")
+        .arg("/dev/null")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() {
+        bail!("does not support -target bpf");
+    }
+
+    Ok(())
+}
+
+/// Returns `Some(N)` for `clang-N`, `Some(0)` for plain `clang`, and `None` for anything else
+fn clang_version_suffix(name: &str) -> Option<u32> {
+    if name == "clang" {
+        return Some(0);
+    }
+
+    name.strip_prefix("clang-")?.parse().ok()
+}
+
+/// Returns the clangs found in `path` and `llvm_dirs`, best first: plain `clang` in `path`
+/// order, then `clang-N` from the newest, then the versioned LLVM installs
+fn candidates(path: &[PathBuf], llvm_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut versioned = Vec::new();
+    let mut plain = Vec::new();
+
+    for dir in path {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        let mut found: Vec<(u32, PathBuf)> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let suffix = clang_version_suffix(path.file_name()?.to_str()?)?;
+                Some((suffix, path))
+            })
+            .collect();
+        found.sort();
+
+        for (suffix, path) in found {
+            if suffix == 0 {
+                plain.push(path);
+            } else {
+                versioned.push((suffix, path));
+            }
+        }
+    }
+
+    // Stable, so ties keep `PATH` order
+    versioned.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    let mut seen = HashSet::new();
+    plain
+        .into_iter()
+        .chain(versioned.into_iter().map(|(_, path)| path))
+        .chain(llvm_dirs.iter().cloned())
+        .filter(|path| seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())))
+        .collect()
+}
+
+/// Pick the clang to build with.
+///
+/// In order of precedence, that is `explicit` (eg. `--clang-path`), `$CLANG`, the
+/// `package.metadata.libbpf.clang` of the packages being built, and finally the first suitable
+/// clang found in `PATH` or in common LLVM install locations.
+pub fn find(
+    reporter: &dyn Reporter,
+    explicit: Option<&Path>,
+    metadata: Option<&Path>,
+    skip_version_checks: bool,
+) -> Result<PathBuf> {
+    let env_clang = env::var_os(CLANG_ENV).map(PathBuf::from);
+    let chosen = explicit
+        .map(|p| (p.to_path_buf(), "--clang-path"))
+        .or_else(|| env_clang.map(|p| (p, "$CLANG")))
+        .or_else(|| metadata.map(|p| (p.to_path_buf(), "package.metadata.libbpf.clang")));
+
+    if let Some((clang, source)) = chosen {
+        if let Err(e) = check_clang(reporter, &clang, skip_version_checks) {
+            bail!("{} (from {}) is invalid: {}", clang.display(), source, e);
+        }
+        return Ok(clang);
+    }
+
+    let path: Vec<PathBuf> = env::var_os("PATH")
+        .map(|p| env::split_paths(&p).collect())
+        .unwrap_or_default();
+    let mut llvm_dirs = Vec::new();
+    for pattern in LLVM_GLOBS {
+        let mut matches: Vec<PathBuf> = glob::glob(pattern)?.filter_map(|p| p.ok()).collect();
+        // Newest install first, assuming versions sort like their directories
+        matches.sort_by(|a, b| b.cmp(a));
+        llvm_dirs.extend(matches);
+    }
+
+    let mut rejected = Vec::new();
+    for clang in candidates(&path, &llvm_dirs) {
+        match check_clang(reporter, &clang, skip_version_checks) {
+            Ok(()) => {
+                reporter.report(Diagnostic::debug(format!("Using {}", clang.display())));
+                return Ok(clang);
+            }
+            Err(e) => rejected.push(format!("\t{}: {}", clang.display(), e)),
+        }
+    }
+
+    let found = if rejected.is_empty() {
+        "No clang found in PATH or in common LLVM install locations".to_string()
+    } else {
+        format!(
+            "No suitable clang found. Rejected:\n{}",
+            rejected.join("\n")
+        )
+    };
+    bail!(
+        "{}\nInstall clang >= 10 with BPF support, or select one with --clang-path, ${} or \
+        package.metadata.libbpf.clang",
+        found,
+        CLANG_ENV
+    )
+}
+
+#[test]
+fn test_extract_version() {
+    let upstream_format = r"clang version 10.0.0
+Target: x86_64-pc-linux-gnu
+Thread model: posix
+InstalledDir: /bin
+";
+    assert_eq!(extract_version(upstream_format).unwrap(), "10.0.0");
+
+    let ubuntu_format = r"Ubuntu clang version 11.0.1-++20201121072624+973b95e0a84-1~exp1~20201121063303.19
+Target: x86_64-pc-linux-gnu
+Thread model: posix
+InstalledDir: /bin
+";
+    assert_eq!(extract_version(ubuntu_format).unwrap(), "11.0.1");
+
+    assert!(extract_version("askldfjwe").is_err());
+    assert!(extract_version("my clang version 1.5").is_err());
+}
+
+#[test]
+fn test_candidates() {
+    let dir = tempfile::tempdir().expect("failed to create temp dir");
+    let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+    fs::create_dir(&first).expect("failed to create dir");
+    fs::create_dir(&second).expect("failed to create dir");
+    for path in &[
+        first.join("clang-11"),
+        first.join("clang-format"),
+        second.join("clang"),
+        second.join("clang-14"),
+    ] {
+        fs::File::create(path).expect("failed to create file");
+    }
+    let llvm = PathBuf::from("/usr/lib/llvm-9/bin/clang");
+
+    assert_eq!(
+        candidates(
+            &[first.clone(), second.clone()],
+            std::slice::from_ref(&llvm)
+        ),
+        vec![
+            second.join("clang"),
+            second.join("clang-14"),
+            first.join("clang-11"),
+            llvm
+        ]
+    );
+    assert!(candidates(&[dir.path().join("missing")], &[]).is_empty());
+}
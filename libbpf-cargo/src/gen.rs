@@ -1,35 +1,66 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
+use std::env;
 use std::ffi::{c_void, CStr, CString};
 use std::fmt::Write as fmt_write;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::ptr;
+use std::str::FromStr;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use memmap::Mmap;
+use serde::Serialize;
 
 use crate::btf;
 use crate::metadata;
 use crate::metadata::UnprocessedObj;
+use crate::report::{Diagnostic, Reporter};
+
+/// `BPF_MAP_TYPE_ARENA`, which the bundled libbpf-sys predates
+pub(crate) const BPF_MAP_TYPE_ARENA: u32 = 33;
+
+/// What `cargo libbpf gen` generates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Rust skeletons
+    Rust,
+    /// A JSON description of the objects, for tooling in other languages
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rust" => Ok(OutputFormat::Rust),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow!("Unknown format {}, expected rust or json", s)),
+        }
+    }
+}
 
+#[derive(Clone, Copy)]
 enum OutputDest<'a> {
     Stdout,
     /// Infer a filename and place file in specified directory
     Directory(&'a Path),
+    /// Infer a filename in specified directory and fail if the file is not up to date
+    Check(&'a Path),
 }
 
 macro_rules! gen_bpf_object_iter {
     ($name:ident, $iter_ty:ty, $next_fn:expr) => {
-        struct $name {
+        pub(crate) struct $name {
             obj: *mut libbpf_sys::bpf_object,
             last: *mut $iter_ty,
         }
 
         impl $name {
-            fn new(obj: *mut libbpf_sys::bpf_object) -> $name {
+            pub(crate) fn new(obj: *mut libbpf_sys::bpf_object) -> $name {
                 $name {
                     obj,
                     last: ptr::null_mut(),
@@ -60,40 +91,376 @@ gen_bpf_object_iter!(
     libbpf_sys::bpf_program__next
 );
 
-/// Run `rustfmt` over `s` and return result
-fn rustfmt(s: &str, rustfmt_path: Option<&PathBuf>) -> Result<String> {
-    let mut cmd = if let Some(r) = rustfmt_path {
-        Command::new(r)
-    } else {
-        Command::new("rustfmt")
+/// Where a line of generated code ends, if inside a string literal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StrState {
+    Code,
+    Str,
+    /// Raw string closed by `"` and this many `#`
+    RawStr(usize),
+}
+
+/// Scan `line` starting in `state`, and return the state at the end of the line.
+///
+/// `indents` has an entry for each bracket still open, telling whether it indents the lines
+/// after it. Only the first bracket a line leaves open does, so eg. `foo(|| {` indents by one
+/// level. Also returns by how many levels the closing brackets the line starts with dedent it.
+fn scan_line(line: &str, mut state: StrState, indents: &mut Vec<bool>) -> (StrState, usize) {
+    let b = line.as_bytes();
+    let is_ident = |i: usize| b[i].is_ascii_alphanumeric() || b[i] == b'_';
+    let line_start = indents.len();
+    let mut line_indents = false;
+    let mut dedent = 0;
+    let mut leading = state == StrState::Code;
+    let mut i = 0;
+    while i < b.len() {
+        match state {
+            StrState::Str => match b[i] {
+                b'\\' => i += 1,
+                b'"' => state = StrState::Code,
+                _ => (),
+            },
+            StrState::RawStr(hashes) => {
+                if b[i] == b'"' && b[i + 1..].iter().take_while(|&&c| c == b'#').count() >= hashes {
+                    state = StrState::Code;
+                    i += hashes;
+                }
+            }
+            StrState::Code => {
+                if !matches!(b[i], b'}' | b')' | b']') {
+                    leading = false;
+                }
+                match b[i] {
+                    b'/' if b.get(i + 1) == Some(&b'/') => break,
+                    b'"' => state = StrState::Str,
+                    b'r' if i == 0
+                        || !is_ident(i - 1)
+                        || (b[i - 1] == b'b' && (i == 1 || !is_ident(i - 2))) =>
+                    {
+                        let hashes = b[i + 1..].iter().take_while(|&&c| c == b'#').count();
+                        if b.get(i + 1 + hashes) == Some(&b'"') {
+                            state = StrState::RawStr(hashes);
+                            i += 1 + hashes;
+                        }
+                    }
+                    // Char literals, as opposed to lifetimes
+                    b'\'' if b.get(i + 1) == Some(&b'\\') => {
+                        i += 3;
+                        while i < b.len() && b[i] != b'\'' {
+                            i += 1;
+                        }
+                    }
+                    b'\'' if b.get(i + 2) == Some(&b'\'') => i += 2,
+                    b'{' | b'(' | b'[' => {
+                        indents.push(!line_indents);
+                        line_indents = true;
+                    }
+                    b'}' | b')' | b']' => {
+                        let indented = indents.pop().unwrap_or(false);
+                        if indents.len() < line_start {
+                            if leading && indented {
+                                dedent += 1;
+                            }
+                        } else if indented {
+                            line_indents = false;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        i += 1;
     }
-    .stdin(Stdio::piped())
-    .stdout(Stdio::piped())
-    .spawn()
-    .context("Failed to spawn rustfmt")?;
+
+    (state, dedent)
+}
+
+/// Indent `s` by bracket nesting, strip trailing whitespace and collapse runs of blank lines.
+///
+/// Generated code is assembled from snippets indented however suits the generator. This gives
+/// it a canonical layout that doesn't depend on whether `rustfmt` is installed, so the same
+/// objects always generate the same skeletons.
+fn indent(s: &str) -> String {
+    let mut out = String::new();
+    let mut state = StrState::Code;
+    let mut indents = Vec::new();
+    let mut blank = true;
+    for line in s.lines() {
+        // Lines continuing a string literal are part of its value
+        if state != StrState::Code {
+            state = scan_line(line, state, &mut indents).0;
+            out.push_str(line);
+            out.push('\n');
+            blank = false;
+            continue;
+        }
+
+        let level = indents.iter().filter(|&&indented| indented).count();
+        let trimmed = line.trim_start();
+        let (end, dedent) = scan_line(trimmed, state, &mut indents);
+        let trimmed = if end == StrState::Code {
+            trimmed.trim_end()
+        } else {
+            trimmed
+        };
+        state = end;
+        if trimmed.is_empty() {
+            if !blank {
+                out.push('\n');
+                blank = true;
+            }
+            continue;
+        }
+
+        out.push_str(&"    ".repeat(level.saturating_sub(dedent)));
+        out.push_str(trimmed);
+        out.push('\n');
+        blank = false;
+    }
+
+    let len = out.trim_end().len();
+    out.truncate(len);
+    out.push('\n');
+    out
+}
+
+/// Lay out generated code canonically with [`indent()`], then run the `rustfmt` at
+/// `rustfmt_path` over it if one is given.
+///
+/// `rustfmt` is only run when asked for, so the output doesn't depend on what is installed on
+/// the machine generating it.
+pub(crate) fn format_code(s: &str, rustfmt_path: Option<&PathBuf>) -> Result<String> {
+    let s = indent(s);
+    let rustfmt_path = match rustfmt_path {
+        Some(path) => path,
+        None => return Ok(s),
+    };
+
+    let mut cmd = Command::new(rustfmt_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn rustfmt")?;
     write!(cmd.stdin.take().unwrap(), "{}", s)?;
     let output = cmd
         .wait_with_output()
         .context("Failed to execute rustfmt")?;
+    if !output.status.success() {
+        bail!("rustfmt failed: {}", output.status);
+    }
 
     Ok(String::from_utf8(output.stdout)?)
 }
 
+/// Returns the lines that differ between `old` and `new`, ie. everything between their common
+/// prefix and suffix
+fn diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(o, n)| o == n)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(o, n)| o == n)
+        .count();
+
+    let mut out = format!("@@ line {} @@\n", prefix + 1);
+    for line in &old[prefix..old.len() - suffix] {
+        out += &format!("-{}\n", line);
+    }
+    for line in &new[prefix..new.len() - suffix] {
+        out += &format!("+{}\n", line);
+    }
+
+    out
+}
+
+/// Write `contents` to `path`. If `check` is set, instead fail if `path` does not already
+/// contain `contents`.
+///
+/// Files that already contain `contents` are left untouched so cargo does not consider them
+/// changed.
+fn write_or_check(path: &Path, contents: &str, check: bool) -> Result<()> {
+    if !check {
+        if fs::read_to_string(path).map_or(false, |existing| existing == contents) {
+            return Ok(());
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(contents.as_bytes())?;
+        return Ok(());
+    }
+
+    let existing =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if existing != contents {
+        bail!(
+            "{} is out of date:\n{}",
+            path.display(),
+            diff(&existing, contents)
+        );
+    }
+
+    Ok(())
+}
+
+/// Rust keywords, strict and reserved, in any edition
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Keywords that can't be used as raw identifiers
+const NON_RAW_KEYWORDS: &[&str] = &["_", "crate", "self", "Self", "super"];
+
+/// Prog names whose `attach_<PROG>()` method would clash with another skeleton method
+const RESERVED_PROG_NAMES: &[&str] = &["all", "filtered", "with_retry"];
+
+/// Prog names whose `<PROG>` field would clash with another field of the generated `Links`
+const RESERVED_LINK_NAMES: &[&str] = &["attach_order"];
+
+/// Turn an object name, eg. from a file name, into something usable in identifiers: characters
+/// that can't appear in identifiers become `_`, and a leading digit is prefixed with `_`
+fn sanitize_obj_name(s: &str) -> String {
+    let mut name: String = s
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.chars().next().map_or(true, |c| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+
+    name
+}
+
+/// Turn a C identifier, eg. a map, prog or struct member name, into a Rust identifier by escaping
+/// keywords. Keywords that can't be raw identifiers get a `_` suffix instead.
+pub(crate) fn escape_ident(s: &str) -> String {
+    if NON_RAW_KEYWORDS.contains(&s) {
+        format!("{}_", s)
+    } else if RUST_KEYWORDS.contains(&s) {
+        format!("r#{}", s)
+    } else {
+        s.to_string()
+    }
+}
+
+/// CamelCase an object name for use in type names, eg. `foo_bar-baz` -> `FooBarBaz`
 fn capitalize_first_letter(s: &str) -> String {
-    if s.is_empty() {
-        return "".to_string();
+    let name = sanitize_obj_name(s)
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .fold(String::new(), |mut acc, part| {
+            let mut chars = part.chars();
+            if let Some(first) = chars.next() {
+                acc.extend(first.to_uppercase());
+                acc.push_str(chars.as_str());
+            }
+            acc
+        });
+
+    if name.chars().next().map_or(true, |c| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
     }
+}
 
-    s.split('_').fold(String::new(), |mut acc, ts| {
-        acc += &ts.chars().next().unwrap().to_uppercase().to_string();
-        if ts.len() > 1 {
-            acc += &ts[1..];
+/// Fail if two maps or two progs of `object` end up with the same Rust identifier, or a prog
+/// name would clash with a generated method or field
+fn check_ident_collisions(object: *mut libbpf_sys::bpf_object) -> Result<()> {
+    let mut maps: BTreeMap<String, String> = BTreeMap::new();
+    for map in MapIter::new(object) {
+        let raw = get_raw_map_name(map)?;
+        let ident = match get_map_name(map)? {
+            Some(name) => escape_ident(&name),
+            None => continue,
+        };
+        if let Some(other) = maps.insert(ident.clone(), raw.clone()) {
+            bail!(
+                "Maps {} and {} both map to identifier {}",
+                other,
+                raw,
+                ident
+            );
         }
-        acc
-    })
+    }
+
+    let mut progs: BTreeMap<String, String> = BTreeMap::new();
+    for prog in ProgIter::new(object) {
+        let raw = get_prog_name(prog)?;
+        if RESERVED_PROG_NAMES.contains(&raw.as_str()) {
+            bail!(
+                "Prog {} clashes with the generated attach_{}() skeleton method, rename it",
+                raw,
+                raw
+            );
+        }
+        if RESERVED_LINK_NAMES.contains(&raw.as_str()) {
+            bail!(
+                "Prog {} clashes with the generated {} field of the skeleton's links, rename it",
+                raw,
+                raw
+            );
+        }
+
+        let ident = escape_ident(&raw);
+        if let Some(other) = progs.insert(ident.clone(), raw.clone()) {
+            bail!(
+                "Progs {} and {} both map to identifier {}",
+                other,
+                raw,
+                ident
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Fail if two of `objs` would generate skeleton modules or types of the same name, eg.
+/// `foo_bar` and `fooBar`, which both become `FooBarSkel`
+fn check_obj_name_collisions(objs: &[UnprocessedObj]) -> Result<()> {
+    let mut seen: BTreeMap<String, &str> = BTreeMap::new();
+    for obj in objs {
+        // Namespaced skeletons keep their structs to their own module
+        let idents = if obj.namespaced_mods {
+            vec![format!(
+                "mod {}",
+                escape_ident(&sanitize_obj_name(&obj.name))
+            )]
+        } else {
+            vec![
+                format!("mod {}_skel", sanitize_obj_name(&obj.name)),
+                format!("struct {}Skel", capitalize_first_letter(&obj.name)),
+            ]
+        };
+
+        for ident in &idents {
+            if let Some(other) = seen.insert(ident.clone(), &obj.name) {
+                bail!(
+                    "Objects {} and {} both generate `{}`, rename one of them",
+                    other,
+                    obj.name,
+                    ident
+                );
+            }
+        }
+    }
+
+    Ok(())
 }
 
-fn get_raw_map_name(map: *const libbpf_sys::bpf_map) -> Result<String> {
+pub(crate) fn get_raw_map_name(map: *const libbpf_sys::bpf_map) -> Result<String> {
     let name_ptr = unsafe { libbpf_sys::bpf_map__name(map) };
     if name_ptr.is_null() {
         bail!("Map name unknown");
@@ -112,7 +479,6 @@ fn canonicalize_internal_map_name(s: &str) -> Option<String> {
     } else if s.ends_with(".kconfig") {
         Some("kconfig".to_string())
     } else {
-        eprintln!("Warning: unrecognized map: {}", s);
         None
     }
 }
@@ -138,6 +504,15 @@ fn get_prog_name(prog: *const libbpf_sys::bpf_program) -> Result<String> {
     Ok(unsafe { CStr::from_ptr(name_ptr) }.to_str()?.to_string())
 }
 
+/// Returns the `#[cfg(feature = "...")]` attribute for the skeleton code of the map or prog
+/// `name`, if `features` (map or prog name -> crate feature) gates it
+fn cfg_feature(features: &BTreeMap<String, String>, name: &str) -> String {
+    match features.get(name) {
+        Some(feature) => format!("#[cfg(feature = {:?})]", feature),
+        None => String::new(),
+    }
+}
+
 fn map_is_mmapable(map: *const libbpf_sys::bpf_map) -> bool {
     let internal = unsafe { libbpf_sys::bpf_map__is_internal(map) };
     let def = unsafe { libbpf_sys::bpf_map__def(map) };
@@ -158,18 +533,16 @@ fn map_is_readonly(map: *const libbpf_sys::bpf_map) -> bool {
 fn gen_skel_c_skel_constructor(
     skel: &mut String,
     object: *mut libbpf_sys::bpf_object,
-    name: &str,
 ) -> Result<()> {
     write!(
         skel,
         r#"
-        fn build_skel_config() -> libbpf_rs::Result<libbpf_rs::skeleton::ObjectSkeletonConfig<'static>>
+        fn build_skel_config(name: &str) -> libbpf_rs::Result<libbpf_rs::skeleton::ObjectSkeletonConfig<'static>>
         {{
             let mut builder = libbpf_rs::skeleton::ObjectSkeletonConfigBuilder::new(DATA);
             builder
-                .name("{name}")
+                .name(name)
         "#,
-        name = name
     )?;
 
     for map in MapIter::new(object) {
@@ -180,12 +553,23 @@ fn gen_skel_c_skel_constructor(
             "false"
         };
 
+        // libbpf names datasec maps after the object, which may be renamed on open
+        let internal_name = if unsafe { libbpf_sys::bpf_map__is_internal(map) } {
+            canonicalize_internal_map_name(&raw_name)
+        } else {
+            None
+        };
+        let name_expr = match internal_name {
+            Some(sec) => format!("libbpf_rs::skeleton::internal_map_name(name, \".{}\")", sec),
+            None => format!("\"{}\"", raw_name),
+        };
+
         write!(
             skel,
             r#"
-            .map("{raw_name}", {mmaped})
+            .map({name_expr}, {mmaped})
             "#,
-            raw_name = raw_name,
+            name_expr = name_expr,
             mmaped = mmaped,
         )?;
     }
@@ -220,6 +604,7 @@ fn gen_skel_map_defs(
     object: *mut libbpf_sys::bpf_object,
     obj_name: &str,
     open: bool,
+    features: &BTreeMap<String, String>,
 ) -> Result<()> {
     // If no non-datasec maps, return early
     if MapIter::new(object)
@@ -267,16 +652,32 @@ fn gen_skel_map_defs(
             None => continue,
         };
 
+        // Maps userspace may only read or write get a view without the other operations
+        let def = unsafe { &*libbpf_sys::bpf_map__def(map) };
+        let (return_ty, view) = if !open && def.map_flags & libbpf_sys::BPF_F_RDONLY != 0 {
+            ("libbpf_rs::ReadOnlyMap<'_>".to_string(), ".as_read_only()")
+        } else if !open && def.map_flags & libbpf_sys::BPF_F_WRONLY != 0 {
+            (
+                "libbpf_rs::WriteOnlyMap<'_>".to_string(),
+                ".as_write_only()",
+            )
+        } else {
+            (format!("&mut {}", return_ty), "")
+        };
+
         write!(
             skel,
             r#"
-            pub fn {map_name}(&mut self) -> &mut {return_ty} {{
-                self.inner.map_unwrap("{raw_map_name}")
+            {cfg}
+            pub fn {map_ident}(&mut self) -> {return_ty} {{
+                self.inner.map_unwrap("{raw_map_name}"){view}
             }}
             "#,
-            map_name = map_name,
+            cfg = cfg_feature(features, &map_name),
+            map_ident = escape_ident(&map_name),
             raw_map_name = get_raw_map_name(map)?,
             return_ty = return_ty,
+            view = view,
         )?;
     }
 
@@ -290,6 +691,7 @@ fn gen_skel_prog_defs(
     object: *mut libbpf_sys::bpf_object,
     obj_name: &str,
     open: bool,
+    features: &BTreeMap<String, String>,
 ) -> Result<()> {
     if ProgIter::new(object).next().is_none() {
         return Ok(());
@@ -323,14 +725,19 @@ fn gen_skel_prog_defs(
     )?;
 
     for prog in ProgIter::new(object) {
+        let prog_name = get_prog_name(prog)?;
+
         write!(
             skel,
             r#"
-            pub fn {prog_name}(&mut self) -> &mut {return_ty} {{
+            {cfg}
+            pub fn {prog_ident}(&mut self) -> &mut {return_ty} {{
                 self.inner.prog_unwrap("{prog_name}")
             }}
             "#,
-            prog_name = get_prog_name(prog)?,
+            cfg = cfg_feature(features, &prog_name),
+            prog_ident = escape_ident(&prog_name),
+            prog_name = prog_name,
             return_ty = return_ty,
         )?;
     }
@@ -358,7 +765,8 @@ fn gen_skel_datasec_defs(skel: &mut String, obj_name: &str, object: &[u8]) -> Re
                 r#"
                 pub mod {}_{}_types {{
                 "#,
-                obj_name, sec_ident,
+                sanitize_obj_name(obj_name),
+                sec_ident,
             )?;
 
             let sec_def = btf.type_definition(idx.try_into().unwrap())?;
@@ -371,6 +779,173 @@ fn gen_skel_datasec_defs(skel: &mut String, obj_name: &str, object: &[u8]) -> Re
     Ok(())
 }
 
+fn gen_skel_types(
+    skel: &mut String,
+    obj_name: &str,
+    object: &[u8],
+    types: &[String],
+) -> Result<()> {
+    if types.is_empty() {
+        return Ok(());
+    }
+
+    let btf = match btf::Btf::new(obj_name, object)? {
+        Some(b) => b,
+        None => return Ok(()),
+    };
+
+    // Types are requested per-package, so not every object will contain every type. Types no
+    // object defines are caught by `check_types_defined()`.
+    let type_ids: Vec<u32> = types
+        .iter()
+        .filter_map(|name| btf.find_named_type(name))
+        .collect();
+    if type_ids.is_empty() {
+        return Ok(());
+    }
+
+    write!(
+        skel,
+        r#"
+        pub mod {}_types {{
+        "#,
+        sanitize_obj_name(obj_name),
+    )?;
+
+    write!(skel, "{}", btf.type_definitions(&type_ids)?)?;
+
+    writeln!(skel, "}}")?;
+
+    Ok(())
+}
+
+/// Fail if any of `types` is not defined in any of `obj_files`, eg. because its name is
+/// misspelled, rather than silently generate no definition for it
+fn check_types_defined(obj_files: &[PathBuf], types: &[String]) -> Result<()> {
+    let mut missing: BTreeSet<&str> = types.iter().map(|t| t.as_str()).collect();
+    for obj_file in obj_files {
+        if missing.is_empty() {
+            break;
+        }
+
+        let file = File::open(obj_file)
+            .with_context(|| format!("Failed to open {}", obj_file.display()))?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        if let Some(btf) = btf::Btf::new(obj_name(obj_file)?, &*mmap)? {
+            missing.retain(|name| btf.find_named_type(name).is_none());
+        }
+    }
+
+    if !missing.is_empty() {
+        bail!(
+            "Types not found in any object: {}",
+            missing.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Generate a `<MAP>_events()` method for each ringbuf map in `ringbufs` (map name -> sample
+/// type name) that passes samples to the callback as references to the generated type
+fn gen_skel_ringbuf_events(
+    skel: &mut String,
+    object: *mut libbpf_sys::bpf_object,
+    raw_obj_name: &str,
+    btf_data: &[u8],
+    ringbufs: &BTreeMap<String, String>,
+    features: &BTreeMap<String, String>,
+) -> Result<()> {
+    if ringbufs.is_empty() {
+        return Ok(());
+    }
+
+    let btf = btf::Btf::new(raw_obj_name, btf_data)?;
+
+    for map in MapIter::new(object) {
+        let map_name = match get_map_name(map)? {
+            Some(n) => n,
+            None => continue,
+        };
+
+        // Ringbufs are configured per-package, so not every object will contain every map
+        let ty = match ringbufs.get(&map_name) {
+            Some(ty) => ty,
+            None => continue,
+        };
+
+        let def = unsafe { libbpf_sys::bpf_map__def(map) };
+        if unsafe { (*def).type_ } != libbpf_sys::BPF_MAP_TYPE_RINGBUF {
+            bail!("Map {} is not a ringbuf", map_name);
+        }
+
+        if btf.as_ref().and_then(|b| b.find_named_type(ty)).is_none() {
+            bail!("Ringbuf {} sample type {} not found in BTF", map_name, ty);
+        }
+
+        write!(
+            skel,
+            r#"
+            {cfg}
+            /// Add the `{map_name}` ringbuf to `builder`. Samples are passed to `callback` without
+            /// being copied.
+            pub fn {map_name}_events<F>(
+                &mut self,
+                builder: &mut libbpf_rs::RingBufferBuilder,
+                callback: F,
+            ) -> libbpf_rs::Result<()>
+            where
+                F: FnMut(&{obj_name}_types::{ty}) -> i32 + 'static,
+            {{
+                // Generated types are plain C data, so any bit pattern is valid
+                unsafe {{ builder.add_typed(self.obj.map_unwrap("{map_name}"), callback)? }};
+
+                Ok(())
+            }}
+            "#,
+            cfg = cfg_feature(features, &map_name),
+            map_name = map_name,
+            obj_name = sanitize_obj_name(raw_obj_name),
+            ty = ty,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn gen_skel_arenas(
+    skel: &mut String,
+    object: *mut libbpf_sys::bpf_object,
+    features: &BTreeMap<String, String>,
+) -> Result<()> {
+    for map in MapIter::new(object) {
+        let map_name = match get_map_name(map)? {
+            Some(n) => n,
+            None => continue,
+        };
+
+        let def = unsafe { libbpf_sys::bpf_map__def(map) };
+        if unsafe { (*def).type_ } != BPF_MAP_TYPE_ARENA {
+            continue;
+        }
+
+        write!(
+            skel,
+            r#"
+            {cfg}
+            /// Map the `{map_name}` arena into this process. See `libbpf_rs::Arena`.
+            pub fn {map_name}_arena(&mut self) -> libbpf_rs::Result<libbpf_rs::Arena> {{
+                self.obj.map_unwrap("{map_name}").arena()
+            }}
+            "#,
+            cfg = cfg_feature(features, &map_name),
+            map_name = map_name,
+        )?;
+    }
+
+    Ok(())
+}
+
 fn gen_skel_map_getter(
     skel: &mut String,
     object: *mut libbpf_sys::bpf_object,
@@ -438,64 +1013,296 @@ fn gen_skel_prog_getter(
     Ok(())
 }
 
-fn gen_skel_datasec_getters(
+/// Generate the `<NAME>SharedMaps` and `<NAME>SharedProgs` structs of a loaded skeleton generated
+/// with `shared`. They hold handles backed by their own fds, so they can be used through `&self`.
+fn gen_skel_shared_defs(
     skel: &mut String,
     object: *mut libbpf_sys::bpf_object,
     obj_name: &str,
-    loaded: bool,
+    features: &BTreeMap<String, String>,
 ) -> Result<()> {
-    for (idx, map) in MapIter::new(object).enumerate() {
-        if !map_is_mmapable(map) {
-            continue;
+    if MapIter::new(object).any(|map| !map_is_mmapable(map)) {
+        write!(
+            skel,
+            r#"
+            pub struct {obj_name}SharedMaps {{
+            "#,
+            obj_name = obj_name,
+        )?;
+
+        for map in MapIter::new(object) {
+            if map_is_mmapable(map) {
+                continue;
+            }
+
+            if let Some(map_name) = get_map_name(map)? {
+                write!(
+                    skel,
+                    r#"{cfg}
+                    pub {map_ident}: libbpf_rs::Map,
+                    "#,
+                    cfg = cfg_feature(features, &map_name),
+                    map_ident = escape_ident(&map_name),
+                )?;
+            }
         }
 
-        let name = match get_map_name(map)? {
-            Some(n) => n,
-            None => continue,
-        };
-        let struct_name = format!(
-            "{obj_name}_{name}_types::{name}",
-            obj_name = obj_name,
-            name = name,
-        );
-        let mutability = if loaded && map_is_readonly(map) {
-            ""
-        } else {
-            "mut"
-        };
+        writeln!(skel, "}}")?;
+    }
 
+    if ProgIter::new(object).next().is_some() {
         write!(
             skel,
             r#"
-            pub fn {name}(&mut self) -> &'a {mut} {struct_name} {{
-                unsafe {{
-                    std::mem::transmute::<*mut std::ffi::c_void, &'a {mut} {struct_name}>(
-                        self.skel_config.map_mmap_ptr({idx}).unwrap()
-                    )
-                }}
-            }}
+            /// Fds of the programs. `None` for programs that were not loaded.
+            pub struct {obj_name}SharedProgs {{
             "#,
-            name = name,
-            struct_name = struct_name,
-            mut = mutability,
-            idx = idx,
+            obj_name = obj_name,
         )?;
+
+        for prog in ProgIter::new(object) {
+            let prog_name = get_prog_name(prog)?;
+
+            write!(
+                skel,
+                r#"{cfg}
+                pub {prog_ident}: Option<std::os::unix::io::OwnedFd>,
+                "#,
+                cfg = cfg_feature(features, &prog_name),
+                prog_ident = escape_ident(&prog_name),
+            )?;
+        }
+
+        writeln!(skel, "}}")?;
     }
 
     Ok(())
 }
 
-fn gen_skel_link_defs(
+/// Generate the code that fills in the structs from [`gen_skel_shared_defs()`] from `obj` on load
+fn gen_skel_shared_init(
     skel: &mut String,
     object: *mut libbpf_sys::bpf_object,
     obj_name: &str,
+    features: &BTreeMap<String, String>,
 ) -> Result<()> {
-    if ProgIter::new(object).next().is_none() {
-        return Ok(());
-    }
-
-    write!(
-        skel,
+    if MapIter::new(object).any(|map| !map_is_mmapable(map)) {
+        write!(
+            skel,
+            r#"
+            let maps = {obj_name}SharedMaps {{
+            "#,
+            obj_name = obj_name,
+        )?;
+
+        for map in MapIter::new(object) {
+            if map_is_mmapable(map) {
+                continue;
+            }
+
+            if let Some(map_name) = get_map_name(map)? {
+                write!(
+                    skel,
+                    r#"{cfg}
+                    {map_ident}: obj.map_unwrap("{raw_map_name}").try_clone()?,
+                    "#,
+                    cfg = cfg_feature(features, &map_name),
+                    map_ident = escape_ident(&map_name),
+                    raw_map_name = get_raw_map_name(map)?,
+                )?;
+            }
+        }
+
+        writeln!(skel, "}};")?;
+    }
+
+    if ProgIter::new(object).next().is_some() {
+        write!(
+            skel,
+            r#"
+            let progs = {obj_name}SharedProgs {{
+            "#,
+            obj_name = obj_name,
+        )?;
+
+        for prog in ProgIter::new(object) {
+            let prog_name = get_prog_name(prog)?;
+
+            write!(
+                skel,
+                r#"{cfg}
+                {prog_ident}: {{
+                    let prog = obj.prog_unwrap("{prog_name}");
+                    if prog.fd() < 0 {{
+                        None
+                    }} else {{
+                        Some(
+                            std::os::unix::io::AsFd::as_fd(prog)
+                                .try_clone_to_owned()
+                                .map_err(|e| libbpf_rs::Error::Internal(e.to_string()))?,
+                        )
+                    }}
+                }},
+                "#,
+                cfg = cfg_feature(features, &prog_name),
+                prog_ident = escape_ident(&prog_name),
+                prog_name = prog_name,
+            )?;
+        }
+
+        writeln!(skel, "}};")?;
+    }
+
+    Ok(())
+}
+
+/// Returns the `maps` and `progs` fields of a loaded skeleton generated with `shared`, as
+/// struct initializers if `init` is set and as declarations otherwise
+fn gen_skel_shared_fields(
+    object: *mut libbpf_sys::bpf_object,
+    obj_name: &str,
+    init: bool,
+) -> String {
+    let mut fields = String::new();
+    if MapIter::new(object).any(|map| !map_is_mmapable(map)) {
+        if init {
+            fields.push_str("maps,\n");
+        } else {
+            fields.push_str(&format!("maps: {}SharedMaps,\n", obj_name));
+        }
+    }
+    if ProgIter::new(object).next().is_some() {
+        if init {
+            fields.push_str("progs,\n");
+        } else {
+            fields.push_str(&format!("progs: {}SharedProgs,\n", obj_name));
+        }
+    }
+
+    fields
+}
+
+/// Generate the `&self` `maps()` and `progs()` getters of a loaded skeleton generated with
+/// `shared`
+fn gen_skel_shared_getters(
+    skel: &mut String,
+    object: *mut libbpf_sys::bpf_object,
+    obj_name: &str,
+) -> Result<()> {
+    if MapIter::new(object).any(|map| !map_is_mmapable(map)) {
+        write!(
+            skel,
+            r#"
+            pub fn maps(&self) -> &{obj_name}SharedMaps {{
+                &self.maps
+            }}
+            "#,
+            obj_name = obj_name,
+        )?;
+    }
+
+    if ProgIter::new(object).next().is_some() {
+        write!(
+            skel,
+            r#"
+            pub fn progs(&self) -> &{obj_name}SharedProgs {{
+                &self.progs
+            }}
+            "#,
+            obj_name = obj_name,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn gen_skel_datasec_getters(
+    skel: &mut String,
+    object: *mut libbpf_sys::bpf_object,
+    obj_name: &str,
+    loaded: bool,
+) -> Result<()> {
+    for (idx, map) in MapIter::new(object).enumerate() {
+        if !map_is_mmapable(map) {
+            continue;
+        }
+
+        let name = match get_map_name(map)? {
+            Some(n) => n,
+            None => continue,
+        };
+
+        // kconfig values are only filled in by libbpf during load
+        if !loaded && name == "kconfig" {
+            continue;
+        }
+
+        let struct_name = format!(
+            "{obj_name}_{name}_types::{name}",
+            obj_name = sanitize_obj_name(obj_name),
+            name = name,
+        );
+        let mutability = if loaded && map_is_readonly(map) {
+            ""
+        } else {
+            "mut"
+        };
+
+        // Once loaded, kernels that can't mmap array maps leave the mmap pointer null
+        let ptr_fn = if loaded {
+            "map_datasec_ptr"
+        } else {
+            "map_mmap_ptr"
+        };
+
+        write!(
+            skel,
+            r#"
+            pub fn {name}(&mut self) -> libbpf_rs::Result<&'a {mut} {struct_name}> {{
+                let ptr = self.skel_config.{ptr_fn}({idx})?;
+                let ptr = self.skel_config.check_map_ptr::<{struct_name}>({idx}, ptr)?;
+
+                Ok(unsafe {{ &{mut} *ptr }})
+            }}
+            "#,
+            name = name,
+            struct_name = struct_name,
+            mut = mutability,
+            ptr_fn = ptr_fn,
+            idx = idx,
+        )?;
+
+        if loaded && !mutability.is_empty() {
+            write!(
+                skel,
+                r#"
+                /// Write changes made through [`Self::{name}()`] to the kernel. Only needed on
+                /// kernels that can't mmap array maps (before 5.5), where [`Self::{name}()`]
+                /// returns a copy of the map's contents.
+                pub fn flush_{name}(&mut self) -> libbpf_rs::Result<()> {{
+                    self.skel_config.map_datasec_flush({idx})
+                }}
+                "#,
+                name = name,
+                idx = idx,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn gen_skel_link_defs(
+    skel: &mut String,
+    object: *mut libbpf_sys::bpf_object,
+    obj_name: &str,
+) -> Result<()> {
+    if ProgIter::new(object).next().is_none() {
+        return Ok(());
+    }
+
+    write!(
+        skel,
         r#"
         #[derive(Default)]
         pub struct {}Links {{
@@ -508,11 +1315,18 @@ fn gen_skel_link_defs(
             skel,
             r#"pub {}: Option<libbpf_rs::Link>,
             "#,
-            get_prog_name(prog)?
+            escape_ident(&get_prog_name(prog)?)
         )?;
     }
 
-    writeln!(skel, "}}")?;
+    write!(
+        skel,
+        r#"
+        /// Names of the programs linked above, in the order they were attached in
+        attach_order: Vec<&'static str>,
+        }}
+        "#,
+    )?;
 
     Ok(())
 }
@@ -536,7 +1350,212 @@ fn gen_skel_link_getter(
     Ok(())
 }
 
-fn open_bpf_object(name: &str, data: &[u8]) -> Result<*mut libbpf_sys::bpf_object> {
+/// Returns the contents of the `license` section of `object`, or an empty string if it has none
+fn get_license(object: &[u8]) -> Result<String> {
+    let elf = goblin::elf::Elf::parse(object)?;
+
+    for sh in &elf.section_headers {
+        match elf.shdr_strtab.get(sh.sh_name) {
+            Some(Ok("license")) => (),
+            _ => continue,
+        }
+
+        let start = sh.sh_offset as usize;
+        let end = start + sh.sh_size as usize;
+        let data = object
+            .get(start..end)
+            .context("license section out of bounds")?;
+        let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+
+        return Ok(std::str::from_utf8(&data[..len])?.to_string());
+    }
+
+    Ok(String::new())
+}
+
+/// Describe the layout of `type_id` as BTF has it: kinds, sizes, and the names, offsets and bit
+/// sizes of members, down through nested types. Type names, typedefs and modifiers don't change
+/// the layout and are left out.
+fn write_type_layout(btf: &btf::Btf, type_id: u32, layout: &mut String) -> Result<()> {
+    let type_id = btf.skip_mods_and_typedefs(type_id)?;
+    match btf.type_by_id(type_id)? {
+        btf::BtfType::Int(t) => write!(layout, "int {} {} {}", t.bits, t.offset, t.encoding as u8)?,
+        // What a pointer points to is not part of the layout
+        btf::BtfType::Ptr(_) => write!(layout, "ptr")?,
+        btf::BtfType::Array(t) => {
+            write!(layout, "[")?;
+            write_type_layout(btf, t.val_type_id, layout)?;
+            write!(layout, "; {}]", t.nelems)?;
+        }
+        btf::BtfType::Struct(t) | btf::BtfType::Union(t) => {
+            let kind = if t.is_struct { "struct" } else { "union" };
+            write!(layout, "{} {} {{", kind, t.size)?;
+            for m in &t.members {
+                write!(layout, " {} {} {} ", m.name, m.bit_offset, m.bit_size)?;
+                write_type_layout(btf, m.type_id, layout)?;
+                write!(layout, ";")?;
+            }
+            write!(layout, " }}")?;
+        }
+        btf::BtfType::Enum(t) => {
+            write!(layout, "enum {} {{", t.size)?;
+            for v in &t.values {
+                write!(layout, " {}={}", v.name, v.value)?;
+            }
+            write!(layout, " }}")?;
+        }
+        ty => write!(layout, "{:?}", ty.kind())?,
+    }
+
+    Ok(())
+}
+
+/// Hash of the layout of the maps an object shares through pins: their names, types, sizes and
+/// flags, and the layout of their key and value types as described by BTF. Only changes along
+/// with the layout, not with the toolchain, libbpf-cargo's code generation or unrelated parts of
+/// the object.
+fn layout_hash(
+    raw_obj_name: &str,
+    object: *mut libbpf_sys::bpf_object,
+    btf_data: &[u8],
+) -> Result<u64> {
+    let btf = btf::Btf::new(raw_obj_name, btf_data)?;
+
+    let mut layout = String::new();
+    for map in MapIter::new(object) {
+        // Datasecs back globals, which are not shared through pins
+        if unsafe { libbpf_sys::bpf_map__is_internal(map) } {
+            continue;
+        }
+
+        let def = unsafe { &*libbpf_sys::bpf_map__def(map) };
+        writeln!(
+            layout,
+            "{} {} {} {} {} {}",
+            get_raw_map_name(map)?,
+            def.type_,
+            def.key_size,
+            def.value_size,
+            def.max_entries,
+            def.map_flags
+        )?;
+
+        let btf = match &btf {
+            Some(btf) => btf,
+            None => continue,
+        };
+        let type_ids = [
+            unsafe { libbpf_sys::bpf_map__btf_key_type_id(map) },
+            unsafe { libbpf_sys::bpf_map__btf_value_type_id(map) },
+        ];
+        for &type_id in type_ids.iter().filter(|&&id| id != 0) {
+            write_type_layout(btf, type_id, &mut layout)?;
+            writeln!(layout)?;
+        }
+    }
+
+    // FNV-1a rather than `DefaultHasher`, whose output may change between rust releases
+    Ok(layout.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    }))
+}
+
+fn gen_skel_info(
+    skel: &mut String,
+    object: *mut libbpf_sys::bpf_object,
+    raw_obj_name: &str,
+    obj_name: &str,
+    data: &[u8],
+) -> Result<()> {
+    let layout_hash = layout_hash(raw_obj_name, object, data)?;
+
+    let mut map_names = Vec::new();
+    for map in MapIter::new(object) {
+        map_names.push(format!("{:?}", get_raw_map_name(map)?));
+    }
+
+    let mut prog_names = Vec::new();
+    let mut prog_sections = Vec::new();
+    let mut prog_types = Vec::new();
+    let mut prog_attach_types = Vec::new();
+    for prog in ProgIter::new(object) {
+        prog_names.push(format!("{:?}", get_prog_name(prog)?));
+
+        let section_ptr = unsafe { libbpf_sys::bpf_program__title(prog, false) };
+        if section_ptr.is_null() {
+            bail!("Prog section unknown");
+        }
+        let section = unsafe { CStr::from_ptr(section_ptr) }.to_str()?;
+        prog_sections.push(format!("{:?}", section));
+
+        prog_types.push(unsafe { libbpf_sys::bpf_program__get_type(prog) }.to_string());
+        prog_attach_types
+            .push(unsafe { libbpf_sys::bpf_program__get_expected_attach_type(prog) }.to_string());
+    }
+
+    write!(
+        skel,
+        r#"
+        /// Contents of the object embedded in [`{name}Skel`], available without loading it
+        #[derive(Clone, Copy, Debug)]
+        pub struct {name}SkelInfo {{
+            /// Names of the maps, as passed to `Object::map()`
+            pub map_names: &'static [&'static str],
+            /// Names of the programs
+            pub prog_names: &'static [&'static str],
+            /// ELF sections of the programs, in the order of `prog_names`
+            pub prog_sections: &'static [&'static str],
+            /// `enum bpf_prog_type` of the programs, in the order of `prog_names`. Convert with
+            /// `libbpf_rs::ProgramType::try_from()`
+            pub prog_types: &'static [u32],
+            /// `enum bpf_attach_type` of the programs, in the order of `prog_names`. Convert
+            /// with `libbpf_rs::ProgramAttachType::try_from()`
+            pub prog_attach_types: &'static [u32],
+            /// License the object declares, empty if none
+            pub license: &'static str,
+            /// Kernel version the object declares, 0 if none
+            pub kern_version: u32,
+        }}
+
+        impl {name}Skel<'_> {{
+            pub const MAP_NAMES: &'static [&'static str] = &[{map_names}];
+            pub const PROG_NAMES: &'static [&'static str] = &[{prog_names}];
+            pub const PROG_SECTIONS: &'static [&'static str] = &[{prog_sections}];
+            pub const PROG_TYPES: &'static [u32] = &[{prog_types}];
+            pub const PROG_ATTACH_TYPES: &'static [u32] = &[{prog_attach_types}];
+            pub const LICENSE: &'static str = {license:?};
+            pub const KERN_VERSION: u32 = {kern_version};
+            /// Hash of the layout of the maps, to check maps pinned by another build against
+            /// with `libbpf_rs::check_pinned_layout()`
+            pub const LAYOUT_HASH: u64 = {layout_hash:#x};
+
+            /// All of the above
+            pub const INFO: {name}SkelInfo = {name}SkelInfo {{
+                map_names: Self::MAP_NAMES,
+                prog_names: Self::PROG_NAMES,
+                prog_sections: Self::PROG_SECTIONS,
+                prog_types: Self::PROG_TYPES,
+                prog_attach_types: Self::PROG_ATTACH_TYPES,
+                license: Self::LICENSE,
+                kern_version: Self::KERN_VERSION,
+            }};
+        }}
+        "#,
+        name = obj_name,
+        map_names = map_names.join(", "),
+        prog_names = prog_names.join(", "),
+        prog_sections = prog_sections.join(", "),
+        prog_types = prog_types.join(", "),
+        prog_attach_types = prog_attach_types.join(", "),
+        license = get_license(data)?,
+        kern_version = unsafe { libbpf_sys::bpf_object__kversion(object) },
+        layout_hash = layout_hash,
+    )?;
+
+    Ok(())
+}
+
+pub(crate) fn open_bpf_object(name: &str, data: &[u8]) -> Result<*mut libbpf_sys::bpf_object> {
     let cname = CString::new(name)?;
     let obj_opts = libbpf_sys::bpf_object_open_opts {
         sz: std::mem::size_of::<libbpf_sys::bpf_object_open_opts>() as libbpf_sys::size_t,
@@ -550,6 +1569,14 @@ fn open_bpf_object(name: &str, data: &[u8]) -> Result<*mut libbpf_sys::bpf_objec
             &obj_opts,
         )
     };
+    // libbpf returns errors as pointers
+    let err = unsafe { libbpf_sys::libbpf_get_error(object as *const c_void) };
+    if err != 0 {
+        bail!(
+            "Failed to bpf_object__open_mem(): {}",
+            std::io::Error::from_raw_os_error(-err as i32)
+        );
+    }
     if object.is_null() {
         bail!("Failed to bpf_object__open_mem()");
     }
@@ -561,6 +1588,8 @@ fn gen_skel_attach(
     skel: &mut String,
     object: *mut libbpf_sys::bpf_object,
     obj_name: &str,
+    features: &BTreeMap<String, String>,
+    timings_cfg: Option<&str>,
 ) -> Result<()> {
     if ProgIter::new(object).next().is_none() {
         return Ok(());
@@ -570,14 +1599,19 @@ fn gen_skel_attach(
         skel,
         r#"
         pub fn attach(&mut self) -> libbpf_rs::Result<()> {{
+            {timing_start}
             let ret = unsafe {{ libbpf_sys::bpf_object__attach_skeleton(self.skel_config.get()) }};
             if ret != 0 {{
                 return Err(libbpf_rs::Error::System(-ret));
             }}
 
-            self.links = {}Links {{
+            self.links = {obj_name}Links {{
         "#,
-        obj_name
+        obj_name = obj_name,
+        timing_start = match timings_cfg {
+            Some(cfg) => format!("{}\nlet start = std::time::Instant::now();", cfg),
+            None => String::new(),
+        },
     )?;
 
     for (idx, prog) in ProgIter::new(object).enumerate() {
@@ -585,7 +1619,7 @@ fn gen_skel_attach(
 
         write!(
             skel,
-            r#"{prog_name}: (|| {{
+            r#"{prog_ident}: (|| {{
                 let ptr = self.skel_config.prog_link_ptr({idx})?;
                 if ptr.is_null() {{
                     Ok(None)
@@ -594,7 +1628,7 @@ fn gen_skel_attach(
                 }}
             }})()?,
             "#,
-            prog_name = prog_name,
+            prog_ident = escape_ident(&prog_name),
             idx = idx,
         )?;
     }
@@ -602,18 +1636,235 @@ fn gen_skel_attach(
     write!(
         skel,
         r#"
+                attach_order: Self::PROG_NAMES.to_vec(),
             }};
+            {timing_end}
 
             Ok(())
         }}
         "#,
+        timing_end = match timings_cfg {
+            Some(cfg) => format!("{}\n{{ self.timings.attach = start.elapsed(); }}", cfg),
+            None => String::new(),
+        },
+    )?;
+
+    // Per-program attach methods let callers treat some programs as optional
+    for prog in ProgIter::new(object) {
+        let prog_name = get_prog_name(prog)?;
+
+        write!(
+            skel,
+            r#"
+            {cfg}
+            pub fn attach_{prog_name}(&mut self) -> libbpf_rs::Result<()> {{
+                let link = self.obj.prog_unwrap("{prog_name}").attach()?;
+                self.links.{prog_ident} = Some(link);
+                self.links.attach_order.push("{prog_name}");
+
+                Ok(())
+            }}
+            "#,
+            cfg = cfg_feature(features, &prog_name),
+            prog_ident = escape_ident(&prog_name),
+            prog_name = prog_name,
+        )?;
+    }
+
+    write!(
+        skel,
+        r#"
+        /// Attach every program like [`Self::attach()`], one at a time, retrying each after
+        /// transient failures as `policy` says. Programs are skipped as per
+        /// `libbpf_rs::Object::attach_all_with_retry()`.
+        pub fn attach_with_retry(&mut self, policy: &libbpf_rs::RetryPolicy) -> libbpf_rs::Result<()> {{
+            let filter = libbpf_rs::AttachFilter::default();
+            for (name, link) in self.obj.attach_all_with_retry(&filter, policy)? {{
+                match name.as_str() {{
+        "#,
+    )?;
+
+    for prog in ProgIter::new(object) {
+        let prog_name = get_prog_name(prog)?;
+
+        write!(
+            skel,
+            r#"
+                    "{prog_name}" => {{
+                        self.links.{prog_ident} = Some(link);
+                        self.links.attach_order.push("{prog_name}");
+                    }}
+            "#,
+            prog_ident = escape_ident(&prog_name),
+            prog_name = prog_name,
+        )?;
+    }
+
+    write!(
+        skel,
+        r#"
+                    _ => (),
+                }}
+            }}
+
+            Ok(())
+        }}
+        "#,
+    )?;
+
+    write!(
+        skel,
+        r#"
+        /// Attach every program, continuing past failures. Returns the name of each program
+        /// that failed to attach along with the error.
+        pub fn attach_all(&mut self) -> Vec<(&'static str, libbpf_rs::Error)> {{
+            let mut failures = Vec::new();
+        "#,
+    )?;
+
+    for prog in ProgIter::new(object) {
+        let prog_name = get_prog_name(prog)?;
+
+        write!(
+            skel,
+            r#"
+            {cfg}
+            if let Err(e) = self.attach_{prog_name}() {{
+                failures.push(("{prog_name}", e));
+            }}
+            "#,
+            cfg = cfg_feature(features, &prog_name),
+            prog_name = prog_name,
+        )?;
+    }
+
+    write!(
+        skel,
+        r#"
+            failures
+        }}
+
+        /// Attach the programs `filter` selects, as per `libbpf_rs::Object::attach_all()`, and
+        /// store their links in `links`
+        pub fn attach_filtered(&mut self, filter: &libbpf_rs::AttachFilter) -> libbpf_rs::Result<()> {{
+            for (name, link) in self.obj.attach_all(filter)? {{
+                match name.as_str() {{
+        "#,
+    )?;
+
+    for prog in ProgIter::new(object) {
+        let prog_name = get_prog_name(prog)?;
+
+        write!(
+            skel,
+            r#"
+                    "{prog_name}" => {{
+                        self.links.{prog_ident} = Some(link);
+                        self.links.attach_order.push("{prog_name}");
+                    }}
+            "#,
+            prog_ident = escape_ident(&prog_name),
+            prog_name = prog_name,
+        )?;
+    }
+
+    write!(
+        skel,
+        r#"
+                    _ => (),
+                }}
+            }}
+
+            Ok(())
+        }}
+
+        /// Shut down: detach every program in the reverse order it was attached in, then
+        /// drain ring buffers and unpin maps as `opts` asks. See `libbpf_rs::Object::detach()`.
+        ///
+        /// Links set on `links` directly are detached last.
+        pub fn detach(&mut self, opts: &libbpf_rs::DetachOptions) -> libbpf_rs::Result<()> {{
+            let mut order = std::mem::take(&mut self.links.attach_order);
+            order.reverse();
+            order.extend(Self::PROG_NAMES.iter().rev());
+
+            let mut links = Vec::new();
+            for name in order {{
+                let link = match name {{
+        "#,
+    )?;
+
+    for prog in ProgIter::new(object) {
+        let prog_name = get_prog_name(prog)?;
+
+        write!(
+            skel,
+            r#"
+                    "{prog_name}" => self.links.{prog_ident}.take(),
+            "#,
+            prog_ident = escape_ident(&prog_name),
+            prog_name = prog_name,
+        )?;
+    }
+
+    write!(
+        skel,
+        r#"
+                    _ => None,
+                }};
+                links.extend(link);
+            }}
+
+            self.obj.detach(links, opts)
+        }}
+        "#,
+    )?;
+
+    Ok(())
+}
+
+/// Generate the BTF derived type definitions of a single skeleton
+///
+/// Only `core` is used, so the definitions may be shared with `no_std` crates.
+fn gen_skel_type_defs(raw_obj_name: &str, object: &[u8], types: &[String]) -> Result<String> {
+    let mut defs = String::new();
+
+    write!(
+        defs,
+        r#"// SPDX-License-Identifier: (LGPL-2.1 OR BSD-2-Clause)
+           //
+           // THIS FILE IS AUTOGENERATED BY CARGO-LIBBPF-GEN!
+        "#
     )?;
 
-    Ok(())
+    gen_skel_datasec_defs(&mut defs, raw_obj_name, object)?;
+    gen_skel_types(&mut defs, raw_obj_name, object, types)?;
+
+    Ok(defs)
 }
 
 /// Generate contents of a single skeleton
-fn gen_skel_contents(_debug: bool, raw_obj_name: &str, obj_file_path: &Path) -> Result<String> {
+///
+/// If `split` is set, type definitions are returned separately and the skeleton `include!`s
+/// them from `<NAME>.types.rs`. Otherwise they are embedded into the skeleton.
+///
+/// If `shared` is set, `maps()` and `progs()` of the loaded skeleton take `&self` and return
+/// handles backed by their own fds, so the skeleton can be shared between threads.
+///
+/// If `included` is set, the skeleton will be `include!`ed by the generated mod.rs and so
+/// must not contain inner attributes.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn gen_skel_contents(
+    reporter: &dyn Reporter,
+    raw_obj_name: &str,
+    obj_file_path: &Path,
+    types: &[String],
+    ringbufs: &BTreeMap<String, String>,
+    features: &BTreeMap<String, String>,
+    timings: Option<&str>,
+    split: bool,
+    shared: bool,
+    included: bool,
+) -> Result<(String, Option<String>)> {
     let mut skel = String::new();
 
     write!(
@@ -621,18 +1872,30 @@ fn gen_skel_contents(_debug: bool, raw_obj_name: &str, obj_file_path: &Path) ->
         r#"// SPDX-License-Identifier: (LGPL-2.1 OR BSD-2-Clause)
            //
            // THIS FILE IS AUTOGENERATED BY CARGO-LIBBPF-GEN!
+        "#
+    )?;
 
-           #![allow(dead_code)]
-           #![allow(non_snake_case)]
-           #![allow(clippy::transmute_ptr_to_ref)]
+    if !included {
+        write!(
+            skel,
+            r#"
+            #![allow(dead_code)]
+            #![allow(non_snake_case)]
+            "#
+        )?;
+    }
 
-           use libbpf_rs::libbpf_sys;
+    write!(
+        skel,
+        r#"
+        use libbpf_rs::libbpf_sys;
         "#
     )?;
 
-    // The name we'll always hand to libbpf
+    // The name we hand to libbpf unless the skeleton builder is given another one
     //
-    // Note it's important this remains consistent b/c libbpf infers map/prog names from this name
+    // Note libbpf infers datasec map names from this name, so the generated skeleton config
+    // derives them from the name the object is actually opened with
     let libbpf_obj_name = format!("{}_bpf", raw_obj_name);
     // We'll use `obj_name` as the rust-ified object name
     let obj_name = capitalize_first_letter(raw_obj_name);
@@ -641,42 +1904,187 @@ fn gen_skel_contents(_debug: bool, raw_obj_name: &str, obj_file_path: &Path) ->
     let file = File::open(obj_file_path)?;
     let mmap = unsafe { Mmap::map(&file)? };
     let object = open_bpf_object(&libbpf_obj_name, &*mmap)?;
+    check_ident_collisions(object)?;
+
+    // Datasecs the skeleton doesn't know get no accessors
+    for map in MapIter::new(object) {
+        if get_map_name(map)?.is_none() {
+            reporter.report(Diagnostic::warning(format!(
+                "unrecognized map: {}",
+                get_raw_map_name(map)?
+            )));
+        }
+    }
 
-    gen_skel_c_skel_constructor(&mut skel, object, &libbpf_obj_name)?;
+    // Ringbuf sample types need rust definitions too
+    let mut types = types.to_vec();
+    types.extend(ringbufs.values().cloned());
+    types.sort();
+    types.dedup();
+    let types = types.as_slice();
+
+    gen_skel_c_skel_constructor(&mut skel, object)?;
+
+    // Timing instrumentation is only generated if asked for, and then only compiled in with the
+    // crate feature `timings` names
+    let timings_cfg = timings.map(|feature| format!("#[cfg(feature = {:?})]", feature));
+    let timing_start = match &timings_cfg {
+        Some(cfg) => format!("{}\nlet start = std::time::Instant::now();", cfg),
+        None => String::new(),
+    };
+    let timings_field = match &timings_cfg {
+        Some(cfg) => format!(
+            r#"{}
+            /// How long bringing up the skeleton took so far
+            pub timings: libbpf_rs::SkelTimings,"#,
+            cfg
+        ),
+        None => String::new(),
+    };
 
     write!(
         skel,
         r#"
+        /// Builder for [`Open{name}Skel`].
+        ///
+        /// Configure how the object is opened through `obj_builder`, eg.
+        /// `builder.obj_builder.debug(true).relaxed_maps(true)`. `ObjectBuilder::name()` has no
+        /// effect, use [`Self::obj_name()`] instead.
+        ///
+        /// Maps declared with `__uint(pinning, LIBBPF_PIN_BY_NAME)` are pinned under, and reused
+        /// from, `builder.obj_builder.pin_root_path(..)` on load.
         #[derive(Default)]
         pub struct {name}SkelBuilder {{
+            /// Options used to open the object
             pub obj_builder: libbpf_rs::ObjectBuilder,
+            /// Maps to reuse instead of creating on load, by name
+            reused_maps: Vec<(String, std::os::unix::io::OwnedFd)>,
+            /// Object name to open with instead of `{libbpf_obj_name}`
+            obj_name: Option<String>,
         }}
 
         impl<'a> {name}SkelBuilder {{
+            /// Name the object `name` instead of `{libbpf_obj_name}`, eg. to tell apart several
+            /// instances of the skeleton in `bpftool` output. libbpf prefixes the names of
+            /// datasec maps, eg. `.bss`, with up to the first 8 characters of it.
+            pub fn obj_name(&mut self, name: &str) -> &mut Self {{
+                self.obj_name = Some(name.to_string());
+                self
+            }}
+
+            /// Reuse the map `fd` refers to for the map `name` instead of creating a new one
+            /// on load, eg. to share a ringbuf or config map with another skeleton. See
+            /// `libbpf_rs::OpenMap::reuse_fd()`.
+            ///
+            /// `fd` is duplicated, so it may be closed once this returns. Opening fails if the
+            /// object has no map `name`.
+            pub fn reuse_map_fd(
+                &mut self,
+                name: &str,
+                fd: std::os::unix::io::BorrowedFd,
+            ) -> libbpf_rs::Result<&mut Self> {{
+                let fd = fd
+                    .try_clone_to_owned()
+                    .map_err(|e| libbpf_rs::Error::Internal(e.to_string()))?;
+                self.reused_maps.push((name.to_string(), fd));
+
+                Ok(self)
+            }}
+
+            /// Open the object using the options set on `obj_builder`
             pub fn open(mut self) -> libbpf_rs::Result<Open{name}Skel<'a>> {{
-                let mut skel_config = build_skel_config()?;
-                let open_opts = self.obj_builder.opts(std::ptr::null());
+                let obj_name = match &self.obj_name {{
+                    Some(name) => Some(
+                        std::ffi::CString::new(name.as_str())
+                            .map_err(|e| libbpf_rs::Error::InvalidInput(e.to_string()))?,
+                    ),
+                    None => None,
+                }};
+                self.obj_builder.apply_memlock_rlimit()?;
+                let open_opts = self
+                    .obj_builder
+                    .opts(obj_name.as_ref().map_or(std::ptr::null(), |name| name.as_ptr()));
+
+                self.open_opts(open_opts)
+            }}
+
+            /// Open the object using raw libbpf options instead of those set on
+            /// `obj_builder`, for options `ObjectBuilder` does not expose. The object is named
+            /// `open_opts.object_name` if set, like with [`Self::obj_name()`].
+            pub fn open_opts(
+                self,
+                open_opts: libbpf_sys::bpf_object_open_opts,
+            ) -> libbpf_rs::Result<Open{name}Skel<'a>> {{
+                {timing_start}
+                let obj_name = if open_opts.object_name.is_null() {{
+                    "{libbpf_obj_name}"
+                }} else {{
+                    unsafe {{ std::ffi::CStr::from_ptr(open_opts.object_name) }}
+                        .to_str()
+                        .map_err(|e| libbpf_rs::Error::InvalidInput(e.to_string()))?
+                }};
+                let mut skel_config = build_skel_config(obj_name)?;
 
                 let ret = unsafe {{ libbpf_sys::bpf_object__open_skeleton(skel_config.get(), &open_opts) }};
                 if ret != 0 {{
                     return Err(libbpf_rs::Error::System(-ret));
                 }}
 
-                let obj = unsafe {{ libbpf_rs::OpenObject::from_ptr(skel_config.object_ptr()) }};
+                let mut obj = unsafe {{ libbpf_rs::OpenObject::from_ptr(skel_config.object_ptr()) }};
+                for (map_name, fd) in &self.reused_maps {{
+                    match obj.map(map_name)? {{
+                        Some(map) => map.reuse_fd(std::os::unix::io::AsFd::as_fd(fd))?,
+                        None => {{
+                            return Err(libbpf_rs::Error::InvalidInput(format!(
+                                "no map named {{}}",
+                                map_name
+                            )))
+                        }}
+                    }}
+                }}
 
                 Ok(Open{name}Skel {{
                     obj,
-                    skel_config
+                    skel_config,
+                    {open_timings}
                 }})
             }}
         }}
         "#,
-        name = obj_name
+        name = obj_name,
+        libbpf_obj_name = libbpf_obj_name,
+        timing_start = timing_start,
+        open_timings = match &timings_cfg {
+            Some(cfg) => format!(
+                r#"{}
+                timings: libbpf_rs::SkelTimings {{
+                    open: start.elapsed(),
+                    ..Default::default()
+                }},"#,
+                cfg
+            ),
+            None => String::new(),
+        },
     )?;
 
-    gen_skel_map_defs(&mut skel, object, &obj_name, true)?;
-    gen_skel_prog_defs(&mut skel, object, &obj_name, true)?;
-    gen_skel_datasec_defs(&mut skel, raw_obj_name, &*mmap)?;
+    gen_skel_map_defs(&mut skel, object, &obj_name, true, features)?;
+    gen_skel_prog_defs(&mut skel, object, &obj_name, true, features)?;
+    let type_defs = if split {
+        write!(
+            skel,
+            r#"
+            include!("{}.types.rs");
+            "#,
+            raw_obj_name
+        )?;
+
+        Some(gen_skel_type_defs(raw_obj_name, &*mmap, types)?)
+    } else {
+        gen_skel_datasec_defs(&mut skel, raw_obj_name, &*mmap)?;
+        gen_skel_types(&mut skel, raw_obj_name, &*mmap, types)?;
+
+        None
+    };
 
     write!(
         skel,
@@ -684,25 +2092,62 @@ fn gen_skel_contents(_debug: bool, raw_obj_name: &str, obj_file_path: &Path) ->
         pub struct Open{name}Skel<'a> {{
             pub obj: libbpf_rs::OpenObject,
             skel_config: libbpf_rs::skeleton::ObjectSkeletonConfig<'a>,
+            {timings_field}
         }}
 
         impl<'a> Open{name}Skel<'a> {{
             pub fn load(mut self) -> libbpf_rs::Result<{name}Skel<'a>> {{
+                {timing_start}
                 let ret = unsafe {{ libbpf_sys::bpf_object__load_skeleton(self.skel_config.get()) }};
                 if ret != 0 {{
                     return Err(libbpf_rs::Error::System(-ret));
                 }}
 
-                let obj = unsafe {{ libbpf_rs::Object::from_ptr(self.obj.take_ptr()) }};
-
+                let {mut_}obj = unsafe {{ libbpf_rs::Object::from_ptr(self.obj.take_ptr()) }};
+        "#,
+        name = &obj_name,
+        mut_ = if shared { "mut " } else { "" },
+        timings_field = timings_field,
+        timing_start = timing_start,
+    )?;
+    if shared {
+        gen_skel_shared_init(&mut skel, object, &obj_name, features)?;
+    }
+    write!(
+        skel,
+        r#"
                 Ok({name}Skel {{
                     obj,
                     skel_config: self.skel_config,
+                    {load_timings}
+                    {shared_fields}
                     {links}
                 }})
             }}
+
+            /// Check that maps pinned under `pin_dir` by an earlier build of this skeleton have
+            /// the same layout, before reusing them. See `libbpf_rs::check_pinned_layout()`.
+            pub fn check_pinned_layout<P: AsRef<std::path::Path>>(&self, pin_dir: P) -> libbpf_rs::Result<()> {{
+                libbpf_rs::check_pinned_layout(pin_dir, {name}Skel::LAYOUT_HASH)
+            }}
         "#,
         name = &obj_name,
+        load_timings = match &timings_cfg {
+            Some(cfg) => format!(
+                r#"{}
+                timings: libbpf_rs::SkelTimings {{
+                    load: start.elapsed(),
+                    ..self.timings
+                }},"#,
+                cfg
+            ),
+            None => String::new(),
+        },
+        shared_fields = if shared {
+            gen_skel_shared_fields(object, &obj_name, true)
+        } else {
+            String::new()
+        },
         links = if ProgIter::new(object).next().is_some() {
             format!(r#"links: {}Links::default()"#, obj_name)
         } else {
@@ -714,8 +2159,12 @@ fn gen_skel_contents(_debug: bool, raw_obj_name: &str, obj_file_path: &Path) ->
     gen_skel_datasec_getters(&mut skel, object, raw_obj_name, false)?;
     writeln!(skel, "}}")?;
 
-    gen_skel_map_defs(&mut skel, object, &obj_name, false)?;
-    gen_skel_prog_defs(&mut skel, object, &obj_name, false)?;
+    if shared {
+        gen_skel_shared_defs(&mut skel, object, &obj_name, features)?;
+    } else {
+        gen_skel_map_defs(&mut skel, object, &obj_name, false, features)?;
+        gen_skel_prog_defs(&mut skel, object, &obj_name, false, features)?;
+    }
     gen_skel_link_defs(&mut skel, object, &obj_name)?;
 
     write!(
@@ -724,8 +2173,16 @@ fn gen_skel_contents(_debug: bool, raw_obj_name: &str, obj_file_path: &Path) ->
         pub struct {name}Skel<'a> {{
             pub obj: libbpf_rs::Object,
             skel_config: libbpf_rs::skeleton::ObjectSkeletonConfig<'a>,
+            {timings_field}
+            {shared_fields}
         "#,
         name = &obj_name,
+        timings_field = timings_field,
+        shared_fields = if shared {
+            gen_skel_shared_fields(object, &obj_name, false)
+        } else {
+            String::new()
+        },
     )?;
     gen_skel_link_getter(&mut skel, object, &obj_name)?;
     write!(
@@ -737,12 +2194,39 @@ fn gen_skel_contents(_debug: bool, raw_obj_name: &str, obj_file_path: &Path) ->
         "#,
         name = &obj_name,
     )?;
-    gen_skel_prog_getter(&mut skel, object, &obj_name, false)?;
-    gen_skel_map_getter(&mut skel, object, &obj_name, false)?;
+    if shared {
+        gen_skel_shared_getters(&mut skel, object, &obj_name)?;
+    } else {
+        gen_skel_prog_getter(&mut skel, object, &obj_name, false)?;
+        gen_skel_map_getter(&mut skel, object, &obj_name, false)?;
+    }
     gen_skel_datasec_getters(&mut skel, object, raw_obj_name, true)?;
-    gen_skel_attach(&mut skel, object, &obj_name)?;
+    gen_skel_attach(
+        &mut skel,
+        object,
+        &obj_name,
+        features,
+        timings_cfg.as_deref(),
+    )?;
+    gen_skel_ringbuf_events(&mut skel, object, raw_obj_name, &*mmap, ringbufs, features)?;
+    gen_skel_arenas(&mut skel, object, features)?;
     writeln!(skel, "}}")?;
 
+    if shared {
+        write!(
+            skel,
+            r#"
+            // Through `&self`, only handles backed by their own fds and libbpf state that no
+            // longer changes once loaded are reachable
+            unsafe impl Send for {name}Skel<'_> {{}}
+            unsafe impl Sync for {name}Skel<'_> {{}}
+            "#,
+            name = &obj_name,
+        )?;
+    }
+
+    gen_skel_info(&mut skel, object, raw_obj_name, &obj_name, &*mmap)?;
+
     // Coerce to &[u8] just to be safe, as we'll be using debug formatting
     let bytes: &[u8] = &*mmap;
     write!(
@@ -753,29 +2237,47 @@ fn gen_skel_contents(_debug: bool, raw_obj_name: &str, obj_file_path: &Path) ->
         bytes
     )?;
 
-    Ok(skel)
+    Ok((skel, type_defs))
 }
 
 /// Generate a single skeleton
+#[allow(clippy::too_many_arguments)]
 fn gen_skel(
-    debug: bool,
+    reporter: &dyn Reporter,
     name: &str,
     obj: &Path,
     out: OutputDest,
     rustfmt_path: Option<&PathBuf>,
+    types: &[String],
+    ringbufs: &BTreeMap<String, String>,
+    features: &BTreeMap<String, String>,
+    timings: Option<&str>,
+    split: bool,
+    shared: bool,
+    included: bool,
 ) -> Result<()> {
     if name.is_empty() {
         bail!("Object file has no name");
     }
 
-    let skel = rustfmt(&gen_skel_contents(debug, name, obj)?, rustfmt_path)?;
+    let (skel, type_defs) = gen_skel_contents(
+        reporter, name, obj, types, ringbufs, features, timings, split, shared, included,
+    )?;
+    let skel = format_code(&skel, rustfmt_path)?;
+    let type_defs = match type_defs {
+        Some(defs) => Some(format_code(&defs, rustfmt_path)?),
+        None => None,
+    };
 
     match out {
         OutputDest::Stdout => print!("{}", skel),
-        OutputDest::Directory(dir) => {
-            let path = dir.join(format!("{}.skel.rs", name));
-            let mut file = File::create(path)?;
-            file.write_all(skel.as_bytes())?;
+        OutputDest::Directory(dir) | OutputDest::Check(dir) => {
+            let check = matches!(out, OutputDest::Check(_));
+
+            write_or_check(&dir.join(format!("{}.skel.rs", name)), &skel, check)?;
+            if let Some(defs) = type_defs {
+                write_or_check(&dir.join(format!("{}.types.rs", name)), &defs, check)?;
+            }
         }
     };
 
@@ -785,14 +2287,32 @@ fn gen_skel(
 /// Generate mod.rs in src/bpf directory of each project.
 ///
 /// Each `UnprocessedObj` in `objs` must belong to same project.
-pub fn gen_mods(objs: &[UnprocessedObj], rustfmt_path: Option<&PathBuf>) -> Result<()> {
+///
+/// If `check` is set, mod.rs is not written and an error is returned if it is out of date.
+///
+/// If `output_dir` is set, mod.rs is placed there instead and `include!`s the skeletons, so it
+/// can itself be `include!`ed from outside the source tree.
+pub fn gen_mods(
+    objs: &[UnprocessedObj],
+    rustfmt_path: Option<&PathBuf>,
+    check: bool,
+    output_dir: Option<&Path>,
+) -> Result<()> {
     if objs.is_empty() {
         return Ok(());
     }
 
-    let mut path = objs[0].path.clone();
-    path.pop();
-    path.push("mod.rs");
+    check_obj_name_collisions(objs)?;
+
+    let path = match output_dir {
+        Some(dir) => dir.join("mod.rs"),
+        None => {
+            let mut path = objs[0].path.clone();
+            path.pop();
+            path.push("mod.rs");
+            path
+        }
+    };
 
     let mut contents = String::new();
     write!(
@@ -806,125 +2326,541 @@ pub fn gen_mods(objs: &[UnprocessedObj], rustfmt_path: Option<&PathBuf>) -> Resu
     )?;
 
     for obj in objs {
-        write!(
-            contents,
-            r#"
-            #[path = "{name}.skel.rs"]
-            mod {name}_skel;
-            "#,
-            name = obj.name
-        )?;
+        // Namespaced skeletons get a public module of their own, others are re-exported below
+        let (vis, mod_name) = if obj.namespaced_mods {
+            ("pub ", escape_ident(&sanitize_obj_name(&obj.name)))
+        } else {
+            ("", format!("{}_skel", sanitize_obj_name(&obj.name)))
+        };
+
+        if output_dir.is_some() {
+            // Inner attributes are not allowed in `include!`ed files, so they're placed on the
+            // module instead
+            write!(
+                contents,
+                r#"
+                #[allow(dead_code)]
+                #[allow(non_snake_case)]
+                {vis}mod {mod_name} {{
+                    include!("{name}.skel.rs");
+                }}
+                "#,
+                vis = vis,
+                mod_name = mod_name,
+                name = obj.name
+            )?;
+        } else {
+            write!(
+                contents,
+                r#"
+                #[path = "{name}.skel.rs"]
+                {vis}mod {mod_name};
+                "#,
+                vis = vis,
+                mod_name = mod_name,
+                name = obj.name
+            )?;
+        }
     }
 
-    for obj in objs {
+    for obj in objs.iter().filter(|obj| !obj.namespaced_mods) {
         write!(
             contents,
             r#"
             pub use {}_skel::*;
             "#,
-            obj.name
+            sanitize_obj_name(&obj.name)
         )?;
     }
 
-    let mut file = File::create(path)?;
-    file.write_all(rustfmt(&contents, rustfmt_path)?.as_bytes())?;
-
-    Ok(())
+    write_or_check(&path, &format_code(&contents, rustfmt_path)?, check)
 }
 
-fn gen_single(debug: bool, obj_file: &Path, rustfmt_path: Option<&PathBuf>) -> i32 {
+/// Returns the object name of `obj_file`, eg. `runqslower` for `runqslower.bpf.o`
+fn obj_name(obj_file: &Path) -> Result<&str> {
     let filename = match obj_file.file_name() {
         Some(n) => n,
-        None => {
-            eprintln!(
-                "Could not determine file name for object file: {}",
-                obj_file.to_string_lossy()
-            );
-            return 1;
-        }
+        None => bail!(
+            "Could not determine file name for object file: {}",
+            obj_file.to_string_lossy()
+        ),
     };
 
-    let name = match filename.to_str() {
+    match filename.to_str() {
         Some(n) => {
             if !n.ends_with(".o") {
-                eprintln!("Object file does not have `.o` suffix: {}", n);
-                return 1;
+                bail!("Object file does not have `.o` suffix: {}", n);
             }
 
-            n.split('.').next().unwrap()
+            Ok(n.split('.').next().unwrap())
         }
-        None => {
-            eprintln!(
-                "Object file name is not valid unicode: {}",
-                filename.to_string_lossy()
-            );
-            return 1;
+        None => bail!(
+            "Object file name is not valid unicode: {}",
+            filename.to_string_lossy()
+        ),
+    }
+}
+
+/// Expand `--object` arguments into object files. Each argument may be an object file, a
+/// directory whose `.o` files are used, or a glob pattern.
+fn find_objects(objects: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+
+    for obj in objects {
+        let mut paths = if obj.is_dir() {
+            let mut paths = Vec::new();
+            for entry in fs::read_dir(obj)? {
+                let path = entry?.path();
+                if path.is_file() && path.extension().map_or(false, |e| e == "o") {
+                    paths.push(path);
+                }
+            }
+            paths
+        } else if obj.exists() {
+            vec![obj.clone()]
+        } else {
+            let pattern = obj
+                .to_str()
+                .with_context(|| format!("Pattern is not valid unicode: {}", obj.display()))?;
+            glob::glob(pattern)?.collect::<std::result::Result<Vec<_>, _>>()?
+        };
+
+        if paths.is_empty() {
+            bail!("No object files found for {}", obj.display());
+        }
+
+        paths.sort();
+        found.append(&mut paths);
+    }
+
+    found.dedup();
+    Ok(found)
+}
+
+fn gen_single(
+    reporter: &dyn Reporter,
+    obj_file: &Path,
+    rustfmt_path: Option<&PathBuf>,
+    types: &[String],
+    shared: bool,
+) -> Result<()> {
+    let name = obj_name(obj_file)?;
+    check_types_defined(&[obj_file.to_path_buf()], types)?;
+
+    gen_skel(
+        reporter,
+        name,
+        obj_file,
+        OutputDest::Stdout,
+        rustfmt_path,
+        types,
+        &BTreeMap::new(),
+        &BTreeMap::new(),
+        None,
+        false,
+        shared,
+        false,
+    )
+    .with_context(|| format!("Failed to generate skeleton for {}", obj_file.display()))
+}
+
+/// A variable of a datasec, ie. a global variable
+#[derive(Debug, Serialize)]
+struct DatasecVarDesc {
+    name: String,
+    offset: u32,
+    size: u32,
+    /// C spelling of the type
+    #[serde(rename = "type")]
+    ty: String,
+}
+
+/// Layout of a `.data`, `.rodata`, `.bss` or `.kconfig` section
+#[derive(Debug, Serialize)]
+struct DatasecDesc {
+    /// Canonical name, as used by the skeleton, eg. `bss`
+    name: String,
+    size: u32,
+    vars: Vec<DatasecVarDesc>,
+}
+
+#[derive(Debug, Serialize)]
+struct MapDesc {
+    name: String,
+    /// `enum bpf_map_type`
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    /// Whether the map backs a datasec
+    internal: bool,
+    /// C spelling of the key and value types, if the map declares them in BTF
+    key_type: Option<String>,
+    value_type: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ProgDesc {
+    name: String,
+    section: String,
+    /// `enum bpf_prog_type`
+    prog_type: u32,
+    /// `enum bpf_attach_type`
+    attach_type: u32,
+}
+
+/// Machine readable description of an object, as printed by `cargo libbpf gen --format json`
+#[derive(Debug, Serialize)]
+pub struct ObjectDesc {
+    name: String,
+    license: String,
+    kern_version: u32,
+    maps: Vec<MapDesc>,
+    progs: Vec<ProgDesc>,
+    datasecs: Vec<DatasecDesc>,
+}
+
+/// Describe the object `obj_file` named `name`
+pub fn describe_object(name: &str, obj_file: &Path) -> Result<ObjectDesc> {
+    let libbpf_obj_name = format!("{}_bpf", name);
+    let file = File::open(obj_file)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    let object = open_bpf_object(&libbpf_obj_name, &*mmap)?;
+    let btf = btf::Btf::new(name, &*mmap)?;
+
+    let btf_type_name = |type_id: u32| -> Result<Option<String>> {
+        match &btf {
+            Some(btf) if type_id != 0 => Ok(Some(btf.c_type_name(type_id)?)),
+            _ => Ok(None),
         }
     };
 
-    match gen_skel(debug, name, obj_file, OutputDest::Stdout, rustfmt_path) {
-        Ok(_) => 0,
-        Err(e) => {
-            eprintln!(
-                "Failed to generate skeleton for {}: {}",
-                obj_file.to_string_lossy(),
-                e
-            );
+    let mut maps = Vec::new();
+    for map in MapIter::new(object) {
+        let def = unsafe { &*libbpf_sys::bpf_map__def(map) };
+        maps.push(MapDesc {
+            name: match get_map_name(map)? {
+                Some(name) => name,
+                None => get_raw_map_name(map)?,
+            },
+            map_type: def.type_,
+            key_size: def.key_size,
+            value_size: def.value_size,
+            max_entries: def.max_entries,
+            map_flags: def.map_flags,
+            internal: unsafe { libbpf_sys::bpf_map__is_internal(map) },
+            key_type: btf_type_name(unsafe { libbpf_sys::bpf_map__btf_key_type_id(map) })?,
+            value_type: btf_type_name(unsafe { libbpf_sys::bpf_map__btf_value_type_id(map) })?,
+        });
+    }
 
-            1
+    let mut progs = Vec::new();
+    for prog in ProgIter::new(object) {
+        let section_ptr = unsafe { libbpf_sys::bpf_program__title(prog, false) };
+        if section_ptr.is_null() {
+            bail!("Prog section unknown");
+        }
+
+        progs.push(ProgDesc {
+            name: get_prog_name(prog)?,
+            section: unsafe { CStr::from_ptr(section_ptr) }.to_str()?.to_string(),
+            prog_type: unsafe { libbpf_sys::bpf_program__get_type(prog) },
+            attach_type: unsafe { libbpf_sys::bpf_program__get_expected_attach_type(prog) },
+        });
+    }
+
+    let mut datasecs = Vec::new();
+    if let Some(btf) = &btf {
+        for ty in btf.types() {
+            let sec = match ty {
+                btf::BtfType::Datasec(sec) => sec,
+                _ => continue,
+            };
+            let sec_name = match canonicalize_internal_map_name(sec.name) {
+                Some(n) => n,
+                None => continue,
+            };
+
+            let mut vars = Vec::new();
+            for var in &sec.vars {
+                let name = match btf.type_by_id(var.type_id)? {
+                    btf::BtfType::Var(v) => v.name.to_string(),
+                    _ => bail!("BTF is invalid! Datasec var does not point to a var"),
+                };
+
+                vars.push(DatasecVarDesc {
+                    name,
+                    offset: var.offset,
+                    size: var.size,
+                    ty: btf.c_type_name(var.type_id)?,
+                });
+            }
+            vars.sort_by_key(|v| v.offset);
+
+            datasecs.push(DatasecDesc {
+                name: sec_name,
+                size: sec.size,
+                vars,
+            });
         }
     }
+
+    let desc = ObjectDesc {
+        name: name.to_string(),
+        license: get_license(&*mmap)?,
+        kern_version: unsafe { libbpf_sys::bpf_object__kversion(object) },
+        maps,
+        progs,
+        datasecs,
+    };
+    unsafe { libbpf_sys::bpf_object__close(object) };
+
+    Ok(desc)
 }
 
-fn gen_project(
-    debug: bool,
+/// Returns the name and path of each object in `objects`, or in the project at `manifest_path` if
+/// `objects` is empty
+pub(crate) fn named_objects(
+    reporter: &dyn Reporter,
     manifest_path: Option<&PathBuf>,
+    objects: &[PathBuf],
+) -> Result<Vec<(String, PathBuf)>> {
+    if objects.is_empty() {
+        Ok(metadata::get(reporter, manifest_path)?
+            .into_iter()
+            .map(|obj| {
+                let obj_file = obj.out.join(format!("{}.bpf.o", obj.name));
+                (obj.name, obj_file)
+            })
+            .collect())
+    } else {
+        find_objects(objects)?
+            .into_iter()
+            .map(|file| Ok((obj_name(&file)?.to_string(), file)))
+            .collect()
+    }
+}
+
+/// Print a JSON array describing each object in `objects`, or in the project at
+/// `manifest_path` if `objects` is empty, to stdout
+fn describe(
+    reporter: &dyn Reporter,
+    manifest_path: Option<&PathBuf>,
+    objects: &[PathBuf],
+) -> Result<()> {
+    let objs = named_objects(reporter, manifest_path, objects)?;
+    if objs.is_empty() {
+        bail!("Did not find any bpf objects to describe");
+    }
+
+    let mut descs = Vec::new();
+    for (name, obj_file) in &objs {
+        let desc = describe_object(name, obj_file)
+            .with_context(|| format!("Failed to describe {}", obj_file.display()))?;
+        descs.push(desc);
+    }
+
+    let json = serde_json::to_string_pretty(&descs).context("Failed to serialize description")?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Generate a skeleton for each of `obj_files` and a `mod.rs` that `include!`s them into
+/// `output_dir`, without going through cargo metadata
+#[allow(clippy::too_many_arguments)]
+fn gen_objects(
+    reporter: &dyn Reporter,
+    obj_files: &[PathBuf],
     rustfmt_path: Option<&PathBuf>,
-) -> i32 {
-    let to_gen = match metadata::get(debug, manifest_path) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("{}", e);
-            return 1;
+    types: &[String],
+    check: bool,
+    split: bool,
+    shared: bool,
+    output_dir: &Path,
+) -> Result<()> {
+    let mut objs: Vec<UnprocessedObj> = Vec::new();
+    for obj_file in obj_files {
+        let name = obj_name(obj_file)?;
+        if let Some(other) = objs.iter().find(|o| o.name == name) {
+            bail!(
+                "{} and {} have the same object name",
+                other.path.display(),
+                obj_file.display()
+            );
         }
+
+        objs.push(UnprocessedObj {
+            package: String::new(),
+            path: obj_file.clone(),
+            out: obj_file
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+                .to_path_buf(),
+            name: name.to_string(),
+            types: types.to_vec(),
+            ringbufs: BTreeMap::new(),
+            features: BTreeMap::new(),
+            clang: None,
+            namespaced_mods: false,
+            timings: None,
+        });
+    }
+
+    check_types_defined(obj_files, types)?;
+
+    if !check {
+        fs::create_dir_all(output_dir)
+            .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+    }
+
+    let dest = if check {
+        OutputDest::Check(output_dir)
+    } else {
+        OutputDest::Directory(output_dir)
+    };
+
+    for obj in &objs {
+        reporter.report(Diagnostic::debug(format!(
+            "Generating skeleton for {}",
+            obj.path.display()
+        )));
+
+        gen_skel(
+            reporter,
+            &obj.name,
+            &obj.path,
+            dest,
+            rustfmt_path,
+            types,
+            &obj.ringbufs,
+            &obj.features,
+            obj.timings.as_deref(),
+            split,
+            shared,
+            true,
+        )
+        .with_context(|| format!("Failed to generate skeleton for {}", obj.path.display()))?;
+    }
+
+    gen_mods(&objs, rustfmt_path, check, Some(output_dir)).context("Failed to generate mod.rs")
+}
+
+/// Returns true if `skel` was generated after `obj` was built, by the running version of
+/// libbpf-cargo
+fn skel_is_fresh(skel: &Path, obj: &Path) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+
+    let skel_modified = match modified(skel) {
+        Some(m) => m,
+        None => return false,
     };
+    let exe = env::current_exe().ok();
+
+    [Some(obj), exe.as_deref()].iter().all(|p| {
+        p.and_then(|p| modified(p))
+            .map_or(false, |m| m <= skel_modified)
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn gen_project(
+    reporter: &dyn Reporter,
+    manifest_path: Option<&PathBuf>,
+    rustfmt_path: Option<&PathBuf>,
+    types: &[String],
+    check: bool,
+    split: bool,
+    shared: bool,
+    output_dir: Option<&Path>,
+    only_stale: bool,
+) -> Result<()> {
+    let to_gen = metadata::get(reporter, manifest_path)?;
+    if to_gen.is_empty() {
+        bail!("Did not find any bpf objects to generate skeleton");
+    }
+    reporter.report(Diagnostic::debug(format!(
+        "Found bpf objs to gen skel:\n{}",
+        to_gen
+            .iter()
+            .map(|obj| format!("\t{:?}", obj))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )));
+
+    // Types are requested per-package, so each must be defined by some object of its package
+    let mut package_types: BTreeMap<&str, (Vec<PathBuf>, Vec<String>)> = BTreeMap::new();
+    for obj in &to_gen {
+        let (obj_files, obj_types) = package_types
+            .entry(&obj.package)
+            .or_insert_with(|| (Vec::new(), types.to_vec()));
+        obj_files.push(obj.out.join(format!("{}.bpf.o", obj.name)));
+        obj_types.extend_from_slice(&obj.types);
+    }
+    for (package, (obj_files, obj_types)) in &package_types {
+        check_types_defined(obj_files, obj_types)
+            .with_context(|| format!("Invalid types for package={}", package))?;
+    }
 
-    if debug && !to_gen.is_empty() {
-        println!("Found bpf objs to gen skel:");
-        for obj in &to_gen {
-            println!("\t{:?}", obj);
+    if let Some(dir) = output_dir {
+        if !check {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
         }
-    } else if to_gen.is_empty() {
-        eprintln!("Did not find any bpf objects to generate skeleton");
-        return 1;
     }
 
     // Map to store package_name -> [UnprocessedObj]
     let mut package_objs: BTreeMap<String, Vec<UnprocessedObj>> = BTreeMap::new();
 
-    for obj in to_gen {
+    for obj in &to_gen {
         let mut obj_file_path = obj.out.clone();
         obj_file_path.push(format!("{}.bpf.o", obj.name));
 
-        let mut skel_path = obj.path.clone();
-        skel_path.pop();
-
-        match gen_skel(
-            debug,
-            &obj.name,
-            obj_file_path.as_path(),
-            OutputDest::Directory(skel_path.as_path()),
-            rustfmt_path,
-        ) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!(
-                    "Failed to generate skeleton for {}: {}",
-                    obj.path.as_path().display(),
-                    e
-                );
-                return 1;
+        let skel_path = match output_dir {
+            Some(dir) => dir.to_path_buf(),
+            None => {
+                let mut path = obj.path.clone();
+                path.pop();
+                path
             }
+        };
+
+        let mut obj_types = obj.types.clone();
+        obj_types.extend_from_slice(types);
+
+        let dest = if check {
+            OutputDest::Check(skel_path.as_path())
+        } else {
+            OutputDest::Directory(skel_path.as_path())
+        };
+
+        let skel_file_path = skel_path.join(format!("{}.skel.rs", obj.name));
+        if only_stale && skel_is_fresh(&skel_file_path, &obj_file_path) {
+            reporter.report(Diagnostic::debug(format!(
+                "{} is up to date",
+                skel_file_path.display()
+            )));
+        } else {
+            gen_skel(
+                reporter,
+                &obj.name,
+                obj_file_path.as_path(),
+                dest,
+                rustfmt_path,
+                &obj_types,
+                &obj.ringbufs,
+                &obj.features,
+                obj.timings.as_deref(),
+                split,
+                shared,
+                output_dir.is_some(),
+            )
+            .with_context(|| {
+                format!(
+                    "Failed to generate skeleton for {}",
+                    obj.path.as_path().display()
+                )
+            })?;
         }
 
         match package_objs.get_mut(&obj.package) {
@@ -935,33 +2871,166 @@ fn gen_project(
         };
     }
 
+    // Object names are unique across the workspace, so a single mod.rs covers every package
+    if output_dir.is_some() {
+        return gen_mods(&to_gen, rustfmt_path, check, output_dir)
+            .context("Failed to generate mod.rs");
+    }
+
     for (package, objs) in package_objs {
-        match gen_mods(&objs, rustfmt_path) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Failed to generate mod.rs for package={}: {}", package, e);
-                return 1;
-            }
-        }
+        gen_mods(&objs, rustfmt_path, check, None)
+            .with_context(|| format!("Failed to generate mod.rs for package={}", package))?;
     }
 
-    0
+    Ok(())
 }
 
+/// Generate skeletons, as `cargo libbpf gen` does.
+///
+/// Skeletons are generated for `objects`, which may be object files, directories or glob
+/// patterns, or the objects of the project at `manifest_path` if `objects` is empty.
+#[allow(clippy::too_many_arguments)]
 pub fn gen(
-    debug: bool,
+    reporter: &dyn Reporter,
     manifest_path: Option<&PathBuf>,
     rustfmt_path: Option<&PathBuf>,
-    object: Option<&PathBuf>,
-) -> i32 {
-    if manifest_path.is_some() && object.is_some() {
-        eprintln!("--manifest-path and --object cannot be used together");
-        return 1;
+    objects: &[PathBuf],
+    types: &[String],
+    check: bool,
+    split: bool,
+    shared: bool,
+    output_dir: Option<&PathBuf>,
+    format: OutputFormat,
+) -> Result<()> {
+    if manifest_path.is_some() && !objects.is_empty() {
+        bail!("--manifest-path and --object cannot be used together");
     }
 
-    if let Some(obj_file) = object {
-        gen_single(debug, obj_file, rustfmt_path)
-    } else {
-        gen_project(debug, manifest_path, rustfmt_path)
+    if format == OutputFormat::Json {
+        if check || split || shared || output_dir.is_some() {
+            bail!(
+                "--format json cannot be used together with --check, --split, --shared or \
+                --output-dir"
+            );
+        }
+
+        return describe(reporter, manifest_path, objects);
+    }
+
+    if check && !objects.is_empty() && output_dir.is_none() {
+        bail!("--check and --object cannot be used together without --output-dir");
+    }
+
+    if split && !objects.is_empty() && output_dir.is_none() {
+        bail!("--split and --object cannot be used together without --output-dir");
+    }
+
+    // When run from a build script, place output relative to cargo's OUT_DIR
+    let output_dir = output_dir.map(|dir| match env::var_os("OUT_DIR") {
+        Some(out_dir) if dir.is_relative() => Path::new(&out_dir).join(dir),
+        _ => dir.clone(),
+    });
+
+    if objects.is_empty() {
+        return gen_project(
+            reporter,
+            manifest_path,
+            rustfmt_path,
+            types,
+            check,
+            split,
+            shared,
+            output_dir.as_deref(),
+            false,
+        );
+    }
+
+    let obj_files = find_objects(objects)?;
+    match output_dir {
+        Some(dir) => gen_objects(
+            reporter,
+            &obj_files,
+            rustfmt_path,
+            types,
+            check,
+            split,
+            shared,
+            &dir,
+        ),
+        None if obj_files.len() == 1 => {
+            gen_single(reporter, &obj_files[0], rustfmt_path, types, shared)
+        }
+        None => bail!("--output-dir is required when --object matches multiple objects"),
     }
 }
+
+/// Same as [`gen()`] for the project at `manifest_path`, except that skeletons generated after
+/// their object was last built are not regenerated
+pub fn gen_stale(
+    reporter: &dyn Reporter,
+    manifest_path: Option<&PathBuf>,
+    rustfmt_path: Option<&PathBuf>,
+) -> Result<()> {
+    gen_project(
+        reporter,
+        manifest_path,
+        rustfmt_path,
+        &[],
+        false,
+        false,
+        false,
+        None,
+        true,
+    )
+}
+
+#[test]
+fn test_capitalize_first_letter() {
+    assert_eq!(capitalize_first_letter("runqslower"), "Runqslower");
+    assert_eq!(capitalize_first_letter("foo_bar"), "FooBar");
+    assert_eq!(capitalize_first_letter("foo__bar_"), "FooBar");
+    assert_eq!(capitalize_first_letter("foo-bar.baz"), "FooBarBaz");
+    assert_eq!(capitalize_first_letter("2fast"), "_2fast");
+    assert_eq!(capitalize_first_letter("_"), "_");
+    assert_eq!(capitalize_first_letter(""), "_");
+}
+
+#[test]
+fn test_sanitize_idents() {
+    assert_eq!(sanitize_obj_name("runqslower"), "runqslower");
+    assert_eq!(sanitize_obj_name("foo-bar.baz"), "foo_bar_baz");
+    assert_eq!(sanitize_obj_name("2fast"), "_2fast");
+
+    assert_eq!(escape_ident("handle__sched_wakeup"), "handle__sched_wakeup");
+    assert_eq!(escape_ident("type"), "r#type");
+    assert_eq!(escape_ident("async"), "r#async");
+    assert_eq!(escape_ident("self"), "self_");
+    assert_eq!(escape_ident("_"), "__");
+}
+
+#[test]
+fn test_check_obj_name_collisions() {
+    let obj = |name: &str| UnprocessedObj {
+        package: "pkg".to_string(),
+        path: PathBuf::from(format!("src/bpf/{}.bpf.c", name)),
+        out: PathBuf::from("target/bpf"),
+        name: name.to_string(),
+        types: Vec::new(),
+        ringbufs: BTreeMap::new(),
+        features: BTreeMap::new(),
+        clang: None,
+        namespaced_mods: false,
+        timings: None,
+    };
+
+    assert!(check_obj_name_collisions(&[obj("foo_bar"), obj("foo_baz")]).is_ok());
+    assert!(check_obj_name_collisions(&[obj("foo_bar"), obj("foo-bar")]).is_err());
+    assert!(check_obj_name_collisions(&[obj("foo_bar"), obj("fooBar")]).is_err());
+
+    let namespaced = |name: &str| UnprocessedObj {
+        namespaced_mods: true,
+        ..obj(name)
+    };
+    assert!(check_obj_name_collisions(&[namespaced("foo_bar"), namespaced("fooBar")]).is_ok());
+    assert!(check_obj_name_collisions(&[namespaced("foo_bar"), namespaced("foo-bar")]).is_err());
+}
@@ -1,6 +1,3 @@
-// Dummy library, so libbpf-cargo can be registered as a dependency in tools like
-// https://github.com/facebookincubator/reindeer and so we can add docs to docs.rs.
-
 //! cargo-libbpf is a cargo subcommand that helps develop and build eBPF (BPF) programs.
 //!
 //! # Configuration
@@ -11,10 +8,30 @@
 //! [package.metadata.libbpf]
 //! prog_dir = "src/other_bpf_dir"  # default: <manifest_directory>/src/bpf
 //! target_dir = "other_target_dir" # default: <target_dir>/bpf
+//! types = ["event"]               # default: []
+//! ringbufs = { events = "event" } # default: {}
+//! features = { dbg = "debug" }    # default: {}
+//! clang = "clang-14"              # default: discovered, see below
+//! namespaced_mods = true          # default: false
+//! timings = "bench"               # default: none
 //! ```
 //!
 //! * `prog_dir`: path relative to package Cargo.toml to search for bpf progs
 //! * `target_dir`: path relative to workspace target directory to place compiled bpf progs
+//! * `types`: names of structs, unions, or enums to generate rust definitions for
+//! * `ringbufs`: ringbuf map names and the type of their samples. The skeleton gets a
+//!   `<MAP>_events()` method which hands each sample to a callback as a reference to the
+//!   generated type
+//! * `features`: map or prog names and the crate feature that gates them. Their skeleton
+//!   accessors, eg. `maps().<MAP>()` or `attach_<PROG>()`, are generated behind
+//!   `#[cfg(feature = "...")]`, so one object can back several feature configurations
+//! * `clang`: clang binary to build bpf progs with. All packages in a workspace must agree
+//! * `namespaced_mods`: place each skeleton in its own `pub mod <NAME>` in the generated
+//!   `mod.rs`, eg. `bpf::runqslower::RunqslowerSkelBuilder`, instead of re-exporting the contents
+//!   of every skeleton side by side. Objects then can't clash with each other's names
+//! * `timings`: crate feature that instruments the skeletons. With it enabled, open, loaded and
+//!   attached skeletons record how long each step took in a `timings` field of type
+//!   `libbpf_rs::SkelTimings`, eg. to catch regressions in load time across releases
 //!
 //! # Subcommands
 //!
@@ -31,20 +48,145 @@
 //! * there may not be any two identical `<NAME>.bpf.c` file names in any two projects in a
 //!   cargo workspace
 //!
+//! The clang used is, in order of precedence, the one passed with `--clang-path`, `$CLANG`,
+//! `package.metadata.libbpf.clang`, or the first clang >= 10 with BPF support found in `PATH`
+//! (`clang`, then `clang-<N>` from the newest) or in common LLVM install locations such as
+//! `/usr/lib/llvm-<N>/bin`. If none is suitable, every candidate is listed with the reason it was
+//! rejected.
+//!
+//! Builds are incremental: clang writes a `<NAME>.bpf.d` dependency file next to each object,
+//! and objects that are newer than their source and every header it includes are not rebuilt.
+//! Changing compiler flags or the clang version is not detected, so remove the object files
+//! (eg. with `cargo clean`) to force a rebuild.
+//!
+//! Objects are compiled in parallel, by default on as many threads as there are CPUs. Use
+//! `--jobs`/`-j` to limit that. All objects are attempted even if some fail to compile, and every
+//! failure is reported.
+//!
+//! Compiled objects are stripped of DWARF debug info with `llvm-strip -g`, which keeps the BTF
+//! libbpf relies on and makes embedded skeletons much smaller. Use `--llvm-strip-path` to point at
+//! a specific `llvm-strip`, or `--no-strip` to keep debug info.
+//!
 //! ## gen
 //!
 //! `cargo libbpf gen` generates a skeleton module for each BPF object file in the project.  Each
 //! `<NAME>.bpf.o` object file will have its own module. One `mod.rs` file is also generated. All
 //! output files are placed into `package.metadata.libbpf.prog_dir`.
 //!
+//! Types listed in `package.metadata.libbpf.types` (or passed with `--type`) are emitted into a
+//! `<NAME>_types` module inside the skeleton. This is useful for sharing definitions, such as ring
+//! buffer events, between BPF and userspace. Each skeleton gets the types its object defines, and
+//! generation fails if a type isn't defined by any object of the package.
+//!
+//! Each skeleton also describes its object through associated consts, eg. `<NAME>Skel::PROG_NAMES`,
+//! `<NAME>Skel::LICENSE` or all of them at once as a `<NAME>SkelInfo` in `<NAME>Skel::INFO`, so
+//! tools can tell what an embedded object contains without loading it.
+//!
+//! `<NAME>Skel::LAYOUT_HASH` hashes the layout of the object's maps. Call
+//! `check_pinned_layout()` on the open skeleton before reusing maps pinned by an earlier build, to
+//! fail with a clear error rather than have a changed object misread them.
+//!
+//! Accessors of maps declared with `BPF_F_RDONLY` or `BPF_F_WRONLY` return a
+//! `libbpf_rs::ReadOnlyMap` or `libbpf_rs::WriteOnlyMap` once loaded, so operations the kernel
+//! would refuse with `EPERM` don't compile. They still expose the map's fd and pinning, and the
+//! map itself through `as_map()`.
+//!
+//! Loaded skeletons get a `<MAP>_arena()` method for each `BPF_MAP_TYPE_ARENA` map, which maps
+//! the arena into the process as a `libbpf_rs::Arena` to share data structures with BPF programs.
+//!
 //! Be careful to run cargo-libbpf-build before running cargo-libbpf-gen. cargo-libbpf-gen reads
 //! object files from `package.metadata.libbpf.target_dir`.
 //!
+//! Generated output is deterministic, so skeletons may be checked in. `cargo libbpf gen --check`
+//! writes nothing and instead fails with a diff if any checked in file is out of date, which is
+//! useful in CI.
+//!
+//! `cargo libbpf gen --split` writes the type definitions of each skeleton into a separate
+//! `<NAME>.types.rs` file, which the skeleton `include!`s. Type definitions only depend on `core`,
+//! so they can also be reused by `no_std` crates, eg. to parse events.
+//!
+//! `cargo libbpf gen --shared` generates loaded skeletons whose `maps()` and `progs()` take
+//! `&self` and return handles backed by their own fds, so a loaded skeleton can be shared between
+//! threads, eg. behind an `Arc`.
+//!
+//! To keep generated code out of the source tree, `cargo libbpf gen --output-dir <DIR>` writes
+//! skeletons and `mod.rs` into `<DIR>` instead. Relative paths are resolved against `OUT_DIR`
+//! when it is set, so a build script can generate into `target/` and the crate can pull the
+//! result in with:
+//!
+//! ```ignore
+//! mod bpf {
+//!     include!(concat!(env!("OUT_DIR"), "/bpf/mod.rs"));
+//! }
+//! ```
+//!
+//! Build systems other than cargo can skip the project metadata and pass objects directly with
+//! `--object`, which takes an object file, a directory of object files or a glob pattern and may
+//! be repeated. A single object's skeleton is printed to stdout. Otherwise `--output-dir` is
+//! required and receives one skeleton per object along with a `mod.rs` `include!`ing them.
+//!
+//! `cargo libbpf gen --format json` generates no skeletons. It instead prints a JSON array
+//! describing each object to stdout: its maps with their types, sizes and flags, its progs with
+//! their sections and attach types, and the layout of its datasecs. Tooling and code generators
+//! in other languages can consume it instead of parsing the objects themselves.
+//!
+//! ## check
+//!
+//! `cargo libbpf check` sanity checks the object files of the project, or those passed with
+//! `--object`, for mistakes that would otherwise only show as load errors, possibly on a target
+//! machine. It checks that each object:
+//!
+//! * is a relocatable 64-bit BPF ELF object of the host's endianness
+//! * has a `.BTF` section, which BTF-defined maps in `.maps` require
+//! * has a NUL-terminated `license` section
+//! * only has program sections whose names libbpf maps to a program type, eg. `kprobe/<FUNC>`.
+//!   Other sections are warned about, as they only load with a section handler
+//! * has map definitions libbpf can parse, with a type, `max_entries` and key and value sizes the
+//!   kernel accepts for that type
+//!
+//! Problems are printed as errors or warnings. The command fails if there are errors, or also on
+//! warnings with `--deny-warnings`.
+//!
 //! ## make
 //!
 //! `cargo libbpf make` sequentially runs cargo-libbpf-build, cargo-libbpf-gen, and `cargo
 //! build`. This is a convenience command so you don't forget any steps. Alternatively, you could
 //! write a Makefile for your project.
+//!
+//! Unlike cargo-libbpf-gen, cargo-libbpf-make only regenerates skeletons that are older than their
+//! object file.
+//!
+//! # Diagnostics
+//!
+//! Progress, warnings and errors are printed for people by default. With `--message-format json`
+//! they are instead printed to stderr as one JSON object per line, eg.
+//! `{"level":"error","message":"Did not find any bpf progs to compile"}`, for tools driving
+//! `cargo libbpf` to parse. `--debug` adds `debug` level messages, eg. which clang is used.
+//!
+//! Build tools may also call the subcommands as [`build()`], [`gen()`], [`check()`] and
+//! [`make()`], which take a [`report::Reporter`] for diagnostics and return why they failed as
+//! an error.
+//!
+//! # Testing generated code
+//!
+//! With the `test-util` feature, [`test_util::SkelTest`] builds a BPF C snippet and returns the
+//! skeleton `cargo libbpf gen` would generate for it, eg. to check in golden copies of the
+//! skeletons produced with a crate's `features`, `ringbufs` or `timings` options.
+
+mod btf;
+mod build;
+mod check;
+mod clang;
+mod gen;
+mod make;
+mod metadata;
+pub mod report;
+#[cfg(test)]
+mod test;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
-#[doc(hidden)]
-pub fn foo() {}
+pub use crate::build::build;
+pub use crate::check::check;
+pub use crate::gen::{gen, OutputFormat};
+pub use crate::make::make;
@@ -3,14 +3,8 @@ use std::process::exit;
 
 use structopt::StructOpt;
 
-mod btf;
-#[doc(hidden)]
-mod build;
-mod gen;
-mod make;
-mod metadata;
-#[cfg(test)]
-mod test;
+use libbpf_cargo::report::{self, Diagnostic, Reporter};
+use libbpf_cargo::{build, check, gen, make, OutputFormat};
 
 #[doc(hidden)]
 #[derive(Debug, StructOpt)]
@@ -45,42 +39,131 @@ enum Command {
     Build {
         #[structopt(short, long)]
         debug: bool,
+        #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+        /// How to print diagnostics
+        ///
+        /// `json` prints each one to stderr as a JSON object on its own line
+        message_format: String,
         #[structopt(long, parse(from_os_str))]
         /// Path to top level Cargo.toml
         manifest_path: Option<PathBuf>,
-        #[structopt(long, parse(from_os_str), default_value = "clang")]
+        #[structopt(long, parse(from_os_str))]
         /// Path to clang binary
-        clang_path: PathBuf,
+        ///
+        /// Defaults to $CLANG, then package.metadata.libbpf.clang, then the first clang >= 10
+        /// with BPF support found in PATH or in common LLVM install locations
+        clang_path: Option<PathBuf>,
         #[structopt(long)]
         /// Skip clang version checks
         skip_clang_version_checks: bool,
+        #[structopt(short, long)]
+        /// Number of BPF objects to compile in parallel. Defaults to the number of CPUs
+        jobs: Option<usize>,
+        #[structopt(long, parse(from_os_str), default_value = "llvm-strip")]
+        /// Path to llvm-strip binary, used to drop debug info but BTF from objects
+        llvm_strip_path: PathBuf,
+        #[structopt(long)]
+        /// Keep debug info in objects instead of running llvm-strip
+        no_strip: bool,
     },
     /// Generate skeleton files
     Gen {
         #[structopt(short, long)]
         debug: bool,
+        #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+        /// How to print diagnostics
+        ///
+        /// `json` prints each one to stderr as a JSON object on its own line
+        message_format: String,
         #[structopt(long, parse(from_os_str))]
         /// Path to top level Cargo.toml
         manifest_path: Option<PathBuf>,
         #[structopt(long, parse(from_os_str))]
         /// Path to rustfmt binary
+        ///
+        /// If not specified, rustfmt is not run and generated code is laid out by libbpf-cargo
+        /// itself, so the output doesn't depend on what is installed on the machine
         rustfmt_path: Option<PathBuf>,
         #[structopt(long, parse(from_os_str))]
         /// Generate skeleton for the specified object file and print results to stdout
         ///
+        /// May also be a directory or glob pattern, and may be specified multiple times. If more
+        /// than one object is found, skeletons and mod.rs are written to --output-dir.
+        ///
         /// When specified, skeletons for the rest of the project will not be generated
-        object: Option<PathBuf>,
+        object: Vec<PathBuf>,
+        #[structopt(long = "type")]
+        /// Name of a struct, union, or enum to generate a rust definition for
+        ///
+        /// May be specified multiple times
+        types: Vec<String>,
+        #[structopt(long)]
+        /// Do not write skeletons. Instead fail if any generated file is out of date
+        check: bool,
+        #[structopt(long)]
+        /// Write type definitions to a separate `<NAME>.types.rs` file
+        ///
+        /// The type definitions only depend on `core`, so they may be reused by `no_std` crates
+        split: bool,
+        #[structopt(long)]
+        /// Make `maps()` and `progs()` of loaded skeletons take `&self`
+        ///
+        /// They return handles backed by their own fds instead, so a loaded skeleton can be
+        /// shared between threads, eg. behind an `Arc`
+        shared: bool,
+        #[structopt(long, parse(from_os_str))]
+        /// Write skeletons and mod.rs to this directory instead of next to the BPF sources
+        ///
+        /// Relative paths are resolved against `OUT_DIR` if it is set, eg. in a build script. The
+        /// generated mod.rs may then be included with
+        /// `include!(concat!(env!("OUT_DIR"), "/<DIR>/mod.rs"))`
+        output_dir: Option<PathBuf>,
+        #[structopt(long, default_value = "rust", possible_values = &["rust", "json"])]
+        /// What to generate
+        ///
+        /// `json` prints a description of the objects' maps, progs and datasec layouts to
+        /// stdout instead of generating skeletons, for use by tooling in other languages
+        format: OutputFormat,
+    },
+    /// Check bpf object files for mistakes that would only show when loading them
+    Check {
+        #[structopt(short, long)]
+        debug: bool,
+        #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+        /// How to print diagnostics
+        ///
+        /// `json` prints each one to stderr as a JSON object on its own line
+        message_format: String,
+        #[structopt(long, parse(from_os_str))]
+        /// Path to top level Cargo.toml
+        manifest_path: Option<PathBuf>,
+        #[structopt(long, parse(from_os_str))]
+        /// Check the specified object file instead of the project's objects
+        ///
+        /// May also be a directory or glob pattern, and may be specified multiple times
+        object: Vec<PathBuf>,
+        #[structopt(long)]
+        /// Fail on warnings too
+        deny_warnings: bool,
     },
     /// Build project
     Make {
         #[structopt(short, long)]
         debug: bool,
+        #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+        /// How to print diagnostics
+        ///
+        /// `json` prints each one to stderr as a JSON object on its own line
+        message_format: String,
         #[structopt(long, parse(from_os_str))]
         /// Path to top level Cargo.toml
         manifest_path: Option<PathBuf>,
-        #[structopt(long, parse(from_os_str), default_value = "clang")]
+        #[structopt(long, parse(from_os_str))]
         /// Path to clang binary
-        clang_path: PathBuf,
+        ///
+        /// Defaults to $CLANG, then package.metadata.libbpf.clang, then the first clang >= 10
+        /// with BPF support found in PATH or in common LLVM install locations
+        clang_path: Option<PathBuf>,
         #[structopt(long)]
         /// Skip clang version checks
         skip_clang_version_checks: bool,
@@ -93,57 +176,137 @@ enum Command {
         cargo_build_args: Vec<String>,
         #[structopt(long, parse(from_os_str))]
         /// Path to rustfmt binary
+        ///
+        /// If not specified, rustfmt is not run and generated code is laid out by libbpf-cargo
+        /// itself, so the output doesn't depend on what is installed on the machine
         rustfmt_path: Option<PathBuf>,
+        #[structopt(short, long)]
+        /// Number of BPF objects to compile in parallel. Defaults to the number of CPUs
+        jobs: Option<usize>,
+        #[structopt(long, parse(from_os_str), default_value = "llvm-strip")]
+        /// Path to llvm-strip binary, used to drop debug info but BTF from objects
+        llvm_strip_path: PathBuf,
+        #[structopt(long)]
+        /// Keep debug info in objects instead of running llvm-strip
+        no_strip: bool,
     },
 }
 
+/// The reporter `--debug`, `--quiet` and `--message-format` ask for
+fn reporter(debug: bool, quiet: bool, message_format: &str) -> Box<dyn Reporter> {
+    match message_format {
+        "json" => Box::new(report::Json::new(debug)),
+        _ if quiet => Box::new(report::Quiet),
+        _ => Box::new(report::Human::new(debug)),
+    }
+}
+
 #[doc(hidden)]
 fn main() {
     let opts = Opt::from_args();
 
-    let rc = match opts.wrapper {
+    let (reporter, result) = match opts.wrapper {
         Wrapper::Libbpf(cmd) => match cmd {
             Command::Build {
                 debug,
+                message_format,
                 manifest_path,
                 clang_path,
                 skip_clang_version_checks,
-            } => build::build(
-                debug,
-                manifest_path.as_ref(),
-                clang_path.as_path(),
-                skip_clang_version_checks,
-            ),
+                jobs,
+                llvm_strip_path,
+                no_strip,
+            } => {
+                let reporter = reporter(debug, false, &message_format);
+                let result = build(
+                    &*reporter,
+                    manifest_path.as_ref(),
+                    clang_path.as_deref(),
+                    skip_clang_version_checks,
+                    jobs,
+                    if no_strip {
+                        None
+                    } else {
+                        Some(llvm_strip_path.as_path())
+                    },
+                );
+                (reporter, result)
+            }
             Command::Gen {
                 debug,
+                message_format,
                 manifest_path,
                 rustfmt_path,
                 object,
-            } => gen::gen(
+                types,
+                check,
+                split,
+                shared,
+                output_dir,
+                format,
+            } => {
+                let reporter = reporter(debug, false, &message_format);
+                let result = gen(
+                    &*reporter,
+                    manifest_path.as_ref(),
+                    rustfmt_path.as_ref(),
+                    &object,
+                    &types,
+                    check,
+                    split,
+                    shared,
+                    output_dir.as_ref(),
+                    format,
+                );
+                (reporter, result)
+            }
+            Command::Check {
                 debug,
-                manifest_path.as_ref(),
-                rustfmt_path.as_ref(),
-                object.as_ref(),
-            ),
+                message_format,
+                manifest_path,
+                object,
+                deny_warnings,
+            } => {
+                let reporter = reporter(debug, false, &message_format);
+                let result = check(&*reporter, manifest_path.as_ref(), &object, deny_warnings);
+                (reporter, result)
+            }
             Command::Make {
                 debug,
+                message_format,
                 manifest_path,
                 clang_path,
                 skip_clang_version_checks,
                 quiet,
                 cargo_build_args,
                 rustfmt_path,
-            } => make::make(
-                debug,
-                manifest_path.as_ref(),
-                clang_path.as_path(),
-                skip_clang_version_checks,
-                quiet,
-                cargo_build_args,
-                rustfmt_path.as_ref(),
-            ),
+                jobs,
+                llvm_strip_path,
+                no_strip,
+            } => {
+                let reporter = reporter(debug, quiet, &message_format);
+                let result = make(
+                    &*reporter,
+                    manifest_path.as_ref(),
+                    clang_path.as_deref(),
+                    skip_clang_version_checks,
+                    quiet,
+                    cargo_build_args,
+                    rustfmt_path.as_ref(),
+                    jobs,
+                    if no_strip {
+                        None
+                    } else {
+                        Some(llvm_strip_path.as_path())
+                    },
+                );
+                (reporter, result)
+            }
         },
     };
 
-    exit(rc);
+    if let Err(e) = result {
+        reporter.report(Diagnostic::error(format!("{:#}", e)));
+        exit(1);
+    }
 }
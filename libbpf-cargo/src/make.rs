@@ -1,34 +1,41 @@
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use anyhow::{bail, Context, Result};
+
+use crate::report::{Diagnostic, Reporter};
 use crate::{build, gen};
 
+/// Build the BPF programs of the project at `manifest_path`, generate their skeletons and run
+/// `cargo build` with `cargo_build_args`, as `cargo libbpf make` does.
+///
+/// `quiet` is passed on to `cargo build` as `--quiet`.
+#[allow(clippy::too_many_arguments)]
 pub fn make(
-    debug: bool,
+    reporter: &dyn Reporter,
     manifest_path: Option<&PathBuf>,
-    clang: &Path,
+    clang: Option<&Path>,
     skip_clang_version_checks: bool,
     quiet: bool,
     cargo_build_args: Vec<String>,
     rustfmt_path: Option<&PathBuf>,
-) -> i32 {
-    if !quiet {
-        println!("Compiling BPF objects");
-    }
-    let mut ret = build::build(debug, manifest_path, clang, skip_clang_version_checks);
-    if ret != 0 {
-        eprintln!("Failed to compile BPF objects");
-        return ret;
-    }
-
-    if !quiet {
-        println!("Generating skeletons");
-    }
-    ret = gen::gen(debug, manifest_path, None, rustfmt_path);
-    if ret != 0 {
-        eprintln!("Failed to generate skeletons");
-        return ret;
-    }
+    jobs: Option<usize>,
+    llvm_strip: Option<&Path>,
+) -> Result<()> {
+    reporter.report(Diagnostic::info("Compiling BPF objects"));
+    build::build(
+        reporter,
+        manifest_path,
+        clang,
+        skip_clang_version_checks,
+        jobs,
+        llvm_strip,
+    )
+    .context("Failed to compile BPF objects")?;
+
+    reporter.report(Diagnostic::info("Generating skeletons"));
+    gen::gen_stale(reporter, manifest_path, rustfmt_path)
+        .context("Failed to generate skeletons")?;
 
     let mut cmd = Command::new("cargo");
     cmd.arg("build");
@@ -39,23 +46,15 @@ pub fn make(
         cmd.arg(arg);
     }
 
-    let status = match cmd.status() {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Failed to spawn child: {}", e);
-            return 1;
-        }
-    };
-
+    let status = cmd.status().context("Failed to spawn child")?;
     if !status.success() {
         let reason = match status.code() {
             Some(rc) => format!("exit code {}", rc),
             None => "killed by signal".to_string(),
         };
 
-        eprintln!("Failed to `cargo build`: {}", reason);
-        return 1;
+        bail!("Failed to `cargo build`: {}", reason);
     }
 
-    0
+    Ok(())
 }
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
@@ -7,10 +8,18 @@ use cargo_metadata::{MetadataCommand, Package};
 use serde::Deserialize;
 use serde_json::value::Value;
 
+use crate::report::{Diagnostic, Reporter};
+
 #[derive(Default, Deserialize)]
 struct LibbpfPackageMetadata {
     prog_dir: Option<PathBuf>,
     target_dir: Option<PathBuf>,
+    types: Option<Vec<String>>,
+    ringbufs: Option<BTreeMap<String, String>>,
+    features: Option<BTreeMap<String, String>>,
+    clang: Option<PathBuf>,
+    namespaced_mods: Option<bool>,
+    timings: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -29,17 +38,30 @@ pub struct UnprocessedObj {
     pub out: PathBuf,
     /// Object name (eg: `runqslower.bpf.c` -> `runqslower`)
     pub name: String,
+    /// Names of additional BTF types to generate rust definitions for
+    pub types: Vec<String>,
+    /// Ringbuf map names mapped to the name of the BTF type of their samples
+    pub ringbufs: BTreeMap<String, String>,
+    /// Map and prog names mapped to the crate feature gating their skeleton accessors
+    pub features: BTreeMap<String, String>,
+    /// Clang the package asks to be built with
+    pub clang: Option<PathBuf>,
+    /// Whether mod.rs places the skeleton in its own `pub mod <NAME>` instead of re-exporting
+    /// its contents
+    pub namespaced_mods: bool,
+    /// Crate feature enabling timing instrumentation in the skeleton, if any
+    pub timings: Option<String>,
 }
 
 fn get_package(
-    debug: bool,
+    reporter: &dyn Reporter,
     package: &Package,
     workspace_target_dir: &Path,
 ) -> Result<Vec<UnprocessedObj>> {
-    if debug {
-        println!("Metadata for package={}", package.name);
-        println!("\t{}", package.metadata);
-    }
+    reporter.report(Diagnostic::debug(format!(
+        "Metadata for package={}\n\t{}",
+        package.name, package.metadata
+    )));
 
     let package_metadata = if package.metadata != Value::Null {
         let PackageMetadata::Libbpf(lpm) = serde_json::from_value(package.metadata.clone())?;
@@ -53,9 +75,10 @@ fn get_package(
     // Remove "Cargo.toml"
     package_root.pop();
     let in_dir = if let Some(d) = package_metadata.prog_dir {
-        if debug {
-            println!("Custom prog_dir={}", d.to_string_lossy());
-        }
+        reporter.report(Diagnostic::debug(format!(
+            "Custom prog_dir={}",
+            d.to_string_lossy()
+        )));
         // Add requested path
         package_root.push(d);
         package_root
@@ -68,9 +91,10 @@ fn get_package(
     // Respect custom target directories specified by package
     let mut target_dir = workspace_target_dir.to_path_buf();
     let out_dir = if let Some(d) = package_metadata.target_dir {
-        if debug {
-            println!("Custom target_dir={}", d.to_string_lossy());
-        }
+        reporter.report(Diagnostic::debug(format!(
+            "Custom target_dir={}",
+            d.to_string_lossy()
+        )));
 
         // Add requested path
         target_dir.push(d);
@@ -81,6 +105,13 @@ fn get_package(
         target_dir
     };
 
+    let types = package_metadata.types.unwrap_or_default();
+    let ringbufs = package_metadata.ringbufs.unwrap_or_default();
+    let features = package_metadata.features.unwrap_or_default();
+    let clang = package_metadata.clang;
+    let namespaced_mods = package_metadata.namespaced_mods.unwrap_or(false);
+    let timings = package_metadata.timings;
+
     // Get an iterator to the input directory. If directory is missing,
     // skip the current project
     let dir_iter = match fs::read_dir(&in_dir) {
@@ -99,7 +130,7 @@ fn get_package(
         }
     };
 
-    Ok(dir_iter
+    let mut objs: Vec<UnprocessedObj> = dir_iter
         .filter_map(|file| {
             let file_path = match file {
                 Ok(f) => f.path(),
@@ -126,16 +157,30 @@ fn get_package(
                             .to_string(),
                         out: out_dir.clone(),
                         path: file_path,
+                        types: types.clone(),
+                        ringbufs: ringbufs.clone(),
+                        features: features.clone(),
+                        clang: clang.clone(),
+                        namespaced_mods,
+                        timings: timings.clone(),
                     });
                 }
             }
 
             None
         })
-        .collect())
+        .collect();
+
+    // Directory iteration order is unspecified, so sort to keep generated output stable
+    objs.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(objs)
 }
 
-pub fn get(debug: bool, manifest_path: Option<&PathBuf>) -> Result<Vec<UnprocessedObj>> {
+pub fn get(
+    reporter: &dyn Reporter,
+    manifest_path: Option<&PathBuf>,
+) -> Result<Vec<UnprocessedObj>> {
     let mut cmd = MetadataCommand::new();
 
     if let Some(path) = manifest_path {
@@ -155,7 +200,7 @@ pub fn get(debug: bool, manifest_path: Option<&PathBuf>) -> Result<Vec<Unprocess
     for id in &metadata.workspace_members {
         for package in &metadata.packages {
             if id == &package.id {
-                match &mut get_package(debug, &package, &metadata.target_directory) {
+                match &mut get_package(reporter, &package, &metadata.target_directory) {
                     Ok(vv) => v.append(vv),
                     Err(e) => bail!("Failed to process package={}, error={}", package.name, e),
                 }
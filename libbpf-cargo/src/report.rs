@@ -0,0 +1,164 @@
+//! Diagnostics of the subcommands, eg. progress, warnings and problems found in objects.
+//!
+//! The subcommands hand diagnostics to a [`Reporter`] instead of printing them, and return why
+//! they failed as an error, so tools embedding libbpf-cargo choose how to present both:
+//!
+//! ```no_run
+//! use std::sync::Mutex;
+//!
+//! use libbpf_cargo::report::{Diagnostic, Level};
+//!
+//! let diagnostics: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+//! let result = libbpf_cargo::build(&diagnostics, None, None, false, None, None);
+//! for diagnostic in diagnostics.into_inner().unwrap() {
+//!     if diagnostic.level >= Level::Warning {
+//!         eprintln!("{}", diagnostic.message);
+//!     }
+//! }
+//! if let Err(e) = result {
+//!     eprintln!("{:#}", e);
+//! }
+//! ```
+
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// How important a [`Diagnostic`] is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// Details of what is done, eg. which clang was picked, shown with `--debug`
+    Debug,
+    /// Progress, eg. which step `make` is at
+    Info,
+    /// Something likely unintended that does not fail the subcommand
+    Warning,
+    /// Something that fails the subcommand
+    Error,
+}
+
+/// A message from a subcommand
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new<M: Into<String>>(level: Level, message: M) -> Self {
+        Diagnostic {
+            level,
+            message: message.into(),
+        }
+    }
+
+    pub fn debug<M: Into<String>>(message: M) -> Self {
+        Self::new(Level::Debug, message)
+    }
+
+    pub fn info<M: Into<String>>(message: M) -> Self {
+        Self::new(Level::Info, message)
+    }
+
+    pub fn warning<M: Into<String>>(message: M) -> Self {
+        Self::new(Level::Warning, message)
+    }
+
+    pub fn error<M: Into<String>>(message: M) -> Self {
+        Self::new(Level::Error, message)
+    }
+}
+
+/// Receives the diagnostics of a subcommand.
+///
+/// Objects are built in parallel, so diagnostics may be reported from several threads at once.
+pub trait Reporter: Sync {
+    fn report(&self, diagnostic: Diagnostic);
+}
+
+/// Collects diagnostics, eg. to present them once the subcommand returns
+impl Reporter for Mutex<Vec<Diagnostic>> {
+    fn report(&self, diagnostic: Diagnostic) {
+        self.lock().unwrap().push(diagnostic);
+    }
+}
+
+/// Prints diagnostics for people, as `cargo libbpf` does by default: progress to stdout,
+/// warnings and errors to stderr. Debug diagnostics are only printed if `debug` is set.
+#[derive(Clone, Copy, Debug)]
+pub struct Human {
+    debug: bool,
+}
+
+impl Human {
+    pub fn new(debug: bool) -> Self {
+        Human { debug }
+    }
+}
+
+impl Reporter for Human {
+    fn report(&self, diagnostic: Diagnostic) {
+        match diagnostic.level {
+            Level::Debug if !self.debug => (),
+            Level::Debug | Level::Info => println!("{}", diagnostic.message),
+            Level::Warning => eprintln!("warning: {}", diagnostic.message),
+            Level::Error => eprintln!("error: {}", diagnostic.message),
+        }
+    }
+}
+
+/// Like [`Human`], but only prints warnings and errors, as `cargo libbpf make --quiet` does
+#[derive(Clone, Copy, Debug)]
+pub struct Quiet;
+
+impl Reporter for Quiet {
+    fn report(&self, diagnostic: Diagnostic) {
+        if diagnostic.level >= Level::Warning {
+            Human::new(false).report(diagnostic);
+        }
+    }
+}
+
+/// Prints each diagnostic as a line of JSON to stderr, eg.
+/// `{"level":"warning","message":"unrecognized map: foo"}`,
+/// as `--message-format json` does. Stdout is left to what subcommands print as their output,
+/// eg. skeletons.
+#[derive(Clone, Copy, Debug)]
+pub struct Json {
+    debug: bool,
+}
+
+impl Json {
+    pub fn new(debug: bool) -> Self {
+        Json { debug }
+    }
+}
+
+impl Reporter for Json {
+    fn report(&self, diagnostic: Diagnostic) {
+        if diagnostic.level == Level::Debug && !self.debug {
+            return;
+        }
+
+        // Serializing a string and an enum can't fail
+        eprintln!("{}", serde_json::to_string(&diagnostic).unwrap());
+    }
+}
+
+#[test]
+fn test_json() {
+    assert_eq!(
+        serde_json::to_string(&Diagnostic::warning("careful")).unwrap(),
+        r#"{"level":"warning","message":"careful"}"#
+    );
+
+    let collected: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+    let reporter: &dyn Reporter = &collected;
+    reporter.report(Diagnostic::debug("one"));
+    reporter.report(Diagnostic::error("two"));
+    assert_eq!(
+        collected.into_inner().unwrap(),
+        vec![Diagnostic::debug("one"), Diagnostic::error("two")]
+    );
+}
@@ -1,15 +1,25 @@
 use std::convert::TryInto;
-use std::fs::{create_dir, read, File, OpenOptions};
+use std::fs::{create_dir, metadata, read, remove_file, File, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
 
 use goblin::Object;
 use memmap::Mmap;
 use tempfile::{tempdir, TempDir};
 
 use crate::btf;
-use crate::{btf::Btf, build::build, make::make};
+use crate::{
+    btf::Btf,
+    build::build,
+    check::{check, check_object, Severity},
+    gen::{describe_object, format_code, gen, OutputFormat},
+    make::make,
+    report::{Diagnostic, Human, Level},
+};
 
 static VMLINUX: &'static str = include_str!("../test_data/vmlinux.h");
 static BPF_HELPERS: &'static str = include_str!("../test_data/bpf_helpers.h");
@@ -141,31 +151,159 @@ fn test_build_default() {
     let (_dir, proj_dir, cargo_toml) = setup_temp_project();
 
     // No bpf progs yet
-    assert_ne!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    assert!(build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None
+    )
+    .is_err());
 
     // Add prog dir
     create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
-    assert_ne!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    assert!(build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None
+    )
+    .is_err());
 
     // Add a prog
     let _prog_file =
         File::create(proj_dir.join("src/bpf/prog.bpf.c")).expect("failed to create prog file");
 
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     // Validate generated object file
     validate_bpf_o(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path());
 }
 
+#[test]
+fn test_build_strip() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+    let mut prog_file =
+        File::create(proj_dir.join("src/bpf/prog.bpf.c")).expect("failed to create prog file");
+    writeln!(prog_file, "int value = 1;").expect("failed to write prog file");
+
+    let obj = proj_dir.join("target/bpf/prog.bpf.o");
+    let sections = || {
+        let buffer = read(&obj).expect("failed to read object file");
+        let elf = match Object::parse(&buffer).expect("failed to parse object file") {
+            Object::Elf(elf) => elf,
+            _ => panic!("wrong object file format"),
+        };
+        elf.section_headers
+            .iter()
+            .map(|sh| {
+                elf.shdr_strtab
+                    .get(sh.sh_name)
+                    .expect("section name out of bounds")
+                    .expect("failed to read section name")
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+    assert!(sections().contains(&".debug_info".to_string()));
+
+    remove_file(&obj).expect("failed to remove object");
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        Some(Path::new("llvm-strip")),
+    )
+    .expect("failed to build");
+    let stripped = sections();
+    assert!(!stripped.iter().any(|name| name.starts_with(".debug")));
+    assert!(stripped.contains(&".BTF".to_string()));
+}
+
+#[test]
+fn test_build_incremental() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+    let mut header =
+        File::create(proj_dir.join("src/bpf/prog.h")).expect("failed to create header");
+    writeln!(header, "#define VALUE 1").expect("failed to write header");
+    let mut prog_file =
+        File::create(proj_dir.join("src/bpf/prog.bpf.c")).expect("failed to create prog file");
+    writeln!(prog_file, "#include \"prog.h\"\nint value = VALUE;")
+        .expect("failed to write prog file");
+
+    let obj = proj_dir.join("target/bpf/prog.bpf.o");
+    let modified = || {
+        metadata(&obj)
+            .and_then(|m| m.modified())
+            .expect("failed to stat object")
+    };
+
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+    let first = modified();
+
+    // Nothing changed, so nothing is rebuilt
+    sleep(Duration::from_secs(1));
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+    assert_eq!(modified(), first);
+
+    // Changing an included header triggers a rebuild
+    writeln!(header, "#define OTHER 2").expect("failed to write header");
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+    assert!(modified() > first);
+    validate_bpf_o(obj.as_path());
+}
+
 #[test]
 fn test_build_invalid_prog() {
     let (_dir, proj_dir, cargo_toml) = setup_temp_project();
@@ -178,10 +316,47 @@ fn test_build_invalid_prog() {
         File::create(proj_dir.join("src/bpf/prog.bpf.c")).expect("failed to create prog file");
     writeln!(prog_file, "1").expect("write to prog file failed");
 
-    assert_ne!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    assert!(build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None
+    )
+    .is_err());
+}
+
+#[test]
+fn test_build_parallel() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+    for i in 0..4 {
+        File::create(proj_dir.join(format!("src/bpf/prog{}.bpf.c", i)))
+            .expect("failed to create prog file");
+    }
+    let mut invalid_file =
+        File::create(proj_dir.join("src/bpf/invalid.bpf.c")).expect("failed to create prog file");
+    writeln!(invalid_file, "1").expect("write to prog file failed");
+
+    // One invalid prog fails the build, but does not stop the others from compiling
+    assert!(build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        Some(2),
+        None
+    )
+    .is_err());
+    for i in 0..4 {
+        validate_bpf_o(
+            proj_dir
+                .join(format!("target/bpf/prog{}.bpf.o", i))
+                .as_path(),
+        );
+    }
 }
 
 #[test]
@@ -200,20 +375,30 @@ fn test_build_custom() {
         .expect("write to Cargo.toml failed");
 
     // No bpf progs yet
-    assert_ne!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    assert!(build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None
+    )
+    .is_err());
 
     // Add a prog
     create_dir(proj_dir.join("src/other_bpf_dir")).expect("failed to create prog dir");
     let _prog_file = File::create(proj_dir.join("src/other_bpf_dir/prog.bpf.c"))
         .expect("failed to create prog file");
 
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     // Validate generated object file
     validate_bpf_o(
@@ -230,24 +415,39 @@ fn test_enforce_file_extension() {
 
     // Add prog dir
     create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
-    assert_ne!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    assert!(build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None
+    )
+    .is_err());
 
     let _prog_file = File::create(proj_dir.join("src/bpf/prog_BAD_EXTENSION.c"))
         .expect("failed to create prog file");
-    assert_ne!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    assert!(build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None
+    )
+    .is_err());
 
     let _prog_file_again = File::create(proj_dir.join("src/bpf/prog_GOOD_EXTENSION.bpf.c"))
         .expect("failed to create prog file");
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 }
 
 #[test]
@@ -255,15 +455,15 @@ fn test_build_workspace() {
     let (_dir, _, workspace_cargo_toml, proj_one_dir, proj_two_dir) = setup_temp_workspace();
 
     // No bpf progs yet
-    assert_ne!(
-        build(
-            true,
-            Some(&workspace_cargo_toml),
-            Path::new("/bin/clang"),
-            true
-        ),
-        0
-    );
+    assert!(build(
+        &Human::new(true),
+        Some(&workspace_cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None
+    )
+    .is_err());
 
     // Create bpf prog for project one
     create_dir(proj_one_dir.join("src/bpf")).expect("failed to create prog dir");
@@ -275,15 +475,15 @@ fn test_build_workspace() {
     let _prog_file_2 = File::create(proj_two_dir.join("src/bpf/prog2.bpf.c"))
         .expect("failed to create prog file 2");
 
-    assert_eq!(
-        build(
-            true,
-            Some(&workspace_cargo_toml),
-            Path::new("/bin/clang"),
-            true
-        ),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&workspace_cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 }
 
 #[test]
@@ -300,15 +500,15 @@ fn test_build_workspace_collision() {
     let _prog_file_2 = File::create(proj_two_dir.join("src/bpf/prog.bpf.c"))
         .expect("failed to create prog file 2");
 
-    assert_ne!(
-        build(
-            true,
-            Some(&workspace_cargo_toml),
-            Path::new("/bin/clang"),
-            true
-        ),
-        0
-    );
+    assert!(build(
+        &Human::new(true),
+        Some(&workspace_cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None
+    )
+    .is_err());
 }
 
 #[test]
@@ -322,18 +522,18 @@ fn test_make_basic() {
     let _prog_file =
         File::create(proj_dir.join("src/bpf/prog.bpf.c")).expect("failed to create prog file");
 
-    assert_eq!(
-        make(
-            true,
-            Some(&cargo_toml),
-            Path::new("/bin/clang"),
-            true,
-            true,
-            Vec::new(),
-            None,
-        ),
-        0
-    );
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
 
     // Validate generated object file
     validate_bpf_o(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path());
@@ -361,18 +561,18 @@ fn test_make_workspace() {
     let _prog_file_2 = File::create(proj_two_dir.join("src/bpf/prog2.bpf.c"))
         .expect("failed to create prog file 2");
 
-    assert_eq!(
-        make(
-            true,
-            Some(&workspace_cargo_toml),
-            Path::new("/bin/clang"),
-            true,
-            true,
-            Vec::new(),
-            None
-        ),
-        0
-    );
+    make(
+        &Human::new(true),
+        Some(&workspace_cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
 
     // Validate generated object files
     validate_bpf_o(
@@ -401,6 +601,36 @@ fn test_make_workspace() {
         .exists());
 }
 
+#[test]
+fn test_format_code_without_rustfmt() {
+    let code = r####"
+        fn foo() {
+        let s = "{
+  not code";
+              let r = r#"}"#;
+
+
+              bar(|| {
+            baz('{', '\'');
+                }).unwrap();
+                }
+    "####;
+
+    let expected = r####"fn foo() {
+    let s = "{
+  not code";
+    let r = r#"}"#;
+
+    bar(|| {
+        baz('{', '\'');
+    }).unwrap();
+}
+"####;
+
+    assert_eq!(format_code(code, None).unwrap(), expected);
+    assert_eq!(format_code(expected, None).unwrap(), expected);
+}
+
 #[test]
 fn test_skeleton_empty_source() {
     let (_dir, proj_dir, cargo_toml) = setup_temp_project();
@@ -412,18 +642,18 @@ fn test_skeleton_empty_source() {
     let _prog_file =
         File::create(proj_dir.join("src/bpf/prog.bpf.c")).expect("failed to create prog file");
 
-    assert_eq!(
-        make(
-            true,
-            Some(&cargo_toml),
-            Path::new("/bin/clang"),
-            true,
-            true,
-            Vec::new(),
-            None
-        ),
-        0
-    );
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
 
     let mut cargo = OpenOptions::new()
         .append(true)
@@ -474,6 +704,100 @@ fn test_skeleton_empty_source() {
     assert!(status.success());
 }
 
+#[test]
+fn test_skeleton_check() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let _prog_file =
+        File::create(proj_dir.join("src/bpf/prog.bpf.c")).expect("failed to create prog file");
+
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    // Freshly generated skeletons are up to date
+    gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        true,
+        false,
+        false,
+        None,
+        OutputFormat::Rust,
+    )
+    .expect("failed to gen");
+
+    // Regenerating is deterministic
+    let skel_path = proj_dir.join("src/bpf/prog.skel.rs");
+    let skel = read(&skel_path).expect("failed to read skeleton");
+    gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        false,
+        false,
+        false,
+        None,
+        OutputFormat::Rust,
+    )
+    .expect("failed to gen");
+    assert_eq!(skel, read(&skel_path).expect("failed to read skeleton"));
+
+    // Stale skeletons are detected
+    let mut skel_file = OpenOptions::new()
+        .append(true)
+        .open(&skel_path)
+        .expect("failed to open skeleton");
+    writeln!(skel_file, "// stale").expect("failed to write to skeleton");
+    assert!(gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        true,
+        false,
+        false,
+        None,
+        OutputFormat::Rust
+    )
+    .is_err());
+
+    // --check and --object are mutually exclusive without --output-dir
+    let obj_path = proj_dir.join("target/bpf/prog.bpf.o");
+    assert!(gen(
+        &Human::new(true),
+        None,
+        None,
+        &[obj_path],
+        &[],
+        true,
+        false,
+        false,
+        None,
+        OutputFormat::Rust
+    )
+    .is_err());
+}
+
 #[test]
 fn test_skeleton_basic() {
     let (_dir, proj_dir, cargo_toml) = setup_temp_project();
@@ -513,18 +837,18 @@ fn test_skeleton_basic() {
     // Lay down the necessary header files
     add_bpf_headers(&proj_dir);
 
-    assert_eq!(
-        make(
-            true,
-            Some(&cargo_toml),
-            Path::new("/bin/clang"),
-            true,
-            true,
-            Vec::new(),
-            None
-        ),
-        0
-    );
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
 
     let mut cargo = OpenOptions::new()
         .append(true)
@@ -554,7 +878,24 @@ fn test_skeleton_basic() {
         use bpf::*;
 
         fn main() {{
-            let builder = ProgSkelBuilder::default();
+            // Check that the skeleton describes its object without loading it
+            assert_eq!(ProgSkel::MAP_NAMES, &["mymap"]);
+            assert_eq!(ProgSkel::PROG_NAMES, &["this_is_my_prog"]);
+            assert_eq!(ProgSkel::PROG_SECTIONS, &["kprobe/foo"]);
+            assert_eq!(ProgSkel::PROG_TYPES, &[libbpf_rs::ProgramType::Kprobe as u32]);
+            assert_eq!(ProgSkel::LICENSE, "");
+            assert_eq!(ProgSkel::INFO.prog_names, ProgSkel::PROG_NAMES);
+            assert_ne!(ProgSkel::LAYOUT_HASH, 0);
+
+            // Check that object options can be passed through the skeleton builder
+            let mut builder = ProgSkelBuilder::default();
+            builder.obj_builder.debug(true).relaxed_maps(true);
+            let mut opts = ProgSkelBuilder::default().obj_builder.opts(std::ptr::null());
+            opts.relaxed_core_relocs = true;
+            let _open_skel = ProgSkelBuilder::default()
+                .open_opts(opts)
+                .expect("failed to open skel");
+
             let mut open_skel = builder
                 .open()
                 .expect("failed to open skel");
@@ -571,9 +912,36 @@ fn test_skeleton_basic() {
             let _map = skel.maps().mymap();
             let _prog = skel.progs().this_is_my_prog();
 
+            // Check that another instance can share the loaded map
+            let mut shared_builder = ProgSkelBuilder::default();
+            shared_builder
+                .reuse_map_fd("mymap", std::os::unix::io::AsFd::as_fd(skel.maps().mymap()))
+                .expect("failed to reuse map");
+            let _shared_skel = shared_builder
+                .open()
+                .expect("failed to open skel")
+                .load()
+                .expect("failed to load skel");
+
             // Check that attach() is generated
             skel.attach().expect("failed to attach progs");
 
+            // Check that per-program attach methods and attach_all() are generated
+            skel.attach_this_is_my_prog().expect("failed to attach prog");
+            let _failures: Vec<(&'static str, libbpf_rs::Error)> = skel.attach_all();
+
+            // Check that attach_filtered() is generated
+            let mut filter = libbpf_rs::AttachFilter::default();
+            filter.exclude("*_not_mine");
+            skel.attach_filtered(&filter).expect("failed to attach progs");
+
+            // Check that attach_with_retry() is generated
+            skel.attach_with_retry(&libbpf_rs::RetryPolicy::default())
+                .expect("failed to attach progs");
+
+            // Check that detach() is generated
+            skel.detach(&libbpf_rs::DetachOptions::default()).expect("failed to detach progs");
+
             // Check that Option<Link> field is generated
             let _mylink = skel.links.this_is_my_prog.unwrap();
         }}
@@ -626,18 +994,18 @@ fn test_skeleton_datasec() {
     // Lay down the necessary header files
     add_bpf_headers(&proj_dir);
 
-    assert_eq!(
-        make(
-            true,
-            Some(&cargo_toml),
-            Path::new("/bin/clang"),
-            true,
-            true,
-            Vec::new(),
-            None
-        ),
-        0
-    );
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
 
     let mut cargo = OpenOptions::new()
         .append(true)
@@ -673,20 +1041,31 @@ fn test_skeleton_datasec() {
                 .expect("failed to open skel");
 
             // Check that we set rodata vars before load
-            open_skel.rodata().myconst = std::ptr::null_mut();
+            open_skel.rodata().expect("failed to get rodata").myconst = std::ptr::null_mut();
 
             // We can always set bss vars
-            open_skel.bss().myglobal = 42;
+            open_skel.bss().expect("failed to get bss").myglobal = 42;
 
             let mut skel = open_skel
                 .load()
                 .expect("failed to load skel");
 
             // We can always set bss vars
-            skel.bss().myglobal = 24;
+            skel.bss().expect("failed to get bss").myglobal = 24;
+            skel.flush_bss().expect("failed to flush bss");
 
             // Read only for rodata after load
-            let _rodata: &prog_rodata_types::rodata = skel.rodata();
+            let _rodata: &prog_rodata_types::rodata = skel.rodata().expect("failed to get rodata");
+
+            // Datasecs still work with the object renamed
+            let mut builder = ProgSkelBuilder::default();
+            builder.obj_name("other_instance");
+            let mut other_skel = builder
+                .open()
+                .expect("failed to open skel")
+                .load()
+                .expect("failed to load skel");
+            other_skel.bss().expect("failed to get bss").myglobal = 1;
         }}
         "#,
     )
@@ -703,7 +1082,7 @@ fn test_skeleton_datasec() {
 }
 
 #[test]
-fn test_btf_dump_basic() {
+fn test_skeleton_split() {
     let (_dir, proj_dir, cargo_toml) = setup_temp_project();
 
     // Add prog dir
@@ -722,15 +1101,14 @@ fn test_btf_dump_basic() {
         #include "vmlinux.h"
         #include "bpf_helpers.h"
 
-        int myglobal = 1;
-
-        struct Foo {{
-            int x;
-            char y[10];
-            void *z;
-        }};
+        int myglobal = 0;
+        void * const myconst = 0;
 
-        struct Foo foo = {{0}};
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
         "#,
     )
     .expect("failed to write prog.bpf.c");
@@ -738,85 +1116,1649 @@ fn test_btf_dump_basic() {
     // Lay down the necessary header files
     add_bpf_headers(&proj_dir);
 
-    // Build the .bpf.o
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    // Regenerate with type definitions split out
+    gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        false,
+        true,
+        false,
+        None,
+        OutputFormat::Rust,
+    )
+    .expect("failed to gen");
+    gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        true,
+        true,
+        false,
+        None,
+        OutputFormat::Rust,
+    )
+    .expect("failed to gen");
+
+    let types = std::fs::read_to_string(proj_dir.join("src/bpf/prog.types.rs"))
+        .expect("failed to read type definitions");
+    assert!(types.contains("pub mod prog_bss_types"));
+    assert!(!types.contains("std::"));
+
+    let skel = std::fs::read_to_string(proj_dir.join("src/bpf/prog.skel.rs"))
+        .expect("failed to read skeleton");
+    assert!(skel.contains(r#"include!("prog.types.rs");"#));
+    assert!(!skel.contains("pub mod prog_bss_types"));
+
+    // --split and --object are mutually exclusive without --output-dir
+    let obj_path = proj_dir.join("target/bpf/prog.bpf.o");
+    assert!(gen(
+        &Human::new(true),
+        None,
+        None,
+        &[obj_path],
+        &[],
+        false,
+        true,
+        false,
+        None,
+        OutputFormat::Rust
+    )
+    .is_err());
 
-    let obj = OpenOptions::new()
-        .read(true)
-        .open(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path())
-        .expect("failed to open object file");
-    let mmap = unsafe { Mmap::map(&obj) }.expect("Failed to mmap object file");
-    let btf = Btf::new("prog", &*mmap)
-        .expect("Failed to initialize Btf")
-        .expect("Did not find .BTF section");
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
 
-    assert!(btf.types().len() > 0);
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
 
-    // Find our types
-    let mut struct_foo: Option<u32> = None;
-    let mut foo: Option<u32> = None;
-    let mut myglobal: Option<u32> = None;
-    for (idx, ty) in btf.types().iter().enumerate() {
-        match ty {
-            btf::BtfType::Struct(t) => {
-                if t.name == "Foo" {
-                    assert!(struct_foo.is_none()); // No duplicates
-                    struct_foo = Some(idx.try_into().unwrap());
-                }
-            }
-            btf::BtfType::Datasec(t) => {
-                for var in &t.vars {
-                    let var_ty = btf
-                        .type_by_id(var.type_id)
-                        .expect("Failed to lookup datasec var");
-                    match var_ty {
-                        btf::BtfType::Var(t) => {
-                            if t.name == "foo" {
-                                assert!(foo.is_none());
-                                foo = Some(var.type_id);
-                            } else if t.name == "myglobal" {
-                                assert!(myglobal.is_none());
-                                myglobal = Some(var.type_id);
-                            }
-                        }
-                        _ => panic!("Datasec var didn't point to a var. Instead: {}", var_ty),
-                    }
-                }
-            }
-            _ => (),
-        }
-    }
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
 
-    assert!(struct_foo.is_some());
-    assert!(foo.is_some());
-    assert!(myglobal.is_some());
+    write!(
+        source,
+        r#"
+        mod bpf;
+        use bpf::*;
 
-    assert_eq!(
-        "Foo",
-        btf.type_declaration(foo.unwrap())
-            .expect("Failed to generate foo decl")
-    );
-    assert_eq!(
-        "i32",
-        btf.type_declaration(myglobal.unwrap())
-            .expect("Failed to generate myglobal decl")
-    );
+        fn main() {{
+            let builder = ProgSkelBuilder::default();
+            let mut open_skel = builder
+                .open()
+                .expect("failed to open skel");
 
-    let foo_defn = r#"#[derive(Debug, Default, Copy, Clone)]
-#[repr(C)]
-pub struct Foo {
-    pub x: i32,
-    pub y: [i8; 10],
-    pub z: *mut std::ffi::c_void,
-}
-"#;
-    assert_eq!(
-        foo_defn,
-        btf.type_definition(struct_foo.unwrap())
-            .expect("Failed to generate struct Foo defn")
+            open_skel.bss().expect("failed to get bss").myglobal = 42;
+
+            let _bss: prog_bss_types::bss = prog_bss_types::bss {{ myglobal: 0 }};
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--manifest-path")
+        .arg(cargo_toml.into_os_string())
+        .status()
+        .expect("failed to spawn cargo-build");
+    assert!(status.success());
+}
+
+#[test]
+fn test_gen_json() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct event {{
+                u32 pid;
+                char comm[16];
+        }};
+
+        struct {{
+                __uint(type, BPF_MAP_TYPE_HASH);
+                __uint(max_entries, 64);
+                __type(key, u32);
+                __type(value, struct event);
+        }} events SEC(".maps");
+
+        u64 counter = 0;
+        const volatile u32 target_pid = 0;
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                counter++;
+                return 0;
+        }}
+
+        char LICENSE[] SEC("license") = "GPL";
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let obj_path = proj_dir.join("target/bpf/prog.bpf.o");
+    let desc = describe_object("prog", &obj_path).expect("failed to describe object");
+    let desc = serde_json::to_value(&desc).expect("failed to serialize description");
+
+    assert_eq!(desc["name"], "prog");
+    assert_eq!(desc["license"], "GPL");
+
+    let events = desc["maps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|m| m["name"] == "events")
+        .expect("events map not described");
+    assert_eq!(events["map_type"], 1);
+    assert_eq!(events["key_size"], 4);
+    assert_eq!(events["value_size"], 20);
+    assert_eq!(events["max_entries"], 64);
+    assert_eq!(events["key_type"], "u32");
+    assert_eq!(events["value_type"], "struct event");
+
+    assert_eq!(desc["progs"][0]["name"], "this_is_my_prog");
+    assert_eq!(desc["progs"][0]["section"], "kprobe/foo");
+
+    let datasecs = desc["datasecs"].as_array().unwrap();
+    let bss = datasecs
+        .iter()
+        .find(|d| d["name"] == "bss")
+        .expect("bss not described");
+    assert_eq!(bss["vars"][0]["name"], "counter");
+    assert_eq!(bss["vars"][0]["type"], "u64");
+    let rodata = datasecs
+        .iter()
+        .find(|d| d["name"] == "rodata")
+        .expect("rodata not described");
+    assert_eq!(rodata["vars"][0]["name"], "target_pid");
+    assert!(rodata["vars"][0]["type"].as_str().unwrap().ends_with("u32"));
+
+    // JSON descriptions are printed to stdout, so can't be combined with options writing files
+    assert!(gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        true,
+        false,
+        false,
+        None,
+        OutputFormat::Json
+    )
+    .is_err());
+    gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        false,
+        false,
+        false,
+        None,
+        OutputFormat::Json,
+    )
+    .expect("failed to gen");
+}
+
+#[test]
+fn test_check() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a valid prog and one with a misspelled section
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct {{
+                __uint(type, BPF_MAP_TYPE_HASH);
+                __uint(max_entries, 64);
+                __type(key, u32);
+                __type(value, u64);
+        }} mymap SEC(".maps");
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+
+        char LICENSE[] SEC("license") = "GPL";
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    let mut bad_prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/bad.bpf.c"))
+        .expect("failed to open bad.bpf.c");
+
+    write!(
+        bad_prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct {{
+                __uint(type, BPF_MAP_TYPE_HASH);
+                __type(key, u32);
+                __type(value, u64);
+        }} nomax SEC(".maps");
+
+        SEC("kporbe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+        "#,
+    )
+    .expect("failed to write bad.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let problems = check_object("prog", &proj_dir.join("target/bpf/prog.bpf.o"))
+        .expect("failed to check prog.bpf.o");
+    assert_eq!(problems, vec![]);
+
+    let problems = check_object("bad", &proj_dir.join("target/bpf/bad.bpf.o"))
+        .expect("failed to check bad.bpf.o");
+    let severities: Vec<_> = problems.iter().map(|p| p.severity).collect();
+    assert_eq!(
+        severities,
+        vec![Severity::Warning, Severity::Warning, Severity::Error]
+    );
+    assert!(problems[0].message.contains("license"));
+    assert!(problems[1].message.contains("kporbe/foo"));
+    assert!(problems[1].message.contains("section_handler"));
+    assert!(problems[2].message.contains("nomax"));
+
+    // The project fails the check because of bad.bpf.o, and its problems are reported
+    let diagnostics: Mutex<Vec<Diagnostic>> = Mutex::new(Vec::new());
+    assert!(check(&diagnostics, Some(&cargo_toml), &[], false).is_err());
+    let diagnostics = diagnostics.into_inner().unwrap();
+    assert!(diagnostics
+        .iter()
+        .any(|d| d.level == Level::Warning && d.message.contains("kporbe/foo")));
+    assert!(diagnostics
+        .iter()
+        .any(|d| d.level == Level::Error && d.message.contains("nomax")));
+    assert!(diagnostics
+        .iter()
+        .any(|d| d.level == Level::Warning && d.message.contains("license")));
+    check(
+        &Human::new(true),
+        None,
+        &[proj_dir.join("target/bpf/prog.bpf.o")],
+        true,
+    )
+    .expect("failed to check");
+}
+
+#[test]
+fn test_skeleton_shared() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct {{
+                __uint(type, BPF_MAP_TYPE_HASH);
+                __uint(max_entries, 1024);
+                __type(key, u32);
+                __type(value, u64);
+        }} mymap SEC(".maps");
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+    gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        false,
+        false,
+        true,
+        None,
+        OutputFormat::Rust,
+    )
+    .expect("failed to gen");
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf;
+        use bpf::*;
+
+        use std::sync::Arc;
+        use std::thread;
+
+        fn main() {{
+            let builder = ProgSkelBuilder::default();
+            let open_skel = builder
+                .open()
+                .expect("failed to open skel");
+            let skel = Arc::new(open_skel.load().expect("failed to load skel"));
+
+            // Loaded skeletons can be used from several threads at once
+            let handles: Vec<_> = (0..2u32)
+                .map(|i| {{
+                    let skel = skel.clone();
+                    thread::spawn(move || {{
+                        let key = i.to_ne_bytes();
+                        let value = 1u64.to_ne_bytes();
+                        skel.maps()
+                            .mymap
+                            .update(&key, &value, libbpf_rs::MapFlags::empty())
+                            .expect("failed to update map");
+                        assert!(skel.progs().this_is_my_prog.is_some());
+                    }})
+                }})
+                .collect();
+            for handle in handles {{
+                handle.join().expect("thread panicked");
+            }}
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--manifest-path")
+        .arg(cargo_toml.into_os_string())
+        .status()
+        .expect("failed to spawn cargo-build");
+    assert!(status.success());
+}
+
+#[test]
+fn test_skeleton_output_dir() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        int myglobal = 0;
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let output_dir = proj_dir.join("target/skel");
+    gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        false,
+        false,
+        false,
+        Some(&output_dir),
+        OutputFormat::Rust,
+    )
+    .expect("failed to gen");
+    gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &[],
+        true,
+        false,
+        false,
+        Some(&output_dir),
+        OutputFormat::Rust,
+    )
+    .expect("failed to gen");
+
+    // Nothing is written into the source tree
+    assert!(!proj_dir.join("src/bpf/prog.skel.rs").exists());
+    assert!(!proj_dir.join("src/bpf/mod.rs").exists());
+
+    // Skeletons are `include!`ed, so they can't have inner attributes
+    let skel =
+        std::fs::read_to_string(output_dir.join("prog.skel.rs")).expect("failed to read skeleton");
+    assert!(!skel.contains("#!["));
+    let mods = std::fs::read_to_string(output_dir.join("mod.rs")).expect("failed to read mod.rs");
+    assert!(mods.contains(r#"include!("prog.skel.rs");"#));
+
+    // Objects passed directly, as a directory or as a glob generate the same output
+    let obj_dir = proj_dir.join("target/bpf");
+    for objects in &[
+        vec![obj_dir.join("prog.bpf.o")],
+        vec![obj_dir.clone()],
+        vec![obj_dir.join("*.bpf.o")],
+    ] {
+        gen(
+            &Human::new(true),
+            None,
+            None,
+            objects,
+            &[],
+            true,
+            false,
+            false,
+            Some(&output_dir),
+            OutputFormat::Rust,
+        )
+        .expect("failed to gen");
+    }
+
+    // Patterns that match nothing are an error
+    assert!(gen(
+        &Human::new(true),
+        None,
+        None,
+        &[obj_dir.join("*.nothing.o")],
+        &[],
+        false,
+        false,
+        false,
+        Some(&output_dir),
+        OutputFormat::Rust
+    )
+    .is_err());
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf {{
+            include!("../target/skel/mod.rs");
+        }}
+        use bpf::*;
+
+        fn main() {{
+            let builder = ProgSkelBuilder::default();
+            let mut open_skel = builder
+                .open()
+                .expect("failed to open skel");
+
+            open_skel.bss().expect("failed to get bss").myglobal = 42;
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--manifest-path")
+        .arg(cargo_toml.into_os_string())
+        .status()
+        .expect("failed to spawn cargo-build");
+    assert!(status.success());
+}
+
+#[test]
+fn test_skeleton_types() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct event {{
+            u32 pid;
+            char comm[16];
+        }};
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(struct event *e)
+        {{
+                return e->pid;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    // Request a definition for `struct event`
+    writeln!(cargo, "[package.metadata.libbpf]").expect("write to Cargo.toml failed");
+    writeln!(cargo, r#"types = ["event"]"#).expect("write to Cargo.toml failed");
+
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    // Misspelled types are reported rather than silently skipped
+    let err = gen(
+        &Human::new(true),
+        Some(&cargo_toml),
+        None,
+        &[],
+        &["evnet".to_string()],
+        false,
+        false,
+        false,
+        None,
+        OutputFormat::Rust,
+    )
+    .expect_err("gen succeeded with an unknown type");
+    assert!(format!("{:#}", err).contains("evnet"));
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf;
+        use bpf::*;
+
+        fn main() {{
+            let event = prog_types::event::default();
+            let _pid: u32 = event.pid;
+            let _comm: [i8; 16] = event.comm;
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--manifest-path")
+        .arg(cargo_toml.into_os_string())
+        .status()
+        .expect("failed to spawn cargo-build");
+    assert!(status.success());
+}
+
+#[test]
+fn test_skeleton_ringbuf_events() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct event {{
+            u32 pid;
+            char comm[16];
+        }};
+
+        // Test vmlinux.h predates BPF_MAP_TYPE_RINGBUF
+        struct {{
+                __uint(type, 27);
+                __uint(max_entries, 4096);
+        }} events SEC(".maps");
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(struct event *e)
+        {{
+                return e->pid;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    // Samples of `events` are `struct event`
+    writeln!(cargo, "[package.metadata.libbpf]").expect("write to Cargo.toml failed");
+    writeln!(cargo, r#"ringbufs = {{ events = "event" }}"#).expect("write to Cargo.toml failed");
+
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf;
+        use bpf::*;
+
+        fn main() {{
+            let builder = ProgSkelBuilder::default();
+            let open_skel = builder
+                .open()
+                .expect("failed to open skel");
+            let mut skel = open_skel.load().expect("failed to load skel");
+
+            let mut rb = libbpf_rs::RingBufferBuilder::new();
+            skel.events_events(&mut rb, |e: &prog_types::event| -> i32 {{
+                let _pid: u32 = e.pid;
+                0
+            }})
+            .expect("failed to add ringbuf");
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--manifest-path")
+        .arg(cargo_toml.into_os_string())
+        .status()
+        .expect("failed to spawn cargo-build");
+    assert!(status.success());
+}
+
+#[test]
+fn test_skeleton_pinning() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct {{
+                __uint(type, BPF_MAP_TYPE_HASH);
+                __uint(max_entries, 1024);
+                __uint(pinning, LIBBPF_PIN_BY_NAME);
+                __type(key, u32);
+                __type(value, u64);
+        }} mypinnedmap SEC(".maps");
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf;
+        use bpf::*;
+
+        fn main() {{
+            let mut builder = ProgSkelBuilder::default();
+            builder
+                .obj_builder
+                .pin_root_path("/sys/fs/bpf/myprog")
+                .expect("failed to set pin root path");
+            let open_skel = builder
+                .open()
+                .expect("failed to open skel");
+
+            // Maps declared with LIBBPF_PIN_BY_NAME get a path under the pin root
+            let _open_path: Option<std::path::PathBuf> = open_skel.maps().mypinnedmap().pin_path();
+
+            // Maps pinned by an earlier build must have the same layout
+            open_skel
+                .check_pinned_layout("/sys/fs/bpf/myprog")
+                .expect("pinned maps have another layout");
+
+            let skel = open_skel
+                .load()
+                .expect("failed to load skel");
+
+            let _path: Option<std::path::PathBuf> = skel.maps().mypinnedmap().pin_path();
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--manifest-path")
+        .arg(cargo_toml.into_os_string())
+        .status()
+        .expect("failed to spawn cargo-build");
+    assert!(status.success());
+}
+
+#[test]
+fn test_skeleton_map_access() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct {{
+                __uint(type, BPF_MAP_TYPE_HASH);
+                __uint(max_entries, 1024);
+                __uint(map_flags, BPF_F_RDONLY);
+                __type(key, u32);
+                __type(value, u64);
+        }} myrdonlymap SEC(".maps");
+
+        struct {{
+                __uint(type, BPF_MAP_TYPE_HASH);
+                __uint(max_entries, 1024);
+                __uint(map_flags, BPF_F_WRONLY);
+                __type(key, u32);
+                __type(value, u64);
+        }} mywronlymap SEC(".maps");
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf;
+        use bpf::*;
+
+        fn main() {{
+            let mut open_skel = ProgSkelBuilder::default()
+                .open()
+                .expect("failed to open skel");
+
+            // Open maps can still be configured
+            let _open_map: &mut libbpf_rs::OpenMap = open_skel.maps().myrdonlymap();
+
+            let mut skel = open_skel
+                .load()
+                .expect("failed to load skel");
+
+            // Loaded maps only expose what userspace may do with them
+            let key = 0u32.to_ne_bytes();
+            let _value: Option<Vec<u8>> = skel
+                .maps()
+                .myrdonlymap()
+                .lookup(&key, libbpf_rs::MapFlags::ANY)
+                .expect("failed to lookup");
+            skel.maps()
+                .mywronlymap()
+                .update(&key, &1u64.to_ne_bytes(), libbpf_rs::MapFlags::ANY)
+                .expect("failed to update");
+
+            // What the kernel allows on any map stays available
+            assert!(skel.maps().myrdonlymap().fd() >= 0);
+            assert_eq!(skel.maps().mywronlymap().as_map().name(), "mywronlymap");
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--manifest-path")
+        .arg(cargo_toml.into_os_string())
+        .status()
+        .expect("failed to spawn cargo-build");
+    assert!(status.success());
+}
+
+#[test]
+fn test_skeleton_features() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct {{
+                __uint(type, BPF_MAP_TYPE_HASH);
+                __uint(max_entries, 1024);
+                __type(key, u32);
+                __type(value, u64);
+        }} debugmap SEC(".maps");
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+
+        SEC("kprobe/bar")
+        int debug_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    // Only generate accessors for the debug map and prog with the `debug` feature
+    writeln!(cargo, "[features]\ndebug = []").expect("write to Cargo.toml failed");
+    writeln!(cargo, "[package.metadata.libbpf]").expect("write to Cargo.toml failed");
+    writeln!(
+        cargo,
+        r#"features = {{ debugmap = "debug", debug_prog = "debug" }}"#
+    )
+    .expect("write to Cargo.toml failed");
+
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    let skel = std::fs::read_to_string(proj_dir.join("src/bpf/prog.skel.rs"))
+        .expect("failed to read skeleton");
+    assert!(skel.contains(r#"#[cfg(feature = "debug")]"#));
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf;
+        use bpf::*;
+
+        fn main() {{
+            let builder = ProgSkelBuilder::default();
+            let open_skel = builder
+                .open()
+                .expect("failed to open skel");
+            let mut skel = open_skel.load().expect("failed to load skel");
+
+            skel.attach_this_is_my_prog().expect("failed to attach prog");
+            let _failures = skel.attach_all();
+
+            #[cfg(feature = "debug")]
+            {{
+                let _map = skel.maps().debugmap();
+                skel.attach_debug_prog().expect("failed to attach prog");
+            }}
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    // Gated accessors must compile both with and without their feature
+    for features in &["", "debug"] {
+        let status = Command::new("cargo")
+            .arg("build")
+            .arg("--quiet")
+            .arg("--features")
+            .arg(features)
+            .arg("--manifest-path")
+            .arg(&cargo_toml)
+            .status()
+            .expect("failed to spawn cargo-build");
+        assert!(status.success());
+    }
+}
+
+#[test]
+fn test_skeleton_namespaced_mods() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add two progs with the same map and prog names
+    for name in &["one", "two"] {
+        let mut prog = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(proj_dir.join(format!("src/bpf/{}.bpf.c", name)))
+            .expect("failed to open prog");
+
+        write!(
+            prog,
+            r#"
+            #include "vmlinux.h"
+            #include "bpf_helpers.h"
+
+            struct {{
+                    __uint(type, BPF_MAP_TYPE_HASH);
+                    __uint(max_entries, 1024);
+                    __type(key, u32);
+                    __type(value, u64);
+            }} mymap SEC(".maps");
+
+            SEC("kprobe/foo")
+            int this_is_my_prog(u64 *ctx)
+            {{
+                    return 0;
+            }}
+            "#,
+        )
+        .expect("failed to write prog");
+    }
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    writeln!(cargo, "[package.metadata.libbpf]").expect("write to Cargo.toml failed");
+    writeln!(cargo, "namespaced_mods = true").expect("write to Cargo.toml failed");
+
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    let mod_rs =
+        std::fs::read_to_string(proj_dir.join("src/bpf/mod.rs")).expect("failed to read mod.rs");
+    assert!(mod_rs.contains("pub mod one;"));
+    assert!(mod_rs.contains("pub mod two;"));
+    assert!(!mod_rs.contains("pub use"));
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf;
+
+        fn main() {{
+            let mut one = bpf::one::OneSkelBuilder::default()
+                .open()
+                .expect("failed to open skel")
+                .load()
+                .expect("failed to load skel");
+            let mut two = bpf::two::TwoSkelBuilder::default()
+                .open()
+                .expect("failed to open skel")
+                .load()
+                .expect("failed to load skel");
+
+            let _map = one.maps().mymap();
+            let _map = two.maps().mymap();
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("--quiet")
+        .arg("--manifest-path")
+        .arg(&cargo_toml)
+        .status()
+        .expect("failed to spawn cargo-build");
+    assert!(status.success());
+}
+
+#[test]
+fn test_skeleton_timings() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return 0;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    let mut cargo = OpenOptions::new()
+        .append(true)
+        .open(&cargo_toml)
+        .expect("failed to open Cargo.toml");
+
+    // Make test project use our development libbpf-rs version
+    writeln!(
+        cargo,
+        r#"
+        libbpf-rs = {{ path = "{}" }}
+        "#,
+        get_libbpf_rs_path().as_path().display()
+    )
+    .expect("failed to write to Cargo.toml");
+
+    // Only instrument the skeleton with the `bench` feature
+    writeln!(cargo, "[features]\nbench = []").expect("write to Cargo.toml failed");
+    writeln!(cargo, "[package.metadata.libbpf]").expect("write to Cargo.toml failed");
+    writeln!(cargo, r#"timings = "bench""#).expect("write to Cargo.toml failed");
+
+    make(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        true,
+        Vec::new(),
+        None,
+        None,
+        None,
+    )
+    .expect("failed to make");
+
+    let skel = std::fs::read_to_string(proj_dir.join("src/bpf/prog.skel.rs"))
+        .expect("failed to read skeleton");
+    assert!(skel.contains("pub timings: libbpf_rs::SkelTimings"));
+
+    let mut source = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(proj_dir.join("src/main.rs"))
+        .expect("failed to open main.rs");
+
+    write!(
+        source,
+        r#"
+        mod bpf;
+        use bpf::*;
+
+        fn main() {{
+            let open_skel = ProgSkelBuilder::default()
+                .open()
+                .expect("failed to open skel");
+            let mut skel = open_skel.load().expect("failed to load skel");
+            skel.attach().expect("failed to attach progs");
+
+            #[cfg(feature = "bench")]
+            {{
+                let timings: libbpf_rs::SkelTimings = skel.timings;
+                eprintln!("open: {{:?}}", timings.open);
+                eprintln!("load: {{:?}}", timings.load);
+                eprintln!("attach: {{:?}}", timings.attach);
+            }}
+        }}
+        "#,
+    )
+    .expect("failed to write to main.rs");
+
+    // The instrumented skeleton must compile both with and without the feature
+    for features in &["", "bench"] {
+        let status = Command::new("cargo")
+            .arg("build")
+            .arg("--quiet")
+            .arg("--features")
+            .arg(features)
+            .arg("--manifest-path")
+            .arg(&cargo_toml)
+            .status()
+            .expect("failed to spawn cargo-build");
+        assert!(status.success());
+    }
+}
+
+#[test]
+fn test_btf_dump_basic() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        int myglobal = 1;
+
+        struct Foo {{
+            int x;
+            char y[10];
+            void *z;
+        }};
+
+        struct Foo foo = {{0}};
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    // Build the .bpf.o
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let obj = OpenOptions::new()
+        .read(true)
+        .open(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path())
+        .expect("failed to open object file");
+    let mmap = unsafe { Mmap::map(&obj) }.expect("Failed to mmap object file");
+    let btf = Btf::new("prog", &*mmap)
+        .expect("Failed to initialize Btf")
+        .expect("Did not find .BTF section");
+
+    assert!(btf.types().len() > 0);
+
+    // Find our types
+    let mut struct_foo: Option<u32> = None;
+    let mut foo: Option<u32> = None;
+    let mut myglobal: Option<u32> = None;
+    for (idx, ty) in btf.types().iter().enumerate() {
+        match ty {
+            btf::BtfType::Struct(t) => {
+                if t.name == "Foo" {
+                    assert!(struct_foo.is_none()); // No duplicates
+                    struct_foo = Some(idx.try_into().unwrap());
+                }
+            }
+            btf::BtfType::Datasec(t) => {
+                for var in &t.vars {
+                    let var_ty = btf
+                        .type_by_id(var.type_id)
+                        .expect("Failed to lookup datasec var");
+                    match var_ty {
+                        btf::BtfType::Var(t) => {
+                            if t.name == "foo" {
+                                assert!(foo.is_none());
+                                foo = Some(var.type_id);
+                            } else if t.name == "myglobal" {
+                                assert!(myglobal.is_none());
+                                myglobal = Some(var.type_id);
+                            }
+                        }
+                        _ => panic!("Datasec var didn't point to a var. Instead: {}", var_ty),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    assert!(struct_foo.is_some());
+    assert!(foo.is_some());
+    assert!(myglobal.is_some());
+
+    assert_eq!(
+        "Foo",
+        btf.type_declaration(foo.unwrap())
+            .expect("Failed to generate foo decl")
+    );
+    assert_eq!(
+        "i32",
+        btf.type_declaration(myglobal.unwrap())
+            .expect("Failed to generate myglobal decl")
+    );
+
+    let foo_defn = r#"#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Foo {
+    pub x: i32,
+    pub y: [i8; 10],
+    pub z: *mut core::ffi::c_void,
+}
+const _: [(); 24] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).x) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 4] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).y) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 16] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).z) as *const u8).offset_from(base as *const u8) as usize
+}];
+"#;
+    assert_eq!(
+        foo_defn,
+        btf.type_definition(struct_foo.unwrap())
+            .expect("Failed to generate struct Foo defn")
     );
 }
 
@@ -863,10 +2805,259 @@ fn test_btf_dump_struct_definition() {
     add_bpf_headers(&proj_dir);
 
     // Build the .bpf.o
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let obj = OpenOptions::new()
+        .read(true)
+        .open(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path())
+        .expect("failed to open object file");
+    let mmap = unsafe { Mmap::map(&obj) }.expect("Failed to mmap object file");
+    let btf = Btf::new("prog", &*mmap)
+        .expect("Failed to initialize Btf")
+        .expect("Did not find .BTF section");
+
+    assert!(btf.types().len() > 0);
+
+    // Find our struct
+    let mut struct_foo: Option<u32> = None;
+    for (idx, ty) in btf.types().iter().enumerate() {
+        match ty {
+            btf::BtfType::Struct(t) => {
+                if t.name == "Foo" {
+                    assert!(struct_foo.is_none()); // No duplicates
+                    struct_foo = Some(idx.try_into().unwrap());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    assert!(struct_foo.is_some());
+
+    // Note how there's 6 bytes of padding. It's not necessary on 64 bit archs but
+    // we've assumed 32 bit arch during padding generation.
+    let foo_defn = r#"#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Foo {
+    pub ip: *mut i32,
+    pub ipp: *mut *mut i32,
+    pub bar: Bar,
+    __pad_18: [u8; 6],
+    pub pb: *mut Bar,
+    pub v: u64,
+    pub cv: i64,
+    pub r: *mut i8,
+}
+const _: [(); 56] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).ip) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 8] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).ipp) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 16] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).bar) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 24] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).pb) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 32] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).v) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 40] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).cv) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 48] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).r) as *const u8).offset_from(base as *const u8) as usize
+}];
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Bar {
+    pub x: u16,
+}
+const _: [(); 2] = [(); core::mem::size_of::<Bar>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Bar>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).x) as *const u8).offset_from(base as *const u8) as usize
+}];
+"#;
+    assert_eq!(
+        foo_defn,
+        btf.type_definition(struct_foo.unwrap())
+            .expect("Failed to generate struct Foo defn")
+    );
+}
+
+#[test]
+fn test_btf_dump_definition_packed_struct() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct Foo {{
+            int x;
+            char y;
+            __s32 z[2];
+        }} __attribute__((packed));
+
+        struct Foo foo;
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    // Build the .bpf.o
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let obj = OpenOptions::new()
+        .read(true)
+        .open(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path())
+        .expect("failed to open object file");
+    let mmap = unsafe { Mmap::map(&obj) }.expect("Failed to mmap object file");
+    let btf = Btf::new("prog", &*mmap)
+        .expect("Failed to initialize Btf")
+        .expect("Did not find .BTF section");
+
+    assert!(btf.types().len() > 0);
+
+    // Find our struct
+    let mut struct_foo: Option<u32> = None;
+    for (idx, ty) in btf.types().iter().enumerate() {
+        match ty {
+            btf::BtfType::Struct(t) => {
+                if t.name == "Foo" {
+                    assert!(struct_foo.is_none()); // No duplicates
+                    struct_foo = Some(idx.try_into().unwrap());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    assert!(struct_foo.is_some());
+
+    let foo_defn = r#"#[derive(Debug, Default, Copy, Clone)]
+#[repr(C, packed)]
+pub struct Foo {
+    pub x: i32,
+    pub y: i8,
+    pub z: [i32; 2],
+}
+const _: [(); 13] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).x) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 4] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).y) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 5] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).z) as *const u8).offset_from(base as *const u8) as usize
+}];
+"#;
     assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
+        foo_defn,
+        btf.type_definition(struct_foo.unwrap())
+            .expect("Failed to generate struct Foo defn")
     );
+}
+
+#[test]
+fn test_btf_dump_definition_bitfield_struct() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct Foo {{
+            unsigned int x: 2;
+            unsigned int y: 3;
+            int z: 4;
+        }};
+
+        struct Foo foo;
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    // Build the .bpf.o
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     let obj = OpenOptions::new()
         .read(true)
@@ -893,37 +3084,215 @@ fn test_btf_dump_struct_definition() {
         }
     }
 
-    assert!(struct_foo.is_some());
+    assert!(struct_foo.is_some());
+
+    let foo_defn = r#"#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Foo {
+    __bitfield_0: [u8; 2],
+    __pad_2: [u8; 2],
+}
+impl Foo {
+    pub fn x(&self) -> u32 {
+        let mut val: u64 = 0;
+        for i in 0..2 {
+            let bit = 0 + i;
+            if self.__bitfield_0[bit / 8] & (1 << (bit % 8)) != 0 {
+                val |= 1 << i;
+            }
+        }
+        val as u32
+    }
+    pub fn set_x(&mut self, val: u32) {
+        let val = val as u64;
+        for i in 0..2 {
+            let bit = 0 + i;
+            if val & (1 << i) != 0 {
+                self.__bitfield_0[bit / 8] |= 1 << (bit % 8);
+            } else {
+                self.__bitfield_0[bit / 8] &= !(1 << (bit % 8));
+            }
+        }
+    }
+    pub fn y(&self) -> u32 {
+        let mut val: u64 = 0;
+        for i in 0..3 {
+            let bit = 2 + i;
+            if self.__bitfield_0[bit / 8] & (1 << (bit % 8)) != 0 {
+                val |= 1 << i;
+            }
+        }
+        val as u32
+    }
+    pub fn set_y(&mut self, val: u32) {
+        let val = val as u64;
+        for i in 0..3 {
+            let bit = 2 + i;
+            if val & (1 << i) != 0 {
+                self.__bitfield_0[bit / 8] |= 1 << (bit % 8);
+            } else {
+                self.__bitfield_0[bit / 8] &= !(1 << (bit % 8));
+            }
+        }
+    }
+    pub fn z(&self) -> i32 {
+        let mut val: u64 = 0;
+        for i in 0..4 {
+            let bit = 5 + i;
+            if self.__bitfield_0[bit / 8] & (1 << (bit % 8)) != 0 {
+                val |= 1 << i;
+            }
+        }
+        if val & (1 << 3) != 0 {
+            val |= !0 << 4;
+        }
+        val as i32
+    }
+    pub fn set_z(&mut self, val: i32) {
+        let val = val as u64;
+        for i in 0..4 {
+            let bit = 5 + i;
+            if val & (1 << i) != 0 {
+                self.__bitfield_0[bit / 8] |= 1 << (bit % 8);
+            } else {
+                self.__bitfield_0[bit / 8] &= !(1 << (bit % 8));
+            }
+        }
+    }
+}
+const _: [(); 4] = [(); core::mem::size_of::<Foo>()];
+"#;
+    assert_eq!(
+        foo_defn,
+        btf.type_definition(struct_foo.unwrap())
+            .expect("Failed to generate struct Foo defn")
+    );
+}
+
+#[test]
+fn test_btf_dump_definition_bitfield_bool_enum() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        enum Color {{
+            RED,
+            GREEN,
+        }};
+
+        struct Foo {{
+            bool b: 1;
+            enum Color c: 3;
+        }};
+
+        struct Foo foo;
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    // Build the .bpf.o
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let obj = OpenOptions::new()
+        .read(true)
+        .open(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path())
+        .expect("failed to open object file");
+    let mmap = unsafe { Mmap::map(&obj) }.expect("Failed to mmap object file");
+    let btf = Btf::new("prog", &*mmap)
+        .expect("Failed to initialize Btf")
+        .expect("Did not find .BTF section");
 
-    // Note how there's 6 bytes of padding. It's not necessary on 64 bit archs but
-    // we've assumed 32 bit arch during padding generation.
+    let struct_foo = btf
+        .find_named_type("Foo")
+        .expect("Failed to find struct Foo");
+
+    // Enum bitfields are accessed as the enum's underlying integer, which needs no definition
     let foo_defn = r#"#[derive(Debug, Default, Copy, Clone)]
 #[repr(C)]
 pub struct Foo {
-    pub ip: *mut i32,
-    pub ipp: *mut *mut i32,
-    pub bar: Bar,
-    __pad_18: [u8; 6],
-    pub pb: *mut Bar,
-    pub v: u64,
-    pub cv: i64,
-    pub r: *mut i8,
+    __bitfield_0: [u8; 1],
+    __pad_1: [u8; 3],
 }
-#[derive(Debug, Default, Copy, Clone)]
-#[repr(C)]
-pub struct Bar {
-    pub x: u16,
+impl Foo {
+    pub fn b(&self) -> bool {
+        let mut val: u64 = 0;
+        for i in 0..1 {
+            let bit = 0 + i;
+            if self.__bitfield_0[bit / 8] & (1 << (bit % 8)) != 0 {
+                val |= 1 << i;
+            }
+        }
+        val != 0
+    }
+    pub fn set_b(&mut self, val: bool) {
+        let val = val as u64;
+        for i in 0..1 {
+            let bit = 0 + i;
+            if val & (1 << i) != 0 {
+                self.__bitfield_0[bit / 8] |= 1 << (bit % 8);
+            } else {
+                self.__bitfield_0[bit / 8] &= !(1 << (bit % 8));
+            }
+        }
+    }
+    pub fn c(&self) -> u32 {
+        let mut val: u64 = 0;
+        for i in 0..3 {
+            let bit = 1 + i;
+            if self.__bitfield_0[bit / 8] & (1 << (bit % 8)) != 0 {
+                val |= 1 << i;
+            }
+        }
+        val as u32
+    }
+    pub fn set_c(&mut self, val: u32) {
+        let val = val as u64;
+        for i in 0..3 {
+            let bit = 1 + i;
+            if val & (1 << i) != 0 {
+                self.__bitfield_0[bit / 8] |= 1 << (bit % 8);
+            } else {
+                self.__bitfield_0[bit / 8] &= !(1 << (bit % 8));
+            }
+        }
+    }
 }
+const _: [(); 4] = [(); core::mem::size_of::<Foo>()];
 "#;
     assert_eq!(
         foo_defn,
-        btf.type_definition(struct_foo.unwrap())
+        btf.type_definition(struct_foo)
             .expect("Failed to generate struct Foo defn")
     );
 }
 
 #[test]
-fn test_btf_dump_definition_packed_struct() {
+fn test_btf_dump_definition_keyword_members() {
     let (_dir, proj_dir, cargo_toml) = setup_temp_project();
 
     // Add prog dir
@@ -943,10 +3312,9 @@ fn test_btf_dump_definition_packed_struct() {
         #include "bpf_helpers.h"
 
         struct Foo {{
-            int x;
-            char y;
-            __s32 z[2];
-        }} __attribute__((packed));
+            int type;
+            unsigned int self: 4;
+        }};
 
         struct Foo foo;
         "#,
@@ -957,10 +3325,15 @@ fn test_btf_dump_definition_packed_struct() {
     add_bpf_headers(&proj_dir);
 
     // Build the .bpf.o
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     let obj = OpenOptions::new()
         .read(true)
@@ -971,41 +3344,56 @@ fn test_btf_dump_definition_packed_struct() {
         .expect("Failed to initialize Btf")
         .expect("Did not find .BTF section");
 
-    assert!(btf.types().len() > 0);
+    let struct_foo = btf
+        .find_named_type("Foo")
+        .expect("Failed to find struct Foo");
 
-    // Find our struct
-    let mut struct_foo: Option<u32> = None;
-    for (idx, ty) in btf.types().iter().enumerate() {
-        match ty {
-            btf::BtfType::Struct(t) => {
-                if t.name == "Foo" {
-                    assert!(struct_foo.is_none()); // No duplicates
-                    struct_foo = Some(idx.try_into().unwrap());
-                }
+    let foo_defn = r#"#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Foo {
+    pub r#type: i32,
+    __bitfield_4: [u8; 1],
+    __pad_5: [u8; 3],
+}
+impl Foo {
+    pub fn self_(&self) -> u32 {
+        let mut val: u64 = 0;
+        for i in 0..4 {
+            let bit = 0 + i;
+            if self.__bitfield_4[bit / 8] & (1 << (bit % 8)) != 0 {
+                val |= 1 << i;
+            }
+        }
+        val as u32
+    }
+    pub fn set_self(&mut self, val: u32) {
+        let val = val as u64;
+        for i in 0..4 {
+            let bit = 0 + i;
+            if val & (1 << i) != 0 {
+                self.__bitfield_4[bit / 8] |= 1 << (bit % 8);
+            } else {
+                self.__bitfield_4[bit / 8] &= !(1 << (bit % 8));
             }
-            _ => (),
         }
     }
-
-    assert!(struct_foo.is_some());
-
-    let foo_defn = r#"#[derive(Debug, Default, Copy, Clone)]
-#[repr(C, packed)]
-pub struct Foo {
-    pub x: i32,
-    pub y: i8,
-    pub z: [i32; 2],
 }
+const _: [(); 8] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).r#type) as *const u8).offset_from(base as *const u8) as usize
+}];
 "#;
     assert_eq!(
         foo_defn,
-        btf.type_definition(struct_foo.unwrap())
+        btf.type_definition(struct_foo)
             .expect("Failed to generate struct Foo defn")
     );
 }
 
 #[test]
-fn test_btf_dump_definition_bitfield_struct_fails() {
+fn test_btf_dump_definition_anonymous_types() {
     let (_dir, proj_dir, cargo_toml) = setup_temp_project();
 
     // Add prog dir
@@ -1025,8 +3413,14 @@ fn test_btf_dump_definition_bitfield_struct_fails() {
         #include "bpf_helpers.h"
 
         struct Foo {{
-            unsigned int x: 2;
-            unsigned int y: 3;
+            int x;
+            union {{
+                int y;
+                char z;
+            }};
+            struct {{
+                u16 a;
+            }} named;
         }};
 
         struct Foo foo;
@@ -1038,10 +3432,15 @@ fn test_btf_dump_definition_bitfield_struct_fails() {
     add_bpf_headers(&proj_dir);
 
     // Build the .bpf.o
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     let obj = OpenOptions::new()
         .read(true)
@@ -1069,7 +3468,88 @@ fn test_btf_dump_definition_bitfield_struct_fails() {
     }
 
     assert!(struct_foo.is_some());
-    assert!(btf.type_definition(struct_foo.unwrap()).is_err());
+
+    // Anonymous types are named after their type ID
+    let mut anon_union: Option<u32> = None;
+    let mut anon_struct: Option<u32> = None;
+    for (idx, ty) in btf.types().iter().enumerate() {
+        match ty {
+            btf::BtfType::Union(t) if t.name.is_empty() => {
+                assert!(anon_union.is_none()); // No duplicates
+                anon_union = Some(idx.try_into().unwrap());
+            }
+            btf::BtfType::Struct(t) if t.name.is_empty() => {
+                assert!(anon_struct.is_none()); // No duplicates
+                anon_struct = Some(idx.try_into().unwrap());
+            }
+            _ => (),
+        }
+    }
+
+    assert!(anon_union.is_some());
+    assert!(anon_struct.is_some());
+
+    let foo_defn = format!(
+        r#"#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Foo {{
+    pub x: i32,
+    pub __anon_1: __anon_{union_id},
+    pub named: __anon_{struct_id},
+}}
+const _: [(); 12] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).x) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 4] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).__anon_1) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 8] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).named) as *const u8).offset_from(base as *const u8) as usize
+}];
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub union __anon_{union_id} {{
+    pub y: i32,
+    pub z: i8,
+}}
+impl core::fmt::Debug for __anon_{union_id} {{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {{
+        write!(f, "(???)")
+    }}
+}}
+impl Default for __anon_{union_id} {{
+    fn default() -> Self {{
+        unsafe {{ core::mem::zeroed() }}
+    }}
+}}
+const _: [(); 4] = [(); core::mem::size_of::<__anon_{union_id}>()];
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct __anon_{struct_id} {{
+    pub a: u16,
+}}
+const _: [(); 2] = [(); core::mem::size_of::<__anon_{struct_id}>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<__anon_{struct_id}>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).a) as *const u8).offset_from(base as *const u8) as usize
+}];
+"#,
+        union_id = anon_union.unwrap(),
+        struct_id = anon_struct.unwrap(),
+    );
+    assert_eq!(
+        foo_defn,
+        btf.type_definition(struct_foo.unwrap())
+            .expect("Failed to generate struct Foo defn")
+    );
 }
 
 #[test]
@@ -1107,10 +3587,15 @@ fn test_btf_dump_definition_enum() {
     add_bpf_headers(&proj_dir);
 
     // Build the .bpf.o
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     let obj = OpenOptions::new()
         .read(true)
@@ -1146,6 +3631,11 @@ pub enum Foo {
     One = 1,
     seven = 7,
 }
+impl Default for Foo {
+    fn default() -> Self {
+        Foo::Zero
+    }
+}
 "#;
     assert_eq!(
         foo_defn,
@@ -1189,10 +3679,15 @@ fn test_btf_dump_definition_union() {
     add_bpf_headers(&proj_dir);
 
     // Build the .bpf.o
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     let obj = OpenOptions::new()
         .read(true)
@@ -1221,13 +3716,24 @@ fn test_btf_dump_definition_union() {
 
     assert!(union_foo.is_some());
 
-    let foo_defn = r#"#[derive(Debug, Copy, Clone)]
+    let foo_defn = r#"#[derive(Copy, Clone)]
 #[repr(C)]
 pub union Foo {
     pub x: i32,
     pub y: u32,
     pub z: [i8; 128],
 }
+impl core::fmt::Debug for Foo {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "(???)")
+    }
+}
+impl Default for Foo {
+    fn default() -> Self {
+        unsafe { core::mem::zeroed() }
+    }
+}
+const _: [(); 128] = [(); core::mem::size_of::<Foo>()];
 "#;
     assert_eq!(
         foo_defn,
@@ -1274,10 +3780,15 @@ fn test_btf_dump_definition_shared_dependent_types() {
     add_bpf_headers(&proj_dir);
 
     // Build the .bpf.o
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     let obj = OpenOptions::new()
         .read(true)
@@ -1312,11 +3823,28 @@ pub struct Foo {
     pub bar: Bar,
     pub bartwo: Bar,
 }
+const _: [(); 4] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).bar) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 2] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).bartwo) as *const u8).offset_from(base as *const u8) as usize
+}];
 #[derive(Debug, Default, Copy, Clone)]
 #[repr(C)]
 pub struct Bar {
     pub x: u16,
 }
+const _: [(); 2] = [(); core::mem::size_of::<Bar>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Bar>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).x) as *const u8).offset_from(base as *const u8) as usize
+}];
 "#;
     assert_eq!(
         foo_defn,
@@ -1325,6 +3853,120 @@ pub struct Bar {
     );
 }
 
+#[test]
+fn test_btf_dump_definition_multiple_types() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct Bar {{
+            u16 x;
+        }};
+
+        struct Foo {{
+            struct Bar bar;
+        }};
+
+        struct Baz {{
+            struct Bar bar;
+            u32 y;
+        }};
+
+        struct Foo foo;
+        struct Baz baz;
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    // Build the .bpf.o
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let obj = OpenOptions::new()
+        .read(true)
+        .open(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path())
+        .expect("failed to open object file");
+    let mmap = unsafe { Mmap::map(&obj) }.expect("Failed to mmap object file");
+    let btf = Btf::new("prog", &*mmap)
+        .expect("Failed to initialize Btf")
+        .expect("Did not find .BTF section");
+
+    let struct_foo = btf.find_named_type("Foo").expect("Failed to find Foo");
+    let struct_baz = btf.find_named_type("Baz").expect("Failed to find Baz");
+    assert!(btf.find_named_type("asdf").is_none());
+
+    // Note how `Bar` is only defined once
+    let defn = r#"#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Foo {
+    pub bar: Bar,
+}
+const _: [(); 2] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).bar) as *const u8).offset_from(base as *const u8) as usize
+}];
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Baz {
+    pub bar: Bar,
+    pub y: u32,
+}
+const _: [(); 8] = [(); core::mem::size_of::<Baz>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Baz>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).bar) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 4] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Baz>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).y) as *const u8).offset_from(base as *const u8) as usize
+}];
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Bar {
+    pub x: u16,
+}
+const _: [(); 2] = [(); core::mem::size_of::<Bar>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Bar>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).x) as *const u8).offset_from(base as *const u8) as usize
+}];
+"#;
+    assert_eq!(
+        defn,
+        btf.type_definitions(&[struct_foo, struct_baz])
+            .expect("Failed to generate definitions")
+    );
+}
+
 #[test]
 fn test_btf_dump_definition_datasec() {
     let (_dir, proj_dir, cargo_toml) = setup_temp_project();
@@ -1362,10 +4004,15 @@ fn test_btf_dump_definition_datasec() {
     add_bpf_headers(&proj_dir);
 
     // Build the .bpf.o
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     let obj = OpenOptions::new()
         .read(true)
@@ -1409,8 +4056,24 @@ pub struct bss {
 pub struct Foo {
     pub x: i32,
     pub y: [i8; 10],
-    pub z: *mut std::ffi::c_void,
+    pub z: *mut core::ffi::c_void,
 }
+const _: [(); 24] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).x) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 4] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).y) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 16] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).z) as *const u8).offset_from(base as *const u8) as usize
+}];
 "#;
     assert_eq!(
         bss_defn,
@@ -1472,10 +4135,15 @@ fn test_btf_dump_definition_datasec_multiple() {
     add_bpf_headers(&proj_dir);
 
     // Build the .bpf.o
-    assert_eq!(
-        build(true, Some(&cargo_toml), Path::new("/bin/clang"), true),
-        0
-    );
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
 
     let obj = OpenOptions::new()
         .read(true)
@@ -1521,8 +4189,24 @@ pub struct bss {
 pub struct Foo {
     pub x: i32,
     pub y: [i8; 10],
-    pub z: *mut std::ffi::c_void,
+    pub z: *mut core::ffi::c_void,
 }
+const _: [(); 24] = [(); core::mem::size_of::<Foo>()];
+const _: [(); 0] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).x) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 4] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).y) as *const u8).offset_from(base as *const u8) as usize
+}];
+const _: [(); 16] = [(); unsafe {
+    let base = core::mem::MaybeUninit::<Foo>::uninit();
+    let base = base.as_ptr();
+    (core::ptr::addr_of!((*base).z) as *const u8).offset_from(base as *const u8) as usize
+}];
 "#;
     assert_eq!(
         bss_defn,
@@ -1544,3 +4228,91 @@ pub struct rodata {
             .expect("Failed to generate rodata")
     );
 }
+
+#[test]
+fn test_btf_dump_definition_datasec_kconfig() {
+    let (_dir, proj_dir, cargo_toml) = setup_temp_project();
+
+    // Add prog dir
+    create_dir(proj_dir.join("src/bpf")).expect("failed to create prog dir");
+
+    // Add a prog
+    let mut prog = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(proj_dir.join("src/bpf/prog.bpf.c"))
+        .expect("failed to open prog.bpf.c");
+
+    write!(
+        prog,
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        extern unsigned int CONFIG_HZ __kconfig;
+        extern unsigned long long LINUX_KERNEL_VERSION __kconfig;
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {{
+                return CONFIG_HZ + LINUX_KERNEL_VERSION;
+        }}
+        "#,
+    )
+    .expect("failed to write prog.bpf.c");
+
+    // Lay down the necessary header files
+    add_bpf_headers(&proj_dir);
+
+    // Build the .bpf.o
+    build(
+        &Human::new(true),
+        Some(&cargo_toml),
+        Some(Path::new("/bin/clang")),
+        true,
+        None,
+        None,
+    )
+    .expect("failed to build");
+
+    let obj = OpenOptions::new()
+        .read(true)
+        .open(proj_dir.as_path().join("target/bpf/prog.bpf.o").as_path())
+        .expect("failed to open object file");
+    let mmap = unsafe { Mmap::map(&obj) }.expect("Failed to mmap object file");
+    let btf = Btf::new("prog", &*mmap)
+        .expect("Failed to initialize Btf")
+        .expect("Did not find .BTF section");
+
+    assert!(btf.types().len() > 0);
+
+    // Find our types
+    let mut kconfig: Option<u32> = None;
+    for (idx, ty) in btf.types().iter().enumerate() {
+        match ty {
+            btf::BtfType::Datasec(t) => {
+                if t.name.contains("kconfig") {
+                    assert!(kconfig.is_none()); // No duplicates
+                    kconfig = Some(idx.try_into().unwrap());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    assert!(kconfig.is_some());
+
+    // libbpf places externs with the largest alignment first
+    let kconfig_defn = r#"#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct kconfig {
+    pub LINUX_KERNEL_VERSION: u64,
+    pub CONFIG_HZ: u32,
+}
+"#;
+    assert_eq!(
+        kconfig_defn,
+        btf.type_definition(kconfig.unwrap())
+            .expect("Failed to generate kconfig")
+    );
+}
@@ -0,0 +1,194 @@
+//! Generate skeletons from BPF C snippets, eg. for golden tests of the code that build options
+//! like `features` or `timings` produce.
+//!
+//! Requires the `test-util` feature. Snippets are built with the clang `cargo libbpf build` would
+//! pick, and their skeletons generated as `cargo libbpf gen` would, without setting up a cargo
+//! project:
+//!
+//! ```no_run
+//! use std::fs;
+//!
+//! use libbpf_cargo::test_util::SkelTest;
+//!
+//! let vmlinux = fs::read_to_string("src/bpf/vmlinux.h").unwrap();
+//! let helpers = fs::read_to_string("src/bpf/bpf_helpers.h").unwrap();
+//! let skel = SkelTest::new(
+//!     "prog",
+//!     r#"
+//!     #include "vmlinux.h"
+//!     #include "bpf_helpers.h"
+//!
+//!     SEC("tracepoint/sched/sched_process_exec")
+//!     int handle_exec(void *ctx) { return 0; }
+//!     "#,
+//! )
+//! .header("vmlinux.h", vmlinux)
+//! .header("bpf_helpers.h", helpers)
+//! .feature("handle_exec", "exec")
+//! .gen()
+//! .expect("failed to generate skeleton");
+//! assert_eq!(skel, fs::read_to_string("tests/golden/prog.skel.rs").unwrap());
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use anyhow::Result;
+
+use crate::build;
+use crate::clang;
+use crate::gen;
+use crate::metadata::UnprocessedObj;
+use crate::report::Quiet;
+
+/// A BPF C snippet and the `package.metadata.libbpf` options to generate its skeleton with.
+#[derive(Clone, Debug)]
+pub struct SkelTest {
+    name: String,
+    source: String,
+    headers: BTreeMap<String, String>,
+    types: Vec<String>,
+    ringbufs: BTreeMap<String, String>,
+    features: BTreeMap<String, String>,
+    timings: Option<String>,
+    clang: Option<PathBuf>,
+}
+
+impl SkelTest {
+    /// Build `source` as `<name>.bpf.c`.
+    pub fn new<N: AsRef<str>, S: AsRef<str>>(name: N, source: S) -> Self {
+        SkelTest {
+            name: name.as_ref().to_string(),
+            source: source.as_ref().to_string(),
+            headers: BTreeMap::new(),
+            types: Vec::new(),
+            ringbufs: BTreeMap::new(),
+            features: BTreeMap::new(),
+            timings: None,
+            clang: None,
+        }
+    }
+
+    /// Place a header at `path` relative to the snippet, for it to `#include "<path>"`.
+    pub fn header<P: AsRef<str>, C: AsRef<str>>(&mut self, path: P, contents: C) -> &mut Self {
+        self.headers
+            .insert(path.as_ref().to_string(), contents.as_ref().to_string());
+        self
+    }
+
+    /// Generate a rust definition for the type `name`, like `types`.
+    pub fn ty<T: AsRef<str>>(&mut self, name: T) -> &mut Self {
+        self.types.push(name.as_ref().to_string());
+        self
+    }
+
+    /// Generate a sample callback for the ringbuf `map` of `ty` samples, like `ringbufs`.
+    pub fn ringbuf<M: AsRef<str>, T: AsRef<str>>(&mut self, map: M, ty: T) -> &mut Self {
+        self.ringbufs
+            .insert(map.as_ref().to_string(), ty.as_ref().to_string());
+        self
+    }
+
+    /// Gate the accessors of map or prog `name` behind `feature`, like `features`.
+    pub fn feature<N: AsRef<str>, F: AsRef<str>>(&mut self, name: N, feature: F) -> &mut Self {
+        self.features
+            .insert(name.as_ref().to_string(), feature.as_ref().to_string());
+        self
+    }
+
+    /// Instrument the skeleton behind `feature`, like `timings`.
+    pub fn timings<F: AsRef<str>>(&mut self, feature: F) -> &mut Self {
+        self.timings = Some(feature.as_ref().to_string());
+        self
+    }
+
+    /// Build with `clang` rather than the discovered one.
+    pub fn clang<P: AsRef<Path>>(&mut self, clang: P) -> &mut Self {
+        self.clang = Some(clang.as_ref().to_path_buf());
+        self
+    }
+
+    /// Build the snippet and return its skeleton, laid out the way `cargo libbpf gen` lays it out.
+    pub fn gen(&self) -> Result<String> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(format!("{}.bpf.c", self.name));
+        fs::write(&path, &self.source)?;
+        for (header, contents) in &self.headers {
+            let header = dir.path().join(header);
+            if let Some(parent) = header.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(header, contents)?;
+        }
+
+        let obj = UnprocessedObj {
+            package: String::new(),
+            path,
+            out: dir.path().to_path_buf(),
+            name: self.name.clone(),
+            types: self.types.clone(),
+            ringbufs: self.ringbufs.clone(),
+            features: self.features.clone(),
+            clang: None,
+            namespaced_mods: false,
+            timings: self.timings.clone(),
+        };
+        let clang = clang::find(&Quiet, self.clang.as_deref(), None, false)?;
+        build::compile(&Quiet, slice::from_ref(&obj), &clang, 1, None)?;
+
+        let (skel, _) = gen::gen_skel_contents(
+            &Quiet,
+            &self.name,
+            &dir.path().join(format!("{}.bpf.o", self.name)),
+            &self.types,
+            &self.ringbufs,
+            &self.features,
+            self.timings.as_deref(),
+            false,
+            false,
+            false,
+        )?;
+        gen::format_code(&skel, None)
+    }
+}
+
+#[test]
+fn test_skel_test() {
+    let skel = SkelTest::new(
+        "prog",
+        r#"
+        #include "vmlinux.h"
+        #include "bpf_helpers.h"
+
+        struct {
+            __uint(type, BPF_MAP_TYPE_ARRAY);
+            __uint(max_entries, 1);
+            __type(key, u32);
+            __type(value, u64);
+        } counts SEC(".maps");
+
+        SEC("kprobe/foo")
+        int this_is_my_prog(u64 *ctx)
+        {
+            return 0;
+        }
+        "#,
+    )
+    .header("vmlinux.h", include_str!("../test_data/vmlinux.h"))
+    .header("bpf_helpers.h", include_str!("../test_data/bpf_helpers.h"))
+    .header(
+        "bpf_helper_defs.h",
+        include_str!("../test_data/bpf_helper_defs.h"),
+    )
+    .feature("counts", "counting")
+    .gen()
+    .expect("failed to generate skeleton");
+
+    assert!(skel.contains("pub struct ProgSkelBuilder"));
+    assert!(skel.contains("pub fn this_is_my_prog(&mut self)"));
+    assert!(skel.contains("#[cfg(feature = \"counting\")]"));
+
+    assert!(SkelTest::new("bad", "not C").gen().is_err());
+}
@@ -0,0 +1,545 @@
+//! Inspect BPF Type Format (BTF) type information at runtime
+//!
+//! For example, to find the offset of a kernel struct field:
+//! ```no_run
+//! use libbpf_rs::btf::{Btf, BtfKind};
+//!
+//! let btf = Btf::from_vmlinux().expect("failed to load kernel BTF");
+//! let task = btf
+//!     .find_by_name_kind("task_struct", BtfKind::Struct)
+//!     .expect("no task_struct");
+//! let pid = btf
+//!     .members(task)
+//!     .expect("not a struct")
+//!     .into_iter()
+//!     .find(|m| m.name.as_deref() == Some("pid"))
+//!     .expect("no pid field");
+//! println!("task_struct::pid is at byte {}", pid.bit_offset / 8);
+//! ```
+
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use num_enum::TryFromPrimitive;
+use strum_macros::Display;
+
+use crate::*;
+
+/// Kind of a BTF type. Maps to `BTF_KIND_*` in kernel uapi.
+#[non_exhaustive]
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, TryFromPrimitive, PartialEq, Display)]
+pub enum BtfKind {
+    Void = 0,
+    Int,
+    Ptr,
+    Array,
+    Struct,
+    Union,
+    Enum,
+    Fwd,
+    Typedef,
+    Volatile,
+    Const,
+    Restrict,
+    Func,
+    FuncProto,
+    Var,
+    Datasec,
+    /// See [`MapType::Unknown`]
+    Unknown = u32::MAX,
+}
+
+/// A member of a struct or union.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BtfMember {
+    /// `None` for anonymous members, eg. nested unnamed unions
+    pub name: Option<String>,
+    pub type_id: u32,
+    /// Offset from the start of the struct, in bits
+    pub bit_offset: u32,
+    /// Size of the member in bits if it is a bitfield
+    pub bitfield_size: Option<u32>,
+}
+
+/// A set of BTF types, eg. those of the running kernel or of a BPF object.
+///
+/// Type ids start at 1. Id 0 is always `void`.
+pub struct Btf<'a> {
+    ptr: *mut libbpf_sys::btf,
+    /// Whether `ptr` must be freed, as opposed to being owned by an object
+    owned: bool,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl Btf<'static> {
+    /// Load the BTF of the running kernel.
+    pub fn from_vmlinux() -> Result<Self> {
+        let ptr = unsafe { libbpf_sys::libbpf_find_kernel_btf() };
+        Self::from_owned_ptr(ptr)
+    }
+
+    /// Load BTF from a raw BTF file, such as `/sys/kernel/btf/vmlinux`, or from the `.BTF`
+    /// section of an ELF file.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path_c = util::path_to_cstring(path)?;
+
+        let ptr = unsafe { libbpf_sys::btf__parse_raw(path_c.as_ptr()) };
+        if unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) } == 0 {
+            return Self::from_owned_ptr(ptr);
+        }
+
+        let ptr = unsafe { libbpf_sys::btf__parse_elf(path_c.as_ptr(), ptr::null_mut()) };
+        Self::from_owned_ptr(ptr)
+    }
+
+    /// Load BTF already loaded into the kernel, eg. the BTF a map or program was created with.
+    pub fn from_id(id: u32) -> Result<Self> {
+        let mut ptr = ptr::null_mut();
+        let ret = unsafe { libbpf_sys::btf__get_from_id(id, &mut ptr) };
+        if ret != 0 {
+            // Error code is returned negative, flip to positive to match errno
+            return Err(Error::System(-ret));
+        }
+
+        Self::from_owned_ptr(ptr)
+    }
+
+    fn from_owned_ptr(ptr: *mut libbpf_sys::btf) -> Result<Self> {
+        let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
+        if err != 0 {
+            return Err(Error::System(err as i32));
+        }
+
+        Ok(Btf {
+            ptr,
+            owned: true,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<'a> Btf<'a> {
+    /// Wrap BTF owned by an object. `ptr` must outlive `'a`.
+    pub(crate) fn from_object_ptr(ptr: *mut libbpf_sys::btf) -> Option<Self> {
+        if ptr.is_null() {
+            return None;
+        }
+
+        Some(Btf {
+            ptr,
+            owned: false,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns the number of types, not counting `void`.
+    pub fn type_cnt(&self) -> u32 {
+        unsafe { libbpf_sys::btf__get_nr_types(self.ptr) }
+    }
+
+    /// Returns an iterator over all type ids, not counting `void`.
+    pub fn type_ids(&self) -> impl Iterator<Item = u32> {
+        1..=self.type_cnt()
+    }
+
+    /// Returns the id of the first type named `name`.
+    pub fn find_by_name<T: AsRef<str>>(&self, name: T) -> Option<u32> {
+        let name = util::str_to_cstring(name.as_ref()).ok()?;
+        let id = unsafe { libbpf_sys::btf__find_by_name(self.ptr, name.as_ptr()) };
+        u32::try_from(id).ok()
+    }
+
+    /// Returns the id of the type named `name` with kind `kind`.
+    pub fn find_by_name_kind<T: AsRef<str>>(&self, name: T, kind: BtfKind) -> Option<u32> {
+        let name = util::str_to_cstring(name.as_ref()).ok()?;
+        let id =
+            unsafe { libbpf_sys::btf__find_by_name_kind(self.ptr, name.as_ptr(), kind as u32) };
+        u32::try_from(id).ok()
+    }
+
+    fn type_by_id(&self, type_id: u32) -> Result<&libbpf_sys::btf_type> {
+        let t = unsafe { libbpf_sys::btf__type_by_id(self.ptr, type_id) };
+        if t.is_null() || type_id > self.type_cnt() {
+            return Err(Error::InvalidInput(format!("invalid type id {}", type_id)));
+        }
+
+        Ok(unsafe { &*t })
+    }
+
+    fn name_by_offset(&self, name_off: u32) -> Result<Option<String>> {
+        if name_off == 0 {
+            return Ok(None);
+        }
+
+        let name = unsafe { libbpf_sys::btf__name_by_offset(self.ptr, name_off) };
+        Ok(Some(util::c_ptr_to_string(name)?))
+    }
+
+    /// Returns the name of a type, or `None` if it is anonymous.
+    pub fn type_name(&self, type_id: u32) -> Result<Option<String>> {
+        if type_id == 0 {
+            return Ok(Some("void".to_string()));
+        }
+
+        self.name_by_offset(self.type_by_id(type_id)?.name_off)
+    }
+
+    pub fn type_kind(&self, type_id: u32) -> Result<BtfKind> {
+        if type_id == 0 {
+            return Ok(BtfKind::Void);
+        }
+
+        let kind = (self.type_by_id(type_id)?.info >> 24) & 0x1f;
+        Ok(BtfKind::try_from(kind).unwrap_or(BtfKind::Unknown))
+    }
+
+    /// Returns the size of a type in bytes, following typedefs and modifiers.
+    pub fn size_of(&self, type_id: u32) -> Result<usize> {
+        let size = unsafe { libbpf_sys::btf__resolve_size(self.ptr, type_id) };
+        if size < 0 {
+            // Error code is returned negative, flip to positive to match errno
+            return Err(Error::System(-size as i32));
+        }
+
+        Ok(size as usize)
+    }
+
+    /// Returns the alignment of a type in bytes, following typedefs and modifiers.
+    pub fn align_of(&self, type_id: u32) -> Result<usize> {
+        let align = unsafe { libbpf_sys::btf__align_of(self.ptr, type_id) };
+        if align <= 0 {
+            return Err(Error::InvalidInput(format!(
+                "alignment of type id {} is unknown",
+                type_id
+            )));
+        }
+
+        Ok(align as usize)
+    }
+
+    /// Returns the members of a struct or union.
+    pub fn members(&self, type_id: u32) -> Result<Vec<BtfMember>> {
+        match self.type_kind(type_id)? {
+            BtfKind::Struct | BtfKind::Union => (),
+            kind => {
+                return Err(Error::InvalidInput(format!(
+                    "type id {} is a {}, not a Struct or Union",
+                    type_id, kind
+                )))
+            }
+        }
+
+        let t = self.type_by_id(type_id)?;
+        let vlen = (t.info & 0xffff) as usize;
+        let kflag = (t.info >> 31) == 1;
+
+        // Members immediately follow the struct type
+        let members = unsafe {
+            slice::from_raw_parts(
+                (t as *const libbpf_sys::btf_type).add(1) as *const libbpf_sys::btf_member,
+                vlen,
+            )
+        };
+
+        members
+            .iter()
+            .map(|m| {
+                // With kflag set, the top 8 bits of the offset hold the bitfield size
+                let (bit_offset, bitfield_size) = if kflag {
+                    let size = m.offset >> 24;
+                    (
+                        m.offset & 0xffffff,
+                        if size != 0 { Some(size) } else { None },
+                    )
+                } else {
+                    (m.offset, None)
+                };
+
+                Ok(BtfMember {
+                    name: self.name_by_offset(m.name_off)?,
+                    type_id: m.type_,
+                    bit_offset,
+                    bitfield_size,
+                })
+            })
+            .collect()
+    }
+
+    /// Format `data` according to type `type_id`, the way `bpftool map dump` does.
+    ///
+    /// The output is JSON: structs, unions and datasecs become objects, arrays become arrays
+    /// (or strings for `char` arrays), enums become the name of their value, and pointers become
+    /// hex strings.
+    pub fn format_value(&self, type_id: u32, data: &[u8]) -> Result<String> {
+        let size = self.size_of(type_id)?;
+        if data.len() < size {
+            return Err(Error::InvalidInput(format!(
+                "data is {} bytes, type id {} needs {}",
+                data.len(),
+                type_id,
+                size
+            )));
+        }
+
+        let mut out = String::new();
+        self.write_value(&mut out, type_id, data, 0, None)?;
+        Ok(out)
+    }
+
+    /// Returns the `u32` sized trailer of `type_id`, as found after int, array and var types.
+    fn type_extra<T>(&self, type_id: u32) -> Result<&T> {
+        let t = self.type_by_id(type_id)?;
+        Ok(unsafe { &*((t as *const libbpf_sys::btf_type).add(1) as *const T) })
+    }
+
+    /// Returns the `vlen` trailing entries of `type_id`, as found after enum and datasec types.
+    fn type_entries<T>(&self, type_id: u32) -> Result<&[T]> {
+        let t = self.type_by_id(type_id)?;
+        let vlen = (t.info & 0xffff) as usize;
+        Ok(unsafe {
+            slice::from_raw_parts((t as *const libbpf_sys::btf_type).add(1) as *const T, vlen)
+        })
+    }
+
+    fn write_value(
+        &self,
+        out: &mut String,
+        type_id: u32,
+        data: &[u8],
+        bit_offset: u32,
+        bitfield_size: Option<u32>,
+    ) -> Result<()> {
+        let type_id = self.resolve(type_id)?;
+        let kind = self.type_kind(type_id)?;
+
+        match kind {
+            BtfKind::Int => {
+                let encoding = *self.type_extra::<u32>(type_id)?;
+                let bits = bitfield_size.unwrap_or(encoding & 0xff);
+                let offset = bit_offset + ((encoding >> 16) & 0xff);
+                let value = read_bits(data, offset, bits)?;
+
+                match (encoding >> 24) & 0x0f {
+                    BTF_INT_BOOL => out.push_str(if value != 0 { "true" } else { "false" }),
+                    BTF_INT_SIGNED if bits > 0 && bits < 128 => {
+                        // Sign extend
+                        let shift = 128 - bits;
+                        out.push_str(&(((value << shift) as i128) >> shift).to_string())
+                    }
+                    BTF_INT_SIGNED => out.push_str(&(value as i128).to_string()),
+                    _ => out.push_str(&value.to_string()),
+                }
+            }
+            BtfKind::Enum => {
+                let bits = bitfield_size.unwrap_or(self.size_of(type_id)? as u32 * 8);
+                let value = read_bits(data, bit_offset, bits)? as u32 as i32;
+                let name = self
+                    .type_entries::<libbpf_sys::btf_enum>(type_id)?
+                    .iter()
+                    .find(|e| e.val == value)
+                    .map(|e| self.name_by_offset(e.name_off))
+                    .transpose()?
+                    .flatten();
+
+                match name {
+                    Some(name) => write_str(out, &name),
+                    None => out.push_str(&value.to_string()),
+                }
+            }
+            BtfKind::Ptr => {
+                let bits = self.size_of(type_id)? as u32 * 8;
+                let value = read_bits(data, bit_offset, bits)?;
+                write_str(out, &format!("{:#x}", value));
+            }
+            BtfKind::Array => {
+                let array = self.type_extra::<libbpf_sys::btf_array>(type_id)?;
+                let elem_size = self.size_of(array.type_)? as u32;
+
+                if self.is_char(array.type_)? {
+                    let start = (bit_offset / 8) as usize;
+                    let bytes = data
+                        .get(start..start + array.nelems as usize)
+                        .ok_or_else(|| Error::InvalidInput("data is too short".to_string()))?;
+                    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+                    if let Ok(s) = std::str::from_utf8(&bytes[..len]) {
+                        write_str(out, s);
+                        return Ok(());
+                    }
+                }
+
+                out.push('[');
+                for i in 0..array.nelems {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    self.write_value(out, array.type_, data, bit_offset + i * elem_size * 8, None)?;
+                }
+                out.push(']');
+            }
+            BtfKind::Struct | BtfKind::Union => {
+                out.push('{');
+                for (i, m) in self.members(type_id)?.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_str(out, m.name.as_deref().unwrap_or(""));
+                    out.push(':');
+                    self.write_value(
+                        out,
+                        m.type_id,
+                        data,
+                        bit_offset + m.bit_offset,
+                        m.bitfield_size,
+                    )?;
+                }
+                out.push('}');
+            }
+            BtfKind::Datasec => {
+                out.push('{');
+                let vars = self.type_entries::<libbpf_sys::btf_var_secinfo>(type_id)?;
+                for (i, var) in vars.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_str(out, &self.type_name(var.type_)?.unwrap_or_default());
+                    out.push(':');
+                    self.write_value(out, var.type_, data, bit_offset + var.offset * 8, None)?;
+                }
+                out.push('}');
+            }
+            kind => {
+                return Err(Error::InvalidInput(format!(
+                    "cannot format type id {} of kind {}",
+                    type_id, kind
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Skip typedefs, modifiers and vars.
+    fn resolve(&self, type_id: u32) -> Result<u32> {
+        let id = unsafe { libbpf_sys::btf__resolve_type(self.ptr, type_id) };
+        if id < 0 {
+            // Error code is returned negative, flip to positive to match errno
+            return Err(Error::System(-id));
+        }
+
+        Ok(id as u32)
+    }
+
+    fn is_char(&self, type_id: u32) -> Result<bool> {
+        let type_id = self.resolve(type_id)?;
+        if self.type_kind(type_id)? != BtfKind::Int {
+            return Ok(false);
+        }
+
+        let encoding = *self.type_extra::<u32>(type_id)?;
+        Ok((encoding & 0xff) == 8
+            && ((encoding >> 24) & 0x0f == BTF_INT_CHAR
+                || self.type_name(type_id)?.as_deref() == Some("char")))
+    }
+}
+
+// From `include/uapi/linux/btf.h`
+const BTF_INT_SIGNED: u32 = 1 << 0;
+const BTF_INT_CHAR: u32 = 1 << 1;
+const BTF_INT_BOOL: u32 = 1 << 2;
+
+/// Read `bits` bits starting at bit `offset` of `data`, in host byte order.
+fn read_bits(data: &[u8], offset: u32, bits: u32) -> Result<u128> {
+    if bits > 128 {
+        return Err(Error::InvalidInput(format!(
+            "{} bit integers are not supported",
+            bits
+        )));
+    }
+
+    let start = (offset / 8) as usize;
+    let end = ((offset + bits + 7) / 8) as usize;
+    let bytes = data
+        .get(start..end)
+        .ok_or_else(|| Error::InvalidInput("data is too short".to_string()))?;
+
+    if bytes.len() > 16 {
+        return Err(Error::InvalidInput(format!(
+            "unaligned {} bit integers are not supported",
+            bits
+        )));
+    }
+
+    // Bit offsets count from the least significant bit on little endian hosts and from the most
+    // significant bit on big endian ones
+    let mut value: u128 = 0;
+    if cfg!(target_endian = "little") {
+        for (i, b) in bytes.iter().enumerate() {
+            value |= (*b as u128) << (i * 8);
+        }
+        value >>= offset % 8;
+    } else {
+        for b in bytes {
+            value = (value << 8) | *b as u128;
+        }
+        value >>= bytes.len() as u32 * 8 - offset % 8 - bits;
+    }
+
+    if bits < 128 {
+        value &= (1 << bits) - 1;
+    }
+
+    Ok(value)
+}
+
+/// Write `s` as a JSON string.
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl<'a> Drop for Btf<'a> {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { libbpf_sys::btf__free(self.ptr) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_bits() {
+        let data = 0x1234_5678u32.to_ne_bytes();
+
+        assert_eq!(read_bits(&data, 0, 32).unwrap(), 0x1234_5678);
+        assert!(read_bits(&data, 8, 32).is_err());
+        assert!(read_bits(&data, 0, 129).is_err());
+
+        if cfg!(target_endian = "little") {
+            assert_eq!(read_bits(&data, 0, 8).unwrap(), 0x78);
+            assert_eq!(read_bits(&data, 4, 8).unwrap(), 0x67);
+            assert_eq!(read_bits(&data, 28, 4).unwrap(), 0x1);
+        }
+    }
+
+    #[test]
+    fn test_write_str() {
+        let mut out = String::new();
+        write_str(&mut out, "a\"b\\c\n");
+        assert_eq!(out, r#""a\"b\\c\u000a""#);
+    }
+}
@@ -0,0 +1,119 @@
+//! Locate BTF for kernels that do not ship their own
+//!
+//! CO-RE relocations need BTF describing the running kernel. Kernels built without
+//! `CONFIG_DEBUG_INFO_BTF` (eg. CentOS 7/8) can instead be served from a
+//! [BTFHub](https://github.com/aquasecurity/btfhub) style archive, laid out as
+//! `<os id>/<os version>/<arch>/<kernel release>.btf`.
+//!
+//! Note that archive files must be decompressed ahead of time.
+//!
+//! ```no_run
+//! use libbpf_rs::ObjectBuilder;
+//!
+//! let mut builder = ObjectBuilder::default();
+//! builder
+//!     .btfhub_archive("/usr/share/btfhub")
+//!     .expect("no BTF available for running kernel");
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nix::sys::utsname;
+
+use crate::*;
+
+/// Where the kernel exposes its own BTF, if it was built with it.
+pub const VMLINUX_BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+
+/// Returns the path of the BTF file for the given system within `archive`.
+pub fn btf_path<P: AsRef<Path>>(
+    archive: P,
+    os_id: &str,
+    os_version: &str,
+    arch: &str,
+    kernel_release: &str,
+) -> PathBuf {
+    archive
+        .as_ref()
+        .join(os_id)
+        .join(os_version)
+        .join(arch)
+        .join(format!("{}.btf", kernel_release))
+}
+
+/// Returns the path of the BTF file for the running system within `archive`, or `None` if the
+/// archive does not contain one.
+pub fn find_btf<P: AsRef<Path>>(archive: P) -> Result<Option<PathBuf>> {
+    let os_release = fs::read_to_string("/etc/os-release").map_err(|e| match e.raw_os_error() {
+        Some(errno) => Error::System(errno),
+        None => Error::Internal(e.to_string()),
+    })?;
+    let (os_id, os_version) = parse_os_release(&os_release).ok_or_else(|| {
+        Error::Internal("/etc/os-release is missing ID or VERSION_ID".to_string())
+    })?;
+
+    let uts = utsname::uname();
+    let path = btf_path(archive, &os_id, &os_version, uts.machine(), uts.release());
+
+    if path.exists() {
+        Ok(Some(path))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the `ID` and `VERSION_ID` fields of an os-release file.
+fn parse_os_release(contents: &str) -> Option<(String, String)> {
+    let mut id = None;
+    let mut version_id = None;
+
+    for line in contents.lines() {
+        let (key, value) = match line.find('=') {
+            Some(idx) => (&line[..idx], &line[idx + 1..]),
+            None => continue,
+        };
+        let value = value
+            .trim()
+            .trim_matches('"')
+            .trim_matches('\'')
+            .to_string();
+
+        match key.trim() {
+            "ID" => id = Some(value),
+            "VERSION_ID" => version_id = Some(value),
+            _ => (),
+        }
+    }
+
+    Some((id?, version_id?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_os_release() {
+        let contents = r#"NAME="CentOS Linux"
+VERSION="7 (Core)"
+ID="centos"
+ID_LIKE="rhel fedora"
+VERSION_ID="7"
+"#;
+        assert_eq!(
+            parse_os_release(contents),
+            Some(("centos".to_string(), "7".to_string()))
+        );
+
+        assert_eq!(parse_os_release("ID=arch\n"), None);
+    }
+
+    #[test]
+    fn test_btf_path() {
+        assert_eq!(
+            btf_path("/btfhub", "centos", "7", "x86_64", "3.10.0-1160.el7.x86_64"),
+            PathBuf::from("/btfhub/centos/7/x86_64/3.10.0-1160.el7.x86_64.btf")
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::mem;
+use std::os::raw::c_void;
+use std::path::Path;
+use std::ptr;
+
+use nix::{libc, sys::utsname};
+
+use crate::*;
+
+/// What the running kernel, together with the bundled libbpf, supports. See
+/// [`KernelCaps::detect()`].
+///
+/// Applications can log it at startup, and use it to pick between eg. a ringbuf and a perf
+/// buffer, or fentry and kprobe programs:
+///
+/// ```no_run
+/// let caps = libbpf_rs::KernelCaps::detect();
+/// eprintln!("kernel capabilities: {:?}", caps);
+/// if !caps.ringbuf {
+///     // Fall back to a perf buffer
+/// }
+/// ```
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KernelCaps {
+    /// `(major, minor)` version of the running kernel, if its release could be parsed
+    pub kernel_version: Option<(u32, u32)>,
+    /// BPF ringbuf maps, Linux 5.8
+    pub ringbuf: bool,
+    /// Loading BTF into the kernel, Linux 4.18
+    pub btf: bool,
+    /// CO-RE relocations against the kernel's own BTF, which needs a kernel built with
+    /// `CONFIG_DEBUG_INFO_BTF`. Kernels without it may still be supported through
+    /// [`btfhub`].
+    pub core: bool,
+    /// fentry, fexit and fmod_ret programs, which need BPF trampolines: Linux 5.5 on x86_64,
+    /// 6.0 on aarch64, and kernel BTF
+    pub fentry: bool,
+    /// BPF cookies, Linux 5.15 and libbpf 0.5
+    pub bpf_cookie: bool,
+    /// tcx links to attach tc programs without qdiscs, Linux 6.6 and libbpf 1.3
+    pub tcx: bool,
+    /// Charging BPF memory to memory cgroups rather than `RLIMIT_MEMLOCK`, Linux 5.11. See
+    /// [`memlock_rlimit_needed()`].
+    pub memcg_accounting: bool,
+}
+
+impl KernelCaps {
+    /// Detect the capabilities of the running kernel.
+    ///
+    /// `ringbuf` and `btf` are probed by creating a ringbuf map and loading a minimal BTF blob,
+    /// which needs `CAP_BPF` or `CAP_SYS_ADMIN`, and on kernels before 5.11 some
+    /// `RLIMIT_MEMLOCK`. Without them, they are reported as unsupported. The rest is derived
+    /// from the kernel version, the presence of kernel BTF, and what the bundled libbpf
+    /// supports. Kernels with backported features may support more than reported.
+    pub fn detect() -> Self {
+        let uts = utsname::uname();
+        let kernel_version = util::kernel_version(uts.release());
+        let at_least = |version: (u32, u32)| kernel_version.map_or(false, |v| v >= version);
+
+        let core = Path::new(btfhub::VMLINUX_BTF_PATH).exists();
+        let trampoline_version = match uts.machine() {
+            "x86_64" => Some((5, 5)),
+            "aarch64" => Some((6, 0)),
+            _ => None,
+        };
+
+        KernelCaps {
+            kernel_version,
+            ringbuf: probe_ringbuf(),
+            btf: probe_btf(),
+            core,
+            fentry: core && trampoline_version.map_or(false, at_least),
+            // The bundled libbpf predates both
+            bpf_cookie: false,
+            tcx: false,
+            memcg_accounting: rlimit::uses_memcg_accounting(uts.release()),
+        }
+    }
+}
+
+pub(crate) fn probe_ringbuf() -> bool {
+    unsafe { libbpf_sys::bpf_probe_map_type(libbpf_sys::BPF_MAP_TYPE_RINGBUF, 0) }
+}
+
+/// Raw BTF with a single `int` type, as libbpf uses to probe for BTF support
+#[repr(C)]
+struct MinimalBtf {
+    header: libbpf_sys::btf_header,
+    types: [u32; 4],
+    strings: [u8; 5],
+}
+
+fn probe_btf() -> bool {
+    let btf = MinimalBtf {
+        header: libbpf_sys::btf_header {
+            magic: 0xeb9f,
+            version: 1,
+            flags: 0,
+            hdr_len: mem::size_of::<libbpf_sys::btf_header>() as u32,
+            type_off: 0,
+            type_len: 16,
+            str_off: 16,
+            str_len: 5,
+        },
+        types: [
+            // name_off, info, size
+            1,
+            (btf::BtfKind::Int as u32) << 24,
+            4,
+            // Signed, 32 bits
+            (1 << 24) | 32,
+        ],
+        strings: *b"\0int\0",
+    };
+    // Leave out trailing padding, the kernel rejects data not covered by the header
+    let size = btf.header.hdr_len + btf.header.str_off + btf.header.str_len;
+
+    let fd = unsafe {
+        libbpf_sys::bpf_load_btf(
+            &btf as *const _ as *const c_void,
+            size,
+            ptr::null_mut(),
+            0,
+            false,
+        )
+    };
+    if fd < 0 {
+        return false;
+    }
+
+    unsafe { libc::close(fd) };
+    true
+}
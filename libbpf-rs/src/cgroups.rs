@@ -0,0 +1,195 @@
+//! Resolve cgroup v2 paths, to attach programs to cgroups by path
+//!
+//! Paths are relative to where the cgroup v2 hierarchy is mounted, eg. `system.slice/foo.service`,
+//! so attach policies can be written per service:
+//! ```no_run
+//! use libbpf_rs::cgroups;
+//!
+//! # fn attach(prog: &mut libbpf_rs::Program) -> libbpf_rs::Result<()> {
+//! let mut links = Vec::new();
+//! for path in cgroups::descendants("system.slice")? {
+//!     if path.extension().map_or(false, |ext| ext == "service") {
+//!         links.push(prog.attach_cgroup_path(&path)?);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Enumerating again later and attaching to the new cgroups keeps up with services being started.
+//! Links to removed cgroups stay valid but no longer see any task.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use nix::libc;
+
+use crate::*;
+
+/// Where the cgroup v2 hierarchy is usually mounted, on its own or next to v1 controllers, if
+/// `/proc/self/mounts` does not list it
+const CGROUP2_PATHS: &[&str] = &["/sys/fs/cgroup", "/sys/fs/cgroup/unified"];
+
+fn io_error(e: io::Error) -> Error {
+    match e.raw_os_error() {
+        Some(errno) => Error::System(errno),
+        None => Error::Internal(e.to_string()),
+    }
+}
+
+/// Returns the first cgroup v2 mount point listed in `mounts`, in `/proc/self/mounts` format
+fn parse_mounts(mounts: &str) -> Option<PathBuf> {
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(_), Some(dir), Some("cgroup2")) => Some(PathBuf::from(dir)),
+            _ => None,
+        }
+    })
+}
+
+/// Returns where the cgroup v2 hierarchy is mounted.
+///
+/// Mounts listed in `/proc/self/mounts` take precedence over the usual locations. Fails with
+/// `ENOENT` if there is no cgroup v2 hierarchy, eg. on a system using cgroup v1 only.
+pub fn root() -> Result<PathBuf> {
+    if let Ok(mounts) = fs::read_to_string("/proc/self/mounts") {
+        if let Some(dir) = parse_mounts(&mounts) {
+            return Ok(dir);
+        }
+    }
+
+    // The root of a v2 hierarchy has `cgroup.controllers`, v1 hierarchies have no such file
+    CGROUP2_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|dir| dir.join("cgroup.controllers").is_file())
+        .ok_or(Error::System(libc::ENOENT))
+}
+
+/// Resolve `path` against [`root()`], unless it is absolute already.
+pub fn resolve<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let path = path.as_ref();
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(root()?.join(path))
+    }
+}
+
+/// Returns every cgroup below `path`, resolved as per [`resolve()`], parents before their
+/// children. Paths are relative to [`root()`], like `path` can be.
+///
+/// Cgroups removed while walking the hierarchy are skipped.
+pub fn descendants<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let root = root()?;
+    let start = resolve(path)?;
+    let mut cgroups = Vec::new();
+    let mut pending = vec![start.clone()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && dir != start => continue,
+            Err(e) => return Err(io_error(e)),
+        };
+
+        for entry in entries {
+            let entry = entry.map_err(io_error)?;
+            // Child cgroups are the directories, control files like `cgroup.procs` are not
+            if !entry.file_type().map_err(io_error)?.is_dir() {
+                continue;
+            }
+
+            let child = entry.path();
+            let relative = child.strip_prefix(&root).map(Path::to_path_buf);
+            cgroups.push(relative.unwrap_or_else(|_| child.clone()));
+            pending.push(child);
+        }
+    }
+
+    // A parent's path is a prefix of its children's, so it sorts first
+    cgroups.sort();
+    Ok(cgroups)
+}
+
+/// An open cgroup v2 directory, as passed to [`Program::attach_cgroup()`].
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    fd: OwnedFd,
+}
+
+impl Cgroup {
+    /// Open the cgroup at `path`, resolved as per [`resolve()`].
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = resolve(path)?;
+        if !path.join("cgroup.procs").is_file() {
+            return Err(Error::InvalidInput(format!(
+                "{} is not a cgroup",
+                path.display()
+            )));
+        }
+
+        let file = File::open(&path).map_err(io_error)?;
+        Ok(Cgroup {
+            path,
+            fd: OwnedFd::from(file),
+        })
+    }
+
+    /// Absolute path of the cgroup.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The cgroup ID, as eg. `bpf_get_current_cgroup_id()` returns and
+    /// [`query::CgroupLinkInfo`] reports.
+    pub fn id(&self) -> Result<u64> {
+        // The ID is the inode number of the cgroup's directory on cgroupfs
+        let file = File::from(self.fd.try_clone().map_err(io_error)?);
+        Ok(file.metadata().map_err(io_error)?.ino())
+    }
+}
+
+impl AsFd for Cgroup {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for Cgroup {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = "sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0
+tmpfs /sys/fs/cgroup tmpfs ro,nosuid,nodev,noexec,mode=755 0 0
+cgroup2 /sys/fs/cgroup/unified cgroup2 rw,nosuid,nodev,noexec,relatime 0 0
+cgroup /sys/fs/cgroup/memory cgroup rw,nosuid,nodev,noexec,relatime,memory 0 0
+";
+        assert_eq!(
+            parse_mounts(mounts),
+            Some(PathBuf::from("/sys/fs/cgroup/unified"))
+        );
+        assert_eq!(parse_mounts("proc /proc proc rw 0 0\n"), None);
+    }
+
+    #[test]
+    fn test_resolve_absolute() {
+        assert_eq!(
+            resolve("/sys/fs/cgroup/system.slice").unwrap(),
+            PathBuf::from("/sys/fs/cgroup/system.slice")
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::time::Duration;
+
+use crate::*;
+
+/// The kind of map an [`EventSource`] reads samples from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventSourceKind {
+    /// A [`MapType::RingBuf`] map, Linux 5.8
+    RingBuf,
+    /// A [`MapType::PerfEventArray`] map, for older kernels
+    PerfBuffer,
+}
+
+enum Inner {
+    RingBuf(RingBuffer),
+    PerfBuffer(PerfBuffer),
+}
+
+/// Samples of a map that is a `ringbuf` where the kernel supports them, and a perf event array
+/// otherwise, so tools support older kernels with a single data path in userspace.
+///
+/// The BPF side declares the map as a ringbuf, and picks the helper to submit samples with
+/// through a read-only global variable:
+///
+/// ```c
+/// const volatile bool use_ringbuf = false;
+///
+/// struct {
+///     __uint(type, BPF_MAP_TYPE_RINGBUF);
+///     __uint(max_entries, 256 * 1024);
+/// } events SEC(".maps");
+///
+/// static void submit(void *ctx, struct event *e)
+/// {
+///     if (use_ringbuf)
+///         bpf_ringbuf_output(&events, e, sizeof(*e), 0);
+///     else
+///         bpf_perf_event_output(ctx, &events, BPF_F_CURRENT_CPU, e, sizeof(*e));
+/// }
+/// ```
+///
+/// The verifier skips the branch the flag rules out, so the ringbuf helper is never checked on
+/// kernels without ringbufs. Userspace prepares the map and sets the flag before loading, then
+/// consumes samples the same way with either map:
+///
+/// ```ignore
+/// let mut open_skel = MySkelBuilder::default().open()?;
+/// let kind = EventSource::prepare(open_skel.maps_mut().events())?;
+/// open_skel.rodata().use_ringbuf = kind == EventSourceKind::RingBuf;
+/// let skel = open_skel.load()?;
+///
+/// let events = EventSource::new(skel.maps().events(), |data: &[u8]| handle(data))?;
+/// loop {
+///     events.poll(Duration::from_millis(100))?;
+/// }
+/// ```
+pub struct EventSource {
+    inner: Inner,
+}
+
+impl EventSource {
+    /// Prepare the ringbuf `map` of an object about to be loaded, turning it into a perf event
+    /// array if the kernel does not support ringbufs. Returns which kind of map it will be.
+    ///
+    /// Probing for ringbufs needs `CAP_BPF` or `CAP_SYS_ADMIN`, like loading the object.
+    pub fn prepare(map: &mut OpenMap) -> Result<EventSourceKind> {
+        let kind = if caps::probe_ringbuf() {
+            EventSourceKind::RingBuf
+        } else {
+            EventSourceKind::PerfBuffer
+        };
+
+        Self::prepare_as(map, kind)?;
+        Ok(kind)
+    }
+
+    /// Like [`EventSource::prepare()`], but use `kind` regardless of what the kernel supports,
+    /// eg. to exercise the perf buffer path on a recent kernel.
+    pub fn prepare_as(map: &mut OpenMap, kind: EventSourceKind) -> Result<()> {
+        if map.map_type() != MapType::RingBuf {
+            return Err(Error::InvalidInput("Must use a RingBuf map".into()));
+        }
+
+        if kind == EventSourceKind::PerfBuffer {
+            // The bundled libbpf can't change a map's type, so create the perf event array
+            // ourselves and have libbpf use it instead
+            let perf = MapBuilder::new(MapType::PerfEventArray)
+                .name(map.name()?)
+                .key_size(4)
+                .value_size(4)
+                .max_entries(util::num_possible_cpus()? as u32)
+                .build()?;
+            map.reuse_map(&perf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Consume the samples of `map`, a ringbuf or perf event array as set up by
+    /// [`EventSource::prepare()`], with `callback`.
+    pub fn new<F>(map: &Map, mut callback: F) -> Result<Self>
+    where
+        F: FnMut(&[u8]) + 'static,
+    {
+        let inner = match map.map_type() {
+            MapType::RingBuf => {
+                let mut builder = RingBufferBuilder::new();
+                builder.add(map, move |data: &[u8]| {
+                    callback(data);
+                    0
+                })?;
+                Inner::RingBuf(builder.build()?)
+            }
+            MapType::PerfEventArray => Inner::PerfBuffer(
+                PerfBufferBuilder::new(map)
+                    .sample_cb(move |_cpu, data: &[u8]| callback(data))
+                    .build()?,
+            ),
+            ty => {
+                return Err(Error::InvalidInput(format!(
+                    "map {} is a {}, not a RingBuf or PerfEventArray",
+                    map.name(),
+                    ty
+                )))
+            }
+        };
+
+        Ok(EventSource { inner })
+    }
+
+    /// The kind of map samples are read from.
+    pub fn kind(&self) -> EventSourceKind {
+        match self.inner {
+            Inner::RingBuf(_) => EventSourceKind::RingBuf,
+            Inner::PerfBuffer(_) => EventSourceKind::PerfBuffer,
+        }
+    }
+
+    /// Wait up to `timeout` for samples and pass them to the callback.
+    pub fn poll(&self, timeout: Duration) -> Result<()> {
+        match &self.inner {
+            Inner::RingBuf(ringbuf) => ringbuf.poll(timeout),
+            Inner::PerfBuffer(perf) => perf.poll(timeout),
+        }
+    }
+
+    /// Pass the samples available to the callback, without waiting.
+    pub fn consume(&self) -> Result<()> {
+        match &self.inner {
+            Inner::RingBuf(ringbuf) => ringbuf.consume(),
+            Inner::PerfBuffer(perf) => perf.consume(),
+        }
+    }
+
+    /// Samples the kernel dropped because a buffer was full. Always 0 with a ringbuf, where
+    /// the BPF side learns of drops instead, when reserving or submitting a sample fails.
+    pub fn lost(&self) -> u64 {
+        match &self.inner {
+            Inner::RingBuf(_) => 0,
+            Inner::PerfBuffer(perf) => perf.stats().lost,
+        }
+    }
+}
+
+impl AsFd for EventSource {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match &self.inner {
+            Inner::RingBuf(ringbuf) => ringbuf.as_fd(),
+            Inner::PerfBuffer(perf) => perf.as_fd(),
+        }
+    }
+}
+
+impl AsRawFd for EventSource {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
@@ -0,0 +1,845 @@
+//! Resolve network interfaces and follow them coming and going
+//!
+//! For example, to keep an XDP program attached to `eth0` across the interface being removed and
+//! re-created, eg. by a container runtime:
+//! ```no_run
+//! # use libbpf_rs::Object;
+//! # fn get_object() -> Object { unimplemented!() }
+//! use libbpf_rs::ifinfo::XdpBinding;
+//!
+//! let mut obj = get_object();
+//! let prog = obj.prog_unwrap("xdp_prog");
+//! let binding = XdpBinding::new(prog, "eth0").expect("failed to attach");
+//! assert!(binding.is_attached());
+//! ```
+//!
+//! [`inventory()`] lists the XDP programs and tc filters attached to every interface, eg. to
+//! detect other tools' programs before attaching.
+
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use nix::{errno, libc};
+
+use crate::*;
+
+/// Netlink `NLMSG_ERROR`, the reply to a failed request
+const NLMSG_ERROR: u16 = 2;
+/// Netlink `NLMSG_DONE`, the end of a dump
+const NLMSG_DONE: u16 = 3;
+/// Netlink `RTM_NEWLINK`, sent when an interface is added or changes state
+const RTM_NEWLINK: u16 = 16;
+/// Netlink `RTM_DELLINK`, sent when an interface is removed
+const RTM_DELLINK: u16 = 17;
+/// Netlink `RTM_GETLINK`, to dump interfaces
+const RTM_GETLINK: u16 = 18;
+/// Netlink `RTM_NEWTFILTER`, a tc filter in a dump
+const RTM_NEWTFILTER: u16 = 44;
+/// Netlink `RTM_GETTFILTER`, to dump the tc filters of a qdisc
+const RTM_GETTFILTER: u16 = 46;
+/// Netlink `NLM_F_REQUEST | NLM_F_DUMP`
+const NLM_F_DUMP_REQUEST: u16 = 0x301;
+/// Multicast group of `RTM_*LINK` notifications
+const RTMGRP_LINK: u32 = 1;
+/// Attribute holding the interface name in `RTM_*LINK` messages
+const IFLA_IFNAME: u16 = 3;
+/// Attribute nesting the `IFLA_XDP_*` attributes in `RTM_*LINK` messages
+const IFLA_XDP: u16 = 43;
+/// `IFLA_XDP` attribute holding the `XDP_ATTACHED_*` mode
+const IFLA_XDP_ATTACHED: u16 = 2;
+/// `IFLA_XDP` attribute holding the program id if a single program is attached
+const IFLA_XDP_PROG_ID: u16 = 4;
+/// `IFLA_XDP` attributes holding the program id of each mode if several are attached
+const IFLA_XDP_DRV_PROG_ID: u16 = 5;
+const IFLA_XDP_SKB_PROG_ID: u16 = 6;
+const IFLA_XDP_HW_PROG_ID: u16 = 7;
+/// `XDP_ATTACHED_*` modes
+const XDP_ATTACHED_DRV: u8 = 1;
+const XDP_ATTACHED_SKB: u8 = 2;
+const XDP_ATTACHED_HW: u8 = 3;
+/// Attribute holding the classifier name in `RTM_*TFILTER` messages
+const TCA_KIND: u16 = 1;
+/// Attribute nesting the classifier's options in `RTM_*TFILTER` messages
+const TCA_OPTIONS: u16 = 2;
+/// `cls_bpf` options holding the program name, flags and id
+const TCA_BPF_NAME: u16 = 7;
+const TCA_BPF_FLAGS: u16 = 8;
+const TCA_BPF_ID: u16 = 11;
+/// `TCA_BPF_FLAGS` flag set for direct action programs
+const TCA_BPF_FLAG_ACT_DIRECT: u32 = 1;
+/// `tcm_parent` of the ingress and egress hooks of a `clsact` qdisc
+const TC_H_CLSACT_INGRESS: u32 = 0xffff_fff2;
+const TC_H_CLSACT_EGRESS: u32 = 0xffff_fff3;
+/// Bits of `rta_type` holding the attribute type, without the nested and byte order flags
+const NLA_TYPE_MASK: u16 = 0x3fff;
+/// Size of `struct nlmsghdr`
+const NLMSG_HDRLEN: usize = 16;
+/// Size of `struct ifinfomsg`
+const IFINFOMSG_LEN: usize = 16;
+/// Size of `struct tcmsg`
+const TCMSG_LEN: usize = 20;
+/// Size of `struct rtattr`
+const RTA_HDRLEN: usize = 4;
+
+/// How often [`XdpBinding`]'s watcher thread checks whether it should exit
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returns the index of the interface called `name`.
+///
+/// Fails with `ENODEV` if there is no such interface.
+pub fn index<T: AsRef<str>>(name: T) -> Result<u32> {
+    let name_c = util::str_to_cstring(name.as_ref())?;
+
+    let idx = unsafe { libc::if_nametoindex(name_c.as_ptr()) };
+    if idx == 0 {
+        Err(Error::System(errno::errno()))
+    } else {
+        Ok(idx)
+    }
+}
+
+/// Returns the name of the interface with index `index`.
+///
+/// Fails with `ENXIO` if there is no such interface.
+pub fn name(index: u32) -> Result<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+
+    let ret = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if ret.is_null() {
+        return Err(Error::System(errno::errno()));
+    }
+
+    util::c_ptr_to_string(buf.as_ptr())
+}
+
+/// A change to a network interface, as reported by [`LinkWatcher`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkEvent {
+    /// The interface was added, or its state changed
+    New {
+        index: u32,
+        name: String,
+        /// Whether the interface is administratively up, ie. has `IFF_UP` set
+        up: bool,
+    },
+    /// The interface was removed
+    Removed { index: u32, name: String },
+}
+
+/// Notifies of interfaces being added, removed or changing state, through a netlink socket
+/// subscribed to `RTMGRP_LINK`.
+///
+/// Only changes after the watcher is created are reported. Create the watcher before looking up
+/// an interface to not miss it coming or going in between.
+pub struct LinkWatcher {
+    fd: OwnedFd,
+}
+
+impl LinkWatcher {
+    pub fn new() -> Result<Self> {
+        let fd = route_socket(libc::SOCK_NONBLOCK)?;
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = RTMGRP_LINK;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        Ok(Self { fd })
+    }
+
+    /// Wait up to `timeout` for interface changes and return them, oldest first. Returns an
+    /// empty list if there were none.
+    ///
+    /// Fails with `ENOBUFS` if notifications were dropped because they were not read quickly
+    /// enough. Re-check the interfaces of interest in that case.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<LinkEvent>> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) };
+        if ret < 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        let mut events = Vec::new();
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                match errno::errno() {
+                    libc::EAGAIN => return Ok(events),
+                    libc::EINTR => continue,
+                    e => return Err(Error::System(e)),
+                }
+            }
+
+            events.extend(parse_link_messages(&buf[..len as usize]));
+        }
+    }
+}
+
+/// Open a `NETLINK_ROUTE` socket, with extra socket `flags`
+fn route_socket(flags: libc::c_int) -> Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | flags,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::System(errno::errno()));
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Send a dump request of `msg_type` with `payload` to the kernel over `fd`, and return the
+/// replies as `(message type, payload)`
+fn dump(fd: &OwnedFd, msg_type: u16, payload: &[u8]) -> Result<Vec<(u16, Vec<u8>)>> {
+    let mut req = Vec::with_capacity(NLMSG_HDRLEN + payload.len());
+    req.extend_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+    req.extend_from_slice(&msg_type.to_ne_bytes());
+    req.extend_from_slice(&NLM_F_DUMP_REQUEST.to_ne_bytes());
+    // Sequence number and port id, the kernel's being 0
+    req.extend_from_slice(&[0; 8]);
+    req.extend_from_slice(payload);
+
+    let ret = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            req.as_ptr() as *const libc::c_void,
+            req.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::System(errno::errno()));
+    }
+
+    let mut replies = Vec::new();
+    let mut buf = vec![0u8; 32768];
+    loop {
+        let len = unsafe {
+            libc::recv(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            match errno::errno() {
+                libc::EINTR => continue,
+                e => return Err(Error::System(e)),
+            }
+        }
+
+        for (msg_type, msg) in messages(&buf[..len as usize]) {
+            match msg_type {
+                // Both carry a negative errno, or 0 if all went well
+                NLMSG_ERROR | NLMSG_DONE => {
+                    return match read_u32(msg, 0).map(|e| e as i32) {
+                        Some(e) if e < 0 => Err(Error::System(-e)),
+                        _ => Ok(replies),
+                    };
+                }
+                _ => replies.push((msg_type, msg.to_vec())),
+            }
+        }
+    }
+}
+
+impl AsFd for LinkWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Round `len` up to the 4 byte alignment of netlink messages and attributes
+fn nl_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes([*data.get(off)?, *data.get(off + 1)?]))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    let bytes = data.get(off..off + 4)?;
+    Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Split a netlink datagram into `(message type, payload)`, up to the first malformed message
+fn messages(data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut msgs = Vec::new();
+    let mut off = 0;
+
+    while let (Some(msg_len), Some(msg_type)) = (read_u32(data, off), read_u16(data, off + 4)) {
+        let msg_len = msg_len as usize;
+        if msg_len < NLMSG_HDRLEN || off + msg_len > data.len() {
+            break;
+        }
+
+        msgs.push((msg_type, &data[off + NLMSG_HDRLEN..off + msg_len]));
+        off += nl_align(msg_len);
+    }
+
+    msgs
+}
+
+/// Split attributes into `(attribute type, value)`, up to the first malformed attribute
+fn attrs(data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    let mut off = 0;
+
+    while let (Some(rta_len), Some(rta_type)) = (read_u16(data, off), read_u16(data, off + 2)) {
+        let rta_len = rta_len as usize;
+        if rta_len < RTA_HDRLEN || off + rta_len > data.len() {
+            break;
+        }
+
+        attrs.push((
+            rta_type & NLA_TYPE_MASK,
+            &data[off + RTA_HDRLEN..off + rta_len],
+        ));
+        off += nl_align(rta_len);
+    }
+
+    attrs
+}
+
+/// A NUL terminated string attribute
+fn attr_string(value: &[u8]) -> String {
+    let value = value.split(|&b| b == 0).next().unwrap_or(value);
+    String::from_utf8_lossy(value).into_owned()
+}
+
+/// Parse the `RTM_NEWLINK` and `RTM_DELLINK` messages in a netlink datagram, skipping any other
+/// or malformed ones
+fn parse_link_messages(data: &[u8]) -> Vec<LinkEvent> {
+    messages(data)
+        .into_iter()
+        .filter(|(msg_type, _)| *msg_type == RTM_NEWLINK || *msg_type == RTM_DELLINK)
+        .filter_map(|(msg_type, msg)| parse_link_message(msg_type, msg))
+        .collect()
+}
+
+/// Parse the `struct ifinfomsg` and attributes following a `RTM_*LINK` message header
+fn parse_link_message(msg_type: u16, data: &[u8]) -> Option<LinkEvent> {
+    // struct ifinfomsg { u8 family; u8 pad; u16 type; s32 index; u32 flags; u32 change; }
+    let index = read_u32(data, 4)?;
+    let flags = read_u32(data, 8)?;
+
+    let name = attrs(data.get(IFINFOMSG_LEN..)?)
+        .into_iter()
+        .find(|(rta_type, _)| *rta_type == IFLA_IFNAME)
+        .map(|(_, value)| attr_string(value))?;
+    if msg_type == RTM_NEWLINK {
+        Some(LinkEvent::New {
+            index,
+            name,
+            up: flags & libc::IFF_UP as u32 != 0,
+        })
+    } else {
+        Some(LinkEvent::Removed { index, name })
+    }
+}
+
+/// How an XDP program is attached to an interface.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XdpMode {
+    /// Run by the driver, `XDP_FLAGS_DRV_MODE`
+    Driver,
+    /// Run by the kernel on `sk_buff`s, for drivers without XDP support, `XDP_FLAGS_SKB_MODE`
+    Generic,
+    /// Offloaded to the NIC, `XDP_FLAGS_HW_MODE`
+    Offload,
+}
+
+/// An XDP program attached to an interface, see [`inventory()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XdpAttachment {
+    pub mode: XdpMode,
+    /// Id of the program, see [`query::ProgInfoIter`]
+    pub prog_id: u32,
+}
+
+/// Which hook of a `clsact` qdisc a tc filter is attached to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcDirection {
+    Ingress,
+    Egress,
+}
+
+/// A tc filter running a BPF program, see [`inventory()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TcFilter {
+    pub direction: TcDirection,
+    /// Filters run in increasing priority order
+    pub priority: u16,
+    pub handle: u32,
+    /// Id of the program, see [`query::ProgInfoIter`]
+    pub prog_id: u32,
+    /// Name tc gave the filter, eg. the object file and section it was loaded from
+    pub name: Option<String>,
+    /// Whether the program's return value is the tc action, ie. `tc filter ... da`
+    pub direct_action: bool,
+}
+
+/// The BPF programs attached to an interface, see [`inventory()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterfacePrograms {
+    pub index: u32,
+    pub name: String,
+    /// Empty if no XDP program is attached. Holds one per mode if several are.
+    pub xdp: Vec<XdpAttachment>,
+    /// `cls_bpf` filters on the interface's `clsact` qdisc, if any
+    pub tc: Vec<TcFilter>,
+}
+
+/// Returns the XDP programs and tc BPF filters attached to every interface, sorted by interface
+/// index, as `ip link` and `tc filter show` would list them. Use it to detect programs of other
+/// tools before attaching, which could conflict or be replaced.
+///
+/// Only filters on the ingress and egress hooks of `clsact` qdiscs, where BPF programs are
+/// usually attached, are listed. Programs attached with tcx or XDP BPF links show up too, but
+/// links are better enumerated with [`query::LinkInfoIter`].
+pub fn inventory() -> Result<Vec<InterfacePrograms>> {
+    let fd = route_socket(0)?;
+
+    // An empty `struct ifinfomsg` dumps every interface
+    let mut interfaces: Vec<InterfacePrograms> = dump(&fd, RTM_GETLINK, &[0; IFINFOMSG_LEN])?
+        .iter()
+        .filter(|(msg_type, _)| *msg_type == RTM_NEWLINK)
+        .filter_map(|(_, msg)| parse_link_programs(msg))
+        .collect();
+    interfaces.sort_by_key(|iface| iface.index);
+
+    for iface in &mut interfaces {
+        for &(parent, direction) in &[
+            (TC_H_CLSACT_INGRESS, TcDirection::Ingress),
+            (TC_H_CLSACT_EGRESS, TcDirection::Egress),
+        ] {
+            // struct tcmsg { u8 family; u8 pad1; u16 pad2; s32 ifindex; u32 handle; u32 parent;
+            // u32 info; }
+            let mut tcmsg = [0u8; TCMSG_LEN];
+            tcmsg[4..8].copy_from_slice(&iface.index.to_ne_bytes());
+            tcmsg[12..16].copy_from_slice(&parent.to_ne_bytes());
+
+            let filters = match dump(&fd, RTM_GETTFILTER, &tcmsg) {
+                Ok(filters) => filters,
+                // No clsact qdisc, or the interface went away meanwhile
+                Err(Error::System(libc::EINVAL)) | Err(Error::System(libc::ENODEV)) => continue,
+                Err(e) => return Err(e),
+            };
+            iface.tc.extend(
+                filters
+                    .iter()
+                    .filter(|(msg_type, _)| *msg_type == RTM_NEWTFILTER)
+                    .filter_map(|(_, msg)| parse_tc_filter(direction, msg)),
+            );
+        }
+    }
+
+    Ok(interfaces)
+}
+
+/// Parse the name and XDP programs of an interface from a `RTM_NEWLINK` message
+fn parse_link_programs(data: &[u8]) -> Option<InterfacePrograms> {
+    let index = read_u32(data, 4)?;
+    let mut name = None;
+    let mut xdp = Vec::new();
+
+    for (rta_type, value) in attrs(data.get(IFINFOMSG_LEN..)?) {
+        match rta_type {
+            IFLA_IFNAME => name = Some(attr_string(value)),
+            IFLA_XDP => xdp = parse_xdp_attrs(value),
+            _ => (),
+        }
+    }
+
+    Some(InterfacePrograms {
+        index,
+        name: name?,
+        xdp,
+        tc: Vec::new(),
+    })
+}
+
+/// Parse the XDP programs in the attributes nested in `IFLA_XDP`
+fn parse_xdp_attrs(data: &[u8]) -> Vec<XdpAttachment> {
+    let mut attached = None;
+    let mut prog_id = None;
+    let mut xdp = Vec::new();
+
+    for (rta_type, value) in attrs(data) {
+        let id = match read_u32(value, 0) {
+            Some(id) => id,
+            None if rta_type == IFLA_XDP_ATTACHED => 0,
+            None => continue,
+        };
+        let mode = match rta_type {
+            IFLA_XDP_ATTACHED => {
+                attached = value.first().copied();
+                continue;
+            }
+            IFLA_XDP_PROG_ID => {
+                prog_id = Some(id);
+                continue;
+            }
+            IFLA_XDP_DRV_PROG_ID => XdpMode::Driver,
+            IFLA_XDP_SKB_PROG_ID => XdpMode::Generic,
+            IFLA_XDP_HW_PROG_ID => XdpMode::Offload,
+            _ => continue,
+        };
+        xdp.push(XdpAttachment { mode, prog_id: id });
+    }
+
+    // With a single program, only `IFLA_XDP_PROG_ID` and its mode are reported
+    if xdp.is_empty() {
+        let mode = match attached {
+            Some(XDP_ATTACHED_DRV) => Some(XdpMode::Driver),
+            Some(XDP_ATTACHED_SKB) => Some(XdpMode::Generic),
+            Some(XDP_ATTACHED_HW) => Some(XdpMode::Offload),
+            _ => None,
+        };
+        if let (Some(mode), Some(prog_id)) = (mode, prog_id) {
+            xdp.push(XdpAttachment { mode, prog_id });
+        }
+    }
+
+    xdp
+}
+
+/// Parse a `RTM_NEWTFILTER` message, if it is for a `cls_bpf` filter
+fn parse_tc_filter(direction: TcDirection, data: &[u8]) -> Option<TcFilter> {
+    let handle = read_u32(data, 8)?;
+    // `tcm_info` holds the priority in its upper half, and the protocol in its lower one
+    let info = read_u32(data, 16)?;
+
+    let mut kind = None;
+    let mut options = None;
+    for (rta_type, value) in attrs(data.get(TCMSG_LEN..)?) {
+        match rta_type {
+            TCA_KIND => kind = Some(attr_string(value)),
+            TCA_OPTIONS => options = Some(value),
+            _ => (),
+        }
+    }
+    // Dumps also list the filter chain itself, without options
+    if kind.as_deref() != Some("bpf") {
+        return None;
+    }
+
+    let mut prog_id = None;
+    let mut name = None;
+    let mut flags = 0;
+    for (rta_type, value) in attrs(options?) {
+        match rta_type {
+            TCA_BPF_ID => prog_id = read_u32(value, 0),
+            TCA_BPF_NAME => name = Some(attr_string(value)),
+            TCA_BPF_FLAGS => flags = read_u32(value, 0).unwrap_or(0),
+            _ => (),
+        }
+    }
+
+    Some(TcFilter {
+        direction,
+        priority: (info >> 16) as u16,
+        handle,
+        prog_id: prog_id?,
+        name,
+        direct_action: flags & TCA_BPF_FLAG_ACT_DIRECT != 0,
+    })
+}
+
+/// Attach `prog_fd` to the interface with index `ifindex` with a BPF link
+fn attach_xdp_link(prog_fd: &OwnedFd, ifindex: u32) -> Result<OwnedFd> {
+    let fd = unsafe {
+        libbpf_sys::bpf_link_create(
+            prog_fd.as_raw_fd(),
+            ifindex as i32,
+            libbpf_sys::BPF_XDP,
+            ptr::null(),
+        )
+    };
+    if fd < 0 {
+        return Err(Error::System(errno::errno()));
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// An XDP program attached to an interface by name, that is re-attached whenever an interface
+/// of that name appears again after being removed.
+///
+/// A watcher thread follows the interface through a [`LinkWatcher`]. The program is detached
+/// and the thread stopped when the binding is dropped.
+pub struct XdpBinding {
+    attached: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl XdpBinding {
+    /// Attach `prog` to the interface called `ifname`, now if it exists and otherwise once it
+    /// appears.
+    ///
+    /// Fails if the interface exists but `prog` can't be attached to it. Later attach failures
+    /// are retried on the next change to the interface, see [`XdpBinding::is_attached()`].
+    pub fn new<T: AsRef<str>>(prog: &Program, ifname: T) -> Result<Self> {
+        let ifname = ifname.as_ref().to_string();
+        // Keep our own fd, so the program can be re-attached even if `prog` is gone by then
+        let prog_fd = prog
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(|e| Error::System(e.raw_os_error().unwrap_or(libc::EBADF)))?;
+
+        // Start watching first, so the interface can't come or go unnoticed
+        let mut watcher = LinkWatcher::new()?;
+        let mut link = match index(&ifname) {
+            Ok(ifindex) => Some((ifindex, attach_xdp_link(&prog_fd, ifindex)?)),
+            Err(Error::System(libc::ENODEV)) => None,
+            Err(e) => return Err(e),
+        };
+
+        let attached = Arc::new(AtomicBool::new(link.is_some()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let attached = attached.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let events = match watcher.poll(STOP_POLL_INTERVAL) {
+                        Ok(events) => events,
+                        // Missed some changes, catch up by looking the interface up again
+                        Err(Error::System(libc::ENOBUFS)) => match index(&ifname) {
+                            Ok(ifindex) => vec![LinkEvent::New {
+                                index: ifindex,
+                                name: ifname.clone(),
+                                up: true,
+                            }],
+                            Err(_) => Vec::new(),
+                        },
+                        Err(Error::System(libc::EINTR)) => Vec::new(),
+                        // Keep the program attached, but don't spin on an error that persists
+                        Err(_) => {
+                            thread::sleep(STOP_POLL_INTERVAL);
+                            Vec::new()
+                        }
+                    };
+
+                    for event in events {
+                        match event {
+                            LinkEvent::New { index, name, .. } if name == ifname => {
+                                if matches!(&link, Some((cur, _)) if *cur == index) {
+                                    continue;
+                                }
+                                link = attach_xdp_link(&prog_fd, index).ok().map(|fd| (index, fd));
+                            }
+                            LinkEvent::Removed { index, .. } => {
+                                if matches!(&link, Some((cur, _)) if *cur == index) {
+                                    link = None;
+                                }
+                            }
+                            _ => (),
+                        }
+                        attached.store(link.is_some(), Ordering::Relaxed);
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            attached,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Whether the program is currently attached, ie. the interface exists and attaching to it
+    /// last time it appeared succeeded.
+    pub fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for XdpBinding {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a netlink message of `msg_type` for interface `index` called `name`
+    fn link_message(msg_type: u16, index: u32, flags: u32, name: &str) -> Vec<u8> {
+        let mut name_attr = Vec::new();
+        let rta_len = RTA_HDRLEN + name.len() + 1;
+        name_attr.extend_from_slice(&(rta_len as u16).to_ne_bytes());
+        name_attr.extend_from_slice(&IFLA_IFNAME.to_ne_bytes());
+        name_attr.extend_from_slice(name.as_bytes());
+        name_attr.push(0);
+        name_attr.resize(nl_align(rta_len), 0);
+
+        let mut ifinfo = vec![0u8; IFINFOMSG_LEN];
+        ifinfo[4..8].copy_from_slice(&index.to_ne_bytes());
+        ifinfo[8..12].copy_from_slice(&flags.to_ne_bytes());
+
+        let msg_len = NLMSG_HDRLEN + ifinfo.len() + name_attr.len();
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&(msg_len as u32).to_ne_bytes());
+        msg.extend_from_slice(&msg_type.to_ne_bytes());
+        msg.resize(NLMSG_HDRLEN, 0);
+        msg.extend(ifinfo);
+        msg.extend(name_attr);
+        msg
+    }
+
+    #[test]
+    fn test_parse_link_messages() {
+        let mut data = link_message(RTM_NEWLINK, 2, libc::IFF_UP as u32, "eth0");
+        // Not a link message, skipped
+        data.extend(link_message(20, 3, 0, "eth1"));
+        data.extend(link_message(RTM_DELLINK, 4, 0, "veth123"));
+        // Truncated, ignored
+        data.extend(&link_message(RTM_NEWLINK, 5, 0, "eth2")[..10]);
+
+        assert_eq!(
+            parse_link_messages(&data),
+            vec![
+                LinkEvent::New {
+                    index: 2,
+                    name: "eth0".to_string(),
+                    up: true,
+                },
+                LinkEvent::Removed {
+                    index: 4,
+                    name: "veth123".to_string(),
+                },
+            ]
+        );
+    }
+
+    /// Build an attribute of `rta_type` holding `value`
+    fn attr(rta_type: u16, value: &[u8]) -> Vec<u8> {
+        let rta_len = RTA_HDRLEN + value.len();
+        let mut attr = Vec::new();
+        attr.extend_from_slice(&(rta_len as u16).to_ne_bytes());
+        attr.extend_from_slice(&rta_type.to_ne_bytes());
+        attr.extend_from_slice(value);
+        attr.resize(nl_align(rta_len), 0);
+        attr
+    }
+
+    #[test]
+    fn test_parse_xdp_attrs() {
+        assert_eq!(parse_xdp_attrs(&attr(IFLA_XDP_ATTACHED, &[0])), vec![]);
+
+        let mut single = attr(IFLA_XDP_ATTACHED, &[XDP_ATTACHED_SKB]);
+        single.extend(attr(IFLA_XDP_PROG_ID, &42u32.to_ne_bytes()));
+        assert_eq!(
+            parse_xdp_attrs(&single),
+            vec![XdpAttachment {
+                mode: XdpMode::Generic,
+                prog_id: 42,
+            }]
+        );
+
+        // XDP_ATTACHED_MULTI
+        let mut multi = attr(IFLA_XDP_ATTACHED, &[4]);
+        multi.extend(attr(IFLA_XDP_DRV_PROG_ID, &7u32.to_ne_bytes()));
+        multi.extend(attr(IFLA_XDP_HW_PROG_ID, &8u32.to_ne_bytes()));
+        assert_eq!(
+            parse_xdp_attrs(&multi),
+            vec![
+                XdpAttachment {
+                    mode: XdpMode::Driver,
+                    prog_id: 7,
+                },
+                XdpAttachment {
+                    mode: XdpMode::Offload,
+                    prog_id: 8,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_tc_filter() {
+        let mut tcmsg = vec![0u8; TCMSG_LEN];
+        tcmsg[8..12].copy_from_slice(&1u32.to_ne_bytes());
+        tcmsg[16..20].copy_from_slice(&((49152u32 << 16) | 0x0300).to_ne_bytes());
+
+        let mut options = attr(TCA_BPF_ID, &12u32.to_ne_bytes());
+        options.extend(attr(TCA_BPF_NAME, b"prog.bpf.o:[tc]\0"));
+        options.extend(attr(TCA_BPF_FLAGS, &TCA_BPF_FLAG_ACT_DIRECT.to_ne_bytes()));
+
+        let mut bpf = tcmsg.clone();
+        bpf.extend(attr(TCA_KIND, b"bpf\0"));
+        // Nested attributes may carry NLA_F_NESTED
+        bpf.extend(attr(TCA_OPTIONS | 0x8000, &options));
+        assert_eq!(
+            parse_tc_filter(TcDirection::Egress, &bpf),
+            Some(TcFilter {
+                direction: TcDirection::Egress,
+                priority: 49152,
+                handle: 1,
+                prog_id: 12,
+                name: Some("prog.bpf.o:[tc]".to_string()),
+                direct_action: true,
+            })
+        );
+
+        let mut u32_filter = tcmsg;
+        u32_filter.extend(attr(TCA_KIND, b"u32\0"));
+        assert_eq!(parse_tc_filter(TcDirection::Ingress, &u32_filter), None);
+    }
+
+    #[test]
+    fn test_index_name() {
+        let idx = index("lo").expect("failed to find lo");
+        assert_eq!(name(idx).expect("failed to find lo"), "lo");
+        assert!(index("libbpf-rs-none").is_err());
+    }
+
+    #[test]
+    fn test_inventory() {
+        let interfaces = inventory().expect("failed to list interfaces");
+        let lo = interfaces
+            .iter()
+            .find(|iface| iface.name == "lo")
+            .expect("failed to find lo");
+        assert_eq!(lo.index, index("lo").unwrap());
+        assert!(lo.xdp.is_empty());
+    }
+}
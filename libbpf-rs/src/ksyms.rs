@@ -0,0 +1,206 @@
+//! Resolve kernel symbols
+//!
+//! For example, to check which kernel function an address belongs to:
+//! ```no_run
+//! use libbpf_rs::ksyms::Ksyms;
+//!
+//! let ksyms = Ksyms::load().expect("failed to load kallsyms");
+//! if let Some(sym) = ksyms.find_by_addr(0xffffffff81000000) {
+//!     println!("{}", sym.name);
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::*;
+
+/// A single entry of `/proc/kallsyms`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ksym {
+    pub addr: u64,
+    /// Symbol type, as reported by `nm`. eg. `T` for a global text symbol.
+    pub kind: char,
+    pub name: String,
+    /// Name of the module the symbol belongs to. `None` for symbols in the core kernel.
+    pub module: Option<String>,
+}
+
+/// A parsed snapshot of the kernel's symbol table.
+///
+/// Parsing kallsyms is relatively expensive, so load it once and reuse it for all lookups.
+/// Note that addresses are all zero if `kernel.kptr_restrict` hides them from the caller.
+#[derive(Debug, Default)]
+pub struct Ksyms {
+    /// Sorted by address
+    syms: Vec<Ksym>,
+    /// Index into `syms` for every symbol name
+    by_name: HashMap<String, usize>,
+}
+
+impl Ksyms {
+    /// Load symbols from `/proc/kallsyms`.
+    pub fn load() -> Result<Self> {
+        Self::load_file("/proc/kallsyms")
+    }
+
+    /// Load symbols from a file in kallsyms format.
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = fs::read_to_string(path).map_err(|e| match e.raw_os_error() {
+            Some(errno) => Error::System(errno),
+            None => Error::Internal(e.to_string()),
+        })?;
+
+        Self::parse(&contents)
+    }
+
+    /// Parse symbols from a string in kallsyms format.
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut syms = Vec::new();
+
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+            let (addr, kind, name) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(addr), Some(kind), Some(name)) => (addr, kind, name),
+                (None, _, _) => continue,
+                _ => return Err(Error::InvalidInput(format!("invalid ksym: {}", line))),
+            };
+
+            let addr = u64::from_str_radix(addr, 16).map_err(|e| {
+                Error::InvalidInput(format!("invalid ksym address {}: {}", addr, e))
+            })?;
+            let kind = kind
+                .chars()
+                .next()
+                .ok_or_else(|| Error::InvalidInput(format!("invalid ksym: {}", line)))?;
+            let module = fields
+                .next()
+                .map(|m| m.trim_start_matches('[').trim_end_matches(']').to_string());
+
+            syms.push(Ksym {
+                addr,
+                kind,
+                name: name.to_string(),
+                module,
+            });
+        }
+
+        syms.sort_by_key(|sym| sym.addr);
+
+        let mut by_name = HashMap::with_capacity(syms.len());
+        for (idx, sym) in syms.iter().enumerate() {
+            // Keep the first occurrence of duplicate (eg. static) symbol names
+            by_name.entry(sym.name.clone()).or_insert(idx);
+        }
+
+        Ok(Ksyms { syms, by_name })
+    }
+
+    /// Look up a symbol by name.
+    pub fn find_by_name<T: AsRef<str>>(&self, name: T) -> Option<&Ksym> {
+        self.by_name.get(name.as_ref()).map(|idx| &self.syms[*idx])
+    }
+
+    /// Look up the symbol `addr` falls within, ie. the symbol with the highest address that is
+    /// less than or equal to `addr`.
+    pub fn find_by_addr(&self, addr: u64) -> Option<&Ksym> {
+        let idx = match self.syms.binary_search_by_key(&addr, |sym| sym.addr) {
+            Ok(mut idx) => {
+                // Multiple symbols can share an address, prefer the last one
+                while idx + 1 < self.syms.len() && self.syms[idx + 1].addr == addr {
+                    idx += 1;
+                }
+                idx
+            }
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+
+        Some(&self.syms[idx])
+    }
+
+    /// Returns the names in `names` that do not exist in the symbol table.
+    pub fn missing<'a, T: AsRef<str>>(&self, names: &'a [T]) -> Vec<&'a str> {
+        names
+            .iter()
+            .map(|name| name.as_ref())
+            .filter(|name| !self.by_name.contains_key(*name))
+            .collect()
+    }
+
+    /// Returns the number of symbols.
+    pub fn len(&self) -> usize {
+        self.syms.len()
+    }
+
+    /// Returns true if there are no symbols.
+    pub fn is_empty(&self) -> bool {
+        self.syms.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KALLSYMS: &str = "\
+ffffffff81000000 T _stext
+ffffffff81000000 T _text
+ffffffff81001000 T do_one_initcall
+ffffffff81002000 t helper
+ffffffffc0000000 t mod_func\t[mymod]
+";
+
+    #[test]
+    fn test_parse() {
+        let ksyms = Ksyms::parse(KALLSYMS).expect("failed to parse");
+        assert_eq!(ksyms.len(), 5);
+
+        let sym = ksyms.find_by_name("mod_func").expect("missing mod_func");
+        assert_eq!(sym.addr, 0xffffffffc0000000);
+        assert_eq!(sym.kind, 't');
+        assert_eq!(sym.module.as_deref(), Some("mymod"));
+
+        let sym = ksyms.find_by_name("helper").expect("missing helper");
+        assert_eq!(sym.module, None);
+
+        assert!(ksyms.find_by_name("asdf").is_none());
+        assert!(Ksyms::parse("ffffffff81000000 T\n").is_err());
+        assert!(Ksyms::parse("zzzz T foo\n").is_err());
+    }
+
+    #[test]
+    fn test_find_by_addr() {
+        let ksyms = Ksyms::parse(KALLSYMS).expect("failed to parse");
+
+        assert!(ksyms.find_by_addr(0x1000).is_none());
+        assert_eq!(
+            ksyms.find_by_addr(0xffffffff81000000).unwrap().name,
+            "_text"
+        );
+        assert_eq!(
+            ksyms.find_by_addr(0xffffffff81000010).unwrap().name,
+            "_text"
+        );
+        assert_eq!(
+            ksyms.find_by_addr(0xffffffff81001000).unwrap().name,
+            "do_one_initcall"
+        );
+        assert_eq!(
+            ksyms.find_by_addr(0xffffffffc0001234).unwrap().name,
+            "mod_func"
+        );
+    }
+
+    #[test]
+    fn test_missing() {
+        let ksyms = Ksyms::parse(KALLSYMS).expect("failed to parse");
+
+        assert!(ksyms.missing(&["_stext", "helper"]).is_empty());
+        assert_eq!(
+            ksyms.missing(&["_stext", "asdf", "qwer"]),
+            vec!["asdf", "qwer"]
+        );
+    }
+}
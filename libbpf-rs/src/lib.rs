@@ -63,9 +63,21 @@
 //! This is probably the best way to understand how libbpf-rs and libbpf-cargo work together.
 //!
 //! [See example here](https://github.com/libbpf/libbpf-rs/tree/master/examples/runqslower).
+//!
+//! ## Features
+//!
+//! * `serde`: implement `Serialize` and `Deserialize` for [`MapSnapshot`], so map contents can be
+//!   saved in any serde supported format.
 
+pub mod btf;
+pub mod btfhub;
+mod caps;
+pub mod cgroups;
 mod error;
+mod event_source;
+pub mod ifinfo;
 mod iter;
+pub mod ksyms;
 mod link;
 mod map;
 mod object;
@@ -73,18 +85,32 @@ mod perf_buffer;
 mod program;
 pub mod query;
 mod ringbuf;
+mod rlimit;
 /// Used for skeleton -- an end user may not consider this API stable
 #[doc(hidden)]
 pub mod skeleton;
+pub mod symbolize;
+pub mod tracefs;
 mod util;
 
 pub use libbpf_sys;
+pub use plain::Plain;
 
+pub use crate::caps::KernelCaps;
 pub use crate::error::{Error, Result};
+pub use crate::event_source::{EventSource, EventSourceKind};
 pub use crate::iter::Iter;
 pub use crate::link::Link;
-pub use crate::map::{Map, MapFlags, MapType, OpenMap};
-pub use crate::object::{Object, ObjectBuilder, OpenObject};
-pub use crate::perf_buffer::{PerfBuffer, PerfBufferBuilder};
-pub use crate::program::{OpenProgram, Program, ProgramAttachType, ProgramType};
-pub use crate::ringbuf::{RingBuffer, RingBufferBuilder};
+pub use crate::map::{
+    Arena, LpmKey, Map, MapBuilder, MapFlags, MapSnapshot, MapType, OpenMap, PerCpuCounter,
+    QueueMap, ReadOnlyMap, RingBufPositions, WriteOnlyMap,
+};
+pub use crate::object::{AttachFilter, DetachOptions, Object, ObjectBuilder, OpenObject};
+pub use crate::perf_buffer::{PerfBuffer, PerfBufferBuilder, PerfBufferStats};
+pub use crate::program::{
+    AttachSpec, OpenProgram, Program, ProgramAttachFlags, ProgramAttachType, ProgramAttachment,
+    ProgramType, RetryPolicy, SectionHandler, SocketFilter, TestRunOptions, TestRunOutput,
+};
+pub use crate::ringbuf::{RingBuffer, RingBufferBuilder, RingBufferStats};
+pub use crate::rlimit::{memlock_rlimit_needed, set_memlock_rlimit};
+pub use crate::skeleton::{check_pinned_layout, SkelTimings};
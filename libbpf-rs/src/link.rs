@@ -1,4 +1,5 @@
 use nix::errno;
+use std::os::unix::io::{AsFd, BorrowedFd};
 use std::path::Path;
 
 use crate::*;
@@ -25,8 +26,24 @@ impl Link {
         Self::new(ptr)
     }
 
-    /// Replace the underlying prog with `prog`.
-    pub fn update_prog(&mut self, prog: Program) -> Result<()> {
+    /// Open a link pinned to bpffs at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path_c = util::path_to_cstring(path)?;
+        let path_ptr = path_c.as_ptr();
+
+        let ptr = unsafe { libbpf_sys::bpf_link__open(path_ptr) };
+        let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
+        if err != 0 {
+            Err(Error::System(err as i32))
+        } else {
+            Ok(Link::new(ptr))
+        }
+    }
+
+    /// Atomically replace the underlying prog with `prog`, without detaching.
+    ///
+    /// Not every link type supports this. Unsupported link types fail with `EOPNOTSUPP`.
+    pub fn update_prog(&mut self, prog: &Program) -> Result<()> {
         let ret = unsafe { libbpf_sys::bpf_link__update_program(self.ptr, prog.ptr) };
         if ret != 0 {
             Err(Error::System(errno::errno()))
@@ -62,12 +79,33 @@ impl Link {
         }
     }
 
+    /// Forcefully detach the underlying prog, even if the link is pinned or its file
+    /// descriptor is held elsewhere. The link stays valid but no longer has any effect.
+    pub fn detach(&self) -> Result<()> {
+        let ret = unsafe { libbpf_sys::bpf_link__detach(self.ptr) };
+        if ret != 0 {
+            // Error code is returned negative, flip to positive to match errno
+            Err(Error::System(-ret))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Returns the file descriptor of the link.
-    pub fn get_fd(&self) -> i32 {
+    pub fn fd(&self) -> i32 {
         unsafe { libbpf_sys::bpf_link__fd(self.ptr) }
     }
 }
 
+/// Use [`BorrowedFd::try_clone_to_owned`] to get an independently owned copy of the fd, eg. to
+/// send to another process.
+impl AsFd for Link {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The fd is owned by libbpf and stays open for as long as `self` lives
+        unsafe { BorrowedFd::borrow_raw(self.fd()) }
+    }
+}
+
 impl Drop for Link {
     fn drop(&mut self) {
         let _ = unsafe { libbpf_sys::bpf_link__destroy(self.ptr) };
@@ -1,15 +1,120 @@
 use core::ffi::c_void;
-use std::convert::TryFrom;
-use std::path::Path;
+use std::cmp;
+use std::convert::{TryFrom, TryInto};
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::Read;
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use bitflags::bitflags;
-use nix::{errno, unistd};
+use nix::sys::mman;
+use nix::{errno, libc, unistd};
 use num_enum::TryFromPrimitive;
+use plain::Plain;
 use strum_macros::Display;
 
+use crate::btf::Btf;
 use crate::*;
 
+/// Size of the header the kernel prepends to every `ringbuf` sample, `BPF_RINGBUF_HDR_SZ`.
+const RINGBUF_HDR_SZ: u64 = 8;
+
+/// `bpf_seq_write()` helper, Linux 5.9
+const BPF_FUNC_SEQ_WRITE: i32 = 127;
+
+/// Build a BPF instruction
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> libbpf_sys::bpf_insn {
+    let mut insn: libbpf_sys::bpf_insn = unsafe { mem::zeroed() };
+    insn.code = code;
+    insn.set_dst_reg(dst);
+    insn.set_src_reg(src);
+    insn.off = off;
+    insn.imm = imm;
+    insn
+}
+
+/// Map element iterator program writing each `key_size` byte key followed by its `value_size`
+/// byte value to the iterator, as [`Map::dump_via_iter()`] reads them:
+///
+/// ```text
+/// int dump(struct bpf_iter__bpf_map_elem *ctx)
+/// {
+///     if (ctx->key && ctx->value) {
+///         bpf_seq_write(ctx->meta->seq, ctx->key, key_size);
+///         bpf_seq_write(ctx->meta->seq, ctx->value, value_size);
+///     }
+///     return 0;
+/// }
+/// ```
+fn map_iter_insns(key_size: u32, value_size: u32) -> Vec<libbpf_sys::bpf_insn> {
+    const MOV64_REG: u8 = 0xbf;
+    const MOV64_IMM: u8 = 0xb7;
+    const LDX_DW: u8 = 0x79;
+    const JEQ_IMM: u8 = 0x15;
+    const CALL: u8 = 0x85;
+    const EXIT: u8 = 0x95;
+
+    vec![
+        insn(MOV64_REG, 6, 1, 0, 0),
+        // ctx->key, ctx->value, NULL once the iteration is done
+        insn(LDX_DW, 7, 6, 16, 0),
+        insn(JEQ_IMM, 7, 0, 12, 0),
+        insn(LDX_DW, 8, 6, 24, 0),
+        insn(JEQ_IMM, 8, 0, 10, 0),
+        // ctx->meta->seq
+        insn(LDX_DW, 1, 6, 0, 0),
+        insn(LDX_DW, 1, 1, 0, 0),
+        insn(MOV64_REG, 2, 7, 0, 0),
+        insn(MOV64_IMM, 3, 0, 0, key_size as i32),
+        insn(CALL, 0, 0, 0, BPF_FUNC_SEQ_WRITE),
+        insn(LDX_DW, 1, 6, 0, 0),
+        insn(LDX_DW, 1, 1, 0, 0),
+        insn(MOV64_REG, 2, 8, 0, 0),
+        insn(MOV64_IMM, 3, 0, 0, value_size as i32),
+        insn(CALL, 0, 0, 0, BPF_FUNC_SEQ_WRITE),
+        insn(MOV64_IMM, 0, 0, 0, 0),
+        insn(EXIT, 0, 0, 0, 0),
+    ]
+}
+
+fn page_size() -> Result<u64> {
+    match unistd::sysconf(unistd::SysconfVar::PAGE_SIZE) {
+        Ok(Some(size)) => Ok(size as u64),
+        _ => Err(Error::Internal("failed to get page size".to_string())),
+    }
+}
+
+/// Smallest valid `ringbuf` size holding `events` samples of `avg_event_size` bytes: a power of
+/// two and a multiple of `page_size`.
+fn ringbuf_size(events: u64, avg_event_size: u64, page_size: u64) -> Option<u32> {
+    // Samples are 8 byte aligned, header included
+    let sample_size = avg_event_size.checked_add(RINGBUF_HDR_SZ + 7)? & !7;
+    let size = events
+        .checked_mul(sample_size)?
+        .max(page_size)
+        .checked_next_power_of_two()?;
+
+    if size > u32::MAX as u64 {
+        None
+    } else {
+        Some(size as u32)
+    }
+}
+
+/// `struct bpf_map_info` as of Linux 5.16, which appended `map_extra` to the fields libbpf-sys
+/// knows about.
+#[repr(C)]
+struct MapInfoWithExtra {
+    info: libbpf_sys::bpf_map_info,
+    map_extra: u64,
+}
+
 /// Represents a parsed but not yet loaded BPF map.
 ///
 /// This object exposes operations that need to happen before the map is created.
@@ -25,6 +130,27 @@ impl OpenMap {
         OpenMap { ptr }
     }
 
+    pub(crate) fn map_type(&self) -> MapType {
+        // bpf_map__def can only return null if passed null
+        let ty = unsafe { (*libbpf_sys::bpf_map__def(self.ptr)).type_ };
+        match MapType::try_from(ty) {
+            Ok(t) => t,
+            Err(_) => MapType::Unknown,
+        }
+    }
+
+    /// Name of the map, eg. `start` or `runqslo.rodata` for internal maps.
+    pub fn name(&self) -> Result<&str> {
+        let name_ptr = unsafe { libbpf_sys::bpf_map__name(self.ptr) };
+        if name_ptr.is_null() {
+            return Err(Error::Internal("Map has no name".to_string()));
+        }
+
+        unsafe { CStr::from_ptr(name_ptr) }
+            .to_str()
+            .map_err(|e| Error::Internal(e.to_string()))
+    }
+
     pub fn set_map_ifindex(&mut self, idx: u32) {
         unsafe { libbpf_sys::bpf_map__set_ifindex(self.ptr, idx) };
     }
@@ -57,10 +183,76 @@ impl OpenMap {
         Ok(())
     }
 
+    /// Size a [`MapType::RingBuf`] map to hold one second worth of samples, given the expected
+    /// `events_per_sec` and the average sample size in bytes. Returns the new `max_entries`.
+    ///
+    /// The size accounts for per-sample headers and is rounded up to a power of two of at least
+    /// a page, as the kernel requires. Use [`Map::ringbuf_positions()`] to check how far behind
+    /// userspace actually falls.
+    pub fn set_ringbuf_size(&mut self, events_per_sec: u64, avg_event_size: u64) -> Result<u32> {
+        if self.map_type() != MapType::RingBuf {
+            return Err(Error::InvalidInput("Must use a RingBuf map".into()));
+        }
+
+        let size = ringbuf_size(events_per_sec, avg_event_size, page_size()?).ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "{} events/s of {} bytes do not fit in a ringbuf",
+                events_per_sec, avg_event_size
+            ))
+        })?;
+        self.set_max_entries(size)?;
+
+        Ok(size)
+    }
+
     pub fn set_inner_map_fd(&mut self, inner: &Map) {
         unsafe { libbpf_sys::bpf_map__set_inner_map_fd(self.ptr, inner.fd()) };
     }
 
+    /// Pin the map to `path` on bpffs when the object is loaded, or reuse the map already pinned
+    /// there if it is compatible. Overrides the path maps declared with
+    /// `__uint(pinning, LIBBPF_PIN_BY_NAME)` get from [`ObjectBuilder::pin_root_path()`].
+    pub fn set_pin_path<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path_c = util::path_to_cstring(path)?;
+
+        let ret = unsafe { libbpf_sys::bpf_map__set_pin_path(self.ptr, path_c.as_ptr()) };
+        if ret != 0 {
+            // Error code is returned negative, flip to positive to match errno
+            return Err(Error::System(-ret));
+        }
+
+        Ok(())
+    }
+
+    /// Path the map will be pinned to or reused from on load, if any. See
+    /// [`OpenMap::set_pin_path()`].
+    pub fn pin_path(&self) -> Option<PathBuf> {
+        let path_ptr = unsafe { libbpf_sys::bpf_map__get_pin_path(self.ptr) };
+        util::c_ptr_to_string(path_ptr).ok().map(PathBuf::from)
+    }
+
+    /// Reuse the kernel map behind `map`, eg. one from a previous instance of the object, instead
+    /// of creating a new one when the object is loaded.
+    ///
+    /// `map` must have the same definition as `self`.
+    pub fn reuse_map(&mut self, map: &Map) -> Result<()> {
+        self.reuse_fd(map.as_fd())
+    }
+
+    /// Reuse the kernel map `fd` refers to instead of creating a new one when the object is
+    /// loaded, eg. a map of another object or process shared with this one.
+    ///
+    /// The map must have the same definition as `self`.
+    pub fn reuse_fd(&mut self, fd: BorrowedFd) -> Result<()> {
+        // libbpf dups the fd, so `fd` stays independently owned
+        let ret = unsafe { libbpf_sys::bpf_map__reuse_fd(self.ptr, fd.as_raw_fd()) };
+        if ret != 0 {
+            return Err(Error::System(-ret));
+        }
+
+        Ok(())
+    }
+
     /// Reuse an already-pinned map for `self`.
     pub fn reuse_pinned_map<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         let cstring = util::path_to_cstring(path)?;
@@ -85,10 +277,128 @@ impl OpenMap {
     }
 }
 
+/// Builder for creating a [`Map`] directly, without a BPF object.
+///
+/// Useful for scratch maps in control plane tools, or to create and pin maps ahead of time
+/// that objects later pick up with [`OpenMap::reuse_pinned_map()`].
+pub struct MapBuilder {
+    name: String,
+    map_type: MapType,
+    map_flags: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    numa_node: Option<u32>,
+    btf_fd: u32,
+    btf_key_type_id: u32,
+    btf_value_type_id: u32,
+}
+
+impl MapBuilder {
+    pub fn new(map_type: MapType) -> Self {
+        MapBuilder {
+            name: String::new(),
+            map_type,
+            map_flags: 0,
+            key_size: 0,
+            value_size: 0,
+            max_entries: 0,
+            numa_node: None,
+            btf_fd: 0,
+            btf_key_type_id: 0,
+            btf_value_type_id: 0,
+        }
+    }
+
+    /// Name of the map. The kernel keeps at most 15 characters.
+    pub fn name<T: AsRef<str>>(&mut self, name: T) -> &mut Self {
+        self.name = name.as_ref().to_string();
+        self
+    }
+
+    /// Key size in bytes
+    pub fn key_size(&mut self, key_size: u32) -> &mut Self {
+        self.key_size = key_size;
+        self
+    }
+
+    /// Value size in bytes
+    pub fn value_size(&mut self, value_size: u32) -> &mut Self {
+        self.value_size = value_size;
+        self
+    }
+
+    pub fn max_entries(&mut self, max_entries: u32) -> &mut Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// `BPF_F_*` map creation flags, eg. `BPF_F_NO_PREALLOC`.
+    pub fn map_flags(&mut self, map_flags: u32) -> &mut Self {
+        self.map_flags = map_flags;
+        self
+    }
+
+    /// Allocate the map's memory on the given NUMA node.
+    pub fn numa_node(&mut self, numa_node: u32) -> &mut Self {
+        self.numa_node = Some(numa_node);
+        self
+    }
+
+    /// Describe the key and value types with BTF type IDs from the BTF object `btf_fd`.
+    pub fn btf(&mut self, btf_fd: u32, key_type_id: u32, value_type_id: u32) -> &mut Self {
+        self.btf_fd = btf_fd;
+        self.btf_key_type_id = key_type_id;
+        self.btf_value_type_id = value_type_id;
+        self
+    }
+
+    /// Create the map. The returned [`Map`] owns the map's fd and closes it when dropped.
+    pub fn build(&self) -> Result<Map> {
+        let name = util::str_to_cstring(&self.name)?;
+
+        let mut map_flags = self.map_flags;
+        if self.numa_node.is_some() {
+            map_flags |= libbpf_sys::BPF_F_NUMA_NODE;
+        }
+
+        let attr = libbpf_sys::bpf_create_map_attr {
+            name: name.as_ptr(),
+            map_type: self.map_type.clone() as libbpf_sys::bpf_map_type,
+            map_flags,
+            key_size: self.key_size,
+            value_size: self.value_size,
+            max_entries: self.max_entries,
+            numa_node: self.numa_node.unwrap_or(0),
+            btf_fd: self.btf_fd,
+            btf_key_type_id: self.btf_key_type_id,
+            btf_value_type_id: self.btf_value_type_id,
+            ..Default::default()
+        };
+
+        let fd = unsafe { libbpf_sys::bpf_create_map_xattr(&attr) };
+        if fd < 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        Ok(Map::new(
+            fd,
+            self.name.clone(),
+            attr.map_type,
+            self.key_size,
+            self.value_size,
+            ptr::null_mut(),
+        ))
+    }
+}
+
 /// Represents a created map.
 ///
 /// Some methods require working with raw bytes. You may find libraries such as
 /// [`plain`](https://crates.io/crates/plain) helpful.
+///
+/// Element operations take `&self` because they are plain syscalls on the map's fd, which the
+/// kernel synchronizes. A `Map` may therefore be shared between threads, eg. behind an `Arc`.
 pub struct Map {
     fd: i32,
     name: String,
@@ -96,6 +406,8 @@ pub struct Map {
     key_size: u32,
     value_size: u32,
     ptr: *mut libbpf_sys::bpf_map,
+    /// Where [`Map::arena()`] first mapped the arena, or 0
+    arena_addr: AtomicUsize,
 }
 
 impl Map {
@@ -114,6 +426,7 @@ impl Map {
             key_size,
             value_size,
             ptr,
+            arena_addr: AtomicUsize::new(0),
         }
     }
 
@@ -143,12 +456,75 @@ impl Map {
         self.value_size
     }
 
+    /// The map's `map_extra`, which some map types take parameters in, eg. the number of hash
+    /// functions of a [`MapType::BloomFilter`] map or the address of a [`MapType::Arena`] map.
+    /// Always 0 on kernels before 5.16, which don't have it.
+    ///
+    /// The bundled libbpf can't set `map_extra` on the maps it creates, so this is only non-zero
+    /// for maps created elsewhere, eg. pinned by another loader.
+    pub fn map_extra(&self) -> Result<u64> {
+        let mut info: MapInfoWithExtra = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<MapInfoWithExtra>() as u32;
+        let ret = unsafe {
+            libbpf_sys::bpf_obj_get_info_by_fd(
+                self.fd,
+                &mut info as *mut MapInfoWithExtra as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        // Older kernels fill in less, leaving `map_extra` zeroed
+        Ok(info.map_extra)
+    }
+
+    /// Returns an independent handle to the same map, backed by a duplicate of its fd. Unlike
+    /// `self`, it may outlive the object the map belongs to, eg. to hand out to other threads.
+    ///
+    /// The handle supports all element operations, but not [`Map::pin`] and [`Map::unpin`].
+    pub fn try_clone(&self) -> Result<Map> {
+        let fd = unistd::dup(self.fd).map_err(|_| Error::System(errno::errno()))?;
+
+        Ok(Map::new(
+            fd,
+            self.name.clone(),
+            self.ty,
+            self.key_size,
+            self.value_size,
+            ptr::null_mut(),
+        ))
+    }
+
+    /// Path the map is pinned to, either declaratively with `LIBBPF_PIN_BY_NAME` and
+    /// [`OpenMap::set_pin_path()`], or with [`Map::pin()`]. `None` if it is not pinned or not
+    /// part of an object.
+    pub fn pin_path(&self) -> Option<PathBuf> {
+        if self.ptr.is_null() || !unsafe { libbpf_sys::bpf_map__is_pinned(self.ptr) } {
+            return None;
+        }
+
+        let path_ptr = unsafe { libbpf_sys::bpf_map__get_pin_path(self.ptr) };
+        util::c_ptr_to_string(path_ptr).ok().map(PathBuf::from)
+    }
+
     /// [Pin](https://facebookmicrosites.github.io/bpf/blog/2018/08/31/object-lifetime.html#bpffs)
     /// this map to bpffs.
     pub fn pin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         let path_c = util::path_to_cstring(path)?;
         let path_ptr = path_c.as_ptr();
 
+        // Maps created by `MapBuilder` are not tracked by libbpf
+        if self.ptr.is_null() {
+            let ret = unsafe { libbpf_sys::bpf_obj_pin(self.fd, path_ptr) };
+            return if ret != 0 {
+                Err(Error::System(errno::errno()))
+            } else {
+                Ok(())
+            };
+        }
+
         let ret = unsafe { libbpf_sys::bpf_map__pin(self.ptr, path_ptr) };
         if ret != 0 {
             // Error code is returned negative, flip to positive to match errno
@@ -161,6 +537,13 @@ impl Map {
     /// [Unpin](https://facebookmicrosites.github.io/bpf/blog/2018/08/31/object-lifetime.html#bpffs)
     /// from bpffs
     pub fn unpin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        if self.ptr.is_null() {
+            return fs::remove_file(path).map_err(|e| match e.raw_os_error() {
+                Some(errno) => Error::System(errno),
+                None => Error::Internal(e.to_string()),
+            });
+        }
+
         let path_c = util::path_to_cstring(path)?;
         let path_ptr = path_c.as_ptr();
 
@@ -211,10 +594,52 @@ impl Map {
         }
     }
 
+    /// Same as [`Map::lookup()`], but with a typed key and value.
+    ///
+    /// `K` and `V` must be exactly [`Map::key_size()`] and [`Map::value_size()`] bytes large.
+    pub fn lookup_typed<K: Plain, V: Plain>(&self, key: &K, flags: MapFlags) -> Result<Option<V>> {
+        self.check_value_type::<V>()?;
+
+        let key = unsafe { plain::as_bytes(key) };
+        match self.lookup(key, flags)? {
+            Some(bytes) => {
+                // Plain types are valid for any bit pattern
+                let mut value: V = unsafe { mem::zeroed() };
+                plain::copy_from_bytes(&mut value, &bytes)
+                    .map_err(|e| Error::Internal(format!("{:?}", e)))?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Same as [`Map::lookup_typed()`], but holds the `struct bpf_spin_lock` in the value while
+    /// copying it out, so the value is consistent with respect to BPF programs updating it under
+    /// the lock.
+    ///
+    /// The map must have been created with BTF describing a value that contains a
+    /// `struct bpf_spin_lock`, or the kernel fails the lookup with `EINVAL`. The lock itself
+    /// reads as zero.
+    pub fn lookup_locked<K: Plain, V: Plain>(&self, key: &K) -> Result<Option<V>> {
+        self.lookup_typed(key, MapFlags::LOCK)
+    }
+
+    fn check_value_type<V>(&self) -> Result<()> {
+        if mem::size_of::<V>() != self.value_size() as usize {
+            return Err(Error::InvalidInput(format!(
+                "value_size {} != {}",
+                mem::size_of::<V>(),
+                self.value_size()
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Deletes an element from the map.
     ///
     /// `key` must have exactly [`Map::key_size()`] elements.
-    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
         if key.len() != self.key_size() as usize {
             return Err(Error::InvalidInput(format!(
                 "key_size {} != {}",
@@ -240,7 +665,7 @@ impl Map {
     /// and [`MapType::Stack`].
     ///
     /// `key` must have exactly [`Map::key_size()`] elements.
-    pub fn lookup_and_delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    pub fn lookup_and_delete(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         if key.len() != self.key_size() as usize {
             return Err(Error::InvalidInput(format!(
                 "key_size {} != {}",
@@ -274,11 +699,124 @@ impl Map {
         }
     }
 
+    /// Looks up and deletes every element of the map, `batch_size` elements per syscall.
+    ///
+    /// Returns the removed `(key, value)` pairs. Unlike iterating with [`Map::keys()`] and
+    /// calling [`Map::lookup_and_delete()`], no element can be updated between being read and
+    /// being deleted, so hash maps can be safely drained while BPF programs keep adding to them.
+    ///
+    /// Requires kernel support for batch operations on this map type, eg. [`MapType::Hash`].
+    /// Per-cpu maps are not supported and fail with `InvalidInput`.
+    pub fn lookup_and_delete_batch(
+        &self,
+        batch_size: u32,
+        elem_flags: MapFlags,
+        flags: MapFlags,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if batch_size == 0 {
+            return Err(Error::InvalidInput(
+                "batch_size must be non-zero".to_string(),
+            ));
+        }
+        // The kernel would write a value for each possible CPU, overflowing `values`
+        match self.map_type() {
+            MapType::PercpuHash
+            | MapType::PercpuArray
+            | MapType::LruPercpuHash
+            | MapType::PercpuCgroupStorage => {
+                return Err(Error::InvalidInput(format!(
+                    "map {} is a {}, per-cpu maps are not supported",
+                    self.name(),
+                    self.map_type()
+                )));
+            }
+            _ => (),
+        }
+
+        let key_size = self.key_size() as usize;
+        let value_size = self.value_size() as usize;
+        let mut keys = vec![0; key_size * batch_size as usize];
+        let mut values = vec![0; value_size * batch_size as usize];
+
+        // The batch position token is opaque. Hash maps use a u32 bucket index, other maps use a
+        // key, so make it large enough for either.
+        let token_size = cmp::max(key_size, mem::size_of::<u64>());
+        let mut in_batch: Vec<u8> = vec![0; token_size];
+        let mut out_batch: Vec<u8> = vec![0; token_size];
+
+        let opts = libbpf_sys::bpf_map_batch_opts {
+            sz: mem::size_of::<libbpf_sys::bpf_map_batch_opts>() as libbpf_sys::size_t,
+            elem_flags: elem_flags.bits,
+            flags: flags.bits,
+        };
+
+        let mut out = Vec::new();
+        let mut first = true;
+        loop {
+            let mut count = batch_size;
+            let ret = unsafe {
+                libbpf_sys::bpf_map_lookup_and_delete_batch(
+                    self.fd as i32,
+                    if first {
+                        ptr::null_mut()
+                    } else {
+                        in_batch.as_mut_ptr() as *mut c_void
+                    },
+                    out_batch.as_mut_ptr() as *mut c_void,
+                    keys.as_mut_ptr() as *mut c_void,
+                    values.as_mut_ptr() as *mut c_void,
+                    &mut count,
+                    &opts,
+                )
+            };
+
+            // ENOENT means the end of the map was reached, possibly with a final partial batch
+            let done = if ret == 0 {
+                false
+            } else {
+                let errno = errno::errno();
+                if errno::Errno::from_i32(errno) == errno::Errno::ENOENT {
+                    true
+                } else {
+                    return Err(Error::System(errno));
+                }
+            };
+
+            for i in 0..count as usize {
+                out.push((
+                    keys[i * key_size..(i + 1) * key_size].to_vec(),
+                    values[i * value_size..(i + 1) * value_size].to_vec(),
+                ));
+            }
+
+            if done {
+                return Ok(out);
+            }
+
+            in_batch.copy_from_slice(&out_batch);
+            first = false;
+        }
+    }
+
+    /// Freeze the map, making it read-only from userspace.
+    ///
+    /// BPF programs may still update the map. Freezing is permanent and, for maps with
+    /// `BPF_F_RDONLY_PROG`, lets the verifier treat the contents as constant.
+    pub fn freeze(&self) -> Result<()> {
+        let ret = unsafe { libbpf_sys::bpf_map_freeze(self.fd as i32) };
+
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(Error::System(errno::errno()))
+        }
+    }
+
     /// Update an element.
     ///
     /// `key` must have exactly [`Map::key_size()`] elements. `value` must have exatly
     /// [`Map::value_size()`] elements.
-    pub fn update(&mut self, key: &[u8], value: &[u8], flags: MapFlags) -> Result<()> {
+    pub fn update(&self, key: &[u8], value: &[u8], flags: MapFlags) -> Result<()> {
         if key.len() != self.key_size() as usize {
             return Err(Error::InvalidInput(format!(
                 "key_size {} != {}",
@@ -311,6 +849,32 @@ impl Map {
         }
     }
 
+    /// Same as [`Map::update()`], but with a typed key and value.
+    ///
+    /// `K` and `V` must be exactly [`Map::key_size()`] and [`Map::value_size()`] bytes large.
+    pub fn update_typed<K: Plain, V: Plain>(
+        &self,
+        key: &K,
+        value: &V,
+        flags: MapFlags,
+    ) -> Result<()> {
+        let (key, value) = unsafe { (plain::as_bytes(key), plain::as_bytes(value)) };
+        self.update(key, value, flags)
+    }
+
+    /// Same as [`Map::update_typed()`], but holds the `struct bpf_spin_lock` in the value while
+    /// copying it in. See [`Map::lookup_locked()`].
+    ///
+    /// The lock in `value` is ignored. [`MapFlags::LOCK`] is added to `flags`.
+    pub fn update_locked<K: Plain, V: Plain>(
+        &self,
+        key: &K,
+        value: &V,
+        flags: MapFlags,
+    ) -> Result<()> {
+        self.update_typed(key, value, flags | MapFlags::LOCK)
+    }
+
     /// Returns an iterator over keys in this map
     ///
     /// Note that if the map is not stable (stable meaning no updates or deletes) during iteration,
@@ -319,81 +883,1077 @@ impl Map {
     pub fn keys(&self) -> MapKeyIter {
         MapKeyIter::new(self, self.key_size())
     }
-}
 
-#[rustfmt::skip]
-bitflags! {
-    /// Flags to configure [`Map`] operations.
-    pub struct MapFlags: u64 {
-	const ANY      = 0;
-	const NO_EXIST = 1;
-	const EXIST    = 1 << 1;
-	const LOCK     = 1 << 2;
+    /// Returns the instruction pointers of stack `stack_id` in a [`MapType::StackTrace`] map,
+    /// innermost frame first.
+    ///
+    /// `stack_id` is what `bpf_get_stackid()` returned in the BPF program. See
+    /// [`symbolize`](crate::symbolize) to turn the addresses into symbols.
+    pub fn lookup_stack(&self, stack_id: u32) -> Result<Option<Vec<u64>>> {
+        if self.map_type() != MapType::StackTrace {
+            return Err(Error::InvalidInput(format!(
+                "map {} is a {}, not a StackTrace",
+                self.name(),
+                self.map_type()
+            )));
+        }
+
+        let value = match self.lookup(&stack_id.to_ne_bytes(), MapFlags::empty())? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        // Stacks shallower than the map's max depth are zero terminated
+        Ok(Some(
+            value
+                .chunks_exact(mem::size_of::<u64>())
+                .map(|ip| u64::from_ne_bytes(ip.try_into().unwrap()))
+                .take_while(|ip| *ip != 0)
+                .collect(),
+        ))
     }
-}
 
-/// Type of a [`Map`]. Maps to `enum bpf_map_type` in kernel uapi.
-#[non_exhaustive]
-#[repr(u32)]
-#[derive(Clone, TryFromPrimitive, PartialEq, Display)]
-pub enum MapType {
-    Unspec = 0,
-    Hash,
-    Array,
-    ProgArray,
-    PerfEventArray,
-    PercpuHash,
-    PercpuArray,
-    StackTrace,
-    CgroupArray,
-    LruHash,
-    LruPercpuHash,
-    LpmTrie,
-    ArrayOfMaps,
-    HashOfMaps,
-    Devmap,
-    Sockmap,
-    Cpumap,
-    Xskmap,
-    Sockhash,
-    CgroupStorage,
-    ReuseportSockarray,
-    PercpuCgroupStorage,
-    Queue,
-    Stack,
-    SkStorage,
-    DevmapHash,
-    StructOps,
-    RingBuf,
-    /// We choose to specify our own "unknown" type here b/c it's really up to the kernel
-    /// to decide if it wants to reject the map. If it accepts it, it just means whoever
-    /// using this library is a bit out of date.
-    Unknown = u32::MAX,
-}
+    /// Same as [`Map::lookup()`] for [`MapType::LpmTrie`] maps. Returns the value of the longest
+    /// prefix in the map that matches `key`.
+    pub fn lookup_lpm(&self, key: &LpmKey, flags: MapFlags) -> Result<Option<Vec<u8>>> {
+        self.check_lpm()?;
+        self.lookup(key.as_bytes(), flags)
+    }
 
-pub struct MapKeyIter<'a> {
-    map: &'a Map,
-    prev: Option<Vec<u8>>,
-    next: Vec<u8>,
-}
+    /// Same as [`Map::update()`] for [`MapType::LpmTrie`] maps.
+    pub fn update_lpm(&self, key: &LpmKey, value: &[u8], flags: MapFlags) -> Result<()> {
+        self.check_lpm()?;
+        self.update(key.as_bytes(), value, flags)
+    }
 
-impl<'a> MapKeyIter<'a> {
-    fn new(map: &'a Map, key_size: u32) -> Self {
-        Self {
-            map,
-            prev: None,
-            next: vec![0; key_size as usize],
+    /// Same as [`Map::delete()`] for [`MapType::LpmTrie`] maps. Only deletes an exact match of
+    /// `key`'s prefix.
+    pub fn delete_lpm(&self, key: &LpmKey) -> Result<()> {
+        self.check_lpm()?;
+        self.delete(key.as_bytes())
+    }
+
+    fn check_lpm(&self) -> Result<()> {
+        if self.map_type() != MapType::LpmTrie {
+            return Err(Error::InvalidInput(format!(
+                "map {} is a {}, not an LpmTrie",
+                self.name(),
+                self.map_type()
+            )));
         }
+
+        Ok(())
     }
-}
 
-impl<'a> Iterator for MapKeyIter<'a> {
-    type Item = Vec<u8>;
+    /// Same as [`Map::lookup()`], but formats the value according to the map's BTF value type,
+    /// the way `bpftool map dump` does. See [`Btf::format_value()`](crate::btf::Btf::format_value).
+    ///
+    /// Fails if the map was created without BTF.
+    pub fn lookup_pretty(&self, key: &[u8], flags: MapFlags) -> Result<Option<String>> {
+        let info = self.info()?;
+        if info.btf_id == 0 || info.btf_value_type_id == 0 {
+            return Err(Error::InvalidInput(format!(
+                "map {} has no BTF value type",
+                self.name()
+            )));
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let prev = self.prev.as_ref().map_or(ptr::null(), |p| p.as_ptr());
+        let value = match self.lookup(key, flags)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
 
-        let ret = unsafe {
+        let btf = Btf::from_id(info.btf_id)?;
+        Ok(Some(btf.format_value(info.btf_value_type_id, &value)?))
+    }
+
+    pub(crate) fn info(&self) -> Result<libbpf_sys::bpf_map_info> {
+        // Padding must be zeroed too, the kernel rejects non-zero trailing bytes
+        let mut info: libbpf_sys::bpf_map_info = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libbpf_sys::bpf_map_info>() as u32;
+        let ret = unsafe {
+            libbpf_sys::bpf_obj_get_info_by_fd(
+                self.fd,
+                &mut info as *mut libbpf_sys::bpf_map_info as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        Ok(info)
+    }
+
+    /// Copy all key/value pairs out of the map, eg. to carry its state across a program upgrade
+    /// or attach it to a bug report. Enable the `serde` feature to serialize the snapshot.
+    ///
+    /// Only [`MapType::Hash`], [`MapType::LruHash`], [`MapType::Array`] and
+    /// [`MapType::LpmTrie`] maps are supported. The same caveats as [`Map::keys()`] apply if the
+    /// map is modified concurrently.
+    pub fn snapshot(&self) -> Result<MapSnapshot> {
+        self.check_snapshot()?;
+
+        let mut entries = Vec::new();
+        for key in self.keys() {
+            // Entries deleted since the key was returned are skipped
+            if let Some(value) = self.lookup(&key, MapFlags::empty())? {
+                entries.push((key, value));
+            }
+        }
+
+        Ok(self.make_snapshot(entries))
+    }
+
+    /// Like [`Map::snapshot()`], but copies the entries out with a built-in map element iterator
+    /// in a single pass in the kernel, rather than a syscall per key. Each hash bucket is read
+    /// under its lock, so large maps modified concurrently come out much more consistent, with
+    /// no entries skipped or returned twice because others were deleted meanwhile.
+    ///
+    /// Falls back to [`Map::snapshot()`] if the iterator can't be used: before Linux 5.9,
+    /// without kernel BTF, or for [`MapType::LpmTrie`] maps.
+    pub fn dump_via_iter(&self) -> Result<MapSnapshot> {
+        self.check_snapshot()?;
+
+        match self.iter_entries() {
+            Ok(entries) => Ok(self.make_snapshot(entries)),
+            Err(_) => self.snapshot(),
+        }
+    }
+
+    /// Copy all key/value pairs out with the program of [`map_iter_insns()`]
+    fn iter_entries(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // libbpf looks up `bpf_iter_bpf_map_elem`, which describes the iterator's context
+        let target = util::str_to_cstring("bpf_map_elem")?;
+        let btf_id = unsafe {
+            libbpf_sys::libbpf_find_vmlinux_btf_id(target.as_ptr(), libbpf_sys::BPF_TRACE_ITER)
+        };
+        if btf_id < 0 {
+            // Error code is returned negative, flip to positive to match errno
+            return Err(Error::System(-btf_id));
+        }
+
+        // bpf_seq_write() is GPL only
+        let license = util::str_to_cstring("GPL")?;
+        let insns = map_iter_insns(self.key_size, self.value_size);
+        let mut attr: libbpf_sys::bpf_load_program_attr = unsafe { mem::zeroed() };
+        attr.prog_type = libbpf_sys::BPF_PROG_TYPE_TRACING;
+        attr.expected_attach_type = libbpf_sys::BPF_TRACE_ITER;
+        attr.insns = insns.as_ptr();
+        attr.insns_cnt = insns.len() as _;
+        attr.license = license.as_ptr();
+        attr.__bindgen_anon_2.attach_btf_id = btf_id as u32;
+        let prog_fd = unsafe { libbpf_sys::bpf_load_program_xattr(&attr, ptr::null_mut(), 0) };
+        if prog_fd < 0 {
+            return Err(Error::System(errno::errno()));
+        }
+        let prog_fd = unsafe { OwnedFd::from_raw_fd(prog_fd) };
+
+        let mut iter_info: libbpf_sys::bpf_iter_link_info = unsafe { mem::zeroed() };
+        iter_info.map.map_fd = self.fd as u32;
+        let mut opts: libbpf_sys::bpf_link_create_opts = unsafe { mem::zeroed() };
+        opts.sz = mem::size_of::<libbpf_sys::bpf_link_create_opts>() as _;
+        opts.iter_info = &mut iter_info;
+        opts.iter_info_len = mem::size_of::<libbpf_sys::bpf_iter_link_info>() as u32;
+        let link_fd = unsafe {
+            libbpf_sys::bpf_link_create(prog_fd.as_raw_fd(), 0, libbpf_sys::BPF_TRACE_ITER, &opts)
+        };
+        if link_fd < 0 {
+            return Err(Error::System(errno::errno()));
+        }
+        let link_fd = unsafe { OwnedFd::from_raw_fd(link_fd) };
+
+        let iter_fd = unsafe { libbpf_sys::bpf_iter_create(link_fd.as_raw_fd()) };
+        if iter_fd < 0 {
+            return Err(Error::System(errno::errno()));
+        }
+        let mut iter = File::from(unsafe { OwnedFd::from_raw_fd(iter_fd) });
+
+        let mut data = Vec::new();
+        iter.read_to_end(&mut data)
+            .map_err(|e| Error::System(e.raw_os_error().unwrap_or(libc::EIO)))?;
+
+        let key_size = self.key_size as usize;
+        Ok(data
+            .chunks_exact(key_size + self.value_size as usize)
+            .map(|entry| (entry[..key_size].to_vec(), entry[key_size..].to_vec()))
+            .collect())
+    }
+
+    fn make_snapshot(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> MapSnapshot {
+        MapSnapshot {
+            name: self.name.clone(),
+            ty: self.ty,
+            key_size: self.key_size,
+            value_size: self.value_size,
+            entries,
+        }
+    }
+
+    /// Write all key/value pairs of `snapshot` into the map, overwriting existing values.
+    ///
+    /// Keys present in the map but not in `snapshot` are left alone. The map must be of the same
+    /// type and have the same key and value sizes as the map the snapshot was taken from.
+    pub fn restore(&self, snapshot: &MapSnapshot) -> Result<()> {
+        self.check_snapshot()?;
+
+        if snapshot.ty != self.ty
+            || snapshot.key_size != self.key_size
+            || snapshot.value_size != self.value_size
+        {
+            return Err(Error::InvalidInput(format!(
+                "snapshot of {} {} ({}/{} byte key/value) does not match map {} {} ({}/{})",
+                snapshot.map_type(),
+                snapshot.name,
+                snapshot.key_size,
+                snapshot.value_size,
+                self.map_type(),
+                self.name(),
+                self.key_size,
+                self.value_size
+            )));
+        }
+
+        for (key, value) in &snapshot.entries {
+            self.update(key, value, MapFlags::ANY)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_snapshot(&self) -> Result<()> {
+        match self.map_type() {
+            MapType::Hash | MapType::LruHash | MapType::Array | MapType::LpmTrie => Ok(()),
+            ty => Err(Error::InvalidInput(format!(
+                "snapshots of {} map {} are not supported",
+                ty,
+                self.name()
+            ))),
+        }
+    }
+
+    /// Returns the current consumer and producer positions of a [`MapType::RingBuf`] map, eg. to
+    /// monitor how far behind userspace falls.
+    pub fn ringbuf_positions(&self) -> Result<RingBufPositions> {
+        if self.map_type() != MapType::RingBuf {
+            return Err(Error::InvalidInput(format!(
+                "map {} is a {}, not a RingBuf",
+                self.name(),
+                self.map_type()
+            )));
+        }
+
+        // The consumer position is on the first page of the map, the producer position on the
+        // second one. Both are readable by anyone holding the fd.
+        let page_size = page_size()? as usize;
+        let ptr = unsafe {
+            mman::mmap(
+                ptr::null_mut(),
+                2 * page_size,
+                mman::ProtFlags::PROT_READ,
+                mman::MapFlags::MAP_SHARED,
+                self.fd,
+                0,
+            )
+        }
+        .map_err(|_| Error::System(errno::errno()))?;
+
+        // The kernel updates both positions concurrently
+        let positions = unsafe {
+            RingBufPositions {
+                consumer_pos: (*(ptr as *const AtomicU64)).load(Ordering::Acquire),
+                producer_pos: (*((ptr as usize + page_size) as *const AtomicU64))
+                    .load(Ordering::Acquire),
+            }
+        };
+
+        // Ignore errors b/c can't really recover from failure
+        let _ = unsafe { mman::munmap(ptr, 2 * page_size) };
+
+        Ok(positions)
+    }
+
+    /// Map the memory of a [`MapType::Arena`] map into this process, to share data structures
+    /// with BPF programs. See [`Arena`].
+    ///
+    /// Arena maps need Linux 6.9. The whole arena, `max_entries` pages, is mapped. The kernel
+    /// fixes the arena's address in userspace when it is created with one in its `map_extra`,
+    /// or else when it is first mapped, and rejects mappings anywhere else. So the arena is
+    /// mapped at its `map_extra` address if it has one, or at the address this `Map` first
+    /// mapped it at. Only one [`Arena`] of a map can exist at a time, calling this again while
+    /// one is alive fails with `EEXIST`.
+    ///
+    /// The bundled libbpf can't create arena maps from objects, as it predates both
+    /// `map_extra` and `__arena` support, so the map has to come from elsewhere, eg. be pinned
+    /// by another loader.
+    pub fn arena(&self) -> Result<Arena> {
+        if self.map_type() != MapType::Arena {
+            return Err(Error::InvalidInput(format!(
+                "map {} is a {}, not an Arena",
+                self.name(),
+                self.map_type()
+            )));
+        }
+
+        let info = self.info()?;
+        let len = info.max_entries as usize * page_size()? as usize;
+        let addr = match self.map_extra()? {
+            0 => self.arena_addr.load(Ordering::Relaxed),
+            addr => addr as usize,
+        };
+        let flags = if addr == 0 {
+            libc::MAP_SHARED
+        } else {
+            // Unlike MAP_FIXED, fails rather than replacing whatever is mapped there already
+            libc::MAP_SHARED | libc::MAP_FIXED_NOREPLACE
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                addr as *mut libc::c_void,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                self.fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::System(errno::errno()));
+        }
+        // Kernels before 4.17 ignore MAP_FIXED_NOREPLACE and treat the address as a hint
+        if addr != 0 && ptr as usize != addr {
+            let _ = unsafe { libc::munmap(ptr, len) };
+            return Err(Error::System(libc::EEXIST));
+        }
+        if addr == 0 {
+            self.arena_addr.store(ptr as usize, Ordering::Relaxed);
+        }
+
+        Ok(Arena {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// Returns a view of this map exposing the keyless operations of [`MapType::Queue`] and
+    /// [`MapType::Stack`] maps.
+    ///
+    /// Fails if the map is of any other type.
+    pub fn as_queue(&self) -> Result<QueueMap> {
+        match self.map_type() {
+            MapType::Queue | MapType::Stack => Ok(QueueMap { map: self }),
+            ty => Err(Error::InvalidInput(format!(
+                "map {} is a {}, not a Queue or Stack",
+                self.name(),
+                ty
+            ))),
+        }
+    }
+
+    /// Returns a view of this map exposing only the operations a map created with
+    /// `BPF_F_RDONLY` allows from userspace, so writes fail to compile rather than with `EPERM`.
+    pub fn as_read_only(&mut self) -> ReadOnlyMap {
+        ReadOnlyMap { map: self }
+    }
+
+    /// Returns a view of this map exposing only the operations a map created with
+    /// `BPF_F_WRONLY` allows from userspace, so reads fail to compile rather than with `EPERM`.
+    pub fn as_write_only(&mut self) -> WriteOnlyMap {
+        WriteOnlyMap { map: self }
+    }
+
+    /// Returns a view of entry `index` of a [`MapType::PercpuArray`] map of `u32` or `u64`
+    /// counters, eg. to read statistics BPF programs increment on each CPU.
+    ///
+    /// Fails if the map is of any other type, or its values aren't 4 or 8 bytes large.
+    pub fn as_percpu_counter(&self, index: u32) -> Result<PerCpuCounter> {
+        if self.map_type() != MapType::PercpuArray {
+            return Err(Error::InvalidInput(format!(
+                "map {} is a {}, not a PercpuArray",
+                self.name(),
+                self.map_type()
+            )));
+        }
+        if self.value_size() != 4 && self.value_size() != 8 {
+            return Err(Error::InvalidInput(format!(
+                "map {} values are {} bytes, not a u32 or u64",
+                self.name(),
+                self.value_size()
+            )));
+        }
+
+        Ok(PerCpuCounter {
+            map: self,
+            index,
+            ncpus: util::num_possible_cpus()?,
+        })
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        // Maps owned by an object are closed by libbpf
+        if self.ptr.is_null() {
+            let _ = unistd::close(self.fd);
+        }
+    }
+}
+
+// Methods taking `&self` only issue syscalls on `fd`, read fields that are immutable after
+// construction, or read libbpf state behind `ptr`. Methods changing that state require
+// `&mut self`.
+unsafe impl Send for Map {}
+unsafe impl Sync for Map {}
+
+/// Use [`BorrowedFd::try_clone_to_owned`] to get an independently owned copy of the fd, eg. to
+/// send to another process.
+impl AsFd for Map {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The fd is owned by libbpf and stays open for as long as `self` lives
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+#[rustfmt::skip]
+bitflags! {
+    /// Flags to configure [`Map`] operations.
+    ///
+    /// `LOCK` makes lookups and updates hold the `struct bpf_spin_lock` in the value, see
+    /// [`Map::lookup_locked()`].
+    pub struct MapFlags: u64 {
+	const ANY      = 0;
+	const NO_EXIST = 1;
+	const EXIST    = 1 << 1;
+	const LOCK     = 1 << 2;
+    }
+}
+
+/// Snapshot of the positions in a [`MapType::RingBuf`] map, in bytes since its creation.
+///
+/// See [`Map::ringbuf_positions()`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RingBufPositions {
+    /// Position up to which userspace consumed samples.
+    pub consumer_pos: u64,
+    /// Position up to which programs reserved samples.
+    pub producer_pos: u64,
+}
+
+impl RingBufPositions {
+    /// Bytes reserved by programs but not consumed yet, samples headers included.
+    pub fn avail_data(&self) -> u64 {
+        self.producer_pos.wrapping_sub(self.consumer_pos)
+    }
+}
+
+/// Type of a [`Map`]. Maps to `enum bpf_map_type` in kernel uapi.
+#[non_exhaustive]
+#[repr(u32)]
+#[derive(Clone, TryFromPrimitive, PartialEq, Display)]
+pub enum MapType {
+    Unspec = 0,
+    Hash,
+    Array,
+    ProgArray,
+    PerfEventArray,
+    PercpuHash,
+    PercpuArray,
+    StackTrace,
+    CgroupArray,
+    LruHash,
+    LruPercpuHash,
+    LpmTrie,
+    ArrayOfMaps,
+    HashOfMaps,
+    Devmap,
+    Sockmap,
+    Cpumap,
+    Xskmap,
+    Sockhash,
+    CgroupStorage,
+    ReuseportSockarray,
+    PercpuCgroupStorage,
+    Queue,
+    Stack,
+    SkStorage,
+    DevmapHash,
+    StructOps,
+    RingBuf,
+    InodeStorage,
+    TaskStorage,
+    BloomFilter,
+    UserRingBuf,
+    CgrpStorage,
+    Arena,
+    /// We choose to specify our own "unknown" type here b/c it's really up to the kernel
+    /// to decide if it wants to reject the map. If it accepts it, it just means whoever
+    /// using this library is a bit out of date.
+    Unknown = u32::MAX,
+}
+
+/// Contents of a [`Map`] as returned by [`Map::snapshot()`].
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapSnapshot {
+    name: String,
+    ty: libbpf_sys::bpf_map_type,
+    key_size: u32,
+    value_size: u32,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl MapSnapshot {
+    /// Name of the map the snapshot was taken from
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn map_type(&self) -> MapType {
+        match MapType::try_from(self.ty) {
+            Ok(t) => t,
+            Err(_) => MapType::Unknown,
+        }
+    }
+
+    /// Key size in bytes
+    pub fn key_size(&self) -> u32 {
+        self.key_size
+    }
+
+    /// Value size in bytes
+    pub fn value_size(&self) -> u32 {
+        self.value_size
+    }
+
+    /// Key/value pairs, in the order the map returned them
+    pub fn entries(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.entries
+    }
+}
+
+/// Key of a [`MapType::LpmTrie`] map.
+///
+/// Matches the kernel's `struct bpf_lpm_trie_key`: a prefix length in host byte order followed
+/// by the data bytes, most significant first. For IP addresses that means network byte order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LpmKey {
+    bytes: Vec<u8>,
+}
+
+impl LpmKey {
+    /// Create a key matching the first `prefix_len` bits of `addr`.
+    ///
+    /// IPv4 addresses produce 8 byte keys and IPv6 addresses 20 byte keys, so the map's key
+    /// size determines which address family it holds.
+    pub fn new(addr: IpAddr, prefix_len: u32) -> Result<Self> {
+        match addr {
+            IpAddr::V4(a) => Self::from_data(prefix_len, &a.octets()),
+            IpAddr::V6(a) => Self::from_data(prefix_len, &a.octets()),
+        }
+    }
+
+    /// Create a key matching the first `prefix_len` bits of arbitrary `data`.
+    pub fn from_data(prefix_len: u32, data: &[u8]) -> Result<Self> {
+        if prefix_len as usize > data.len() * 8 {
+            return Err(Error::InvalidInput(format!(
+                "prefix_len {} exceeds {} bits of data",
+                prefix_len,
+                data.len() * 8
+            )));
+        }
+
+        let mut bytes = Vec::with_capacity(mem::size_of::<u32>() + data.len());
+        bytes.extend_from_slice(&prefix_len.to_ne_bytes());
+        bytes.extend_from_slice(data);
+
+        Ok(LpmKey { bytes })
+    }
+
+    /// Number of leading bits of the data that are matched.
+    pub fn prefix_len(&self) -> u32 {
+        let mut prefix_len = [0; mem::size_of::<u32>()];
+        prefix_len.copy_from_slice(&self.bytes[..mem::size_of::<u32>()]);
+        u32::from_ne_bytes(prefix_len)
+    }
+
+    /// Key data, without the prefix length.
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[mem::size_of::<u32>()..]
+    }
+
+    /// Raw key bytes, as expected by [`Map::lookup()`] and friends.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// A [`MapType::Queue`] or [`MapType::Stack`] map.
+///
+/// Elements of these maps have no key. Queues pop elements in FIFO order, stacks in LIFO order.
+/// Create one with [`Map::as_queue()`].
+pub struct QueueMap<'a> {
+    map: &'a Map,
+}
+
+impl<'a> QueueMap<'a> {
+    /// Push an element.
+    ///
+    /// `value` must have exactly [`Map::value_size()`] elements. If the map is full, this fails
+    /// unless `flags` contains [`MapFlags::EXIST`], in which case the oldest element is dropped
+    /// to make room.
+    pub fn push(&self, value: &[u8], flags: MapFlags) -> Result<()> {
+        if value.len() != self.map.value_size() as usize {
+            return Err(Error::InvalidInput(format!(
+                "value_size {} != {}",
+                value.len(),
+                self.map.value_size()
+            )));
+        };
+
+        let ret = unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.map.fd(),
+                ptr::null(),
+                value.as_ptr() as *const c_void,
+                flags.bits,
+            )
+        };
+
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(Error::System(errno::errno()))
+        }
+    }
+
+    /// Remove and return the next element, or `None` if the map is empty.
+    pub fn pop(&self) -> Result<Option<Vec<u8>>> {
+        self.lookup(true)
+    }
+
+    /// Return the next element without removing it, or `None` if the map is empty.
+    pub fn peek(&self) -> Result<Option<Vec<u8>>> {
+        self.lookup(false)
+    }
+
+    fn lookup(&self, delete: bool) -> Result<Option<Vec<u8>>> {
+        let mut out: Vec<u8> = Vec::with_capacity(self.map.value_size() as usize);
+
+        let ret = unsafe {
+            if delete {
+                libbpf_sys::bpf_map_lookup_and_delete_elem(
+                    self.map.fd(),
+                    ptr::null(),
+                    out.as_mut_ptr() as *mut c_void,
+                )
+            } else {
+                libbpf_sys::bpf_map_lookup_elem(
+                    self.map.fd(),
+                    ptr::null(),
+                    out.as_mut_ptr() as *mut c_void,
+                )
+            }
+        };
+
+        if ret == 0 {
+            unsafe {
+                out.set_len(self.map.value_size() as usize);
+            }
+            Ok(Some(out))
+        } else {
+            let errno = errno::errno();
+            if errno::Errno::from_i32(errno) == errno::Errno::ENOENT {
+                Ok(None)
+            } else {
+                Err(Error::System(errno))
+            }
+        }
+    }
+}
+
+/// A map userspace may only read, eg. one created with `BPF_F_RDONLY` to publish state from BPF
+/// programs. Create one with [`Map::as_read_only()`].
+pub struct ReadOnlyMap<'a> {
+    map: &'a mut Map,
+}
+
+impl<'a> ReadOnlyMap<'a> {
+    /// The underlying map, eg. to pass it to [`OpenMap::reuse_map()`].
+    pub fn as_map(&self) -> &Map {
+        self.map
+    }
+
+    /// See [`Map::fd()`].
+    pub fn fd(&self) -> i32 {
+        self.map.fd()
+    }
+
+    /// See [`Map::pin()`].
+    pub fn pin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.map.pin(path)
+    }
+
+    /// See [`Map::unpin()`].
+    pub fn unpin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.map.unpin(path)
+    }
+
+    /// See [`Map::name()`].
+    pub fn name(&self) -> &str {
+        self.map.name()
+    }
+
+    /// See [`Map::key_size()`].
+    pub fn key_size(&self) -> u32 {
+        self.map.key_size()
+    }
+
+    /// See [`Map::value_size()`].
+    pub fn value_size(&self) -> u32 {
+        self.map.value_size()
+    }
+
+    /// See [`Map::lookup()`].
+    pub fn lookup(&self, key: &[u8], flags: MapFlags) -> Result<Option<Vec<u8>>> {
+        self.map.lookup(key, flags)
+    }
+
+    /// See [`Map::lookup_typed()`].
+    pub fn lookup_typed<K: Plain, V: Plain>(&self, key: &K, flags: MapFlags) -> Result<Option<V>> {
+        self.map.lookup_typed(key, flags)
+    }
+
+    /// See [`Map::keys()`].
+    pub fn keys(&self) -> MapKeyIter {
+        self.map.keys()
+    }
+
+    /// See [`Map::snapshot()`].
+    pub fn snapshot(&self) -> Result<MapSnapshot> {
+        self.map.snapshot()
+    }
+}
+
+/// A map userspace may only write, eg. one created with `BPF_F_WRONLY` to pass configuration to
+/// BPF programs. Create one with [`Map::as_write_only()`].
+///
+/// Iterating over the keys of such maps takes reading them, so only known keys can be deleted.
+pub struct WriteOnlyMap<'a> {
+    map: &'a mut Map,
+}
+
+impl<'a> WriteOnlyMap<'a> {
+    /// The underlying map, eg. to pass it to [`OpenMap::reuse_map()`].
+    pub fn as_map(&self) -> &Map {
+        self.map
+    }
+
+    /// See [`Map::fd()`].
+    pub fn fd(&self) -> i32 {
+        self.map.fd()
+    }
+
+    /// See [`Map::pin()`].
+    pub fn pin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.map.pin(path)
+    }
+
+    /// See [`Map::unpin()`].
+    pub fn unpin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.map.unpin(path)
+    }
+
+    /// See [`Map::name()`].
+    pub fn name(&self) -> &str {
+        self.map.name()
+    }
+
+    /// See [`Map::key_size()`].
+    pub fn key_size(&self) -> u32 {
+        self.map.key_size()
+    }
+
+    /// See [`Map::value_size()`].
+    pub fn value_size(&self) -> u32 {
+        self.map.value_size()
+    }
+
+    /// See [`Map::update()`].
+    pub fn update(&self, key: &[u8], value: &[u8], flags: MapFlags) -> Result<()> {
+        self.map.update(key, value, flags)
+    }
+
+    /// See [`Map::update_typed()`].
+    pub fn update_typed<K: Plain, V: Plain>(
+        &self,
+        key: &K,
+        value: &V,
+        flags: MapFlags,
+    ) -> Result<()> {
+        self.map.update_typed(key, value, flags)
+    }
+
+    /// See [`Map::delete()`].
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.map.delete(key)
+    }
+}
+
+impl AsFd for ReadOnlyMap<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.map.as_fd()
+    }
+}
+
+impl AsFd for WriteOnlyMap<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.map.as_fd()
+    }
+}
+
+/// The memory of a [`MapType::Arena`] map, shared with BPF programs. Create one with
+/// [`Map::arena()`].
+///
+/// BPF programs may modify the arena at any time. [`Arena::read()`] and [`Arena::write()`] copy
+/// bytes in and out, which is always safe. Slices and typed views of the arena are `unsafe`, as
+/// the caller must make sure BPF programs don't write to what they reference, or otherwise
+/// synchronize with them, eg. through atomics in the arena.
+///
+/// Pointers BPF programs store in the arena are valid in this process too, as the kernel places
+/// the arena at the same address for both. The mapping stays valid after the map is dropped, until
+/// the `Arena` is.
+pub struct Arena {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Arena {
+    /// Size of the arena in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Address of the start of the arena.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Offset into the arena of `addr`, eg. a pointer read from a data structure built by a BPF
+    /// program. `None` if `addr` is outside of the arena.
+    pub fn offset_of(&self, addr: u64) -> Option<usize> {
+        let offset = addr.checked_sub(self.ptr as u64)? as usize;
+        if offset < self.len {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(Error::InvalidInput(format!(
+                "{} bytes at offset {} are out of the arena's {} bytes",
+                len, offset, self.len
+            ))),
+        }
+    }
+
+    /// Copy `buf.len()` bytes at `offset` of the arena into `buf`.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.check_range(offset, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(self.ptr.add(offset), buf.as_mut_ptr(), buf.len()) };
+
+        Ok(())
+    }
+
+    /// Copy `data` into the arena at `offset`.
+    ///
+    /// Takes `&mut self` so no slice or view returned by the other methods can alias the bytes
+    /// written.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.check_range(offset, data.len())?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(offset), data.len()) };
+
+        Ok(())
+    }
+
+    /// Returns the whole arena as a slice.
+    ///
+    /// # Safety
+    ///
+    /// BPF programs must not write to the arena while the slice is alive.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        slice::from_raw_parts(self.ptr, self.len)
+    }
+
+    /// Returns the whole arena as a mutable slice.
+    ///
+    /// # Safety
+    ///
+    /// BPF programs must not access the arena while the slice is alive.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        slice::from_raw_parts_mut(self.ptr, self.len)
+    }
+
+    /// Returns the `T` at `offset` of the arena, eg. the root of a data structure BPF programs
+    /// built. Fails if it is out of the arena or misaligned.
+    ///
+    /// # Safety
+    ///
+    /// BPF programs must not write to the `T` while the reference is alive, unless `T` only
+    /// synchronizes through atomics.
+    pub unsafe fn view<T: Plain>(&self, offset: usize) -> Result<&T> {
+        Ok(&*self.typed_ptr(offset)?)
+    }
+
+    /// Returns the `T` at `offset` of the arena mutably, eg. to set up a data structure before
+    /// attaching the BPF programs that use it. Fails if it is out of the arena or misaligned.
+    ///
+    /// # Safety
+    ///
+    /// BPF programs must not access the `T` while the reference is alive.
+    pub unsafe fn view_mut<T: Plain>(&mut self, offset: usize) -> Result<&mut T> {
+        Ok(&mut *self.typed_ptr(offset)?)
+    }
+
+    fn typed_ptr<T>(&self, offset: usize) -> Result<*mut T> {
+        self.check_range(offset, mem::size_of::<T>())?;
+
+        let ptr = unsafe { self.ptr.add(offset) };
+        if ptr as usize % mem::align_of::<T>() != 0 {
+            return Err(Error::InvalidInput(format!(
+                "offset {} is not aligned to {} bytes",
+                offset,
+                mem::align_of::<T>()
+            )));
+        }
+
+        Ok(ptr as *mut T)
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        // Ignore errors b/c can't really recover from failure
+        let _ = unsafe { mman::munmap(self.ptr as *mut c_void, self.len) };
+    }
+}
+
+// The arena is shared with BPF programs running on every CPU anyway, accesses through `&self`
+// either only read or are unsafe
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+/// A counter in a [`MapType::PercpuArray`] map, with a separate value for each CPU.
+///
+/// Values are kept for every possible CPU, including those that are offline or not plugged
+/// in, which keep the counts they had when they went offline. Create one with
+/// [`Map::as_percpu_counter()`].
+pub struct PerCpuCounter<'a> {
+    map: &'a Map,
+    index: u32,
+    /// Number of possible CPUs, which the kernel reads and writes a value for
+    ncpus: usize,
+}
+
+impl<'a> PerCpuCounter<'a> {
+    /// Returns the value of each possible CPU, indexed by CPU number.
+    pub fn per_cpu(&self) -> Result<Vec<u64>> {
+        // Each CPU's value is padded to 8 bytes
+        let mut values = vec![0u64; self.ncpus];
+        let ret = unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                self.map.fd(),
+                &self.index as *const u32 as *const c_void,
+                values.as_mut_ptr() as *mut c_void,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        // u32 values are in the low bytes of their slot
+        if self.map.value_size() == 4 {
+            for value in &mut values {
+                *value = u64::from(u32::from_ne_bytes(
+                    value.to_ne_bytes()[..4].try_into().unwrap(),
+                ));
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Returns the value of each online CPU, as `(cpu, value)`.
+    pub fn per_online_cpu(&self) -> Result<Vec<(u32, u64)>> {
+        let values = self.per_cpu()?;
+
+        Ok(util::online_cpus()?
+            .into_iter()
+            .filter_map(|cpu| values.get(cpu as usize).map(|v| (cpu, *v)))
+            .collect())
+    }
+
+    /// Returns the total of all CPUs' values, including offline CPUs. Wraps around on overflow,
+    /// like the counters themselves.
+    pub fn sum(&self) -> Result<u64> {
+        Ok(self
+            .per_cpu()?
+            .into_iter()
+            .fold(0u64, |sum, v| sum.wrapping_add(v)))
+    }
+
+    /// Set the values of all CPUs to 0.
+    ///
+    /// This is not atomic with respect to BPF programs, so increments made while resetting may
+    /// be lost.
+    pub fn reset(&self) -> Result<()> {
+        let values = vec![0u64; self.ncpus];
+        let ret = unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.map.fd(),
+                &self.index as *const u32 as *const c_void,
+                values.as_ptr() as *const c_void,
+                MapFlags::ANY.bits,
+            )
+        };
+
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(Error::System(errno::errno()))
+        }
+    }
+}
+
+pub struct MapKeyIter<'a> {
+    map: &'a Map,
+    prev: Option<Vec<u8>>,
+    next: Vec<u8>,
+}
+
+impl<'a> MapKeyIter<'a> {
+    fn new(map: &'a Map, key_size: u32) -> Self {
+        Self {
+            map,
+            prev: None,
+            next: vec![0; key_size as usize],
+        }
+    }
+}
+
+impl<'a> Iterator for MapKeyIter<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let prev = self.prev.as_ref().map_or(ptr::null(), |p| p.as_ptr());
+
+        let ret = unsafe {
             libbpf_sys::bpf_map_get_next_key(self.map.fd(), prev as _, self.next.as_mut_ptr() as _)
         };
         if ret != 0 {
@@ -404,3 +1964,18 @@ impl<'a> Iterator for MapKeyIter<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ringbuf_size() {
+        // Rounded up to a page
+        assert_eq!(ringbuf_size(1, 1, 4096), Some(4096));
+        // 1000 samples of 20 + 8 bytes, 8 byte aligned and rounded up to a power of two
+        assert_eq!(ringbuf_size(1000, 20, 4096), Some(32768));
+        assert_eq!(ringbuf_size(1 << 20, 1 << 20, 4096), None);
+        assert_eq!(ringbuf_size(u64::MAX, 1, 4096), None);
+    }
+}
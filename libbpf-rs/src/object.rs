@@ -1,20 +1,35 @@
 use core::ffi::c_void;
 use std::collections::HashMap;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::mem;
 use std::os::raw::c_char;
 use std::path::Path;
 use std::ptr;
+use std::slice;
+use std::time::Duration;
 
-use nix::errno;
+use nix::{errno, libc};
 
+use crate::btf::{Btf, BtfKind};
+use crate::btfhub;
+use crate::ksyms::Ksyms;
 use crate::util;
 use crate::*;
 
+// From `include/uapi/linux/btf.h`
+const BTF_KIND_DATASEC: u32 = 15;
+
 /// Builder for creating an [`OpenObject`]. Typically the entry point into libbpf-rs.
 pub struct ObjectBuilder {
     name: String,
     relaxed_maps: bool,
+    relaxed_core_relocs: bool,
+    pin_root_path: Option<CString>,
+    kconfig: Option<CString>,
+    btf_custom_path: Option<CString>,
+    log_level: i32,
+    bump_memlock_rlimit: bool,
+    section_handlers: Vec<(String, SectionHandler)>,
 }
 
 impl ObjectBuilder {
@@ -30,6 +45,81 @@ impl ObjectBuilder {
         self
     }
 
+    /// Option to skip CO-RE relocations that fail to resolve instead of failing the load.
+    pub fn relaxed_core_relocs(&mut self, relaxed_core_relocs: bool) -> &mut Self {
+        self.relaxed_core_relocs = relaxed_core_relocs;
+        self
+    }
+
+    /// Directory maps with `LIBBPF_PIN_BY_NAME` pinning are pinned under. Defaults to
+    /// `/sys/fs/bpf`.
+    pub fn pin_root_path<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        self.pin_root_path = Some(util::path_to_cstring(path)?);
+        Ok(self)
+    }
+
+    /// Path to a BTF file describing the target kernel to use for CO-RE relocations, instead
+    /// of the running kernel's BTF. Useful on kernels built without `CONFIG_DEBUG_INFO_BTF`.
+    ///
+    /// Only takes effect if the object is loaded with [`OpenObject::load`].
+    pub fn btf_custom_path<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        self.btf_custom_path = Some(util::path_to_cstring(path)?);
+        Ok(self)
+    }
+
+    /// Use BTF from a BTFHub style `archive` for CO-RE relocations if the running kernel does
+    /// not provide its own. See [`btfhub`] for the expected layout.
+    ///
+    /// Fails if the running kernel has no BTF and `archive` has none for it either.
+    ///
+    /// Only takes effect if the object is loaded with [`OpenObject::load`].
+    pub fn btfhub_archive<P: AsRef<Path>>(&mut self, archive: P) -> Result<&mut Self> {
+        if Path::new(btfhub::VMLINUX_BTF_PATH).exists() {
+            return Ok(self);
+        }
+
+        match btfhub::find_btf(archive.as_ref())? {
+            Some(path) => self.btf_custom_path(path),
+            None => Err(Error::InvalidInput(format!(
+                "no BTF for running kernel in {}",
+                archive.as_ref().display()
+            ))),
+        }
+    }
+
+    /// Verbosity of the kernel verifier log libbpf requests when loading programs. 0 (the
+    /// default) only requests a log if loading fails.
+    ///
+    /// Only takes effect if the object is loaded with [`OpenObject::load`].
+    pub fn kernel_log_level(&mut self, log_level: u32) -> &mut Self {
+        self.log_level = log_level as i32;
+        self
+    }
+
+    /// Override kconfig values the object's `__kconfig` externs are resolved from, instead of
+    /// reading the running kernel's config. `kconfig` is in the same format as a kernel
+    /// `.config` file, eg. `"CONFIG_HZ=100\nCONFIG_BPF_SYSCALL=y\n"`.
+    pub fn kconfig<T: AsRef<str>>(&mut self, kconfig: T) -> Result<&mut Self> {
+        self.kconfig = Some(util::str_to_cstring(kconfig.as_ref())?);
+        Ok(self)
+    }
+
+    /// Use `handler` for programs whose section name starts with `prefix`, eg. `"mytool/"`.
+    /// Overrides libbpf's own handling of the section. If several prefixes match, the longest
+    /// one wins.
+    ///
+    /// Only takes effect for objects opened with [`ObjectBuilder::open_file`] or
+    /// [`ObjectBuilder::open_memory`].
+    pub fn section_handler<T: AsRef<str>>(
+        &mut self,
+        prefix: T,
+        handler: SectionHandler,
+    ) -> &mut Self {
+        self.section_handlers
+            .push((prefix.as_ref().to_string(), handler));
+        self
+    }
+
     /// Option to print debug output to stderr.
     pub fn debug(&mut self, dbg: bool) -> &mut Self {
         extern "C" fn cb(
@@ -58,6 +148,51 @@ impl ObjectBuilder {
         self
     }
 
+    fn new_open_object(&self, ptr: *mut libbpf_sys::bpf_object) -> OpenObject {
+        let mut obj = OpenObject::new(ptr);
+        obj.btf_custom_path = self.btf_custom_path.clone();
+        obj.log_level = self.log_level;
+        obj.section_handlers = self.section_handlers.clone();
+
+        let mut prog = ptr::null_mut();
+        loop {
+            prog = unsafe { libbpf_sys::bpf_program__next(prog, ptr) };
+            if prog.is_null() {
+                break;
+            }
+            if let Some(handler) = find_section_handler(&self.section_handlers, prog) {
+                // Go through `prog()` so what the handler sets on the `OpenProgram` is kept
+                let name = util::c_ptr_to_string(unsafe { libbpf_sys::bpf_program__name(prog) });
+                if let Ok(Some(open_prog)) = name.and_then(|name| obj.prog(name)) {
+                    handler.apply(open_prog);
+                }
+            }
+        }
+
+        obj
+    }
+
+    /// Remove the process' `RLIMIT_MEMLOCK` limit when opening the object, if the running
+    /// kernel needs it. See [`set_memlock_rlimit()`]. Opening fails if the limit can't be
+    /// removed.
+    pub fn bump_memlock_rlimit(&mut self, bump: bool) -> &mut Self {
+        self.bump_memlock_rlimit = bump;
+        self
+    }
+
+    /// Remove the `RLIMIT_MEMLOCK` limit if asked to with
+    /// [`ObjectBuilder::bump_memlock_rlimit()`].
+    ///
+    /// Used for skeleton -- an end user may not consider this API stable
+    #[doc(hidden)]
+    pub fn apply_memlock_rlimit(&self) -> Result<()> {
+        if self.bump_memlock_rlimit && memlock_rlimit_needed() {
+            set_memlock_rlimit(u64::MAX)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Used for skeleton -- an end user may not consider this API stable
     #[doc(hidden)]
     pub fn opts(&mut self, name: *const c_char) -> libbpf_sys::bpf_object_open_opts {
@@ -65,10 +200,16 @@ impl ObjectBuilder {
             sz: mem::size_of::<libbpf_sys::bpf_object_open_opts>() as libbpf_sys::size_t,
             object_name: name,
             relaxed_maps: self.relaxed_maps,
-            relaxed_core_relocs: false,
-            pin_root_path: ptr::null(),
+            relaxed_core_relocs: self.relaxed_core_relocs,
+            pin_root_path: self
+                .pin_root_path
+                .as_ref()
+                .map_or(ptr::null(), |path| path.as_ptr()),
             attach_prog_fd: 0,
-            kconfig: ptr::null(),
+            kconfig: self
+                .kconfig
+                .as_ref()
+                .map_or(ptr::null(), |kconfig| kconfig.as_ptr()),
         }
     }
 
@@ -90,6 +231,7 @@ impl ObjectBuilder {
             ptr::null()
         };
 
+        self.apply_memlock_rlimit()?;
         let opts = self.opts(name_ptr);
 
         let obj = unsafe { libbpf_sys::bpf_object__open_file(path_ptr, &opts) };
@@ -98,7 +240,7 @@ impl ObjectBuilder {
             return Err(Error::System(err as i32));
         }
 
-        Ok(OpenObject::new(obj))
+        Ok(self.new_open_object(obj))
     }
 
     pub fn open_memory<T: AsRef<str>>(&mut self, name: T, mem: &[u8]) -> Result<OpenObject> {
@@ -112,6 +254,7 @@ impl ObjectBuilder {
             ptr::null()
         };
 
+        self.apply_memlock_rlimit()?;
         let opts = self.opts(name_ptr);
 
         let obj = unsafe {
@@ -126,7 +269,40 @@ impl ObjectBuilder {
             return Err(Error::System(err as i32));
         }
 
-        Ok(OpenObject::new(obj))
+        Ok(self.new_open_object(obj))
+    }
+
+    /// Open the object at `path` as a view over the maps another process loaded from it and
+    /// pinned under `pin_dir`, eg. with [`Object::pin_maps()`]. Useful for control tools that
+    /// adopt state created by another loader.
+    ///
+    /// Programs are not loaded. Internal maps (`.data`, `.rodata`, `.bss`, ...) are created
+    /// afresh from the object file. Fails if any other map is not pinned under `pin_dir`.
+    pub fn open_pinned<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        path: P,
+        pin_dir: Q,
+    ) -> Result<Object> {
+        let mut obj = self.open_file(path)?;
+
+        let reused = obj.reuse_pinned_maps(pin_dir.as_ref())?;
+        let missing: Vec<String> = map_names(obj.ptr, false)?
+            .into_iter()
+            .filter(|name| !reused.contains(name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::InvalidInput(format!(
+                "maps not pinned under {}: {}",
+                pin_dir.as_ref().display(),
+                missing.join(", ")
+            )));
+        }
+
+        for prog in obj.progs()? {
+            prog.set_autoload(false)?;
+        }
+
+        obj.load()
     }
 }
 
@@ -135,6 +311,13 @@ impl Default for ObjectBuilder {
         ObjectBuilder {
             name: String::new(),
             relaxed_maps: false,
+            relaxed_core_relocs: false,
+            pin_root_path: None,
+            kconfig: None,
+            btf_custom_path: None,
+            log_level: 0,
+            bump_memlock_rlimit: false,
+            section_handlers: Vec::new(),
         }
     }
 }
@@ -157,6 +340,168 @@ fn find_prog_in_object(
     Ok(util::ptr_to_option(ptr))
 }
 
+/// Returns the names of all maps in `obj`. Internal maps, ie. `.data`, `.bss` etc., are only
+/// included if `internal` is set.
+fn map_names(obj: *const libbpf_sys::bpf_object, internal: bool) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut map = ptr::null_mut();
+    loop {
+        map = unsafe { libbpf_sys::bpf_map__next(map, obj) };
+        if map.is_null() {
+            break;
+        }
+        if !internal && unsafe { libbpf_sys::bpf_map__is_internal(map) } {
+            continue;
+        }
+
+        names.push(util::c_ptr_to_string(unsafe {
+            libbpf_sys::bpf_map__name(map)
+        })?);
+    }
+
+    Ok(names)
+}
+
+/// Returns the names of all programs in `obj`.
+fn prog_names(obj: *const libbpf_sys::bpf_object) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut prog = ptr::null_mut();
+    loop {
+        prog = unsafe { libbpf_sys::bpf_program__next(prog, obj) };
+        if prog.is_null() {
+            break;
+        }
+
+        names.push(util::c_ptr_to_string(unsafe {
+            libbpf_sys::bpf_program__name(prog)
+        })?);
+    }
+
+    Ok(names)
+}
+
+/// Returns the values of `entries` named `names`, in that order.
+fn in_order<'a, T>(entries: &'a mut HashMap<String, T>, names: &[String]) -> Vec<&'a mut T> {
+    let mut by_name: HashMap<&str, &mut T> = entries
+        .iter_mut()
+        .map(|(name, entry)| (name.as_str(), entry))
+        .collect();
+
+    names
+        .iter()
+        .filter_map(|name| by_name.remove(name.as_str()))
+        .collect()
+}
+
+/// Section prefixes of programs attaching to a kernel function or tracepoint named by the rest
+/// of the section, along with the prefix and kind of the BTF type libbpf looks the name up as
+const BTF_ATTACH_SECTIONS: &[(&str, &str, BtfKind)] = &[
+    ("fentry/", "", BtfKind::Func),
+    ("fentry.s/", "", BtfKind::Func),
+    ("fexit/", "", BtfKind::Func),
+    ("fexit.s/", "", BtfKind::Func),
+    ("fmod_ret/", "", BtfKind::Func),
+    ("fmod_ret.s/", "", BtfKind::Func),
+    ("lsm/", "bpf_lsm_", BtfKind::Func),
+    ("lsm.s/", "bpf_lsm_", BtfKind::Func),
+    ("tp_btf/", "btf_trace_", BtfKind::Typedef),
+];
+
+/// Returns the handler with the longest prefix matching the section of `prog`, if any.
+fn find_section_handler(
+    handlers: &[(String, SectionHandler)],
+    prog: *const libbpf_sys::bpf_program,
+) -> Option<&SectionHandler> {
+    if handlers.is_empty() {
+        return None;
+    }
+
+    let title = unsafe { libbpf_sys::bpf_program__title(prog, false) };
+    let err = unsafe { libbpf_sys::libbpf_get_error(title as *const _) };
+    if err != 0 {
+        return None;
+    }
+    let section = unsafe { CStr::from_ptr(title) }.to_bytes();
+
+    handlers
+        .iter()
+        .filter(|(prefix, _)| section.starts_with(prefix.as_bytes()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, handler)| handler)
+}
+
+/// Selects programs by name for [`Object::attach_all()`], with shell style globs where `*`
+/// matches any run of characters and `?` any single one.
+///
+/// A program is selected if it matches any `include` pattern, or if there are none, and no
+/// `exclude` pattern.
+#[derive(Clone, Debug, Default)]
+pub struct AttachFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl AttachFilter {
+    /// Only select programs matching `pattern`, or any other included pattern.
+    pub fn include<T: AsRef<str>>(&mut self, pattern: T) -> &mut Self {
+        self.include.push(pattern.as_ref().to_string());
+        self
+    }
+
+    /// Skip programs matching `pattern`, even if they are included.
+    pub fn exclude<T: AsRef<str>>(&mut self, pattern: T) -> &mut Self {
+        self.exclude.push(pattern.as_ref().to_string());
+        self
+    }
+
+    /// Whether the program called `name` is selected.
+    pub fn matches<T: AsRef<str>>(&self, name: T) -> bool {
+        let name = name.as_ref();
+
+        (self.include.is_empty() || self.include.iter().any(|p| util::glob_match(p, name)))
+            && !self.exclude.iter().any(|p| util::glob_match(p, name))
+    }
+}
+
+/// What [`Object::detach()`] does once programs are detached.
+pub struct DetachOptions<'a> {
+    drain: Vec<&'a RingBuffer>,
+    drain_timeout: Duration,
+    unpin_maps: bool,
+}
+
+impl Default for DetachOptions<'_> {
+    fn default() -> Self {
+        DetachOptions {
+            drain: Vec::new(),
+            drain_timeout: Duration::from_secs(1),
+            unpin_maps: false,
+        }
+    }
+}
+
+impl<'a> DetachOptions<'a> {
+    /// Drain `ringbuf` with [`RingBuffer::drain()`], so samples programs submitted before they
+    /// were detached are not lost.
+    pub fn drain(&mut self, ringbuf: &'a RingBuffer) -> &mut Self {
+        self.drain.push(ringbuf);
+        self
+    }
+
+    /// Give up draining each ring buffer after `timeout`. Defaults to 1 second.
+    pub fn drain_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Unpin the maps of the object that are pinned, eg. with `LIBBPF_PIN_BY_NAME`, so no state
+    /// is left behind on bpffs.
+    pub fn unpin_maps(&mut self, unpin_maps: bool) -> &mut Self {
+        self.unpin_maps = unpin_maps;
+        self
+    }
+}
+
 /// Represents an opened (but not yet loaded) BPF object file.
 ///
 /// Use this object to access [`OpenMap`]s and [`OpenProgram`]s.
@@ -164,6 +509,9 @@ pub struct OpenObject {
     ptr: *mut libbpf_sys::bpf_object,
     maps: HashMap<String, OpenMap>,
     progs: HashMap<String, OpenProgram>,
+    btf_custom_path: Option<CString>,
+    log_level: i32,
+    section_handlers: Vec<(String, SectionHandler)>,
 }
 
 impl OpenObject {
@@ -172,6 +520,9 @@ impl OpenObject {
             ptr,
             maps: HashMap::new(),
             progs: HashMap::new(),
+            btf_custom_path: None,
+            log_level: 0,
+            section_handlers: Vec::new(),
         }
     }
 
@@ -242,15 +593,245 @@ impl OpenObject {
         self.prog(name).unwrap().unwrap()
     }
 
+    /// Returns every map of the object, including internal maps, in the order they appear in
+    /// the object file. Useful to work with objects whose maps aren't known in advance.
+    pub fn maps(&mut self) -> Result<Vec<&mut OpenMap>> {
+        let names = map_names(self.ptr, true)?;
+        for name in &names {
+            self.map(name)?;
+        }
+
+        Ok(in_order(&mut self.maps, &names))
+    }
+
+    /// Returns every program of the object, in the order they appear in the object file.
+    pub fn progs(&mut self) -> Result<Vec<&mut OpenProgram>> {
+        let names = prog_names(self.ptr)?;
+        for name in &names {
+            self.prog(name)?;
+        }
+
+        Ok(in_order(&mut self.progs, &names))
+    }
+
+    /// Returns the object's BTF, if it has any.
+    pub fn btf(&self) -> Option<Btf> {
+        Btf::from_object_ptr(unsafe { libbpf_sys::bpf_object__btf(self.ptr) })
+    }
+
+    /// Returns the names of the kernel symbols (`__ksym` externs) this object references.
+    pub fn ksym_externs(&self) -> Result<Vec<String>> {
+        let btf = unsafe { libbpf_sys::bpf_object__btf(self.ptr) };
+        if btf.is_null() {
+            return Ok(Vec::new());
+        }
+
+        let btf_name = |name_off: u32| -> Result<String> {
+            let ptr = unsafe { libbpf_sys::btf__name_by_offset(btf, name_off) };
+            util::c_ptr_to_string(ptr)
+        };
+
+        let mut names = Vec::new();
+        let nr_types = unsafe { libbpf_sys::btf__get_nr_types(btf) };
+        for type_id in 1..=nr_types {
+            let t = unsafe { &*libbpf_sys::btf__type_by_id(btf, type_id) };
+            let kind = (t.info >> 24) & 0x1f;
+            if kind != BTF_KIND_DATASEC || btf_name(t.name_off)? != ".ksyms" {
+                continue;
+            }
+
+            // Var section infos immediately follow the datasec type
+            let vlen = (t.info & 0xffff) as usize;
+            let vars = unsafe {
+                slice::from_raw_parts(
+                    (t as *const libbpf_sys::btf_type).add(1) as *const libbpf_sys::btf_var_secinfo,
+                    vlen,
+                )
+            };
+            for var in vars {
+                // Typeless ksyms are vars, kfuncs are funcs. Both carry the symbol name.
+                let var_t = unsafe { &*libbpf_sys::btf__type_by_id(btf, var.type_) };
+                names.push(btf_name(var_t.name_off)?);
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Check that every kernel symbol this object references exists in `ksyms`.
+    ///
+    /// Loading an object with unresolvable ksyms fails with a generic error, so this is
+    /// useful to tell the user exactly which symbols are missing before attempting load.
+    pub fn verify_ksyms(&self, ksyms: &Ksyms) -> Result<()> {
+        let externs = self.ksym_externs()?;
+        let missing = ksyms.missing(&externs);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidInput(format!(
+                "missing kernel symbols: {}",
+                missing.join(", ")
+            )))
+        }
+    }
+
+    /// Check that the kernel function every fentry, fexit, fmod_ret, lsm and tp_btf program
+    /// attaches to exists in `btf`, typically [`Btf::from_vmlinux()`].
+    ///
+    /// Loading fails with a bare `ENOENT` for the first missing target, so this is useful to
+    /// tell the user about all of them before attempting load, along with the closest existing
+    /// functions in case they were renamed. Programs that won't be loaded or that attach to
+    /// another BPF program are skipped.
+    pub fn verify_attach_targets(&self, btf: &Btf) -> Result<()> {
+        let mut targets = Vec::new();
+        let mut prog = ptr::null_mut();
+        loop {
+            prog = unsafe { libbpf_sys::bpf_program__next(prog, self.ptr) };
+            if prog.is_null() {
+                break;
+            }
+            if !unsafe { libbpf_sys::bpf_program__autoload(prog) } {
+                continue;
+            }
+
+            let name = util::c_ptr_to_string(unsafe { libbpf_sys::bpf_program__name(prog) })?;
+            let section =
+                util::c_ptr_to_string(unsafe { libbpf_sys::bpf_program__title(prog, false) })?;
+            let (prefix, btf_prefix, kind) = match BTF_ATTACH_SECTIONS
+                .iter()
+                .find(|(prefix, _, _)| section.starts_with(prefix))
+            {
+                Some(entry) => *entry,
+                None => continue,
+            };
+
+            let target = match self.progs.get(&name).and_then(|p| p.attach_target()) {
+                Some((0, func)) => func.to_string(),
+                Some(_) => continue,
+                None => section[prefix.len()..].to_string(),
+            };
+            targets.push((name, section, target, btf_prefix, kind));
+        }
+
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        // Named functions and typedefs, to look for similar names in when a target is missing
+        let mut btf_names: Vec<(BtfKind, String)> = Vec::new();
+        for type_id in btf.type_ids() {
+            let kind = btf.type_kind(type_id)?;
+            if kind != BtfKind::Func && kind != BtfKind::Typedef {
+                continue;
+            }
+            if let Some(name) = btf.type_name(type_id)? {
+                btf_names.push((kind, name));
+            }
+        }
+
+        let mut missing = Vec::new();
+        for (name, section, target, btf_prefix, kind) in targets {
+            let btf_name = format!("{}{}", btf_prefix, target);
+            if btf.find_by_name_kind(&btf_name, kind).is_some() {
+                continue;
+            }
+
+            let candidates = btf_names
+                .iter()
+                .filter(|(k, _)| *k == kind)
+                .filter_map(|(_, n)| n.strip_prefix(btf_prefix));
+            let suggestions = util::closest_matches(&target, candidates, 3);
+            if suggestions.is_empty() {
+                missing.push(format!("{} ({})", name, section));
+            } else {
+                missing.push(format!(
+                    "{} ({}, did you mean {}?)",
+                    name,
+                    section,
+                    suggestions.join(" or ")
+                ));
+            }
+        }
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidInput(format!(
+                "missing attach targets: {}",
+                missing.join(", ")
+            )))
+        }
+    }
+
+    /// Share maps with `old`, typically a previous version of this object that is still running.
+    ///
+    /// Every map of `self` that has a map of the same name, type, key size and value size in
+    /// `old` reuses it instead of creating a new one on load, so their contents carry over.
+    /// Internal maps (`.data`, `.rodata`, `.bss`, ...) are never shared. Returns the names of the
+    /// shared maps.
+    pub fn reuse_maps(&mut self, old: &mut Object) -> Result<Vec<String>> {
+        let mut reused = Vec::new();
+
+        for name in map_names(self.ptr, false)? {
+            let old_map = match old.map(&name)? {
+                Some(m) => m,
+                None => continue,
+            };
+
+            // bpf_map__def can only return null if passed null
+            let ptr = find_map_in_object(self.ptr, &name)?.unwrap();
+            let def = unsafe { ptr::read(libbpf_sys::bpf_map__def(ptr)) };
+            if def.type_ != old_map.map_type() as u32
+                || def.key_size != old_map.key_size()
+                || def.value_size != old_map.value_size()
+            {
+                continue;
+            }
+
+            self.map_unwrap(&name).reuse_map(old_map)?;
+            reused.push(name);
+        }
+
+        Ok(reused)
+    }
+
+    /// Reuse maps pinned under `dir` by name, eg. with [`Object::pin_maps()`], instead of
+    /// creating new ones on load. Internal maps (`.data`, `.rodata`, `.bss`, ...) are never
+    /// reused. Returns the names of the reused maps.
+    pub fn reuse_pinned_maps<P: AsRef<Path>>(&mut self, dir: P) -> Result<Vec<String>> {
+        let mut reused = Vec::new();
+
+        for name in map_names(self.ptr, false)? {
+            let path = dir.as_ref().join(&name);
+            if !path.exists() {
+                continue;
+            }
+
+            self.map_unwrap(&name).reuse_pinned_map(path)?;
+            reused.push(name);
+        }
+
+        Ok(reused)
+    }
+
     /// Load the maps and programs contained in this BPF object into the system.
     pub fn load(mut self) -> Result<Object> {
-        let ret = unsafe { libbpf_sys::bpf_object__load(self.ptr) };
+        let attr = libbpf_sys::bpf_object_load_attr {
+            obj: self.ptr,
+            log_level: self.log_level,
+            target_btf_path: self
+                .btf_custom_path
+                .as_ref()
+                .map_or(ptr::null(), |path| path.as_ptr()),
+        };
+        let ret = unsafe { libbpf_sys::bpf_object__load_xattr(&attr) };
         if ret != 0 {
-            // bpf_object__load() returns errno as negative, so flip
+            // bpf_object__load_xattr() returns errno as negative, so flip
             return Err(Error::System(-ret));
         }
 
-        let obj = Object::new(self.ptr);
+        let mut obj = Object::new(self.ptr);
+        obj.section_handlers = mem::take(&mut self.section_handlers);
 
         // Prevent object from being closed once `self` is dropped
         self.ptr = ptr::null_mut();
@@ -281,6 +862,7 @@ pub struct Object {
     ptr: *mut libbpf_sys::bpf_object,
     maps: HashMap<String, Map>,
     progs: HashMap<String, Program>,
+    section_handlers: Vec<(String, SectionHandler)>,
 }
 
 impl Object {
@@ -289,6 +871,7 @@ impl Object {
             ptr,
             maps: HashMap::new(),
             progs: HashMap::new(),
+            section_handlers: Vec::new(),
         }
     }
 
@@ -304,6 +887,11 @@ impl Object {
         Self::new(ptr)
     }
 
+    /// Returns the object's BTF, if it has any.
+    pub fn btf(&self) -> Option<Btf> {
+        Btf::from_object_ptr(unsafe { libbpf_sys::bpf_object__btf(self.ptr) })
+    }
+
     pub fn map<T: AsRef<str>>(&mut self, name: T) -> Result<Option<&mut Map>> {
         if self.maps.contains_key(name.as_ref()) {
             Ok(self.maps.get_mut(name.as_ref()))
@@ -347,8 +935,11 @@ impl Object {
             }
             let section = util::c_ptr_to_string(title)?;
 
-            self.progs
-                .insert(owned_name.clone(), Program::new(ptr, owned_name, section));
+            let mut prog = Program::new(ptr, owned_name.clone(), section);
+            if let Some(handler) = find_section_handler(&self.section_handlers, ptr) {
+                prog.set_section_handler(handler);
+            }
+            self.progs.insert(owned_name, prog);
 
             Ok(self.progs.get_mut(name.as_ref()))
         } else {
@@ -360,6 +951,159 @@ impl Object {
     pub fn prog_unwrap<T: AsRef<str>>(&mut self, name: T) -> &mut Program {
         self.prog(name).unwrap().unwrap()
     }
+
+    /// Returns every map of the object, including internal maps, in the order they appear in
+    /// the object file. Useful to work with objects whose maps aren't known in advance.
+    pub fn maps(&mut self) -> Result<Vec<&mut Map>> {
+        let names = map_names(self.ptr, true)?;
+        for name in &names {
+            self.map(name)?;
+        }
+
+        Ok(in_order(&mut self.maps, &names))
+    }
+
+    /// Returns every program of the object, in the order they appear in the object file.
+    pub fn progs(&mut self) -> Result<Vec<&mut Program>> {
+        let names = prog_names(self.ptr)?;
+        for name in &names {
+            self.prog(name)?;
+        }
+
+        Ok(in_order(&mut self.progs, &names))
+    }
+
+    /// Attach every loaded program selected by `filter` with [`Program::attach()`], and return
+    /// their names and links in the order they appear in the object file.
+    ///
+    /// Programs whose section does not support auto-attach, ie. for which `attach()` fails with
+    /// `ESRCH`, and programs not loaded because of [`OpenProgram::set_autoload()`] are skipped.
+    /// If any other program fails to attach, the ones already attached are detached again and
+    /// its error is returned.
+    pub fn attach_all(&mut self, filter: &AttachFilter) -> Result<Vec<(String, Link)>> {
+        self.attach_each(filter, |prog| prog.attach())
+    }
+
+    /// Like [`Object::attach_all()`], but retry each program after transient failures as
+    /// `policy` says, see [`Program::attach_with_retry()`].
+    pub fn attach_all_with_retry(
+        &mut self,
+        filter: &AttachFilter,
+        policy: &RetryPolicy,
+    ) -> Result<Vec<(String, Link)>> {
+        self.attach_each(filter, |prog| {
+            prog.attach_with_retry(&AttachSpec::Auto, policy)
+        })
+    }
+
+    fn attach_each<F>(
+        &mut self,
+        filter: &AttachFilter,
+        mut attach: F,
+    ) -> Result<Vec<(String, Link)>>
+    where
+        F: FnMut(&mut Program) -> Result<Link>,
+    {
+        let mut links = Vec::new();
+        for prog in self.progs()? {
+            if !filter.matches(prog.name()) || prog.fd() < 0 {
+                continue;
+            }
+
+            match attach(prog) {
+                Ok(link) => links.push((prog.name().to_string(), link)),
+                Err(Error::System(libc::ESRCH)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(links)
+    }
+
+    /// [Pin](https://facebookmicrosites.github.io/bpf/blog/2018/08/31/object-lifetime.html#bpffs)
+    /// every map of this object to `dir/<map name>` on bpffs.
+    pub fn pin_maps<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        let path_c = util::path_to_cstring(dir)?;
+
+        let ret = unsafe { libbpf_sys::bpf_object__pin_maps(self.ptr, path_c.as_ptr()) };
+        if ret != 0 {
+            // Error code is returned negative, flip to positive to match errno
+            Err(Error::System(-ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Unpin every map of this object from `dir`, as pinned by [`Object::pin_maps()`].
+    pub fn unpin_maps<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        let path_c = util::path_to_cstring(dir)?;
+
+        let ret = unsafe { libbpf_sys::bpf_object__unpin_maps(self.ptr, path_c.as_ptr()) };
+        if ret != 0 {
+            // Error code is returned negative, flip to positive to match errno
+            Err(Error::System(-ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Shut down in one go: detach `links` in order by dropping them, then drain ring buffers
+    /// and unpin maps as `opts` asks.
+    ///
+    /// Pass links most recently attached first, so programs are detached in the reverse order
+    /// they were attached in, eg. consumers before the producers feeding them. Every step is
+    /// attempted even if an earlier one fails, and the first error is returned.
+    pub fn detach(&mut self, links: Vec<Link>, opts: &DetachOptions) -> Result<()> {
+        for link in links {
+            drop(link);
+        }
+
+        let mut result = Ok(());
+        for ringbuf in &opts.drain {
+            if let Err(e) = ringbuf.drain(opts.drain_timeout) {
+                result = result.and(Err(e));
+            }
+        }
+
+        if opts.unpin_maps {
+            // Without a directory, libbpf unpins maps from their own pin path
+            let ret = unsafe { libbpf_sys::bpf_object__unpin_maps(self.ptr, ptr::null()) };
+            if ret != 0 {
+                // Error code is returned negative, flip to positive to match errno
+                result = result.and(Err(Error::System(-ret)));
+            }
+        }
+
+        result
+    }
+
+    /// Upgrade to `new` in place without a window where no program is attached.
+    ///
+    /// `new` shares maps with `self` as per [`OpenObject::reuse_maps()`] and is loaded, then its
+    /// programs are attached with [`Object::attach_all()`], which skips programs that are not
+    /// loaded or can't be auto-attached. Only then are `links` replaced with the new links,
+    /// detaching the old programs, and `self` replaced with the new object. If any step fails,
+    /// `self` and `links` are left untouched.
+    ///
+    /// Programs that cannot be auto-attached, or hooks that only take one program at a time (eg.
+    /// XDP), need to go through [`OpenObject::reuse_maps()`] and [`Link::update_prog()`]
+    /// instead.
+    pub fn replace_with(&mut self, links: &mut Vec<Link>, mut new: OpenObject) -> Result<()> {
+        new.reuse_maps(self)?;
+        let mut obj = new.load()?;
+
+        let new_links = obj
+            .attach_all(&AttachFilter::default())?
+            .into_iter()
+            .map(|(_, link)| link)
+            .collect();
+
+        // Drop the old links before the old object they belong to
+        *links = new_links;
+        *self = obj;
+
+        Ok(())
+    }
 }
 
 impl Drop for Object {
@@ -1,5 +1,9 @@
 use core::ffi::c_void;
 use std::boxed::Box;
+use std::cell::Cell;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::ptr;
 use std::slice;
 use std::time::Duration;
 
@@ -18,16 +22,84 @@ impl<T> SampleCb for T where T: FnMut(i32, &[u8]) + 'static {}
 pub trait LostCb: FnMut(i32, u64) + 'static {}
 impl<T> LostCb for T where T: FnMut(i32, u64) + 'static {}
 
+/// Sample callbacks as stored, see [`PerfBufferBuilder::sample_cb_fallible()`].
+type FallibleSampleCb = dyn FnMut(i32, &[u8]) -> Result<()>;
+
+// From `include/uapi/linux/perf_event.h`
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_BPF_OUTPUT: u64 = 10;
+const PERF_SAMPLE_RAW: u64 = 1 << 10;
+const PERF_ATTR_FLAG_WATERMARK: u64 = 1 << 14;
+const PERF_RECORD_LOST: u32 = 2;
+const PERF_RECORD_SAMPLE: u32 = 9;
+
+/// `struct perf_event_attr` up to `PERF_ATTR_SIZE_VER5`. libbpf only forward declares it.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved_2: u16,
+}
+
+#[repr(C)]
+struct PerfEventHeader {
+    type_: u32,
+    misc: u16,
+    size: u16,
+}
+
+/// When the kernel wakes up the consumer of each per-CPU buffer.
+enum Wakeup {
+    /// After this many samples
+    Events(u32),
+    /// After this many bytes
+    Watermark(u32),
+}
+
 struct CbStruct {
-    sample_cb: Option<Box<dyn SampleCb>>,
+    sample_cb: Option<Box<FallibleSampleCb>>,
     lost_cb: Option<Box<dyn LostCb>>,
+    stats: PerfBufferStats,
+    /// Error returned by the sample callback, surfaced by the next `PerfBuffer` method
+    error: Cell<Option<Error>>,
+}
+
+/// Counters kept by a [`PerfBuffer`] since it was built, across all CPUs.
+///
+/// Counters only increase, so they can be exported as is, eg. as Prometheus counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PerfBufferStats {
+    /// Samples received, whether or not a sample callback is set.
+    pub events: u64,
+    /// Bytes of sample data received.
+    pub bytes: u64,
+    /// Samples the kernel dropped because a per-CPU buffer was full.
+    pub lost: u64,
 }
 
 /// Builds [`PerfBuffer`] instances.
 pub struct PerfBufferBuilder<'a> {
     map: &'a Map,
     pages: usize,
-    sample_cb: Option<Box<dyn SampleCb>>,
+    wakeup: Wakeup,
+    sample_cb: Option<Box<FallibleSampleCb>>,
     lost_cb: Option<Box<dyn LostCb>>,
 }
 
@@ -36,6 +108,7 @@ impl<'a> PerfBufferBuilder<'a> {
         Self {
             map,
             pages: 64,
+            wakeup: Wakeup::Events(1),
             sample_cb: None,
             lost_cb: None,
         }
@@ -49,10 +122,24 @@ impl<'a> PerfBufferBuilder<'a> {
     /// [`plain`](https://crates.io/crates/plain) helpful.
     ///
     /// Callback arguments are: `(cpu, data)`.
-    pub fn sample_cb<NewCb: SampleCb>(self, cb: NewCb) -> PerfBufferBuilder<'a> {
+    pub fn sample_cb<NewCb: SampleCb>(self, mut cb: NewCb) -> PerfBufferBuilder<'a> {
+        self.sample_cb_fallible(move |cpu, data: &[u8]| {
+            cb(cpu, data);
+            Ok(())
+        })
+    }
+
+    /// Same as [`PerfBufferBuilder::sample_cb()`], but the callback may fail. An error stops
+    /// processing samples and is returned by the [`PerfBuffer`] method that invoked the
+    /// callback, eg. [`PerfBuffer::poll()`].
+    pub fn sample_cb_fallible<NewCb>(self, cb: NewCb) -> PerfBufferBuilder<'a>
+    where
+        NewCb: FnMut(i32, &[u8]) -> Result<()> + 'static,
+    {
         PerfBufferBuilder {
             map: self.map,
             pages: self.pages,
+            wakeup: self.wakeup,
             sample_cb: Some(Box::new(cb)),
             lost_cb: self.lost_cb,
         }
@@ -65,6 +152,7 @@ impl<'a> PerfBufferBuilder<'a> {
         PerfBufferBuilder {
             map: self.map,
             pages: self.pages,
+            wakeup: self.wakeup,
             sample_cb: self.sample_cb,
             lost_cb: Some(Box::new(cb)),
         }
@@ -76,6 +164,23 @@ impl<'a> PerfBufferBuilder<'a> {
         self
     }
 
+    /// Wake up the consumer of a per-CPU buffer once it has `count` samples. Defaults to 1.
+    ///
+    /// Batching wakeups saves CPU under high event rates at the cost of latency. Overrides
+    /// [`PerfBufferBuilder::wakeup_watermark()`].
+    pub fn wakeup_events(&mut self, count: u32) -> &mut Self {
+        self.wakeup = Wakeup::Events(count);
+        self
+    }
+
+    /// Wake up the consumer of a per-CPU buffer once it holds `bytes` bytes of samples.
+    ///
+    /// Overrides [`PerfBufferBuilder::wakeup_events()`].
+    pub fn wakeup_watermark(&mut self, bytes: u32) -> &mut Self {
+        self.wakeup = Wakeup::Watermark(bytes);
+        self
+    }
+
     pub fn build(self) -> Result<PerfBuffer> {
         if self.map.map_type() != MapType::PerfEventArray {
             return Err(Error::InvalidInput(
@@ -89,31 +194,43 @@ impl<'a> PerfBufferBuilder<'a> {
             ));
         }
 
-        let c_sample_cb: libbpf_sys::perf_buffer_sample_fn = if self.sample_cb.is_some() {
-            Some(Self::call_sample_cb)
-        } else {
-            None
-        };
-
-        let c_lost_cb: libbpf_sys::perf_buffer_lost_fn = if self.lost_cb.is_some() {
-            Some(Self::call_lost_cb)
-        } else {
-            None
+        // Same as what perf_buffer__new() uses, plus the user's tuning
+        let mut attr = PerfEventAttr {
+            type_: PERF_TYPE_SOFTWARE,
+            size: mem::size_of::<PerfEventAttr>() as u32,
+            config: PERF_COUNT_SW_BPF_OUTPUT,
+            sample_period: 1,
+            sample_type: PERF_SAMPLE_RAW,
+            ..Default::default()
         };
+        match self.wakeup {
+            Wakeup::Events(count) => attr.wakeup = count,
+            Wakeup::Watermark(bytes) => {
+                attr.flags |= PERF_ATTR_FLAG_WATERMARK;
+                attr.wakeup = bytes;
+            }
+        }
 
         let callback_struct_ptr = Box::into_raw(Box::new(CbStruct {
             sample_cb: self.sample_cb,
             lost_cb: self.lost_cb,
+            stats: PerfBufferStats::default(),
+            error: Cell::new(None),
         }));
 
-        let opts = libbpf_sys::perf_buffer_opts {
-            sample_cb: c_sample_cb,
-            lost_cb: c_lost_cb,
+        // The raw API is the only one that takes a custom attr. Records are decoded in
+        // `call_event_cb` instead.
+        let opts = libbpf_sys::perf_buffer_raw_opts {
+            attr: &mut attr as *mut PerfEventAttr as *mut libbpf_sys::perf_event_attr,
+            event_cb: Some(Self::call_event_cb),
             ctx: callback_struct_ptr as *mut _,
+            cpu_cnt: 0,
+            cpus: ptr::null_mut(),
+            map_keys: ptr::null_mut(),
         };
 
         let ptr = unsafe {
-            libbpf_sys::perf_buffer__new(self.map.fd(), self.pages as libbpf_sys::size_t, &opts)
+            libbpf_sys::perf_buffer__new_raw(self.map.fd(), self.pages as libbpf_sys::size_t, &opts)
         };
         let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
         if err != 0 {
@@ -126,20 +243,42 @@ impl<'a> PerfBufferBuilder<'a> {
         }
     }
 
-    unsafe extern "C" fn call_sample_cb(ctx: *mut c_void, cpu: i32, data: *mut c_void, size: u32) {
+    unsafe extern "C" fn call_event_cb(
+        ctx: *mut c_void,
+        cpu: i32,
+        event: *mut libbpf_sys::perf_event_header,
+    ) -> libbpf_sys::bpf_perf_event_ret {
         let callback_struct = ctx as *mut CbStruct;
+        let header = event as *const PerfEventHeader;
+        let body = header.add(1) as *const u8;
 
-        if let Some(cb) = &mut (*callback_struct).sample_cb {
-            cb(cpu, slice::from_raw_parts(data as *const u8, size as usize));
-        }
-    }
+        match (*header).type_ {
+            PERF_RECORD_SAMPLE => {
+                // PERF_SAMPLE_RAW: u32 size followed by the data
+                let size = ptr::read_unaligned(body as *const u32);
+                (*callback_struct).stats.events += 1;
+                (*callback_struct).stats.bytes += size as u64;
 
-    unsafe extern "C" fn call_lost_cb(ctx: *mut c_void, cpu: i32, count: u64) {
-        let callback_struct = ctx as *mut CbStruct;
+                if let Some(cb) = &mut (*callback_struct).sample_cb {
+                    if let Err(e) = cb(cpu, slice::from_raw_parts(body.add(4), size as usize)) {
+                        (*callback_struct).error.set(Some(e));
+                        return libbpf_sys::LIBBPF_PERF_EVENT_ERROR;
+                    }
+                }
+            }
+            PERF_RECORD_LOST => {
+                // u64 id followed by u64 lost count
+                let lost = ptr::read_unaligned(body.add(8) as *const u64);
+                (*callback_struct).stats.lost += lost;
 
-        if let Some(cb) = &mut (*callback_struct).lost_cb {
-            cb(cpu, count);
+                if let Some(cb) = &mut (*callback_struct).lost_cb {
+                    cb(cpu, lost);
+                }
+            }
+            _ => (),
         }
+
+        libbpf_sys::LIBBPF_PERF_EVENT_CONT
     }
 }
 
@@ -154,12 +293,87 @@ pub struct PerfBuffer {
 impl PerfBuffer {
     pub fn poll(&self, timeout: Duration) -> Result<()> {
         let ret = unsafe { libbpf_sys::perf_buffer__poll(self.ptr, timeout.as_millis() as i32) };
+        self.check_callback_error()?;
         if ret < 0 {
             Err(Error::System(-ret))
         } else {
             Ok(())
         }
     }
+
+    /// Process samples already in the buffers without waiting. Use together with the
+    /// [`AsFd`] implementation to drive the buffer from an existing event loop.
+    pub fn consume(&self) -> Result<()> {
+        let ret = unsafe { libbpf_sys::perf_buffer__consume(self.ptr) };
+        self.check_callback_error()?;
+        if ret < 0 {
+            // Error code is returned negative, flip to positive to match errno
+            Err(Error::System(-ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Same as [`PerfBuffer::consume()`], but only for the per-CPU buffer at `idx`. See
+    /// [`PerfBuffer::buffer_fd()`].
+    pub fn consume_buffer(&self, idx: usize) -> Result<()> {
+        let ret =
+            unsafe { libbpf_sys::perf_buffer__consume_buffer(self.ptr, idx as libbpf_sys::size_t) };
+        self.check_callback_error()?;
+        if ret < 0 {
+            // Error code is returned negative, flip to positive to match errno
+            Err(Error::System(-ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the number of per-CPU buffers.
+    pub fn buffer_cnt(&self) -> usize {
+        unsafe { libbpf_sys::perf_buffer__buffer_cnt(self.ptr) as usize }
+    }
+
+    /// Returns the perf event fd of the per-CPU buffer at `idx`, where `idx` is less than
+    /// [`PerfBuffer::buffer_cnt()`].
+    pub fn buffer_fd(&self, idx: usize) -> Result<i32> {
+        let ret =
+            unsafe { libbpf_sys::perf_buffer__buffer_fd(self.ptr, idx as libbpf_sys::size_t) };
+        if ret < 0 {
+            // Error code is returned negative, flip to positive to match errno
+            Err(Error::System(-ret))
+        } else {
+            Ok(ret)
+        }
+    }
+
+    /// Returns the counters accumulated since this `PerfBuffer` was built.
+    pub fn stats(&self) -> PerfBufferStats {
+        self._cb_struct.stats
+    }
+
+    /// Returns the error a fallible sample callback stopped processing with, if any.
+    fn check_callback_error(&self) -> Result<()> {
+        match self._cb_struct.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the epoll fd libbpf waits on in [`PerfBuffer::poll()`]. It becomes readable when any
+/// per-CPU buffer has data, after which [`PerfBuffer::consume()`] processes it.
+impl AsFd for PerfBuffer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The fd is owned by libbpf and stays open for as long as `self` lives
+        unsafe { BorrowedFd::borrow_raw(libbpf_sys::perf_buffer__epoll_fd(self.ptr)) }
+    }
+}
+
+/// See the [`AsFd`] implementation.
+impl AsRawFd for PerfBuffer {
+    fn as_raw_fd(&self) -> RawFd {
+        unsafe { libbpf_sys::perf_buffer__epoll_fd(self.ptr) }
+    }
 }
 
 impl Drop for PerfBuffer {
@@ -1,8 +1,16 @@
+use core::ffi::c_void;
 use std::convert::TryFrom;
-use std::os::raw::c_char;
+use std::ffi::CStr;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
 use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use nix::errno;
+use bitflags::bitflags;
+use nix::{errno, libc};
 use num_enum::TryFromPrimitive;
 use strum_macros::Display;
 
@@ -13,11 +21,34 @@ use crate::*;
 /// This object exposes operations that need to happen before the program is loaded.
 pub struct OpenProgram {
     ptr: *mut libbpf_sys::bpf_program,
+    /// Set by [`OpenProgram::set_attach_target()`], which libbpf has no getter for
+    attach_target: Option<(i32, String)>,
 }
 
 impl OpenProgram {
     pub(crate) fn new(ptr: *mut libbpf_sys::bpf_program) -> Self {
-        OpenProgram { ptr }
+        OpenProgram {
+            ptr,
+            attach_target: None,
+        }
+    }
+
+    /// The `(attach_prog_fd, attach_func_name)` set with [`OpenProgram::set_attach_target()`]
+    pub(crate) fn attach_target(&self) -> Option<(i32, &str)> {
+        self.attach_target
+            .as_ref()
+            .map(|(fd, name)| (*fd, name.as_str()))
+    }
+
+    pub fn name(&self) -> Result<&str> {
+        let name_ptr = unsafe { libbpf_sys::bpf_program__name(self.ptr) };
+        if name_ptr.is_null() {
+            return Err(Error::Internal("Program has no name".to_string()));
+        }
+
+        unsafe { CStr::from_ptr(name_ptr) }
+            .to_str()
+            .map_err(|e| Error::Internal(e.to_string()))
     }
 
     pub fn set_prog_type(&mut self, prog_type: ProgramType) {
@@ -32,11 +63,66 @@ impl OpenProgram {
         }
     }
 
+    /// Set the function an fentry, fexit or freplace program attaches to. `attach_func_name` is
+    /// the name of a kernel function if `attach_prog_fd` is 0. Otherwise it's the name of a
+    /// (sub)program of the loaded BPF program `attach_prog_fd`, which must have BTF, so that BPF
+    /// programs can be traced with BPF programs.
+    ///
+    /// Once loaded, attach with [`Program::attach_trace()`].
+    pub fn set_attach_target<T: AsRef<str>>(
+        &mut self,
+        attach_prog_fd: i32,
+        attach_func_name: T,
+    ) -> Result<()> {
+        let name_c = util::str_to_cstring(attach_func_name.as_ref())?;
+        let ret = unsafe {
+            libbpf_sys::bpf_program__set_attach_target(self.ptr, attach_prog_fd, name_c.as_ptr())
+        };
+        if ret != 0 {
+            // Error code is returned negative, flip to positive to match errno
+            Err(Error::System(-ret))
+        } else {
+            self.attach_target = Some((attach_prog_fd, attach_func_name.as_ref().to_string()));
+            Ok(())
+        }
+    }
+
     pub fn set_ifindex(&mut self, idx: u32) {
         unsafe {
             libbpf_sys::bpf_program__set_ifindex(self.ptr, idx);
         }
     }
+
+    /// Returns the number of instructions in the program, before any processing by libbpf or
+    /// the verifier.
+    ///
+    /// The instructions themselves are only available once loaded, see
+    /// [`Program::xlated_insns()`]: reading them before needs `bpf_program__insns()`, which is
+    /// new in libbpf 0.7 and so missing from the bundled libbpf.
+    pub fn insn_cnt(&self) -> usize {
+        let size = unsafe { libbpf_sys::bpf_program__size(self.ptr) };
+        size as usize / mem::size_of::<libbpf_sys::bpf_insn>()
+    }
+
+    /// Returns whether this program will be loaded along with the rest of the object.
+    pub fn autoload(&self) -> bool {
+        unsafe { libbpf_sys::bpf_program__autoload(self.ptr) }
+    }
+
+    /// Set whether this program is loaded along with the rest of the object. Programs are
+    /// autoloaded by default.
+    ///
+    /// This is useful for objects carrying multiple variants of a program where only some
+    /// will pass verification on the running kernel.
+    pub fn set_autoload(&mut self, autoload: bool) -> Result<()> {
+        let ret = unsafe { libbpf_sys::bpf_program__set_autoload(self.ptr, autoload) };
+        if ret != 0 {
+            // Error code is returned negative, flip to positive to match errno
+            Err(Error::System(-ret))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// Type of a [`Program`]. Maps to `enum bpf_prog_type` in kernel uapi.
@@ -111,10 +197,194 @@ pub enum ProgramAttachType {
     TraceFexit,
     ModifyReturn,
     LsmMac,
+    TraceIter,
+    CgroupInet4Getpeername,
+    CgroupInet6Getpeername,
+    CgroupInet4Getsockname,
+    CgroupInet6Getsockname,
+    XdpDevmap,
+    CgroupInetSockRelease,
+    XdpCpumap,
+    SkLookup,
+    Xdp,
+    SkSkbVerdict,
+    SkReuseportSelect,
+    SkReuseportSelectOrMigrate,
+    PerfEvent,
+    TraceKprobeMulti,
+    LsmCgroup,
+    StructOps,
+    Netfilter,
+    TcxIngress,
+    TcxEgress,
+    TraceUprobeMulti,
+    CgroupUnixConnect,
+    CgroupUnixSendmsg,
+    CgroupUnixRecvmsg,
+    CgroupUnixGetpeername,
+    CgroupUnixGetsockname,
+    NetkitPrimary,
+    NetkitPeer,
     /// See [`MapType::Unknown`]
     Unknown = u32::MAX,
 }
 
+/// An attach mechanism for [`Program::attach_with_fallback()`] or
+/// [`Program::attach_with_retry()`], along with its target.
+#[derive(Clone, Debug)]
+pub enum AttachSpec {
+    /// See [`Program::attach()`].
+    Auto,
+    /// See [`Program::attach_trace()`]. Covers fentry, fexit and `tp_btf`.
+    Trace,
+    /// See [`Program::attach_kprobe()`].
+    Kprobe { retprobe: bool, func_name: String },
+    /// See [`Program::attach_tracepoint()`].
+    Tracepoint { category: String, name: String },
+    /// See [`Program::attach_raw_tracepoint()`].
+    RawTracepoint(String),
+    /// See [`Program::attach_uprobe()`].
+    Uprobe {
+        retprobe: bool,
+        pid: i32,
+        binary_path: String,
+        func_offset: usize,
+    },
+    /// See [`Program::attach_lsm()`].
+    Lsm,
+    /// See [`Program::attach_cgroup()`].
+    Cgroup(i32),
+    /// See [`Program::attach_perf_event()`].
+    PerfEvent(i32),
+    /// See [`Program::attach_xdp()`].
+    Xdp(i32),
+}
+
+/// How [`Program::attach_with_retry()`] retries attaching after transient failures, eg. `EBUSY`
+/// while racing with an interface being set up or another agent detaching its programs.
+///
+/// By default, attaching is attempted 5 times on `EBUSY` or `EAGAIN`, waiting 10ms before the
+/// first retry and twice as long before each following one, up to 1 second.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    errnos: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            errnos: vec![libc::EBUSY, libc::EAGAIN],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Give up after `attempts` attempts, including the first one. 0 or 1 disable retries.
+    pub fn attempts(&mut self, attempts: u32) -> &mut Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Wait `backoff` before the first retry. Each following retry waits twice as long as the
+    /// previous one, up to [`RetryPolicy::max_backoff()`].
+    pub fn backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Never wait longer than `max_backoff` between attempts.
+    pub fn max_backoff(&mut self, max_backoff: Duration) -> &mut Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Also retry on [`Error::System`] errors with `errno`.
+    pub fn retry_on(&mut self, errno: i32) -> &mut Self {
+        self.errnos.push(errno);
+        self
+    }
+
+    /// Run `f` until it succeeds, fails with an error that is not retried, or runs out of
+    /// attempts, and return its last result. Works with any attach method, eg.
+    /// `policy.run(|| prog.attach_xdp(ifindex))`.
+    pub fn run<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(Error::System(errno))
+                    if attempt < self.attempts && self.errnos.contains(&errno) =>
+                {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+type AttachFn = dyn Fn(&mut Program) -> Result<Link> + Send + Sync;
+
+/// Describes programs in a section libbpf does not know about, eg. one emitted by another
+/// toolchain. Register it for a section name prefix with [`ObjectBuilder::section_handler()`].
+///
+/// Without a handler, such programs have no type and fail to load, and [`Program::attach()`]
+/// fails with `ESRCH`.
+#[derive(Clone)]
+pub struct SectionHandler {
+    prog_type: ProgramType,
+    attach_type: Option<ProgramAttachType>,
+    attach_fn: Option<Arc<AttachFn>>,
+}
+
+impl SectionHandler {
+    /// Programs in matching sections are of type `prog_type`.
+    pub fn new(prog_type: ProgramType) -> Self {
+        SectionHandler {
+            prog_type,
+            attach_type: None,
+            attach_fn: None,
+        }
+    }
+
+    /// Programs in matching sections have the expected attach type `attach_type`.
+    pub fn attach_type(mut self, attach_type: ProgramAttachType) -> Self {
+        self.attach_type = Some(attach_type);
+        self
+    }
+
+    /// Have [`Program::attach()`] call `attach_fn` for programs in matching sections, eg. to
+    /// parse the attach target out of [`Program::section()`] and call one of the `attach_*`
+    /// methods.
+    ///
+    /// `attach_fn` must not call [`Program::attach()`] itself.
+    pub fn attach_fn<F>(mut self, attach_fn: F) -> Self
+    where
+        F: Fn(&mut Program) -> Result<Link> + Send + Sync + 'static,
+    {
+        self.attach_fn = Some(Arc::new(attach_fn));
+        self
+    }
+
+    pub(crate) fn apply(&self, prog: &mut OpenProgram) {
+        prog.set_prog_type(self.prog_type.clone());
+        if let Some(attach_type) = &self.attach_type {
+            prog.set_attach_type(attach_type.clone());
+        }
+    }
+}
+
 /// Represents a loaded [`Program`].
 ///
 /// This struct is not safe to clone because the underlying libbpf resource cannot currently
@@ -126,11 +396,21 @@ pub struct Program {
     pub(crate) ptr: *mut libbpf_sys::bpf_program,
     name: String,
     section: String,
+    attach_fn: Option<Arc<AttachFn>>,
 }
 
 impl Program {
     pub(crate) fn new(ptr: *mut libbpf_sys::bpf_program, name: String, section: String) -> Self {
-        Program { ptr, name, section }
+        Program {
+            ptr,
+            name,
+            section,
+            attach_fn: None,
+        }
+    }
+
+    pub(crate) fn set_section_handler(&mut self, handler: &SectionHandler) {
+        self.attach_fn = handler.attach_fn.clone();
     }
 
     pub fn name(&self) -> &str {
@@ -163,6 +443,160 @@ impl Program {
         }
     }
 
+    /// Returns the program's instructions as rewritten by the verifier, ie. "xlated"
+    /// instructions. Comparing them with the object file shows what the verifier patched.
+    ///
+    /// Map addresses are replaced by map ids unless the caller has `CAP_SYS_ADMIN`.
+    pub fn xlated_insns(&self) -> Result<Vec<libbpf_sys::bpf_insn>> {
+        let len = self.info()?.xlated_prog_len as usize;
+        let mut insns = Vec::with_capacity(len / mem::size_of::<libbpf_sys::bpf_insn>());
+
+        let len = self
+            .info_with(|info| {
+                info.xlated_prog_len =
+                    (insns.capacity() * mem::size_of::<libbpf_sys::bpf_insn>()) as u32;
+                info.xlated_prog_insns = insns.as_mut_ptr() as u64;
+            })?
+            .xlated_prog_len as usize;
+
+        // The kernel reports the full length even if it only copied part of it
+        unsafe {
+            insns.set_len(
+                insns
+                    .capacity()
+                    .min(len / mem::size_of::<libbpf_sys::bpf_insn>()),
+            );
+        }
+        Ok(insns)
+    }
+
+    /// Returns the native machine code the program was JIT compiled to, eg. to feed into a
+    /// disassembler. Empty if the JIT is disabled.
+    pub fn jited_insns(&self) -> Result<Vec<u8>> {
+        let len = self.info()?.jited_prog_len as usize;
+        let mut insns: Vec<u8> = Vec::with_capacity(len);
+
+        let len = self
+            .info_with(|info| {
+                info.jited_prog_len = insns.capacity() as u32;
+                info.jited_prog_insns = insns.as_mut_ptr() as u64;
+            })?
+            .jited_prog_len as usize;
+
+        unsafe {
+            insns.set_len(insns.capacity().min(len));
+        }
+        Ok(insns)
+    }
+
+    /// Returns the ids of the maps the program uses, including those bound with
+    /// [`Program::bind_map()`].
+    pub fn map_ids(&self) -> Result<Vec<u32>> {
+        let nr = self.info()?.nr_map_ids as usize;
+        let mut ids: Vec<u32> = Vec::with_capacity(nr);
+
+        let nr = self
+            .info_with(|info| {
+                info.nr_map_ids = ids.capacity() as u32;
+                info.map_ids = ids.as_mut_ptr() as u64;
+            })?
+            .nr_map_ids as usize;
+
+        unsafe {
+            ids.set_len(ids.capacity().min(nr));
+        }
+        Ok(ids)
+    }
+
+    /// Bind `map` to this program, as if the program used it, eg. for a metadata map only
+    /// userspace reads. The map then lives as long as the program, and shows up in
+    /// [`Program::map_ids()`] and tools like `bpftool prog show`.
+    ///
+    /// Needs Linux 5.10.
+    pub fn bind_map(&self, map: &Map) -> Result<()> {
+        let ret = unsafe { libbpf_sys::bpf_prog_bind_map(self.fd(), map.fd(), ptr::null()) };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        Ok(())
+    }
+
+    /// Run the program once or more in the kernel on `opts`' input, without attaching it, as
+    /// `BPF_PROG_TEST_RUN` does. Supported by eg. XDP, tc and socket filter programs, which
+    /// take a packet as `data_in`, and raw tracepoint programs.
+    ///
+    /// `ctx_in` sets up the program's context, eg. `__sk_buff` fields for tc programs, which
+    /// needs Linux 5.2. With a `repeat` count, [`TestRunOutput::duration`] is the average
+    /// duration of one run, to benchmark the per-packet cost of a program.
+    pub fn test_run(&self, opts: &TestRunOptions) -> Result<TestRunOutput> {
+        let mut data_out: Vec<u8> = vec![0; opts.data_out_size];
+        let mut ctx_out: Vec<u8> = vec![0; opts.ctx_out_size];
+
+        let mut attr: libbpf_sys::bpf_prog_test_run_attr = unsafe { mem::zeroed() };
+        attr.prog_fd = self.fd();
+        attr.repeat = opts.repeat;
+        attr.data_in = opts.data_in.as_ptr() as *const c_void;
+        attr.data_size_in = opts.data_in.len() as u32;
+        if !data_out.is_empty() {
+            attr.data_out = data_out.as_mut_ptr() as *mut c_void;
+            attr.data_size_out = data_out.len() as u32;
+        }
+        if !opts.ctx_in.is_empty() {
+            attr.ctx_in = opts.ctx_in.as_ptr() as *const c_void;
+            attr.ctx_size_in = opts.ctx_in.len() as u32;
+        }
+        if !ctx_out.is_empty() {
+            attr.ctx_out = ctx_out.as_mut_ptr() as *mut c_void;
+            attr.ctx_size_out = ctx_out.len() as u32;
+        }
+
+        let ret = unsafe { libbpf_sys::bpf_prog_test_run_xattr(&mut attr) };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        // The kernel reports the sizes of the output, which fit as it fails with `ENOSPC`
+        // otherwise
+        data_out.truncate(attr.data_size_out as usize);
+        ctx_out.truncate(attr.ctx_size_out as usize);
+
+        Ok(TestRunOutput {
+            retval: attr.retval,
+            duration: Duration::from_nanos(attr.duration as u64),
+            data_out,
+            ctx_out,
+        })
+    }
+
+    fn info(&self) -> Result<libbpf_sys::bpf_prog_info> {
+        self.info_with(|_| ())
+    }
+
+    /// Query `bpf_prog_info`, letting `setup` point it at buffers first.
+    fn info_with<F>(&self, setup: F) -> Result<libbpf_sys::bpf_prog_info>
+    where
+        F: FnOnce(&mut libbpf_sys::bpf_prog_info),
+    {
+        // Padding must be zeroed too, see `query`
+        let mut info: libbpf_sys::bpf_prog_info = unsafe { mem::zeroed() };
+        setup(&mut info);
+
+        let mut len = mem::size_of::<libbpf_sys::bpf_prog_info>() as u32;
+        let ret = unsafe {
+            libbpf_sys::bpf_obj_get_info_by_fd(
+                self.fd(),
+                &mut info as *mut libbpf_sys::bpf_prog_info as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        Ok(info)
+    }
+
     /// [Pin](https://facebookmicrosites.github.io/bpf/blog/2018/08/31/object-lifetime.html#bpffs)
     /// this program to bpffs.
     pub fn pin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
@@ -193,8 +627,12 @@ impl Program {
         }
     }
 
-    /// Auto-attach based on prog section
+    /// Auto-attach based on prog section, or with the [`SectionHandler`] registered for it.
     pub fn attach(&mut self) -> Result<Link> {
+        if let Some(attach_fn) = self.attach_fn.clone() {
+            return attach_fn(self);
+        }
+
         let ptr = unsafe { libbpf_sys::bpf_program__attach(self.ptr) };
         let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
         if err != 0 {
@@ -204,6 +642,56 @@ impl Program {
         }
     }
 
+    /// Attach with each of `specs` in order until one succeeds. Returns the index into `specs`
+    /// of the one that did, along with the link.
+    ///
+    /// A program can only be attached in ways its [`ProgramType`] allows, so falling back from
+    /// eg. fentry to kprobe on kernels without BPF trampolines takes one program per mechanism.
+    /// Disable the variants the running kernel can't load with [`OpenProgram::set_autoload()`]
+    /// and try each of the remaining ones with its own `specs`.
+    ///
+    /// If every spec fails, returns the error of the last one. Fails with `EINVAL` if `specs`
+    /// is empty.
+    pub fn attach_with_fallback(&mut self, specs: &[AttachSpec]) -> Result<(usize, Link)> {
+        let mut last_err = Error::System(libc::EINVAL);
+        for (idx, spec) in specs.iter().enumerate() {
+            match self.attach_spec(spec) {
+                Ok(link) => return Ok((idx, link)),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Attach with `spec`, retrying transient failures as `policy` says.
+    pub fn attach_with_retry(&mut self, spec: &AttachSpec, policy: &RetryPolicy) -> Result<Link> {
+        policy.run(|| self.attach_spec(spec))
+    }
+
+    fn attach_spec(&mut self, spec: &AttachSpec) -> Result<Link> {
+        match spec {
+            AttachSpec::Auto => self.attach(),
+            AttachSpec::Trace => self.attach_trace(),
+            AttachSpec::Kprobe {
+                retprobe,
+                func_name,
+            } => self.attach_kprobe(*retprobe, func_name),
+            AttachSpec::Tracepoint { category, name } => self.attach_tracepoint(category, name),
+            AttachSpec::RawTracepoint(name) => self.attach_raw_tracepoint(name),
+            AttachSpec::Uprobe {
+                retprobe,
+                pid,
+                binary_path,
+                func_offset,
+            } => self.attach_uprobe(*retprobe, *pid, binary_path, *func_offset),
+            AttachSpec::Lsm => self.attach_lsm(),
+            AttachSpec::Cgroup(fd) => self.attach_cgroup(*fd),
+            AttachSpec::PerfEvent(fd) => self.attach_perf_event(*fd),
+            AttachSpec::Xdp(ifindex) => self.attach_xdp(*ifindex),
+        }
+    }
+
     /// Attach this program to a
     /// [cgroup](https://www.kernel.org/doc/html/latest/admin-guide/cgroup-v2.html).
     pub fn attach_cgroup(&mut self, cgroup_fd: i32) -> Result<Link> {
@@ -216,6 +704,15 @@ impl Program {
         }
     }
 
+    /// Attach this program to the cgroup at `path`, resolved as per [`cgroups::resolve()`], eg.
+    /// `system.slice/foo.service`.
+    ///
+    /// The cgroup is only opened to attach, the link keeps its own reference to it.
+    pub fn attach_cgroup_path<P: AsRef<Path>>(&mut self, path: P) -> Result<Link> {
+        let cgroup = cgroups::Cgroup::open(path)?;
+        self.attach_cgroup(cgroup.as_raw_fd())
+    }
+
     /// Attach this program to a [perf event](https://linux.die.net/man/2/perf_event_open).
     pub fn attach_perf_event(&mut self, pfd: i32) -> Result<Link> {
         let ptr = unsafe { libbpf_sys::bpf_program__attach_perf_event(self.ptr, pfd) };
@@ -236,13 +733,13 @@ impl Program {
         binary_path: T,
         func_offset: usize,
     ) -> Result<Link> {
-        let path = binary_path.as_ref().as_ptr() as *const c_char;
+        let path = util::str_to_cstring(binary_path.as_ref())?;
         let ptr = unsafe {
             libbpf_sys::bpf_program__attach_uprobe(
                 self.ptr,
                 retprobe,
                 pid,
-                path,
+                path.as_ptr(),
                 func_offset as libbpf_sys::size_t,
             )
         };
@@ -257,12 +754,9 @@ impl Program {
     /// Attach this program to a [kernel
     /// probe](https://www.kernel.org/doc/html/latest/trace/kprobetrace.html).
     pub fn attach_kprobe<T: AsRef<str>>(&mut self, retprobe: bool, func_name: T) -> Result<Link> {
+        let func_name = util::str_to_cstring(func_name.as_ref())?;
         let ptr = unsafe {
-            libbpf_sys::bpf_program__attach_kprobe(
-                self.ptr,
-                retprobe,
-                func_name.as_ref().as_ptr() as *const c_char,
-            )
+            libbpf_sys::bpf_program__attach_kprobe(self.ptr, retprobe, func_name.as_ptr())
         };
         let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
         if err != 0 {
@@ -273,13 +767,19 @@ impl Program {
     }
 
     /// Attach this program to a [kernel
-    /// tracepoint](https://www.kernel.org/doc/html/latest/trace/tracepoints.html).
+    /// tracepoint](https://www.kernel.org/doc/html/latest/trace/tracepoints.html), eg.
+    /// `attach_tracepoint("sched", "sched_switch")`.
+    ///
+    /// Available tracepoints can be listed with [`tracefs::tracepoints()`] and checked for with
+    /// [`tracefs::tracepoint_exists()`].
     pub fn attach_tracepoint<T: AsRef<str>>(&mut self, tp_category: T, tp_name: T) -> Result<Link> {
+        let tp_category = util::str_to_cstring(tp_category.as_ref())?;
+        let tp_name = util::str_to_cstring(tp_name.as_ref())?;
         let ptr = unsafe {
             libbpf_sys::bpf_program__attach_tracepoint(
                 self.ptr,
-                tp_category.as_ref().as_ptr() as *const c_char,
-                tp_name.as_ref().as_ptr() as *const c_char,
+                tp_category.as_ptr(),
+                tp_name.as_ptr(),
             )
         };
         let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
@@ -293,12 +793,9 @@ impl Program {
     /// Attach this program to a [raw kernel
     /// tracepoint](https://lwn.net/Articles/748352/).
     pub fn attach_raw_tracepoint<T: AsRef<str>>(&mut self, tp_name: T) -> Result<Link> {
-        let ptr = unsafe {
-            libbpf_sys::bpf_program__attach_raw_tracepoint(
-                self.ptr,
-                tp_name.as_ref().as_ptr() as *const c_char,
-            )
-        };
+        let tp_name = util::str_to_cstring(tp_name.as_ref())?;
+        let ptr =
+            unsafe { libbpf_sys::bpf_program__attach_raw_tracepoint(self.ptr, tp_name.as_ptr()) };
         let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
         if err != 0 {
             Err(Error::System(err as i32))
@@ -318,7 +815,8 @@ impl Program {
         }
     }
 
-    /// Attach to a [fentry/fexit kernel probe](https://lwn.net/Articles/801479/)
+    /// Attach to a [fentry/fexit kernel probe](https://lwn.net/Articles/801479/), or the BPF
+    /// program set with [`OpenProgram::set_attach_target()`]
     pub fn attach_trace(&mut self) -> Result<Link> {
         let ptr = unsafe { libbpf_sys::bpf_program__attach_trace(self.ptr) };
         let err = unsafe { libbpf_sys::libbpf_get_error(ptr as *const _) };
@@ -340,6 +838,103 @@ impl Program {
         }
     }
 
+    /// Attach this program to `target_fd` with `bpf_prog_attach()`, for attach points that
+    /// predate BPF links, eg. a cgroup for [`ProgramAttachType::CgroupDevice`] or
+    /// [`ProgramAttachType::CgroupSockOps`], a sockmap for [`ProgramAttachType::SkMsgVerdict`] or
+    /// the current network namespace (`target_fd` 0) for [`ProgramAttachType::FlowDissector`].
+    ///
+    /// The program is detached when the returned [`ProgramAttachment`] is dropped. It keeps its
+    /// own copy of `target_fd`, so the caller may close theirs in the meantime.
+    pub fn attach_fd(
+        &self,
+        target_fd: i32,
+        attach_type: ProgramAttachType,
+        flags: ProgramAttachFlags,
+    ) -> Result<ProgramAttachment> {
+        if self.fd() < 0 {
+            return Err(Error::InvalidInput(format!(
+                "program {} is not loaded",
+                self.name
+            )));
+        }
+
+        // Keep our own fds, so the attachment can be undone even if `self` is gone by then, and
+        // the target isn't mistaken for whatever reuses its fd number once the caller closes it
+        let prog_fd = self
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(|e| Error::System(e.raw_os_error().unwrap_or(libc::EBADF)))?;
+        // Flow dissectors attach to the current network namespace, the kernel ignores the fd
+        let target = if matches!(attach_type, ProgramAttachType::FlowDissector) {
+            None
+        } else if target_fd < 0 {
+            return Err(Error::System(libc::EBADF));
+        } else {
+            let target = unsafe { BorrowedFd::borrow_raw(target_fd) }
+                .try_clone_to_owned()
+                .map_err(|e| Error::System(e.raw_os_error().unwrap_or(libc::EBADF)))?;
+            Some(target)
+        };
+
+        let ret = unsafe {
+            libbpf_sys::bpf_prog_attach(
+                prog_fd.as_raw_fd(),
+                target.as_ref().map_or(target_fd, |fd| fd.as_raw_fd()),
+                attach_type.clone() as u32,
+                flags.bits,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        Ok(ProgramAttachment {
+            prog_fd,
+            target,
+            attach_type: Some(attach_type),
+        })
+    }
+
+    /// Attach this [`ProgramType::SocketFilter`] program to `socket`, eg. an `AF_PACKET` or raw
+    /// socket, with `SO_ATTACH_BPF`. The socket then only receives the packets the program
+    /// accepts, truncated to the length it returns.
+    ///
+    /// A socket has a single filter, attaching replaces the previous one. The program is detached
+    /// when the returned [`SocketFilter`] is dropped.
+    pub fn attach_socket<'a, S: AsFd>(&self, socket: &'a S) -> Result<SocketFilter<'a>> {
+        let socket = socket.as_fd();
+        let prog_fd = self.fd();
+
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_BPF,
+                &prog_fd as *const i32 as *const c_void,
+                mem::size_of::<i32>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        Ok(SocketFilter {
+            socket,
+            attached: true,
+        })
+    }
+
+    /// Detach this program from `target_fd`, as attached with `bpf_prog_attach()`, eg. by
+    /// [`Program::attach_sockmap()`] or another process.
+    pub fn detach_fd(&self, target_fd: i32, attach_type: ProgramAttachType) -> Result<()> {
+        let ret = unsafe { libbpf_sys::bpf_prog_detach2(self.fd(), target_fd, attach_type as u32) };
+        if ret != 0 {
+            Err(Error::System(errno::errno()))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Attach this program to [XDP](https://lwn.net/Articles/825998/)
     pub fn attach_xdp(&mut self, ifindex: i32) -> Result<Link> {
         let ptr = unsafe { libbpf_sys::bpf_program__attach_xdp(self.ptr, ifindex) };
@@ -350,4 +945,185 @@ impl Program {
             Ok(Link::new(ptr))
         }
     }
+
+    /// Attach this program to the interface called `ifname` with XDP. See [`ifinfo`] to look up
+    /// interfaces, or to re-attach when the interface is re-created.
+    pub fn attach_xdp_by_name<T: AsRef<str>>(&mut self, ifname: T) -> Result<Link> {
+        let ifindex = ifinfo::index(ifname)?;
+        self.attach_xdp(ifindex as i32)
+    }
+}
+
+bitflags! {
+    /// Flags for [`Program::attach_fd()`].
+    pub struct ProgramAttachFlags: u32 {
+        /// Let a program attached further down the cgroup hierarchy override this one.
+        const ALLOW_OVERRIDE = 1;
+        /// Let several programs be attached to the same cgroup and attach type.
+        const ALLOW_MULTI    = 1 << 1;
+    }
+}
+
+/// Input and options for [`Program::test_run()`].
+#[derive(Clone, Debug, Default)]
+pub struct TestRunOptions<'a> {
+    data_in: &'a [u8],
+    data_out_size: usize,
+    ctx_in: &'a [u8],
+    ctx_out_size: usize,
+    repeat: u32,
+}
+
+impl<'a> TestRunOptions<'a> {
+    /// Run the program on `data`, eg. a packet starting with its Ethernet header.
+    pub fn data_in(&mut self, data: &'a [u8]) -> &mut Self {
+        self.data_in = data;
+        self
+    }
+
+    /// Return up to `size` bytes of the data as the program left it, eg. a rewritten packet.
+    pub fn data_out_size(&mut self, size: usize) -> &mut Self {
+        self.data_out_size = size;
+        self
+    }
+
+    /// Run the program on the context `ctx`, eg. a `struct __sk_buff`. Fields the program type
+    /// doesn't let userspace set must be zero.
+    pub fn ctx_in(&mut self, ctx: &'a [u8]) -> &mut Self {
+        self.ctx_in = ctx;
+        self
+    }
+
+    /// Return up to `size` bytes of the context as the program left it.
+    pub fn ctx_out_size(&mut self, size: usize) -> &mut Self {
+        self.ctx_out_size = size;
+        self
+    }
+
+    /// Run the program `repeat` times. 0, the default, runs it once.
+    pub fn repeat(&mut self, repeat: u32) -> &mut Self {
+        self.repeat = repeat;
+        self
+    }
+}
+
+/// What [`Program::test_run()`] returns.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestRunOutput {
+    /// Value the program returned, eg. an XDP action
+    pub retval: u32,
+    /// Average duration of one run
+    pub duration: Duration,
+    /// Data as the program left it, up to [`TestRunOptions::data_out_size()`] bytes
+    pub data_out: Vec<u8>,
+    /// Context as the program left it, up to [`TestRunOptions::ctx_out_size()`] bytes
+    pub ctx_out: Vec<u8>,
+}
+
+/// A program attached with [`Program::attach_fd()`]. Detaches the program when dropped.
+pub struct ProgramAttachment {
+    prog_fd: OwnedFd,
+    /// `None` for attach types whose target isn't an fd, see [`Program::attach_fd()`]
+    target: Option<OwnedFd>,
+    /// `None` once detached
+    attach_type: Option<ProgramAttachType>,
+}
+
+impl ProgramAttachment {
+    /// Detach the program, reporting failure unlike dropping `self`.
+    pub fn detach(mut self) -> Result<()> {
+        self.detach_inner()
+    }
+
+    fn detach_inner(&mut self) -> Result<()> {
+        let attach_type = match self.attach_type.take() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        let ret = unsafe {
+            libbpf_sys::bpf_prog_detach2(
+                self.prog_fd.as_raw_fd(),
+                self.target.as_ref().map_or(0, |fd| fd.as_raw_fd()),
+                attach_type as u32,
+            )
+        };
+        if ret != 0 {
+            Err(Error::System(errno::errno()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for ProgramAttachment {
+    fn drop(&mut self) {
+        let _ = self.detach_inner();
+    }
+}
+
+/// A program attached to a socket with [`Program::attach_socket()`]. Detaches the program when
+/// dropped.
+pub struct SocketFilter<'a> {
+    socket: BorrowedFd<'a>,
+    attached: bool,
+}
+
+impl SocketFilter<'_> {
+    /// Detach the program, reporting failure unlike dropping `self`.
+    pub fn detach(mut self) -> Result<()> {
+        self.detach_inner()
+    }
+
+    fn detach_inner(&mut self) -> Result<()> {
+        if !self.attached {
+            return Ok(());
+        }
+        self.attached = false;
+
+        // `SO_DETACH_BPF` is an alias of `SO_DETACH_FILTER`, which ignores its value but still
+        // wants an int
+        let unused: i32 = 0;
+        let ret = unsafe {
+            libc::setsockopt(
+                self.socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_DETACH_FILTER,
+                &unused as *const i32 as *const c_void,
+                mem::size_of::<i32>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            Err(Error::System(errno::errno()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for SocketFilter<'_> {
+    fn drop(&mut self) {
+        let _ = self.detach_inner();
+    }
+}
+
+// Methods taking `&self` only read program state libbpf does not modify after load. Methods
+// that change state (attach, pin) require `&mut self`.
+unsafe impl Send for Program {}
+unsafe impl Sync for Program {}
+
+/// Use [`BorrowedFd::try_clone_to_owned`] to get an independently owned copy of the fd, eg. to
+/// send to another process.
+///
+/// # Panics
+///
+/// Panics if the program was not loaded, see [`OpenProgram::set_autoload`].
+impl AsFd for Program {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        let fd = self.fd();
+        assert!(fd >= 0, "program {} is not loaded", self.name);
+
+        // The fd is owned by libbpf and stays open for as long as `self` lives
+        unsafe { BorrowedFd::borrow_raw(fd) }
+    }
 }
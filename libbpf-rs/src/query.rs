@@ -14,10 +14,12 @@ use core::ffi::c_void;
 use std::convert::TryFrom;
 use std::mem::size_of;
 use std::os::raw::c_char;
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd};
 use std::string::String;
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use nix::{errno, unistd::close};
+use nix::{errno, libc, unistd::close};
 
 use crate::*;
 
@@ -371,3 +373,245 @@ gen_info_impl!(
     libbpf_sys::bpf_link_get_next_id,
     libbpf_sys::bpf_link_get_fd_by_id
 );
+
+/// Make the kernel account the run time and run count of every BPF program, as reported in
+/// [`ProgramInfo::run_time_ns`] and [`ProgramInfo::run_cnt`], for as long as the returned fd is
+/// open.
+///
+/// Accounting is off by default because of its overhead. It is also on while the
+/// `kernel.bpf_stats_enabled` sysctl is set.
+pub fn enable_stats() -> Result<OwnedFd> {
+    let fd = unsafe { libbpf_sys::bpf_enable_stats(libbpf_sys::BPF_STATS_RUN_TIME) };
+    if fd < 0 {
+        return Err(Error::System(errno::errno()));
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Programs attached to a target, see [`query_attached()`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttachedPrograms {
+    /// Flags the programs were attached with, eg. [`ProgramAttachFlags::ALLOW_MULTI`]
+    pub attach_flags: ProgramAttachFlags,
+    /// Ids of the attached programs, in the order they run. See [`ProgInfoIter`] for their
+    /// details.
+    pub prog_ids: Vec<u32>,
+}
+
+/// Returns the programs attached to `target_fd` as `attach_type` with `bpf_prog_attach()`, eg.
+/// to a cgroup or a sockmap, so attaching can be reconciled with what already is rather than
+/// failing with `EEXIST`.
+///
+/// Programs attached through BPF links are listed too. Only programs attached to the target
+/// itself are, not those a cgroup inherits from its ancestors.
+pub fn query_attached(target_fd: i32, attach_type: ProgramAttachType) -> Result<AttachedPrograms> {
+    let attach_type = attach_type as u32;
+    loop {
+        // Without a buffer, the kernel only reports how many programs there are
+        let mut attach_flags = 0;
+        let mut prog_cnt = 0;
+        let ret = unsafe {
+            libbpf_sys::bpf_prog_query(
+                target_fd,
+                attach_type,
+                0,
+                &mut attach_flags,
+                std::ptr::null_mut(),
+                &mut prog_cnt,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        let mut prog_ids = vec![0; prog_cnt as usize];
+        let ret = unsafe {
+            libbpf_sys::bpf_prog_query(
+                target_fd,
+                attach_type,
+                0,
+                &mut attach_flags,
+                prog_ids.as_mut_ptr(),
+                &mut prog_cnt,
+            )
+        };
+        match ret {
+            0 => {
+                prog_ids.truncate(prog_cnt as usize);
+                return Ok(AttachedPrograms {
+                    attach_flags: ProgramAttachFlags::from_bits_truncate(attach_flags),
+                    prog_ids,
+                });
+            }
+            // More programs were attached in between, try again with a bigger buffer
+            _ if errno::errno() == libc::ENOSPC => continue,
+            _ => return Err(Error::System(errno::errno())),
+        }
+    }
+}
+
+/// How much a program ran between two samples of a [`ProgramStatsWatcher`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgramStatsDelta {
+    pub id: u32,
+    pub name: String,
+    pub run_cnt: u64,
+    pub run_time_ns: u64,
+    /// Time between the two samples
+    pub elapsed: Duration,
+}
+
+impl ProgramStatsDelta {
+    /// Runs per second
+    pub fn run_rate(&self) -> f64 {
+        self.run_cnt as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Average duration of a run. `None` if the program did not run.
+    pub fn avg_run_time(&self) -> Option<Duration> {
+        if self.run_cnt == 0 {
+            None
+        } else {
+            Some(Duration::from_nanos(self.run_time_ns / self.run_cnt))
+        }
+    }
+
+    /// Share of one CPU spent running the program, eg. `0.01` for 1%. Compare against an
+    /// overhead budget to tell when a probe gets too expensive.
+    pub fn cpu_fraction(&self) -> f64 {
+        self.run_time_ns as f64 / self.elapsed.as_nanos() as f64
+    }
+}
+
+struct WatchedProgram {
+    fd: OwnedFd,
+    id: u32,
+    name: String,
+    last_sample: Instant,
+    run_cnt: u64,
+    run_time_ns: u64,
+}
+
+/// Samples [`ProgramInfo::run_cnt`] and [`ProgramInfo::run_time_ns`] of a set of programs and
+/// reports how much they changed in between.
+///
+/// The kernel only accounts run time while stats are enabled, see [`enable_stats()`].
+///
+/// For example, to warn whenever a program uses more than 1% of a CPU:
+/// ```no_run
+/// # use libbpf_rs::Program;
+/// # fn get_prog() -> Program { unimplemented!() }
+/// use std::time::Duration;
+/// use libbpf_rs::query::{enable_stats, ProgramStatsWatcher};
+///
+/// let _stats = enable_stats().expect("failed to enable stats");
+/// let mut watcher = ProgramStatsWatcher::default();
+/// watcher.add(&get_prog()).expect("failed to watch prog");
+/// watcher
+///     .run(Duration::from_secs(10), |deltas| {
+///         for delta in deltas.iter().filter(|d| d.cpu_fraction() > 0.01) {
+///             eprintln!("{} is over budget", delta.name);
+///         }
+///         true
+///     })
+///     .expect("failed to sample stats");
+/// ```
+#[derive(Default)]
+pub struct ProgramStatsWatcher {
+    progs: Vec<WatchedProgram>,
+}
+
+impl ProgramStatsWatcher {
+    /// Watch `prog`, starting from its current stats.
+    pub fn add(&mut self, prog: &Program) -> Result<&mut Self> {
+        let fd = prog
+            .as_fd()
+            .try_clone_to_owned()
+            .map_err(|e| Error::System(e.raw_os_error().unwrap_or(libc::EBADF)))?;
+
+        self.add_fd(fd)
+    }
+
+    /// Watch the program with id `id`, eg. one loaded by another process, starting from its
+    /// current stats.
+    pub fn add_id(&mut self, id: u32) -> Result<&mut Self> {
+        let fd = unsafe { libbpf_sys::bpf_prog_get_fd_by_id(id) };
+        if fd < 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        self.add_fd(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    fn add_fd(&mut self, fd: OwnedFd) -> Result<&mut Self> {
+        let info = prog_info(&fd)?;
+        self.progs.push(WatchedProgram {
+            fd,
+            id: info.id,
+            name: name_arr_to_string(&info.name, "(?)"),
+            last_sample: Instant::now(),
+            run_cnt: info.run_cnt,
+            run_time_ns: info.run_time_ns,
+        });
+
+        Ok(self)
+    }
+
+    /// Sample every watched program and return how much each ran since the previous sample,
+    /// or since it was added, in the order they were added.
+    pub fn sample(&mut self) -> Result<Vec<ProgramStatsDelta>> {
+        let mut deltas = Vec::with_capacity(self.progs.len());
+        for prog in &mut self.progs {
+            let info = prog_info(&prog.fd)?;
+            let now = Instant::now();
+
+            deltas.push(ProgramStatsDelta {
+                id: prog.id,
+                name: prog.name.clone(),
+                run_cnt: info.run_cnt.saturating_sub(prog.run_cnt),
+                run_time_ns: info.run_time_ns.saturating_sub(prog.run_time_ns),
+                elapsed: now.duration_since(prog.last_sample),
+            });
+
+            prog.last_sample = now;
+            prog.run_cnt = info.run_cnt;
+            prog.run_time_ns = info.run_time_ns;
+        }
+
+        Ok(deltas)
+    }
+
+    /// Call [`ProgramStatsWatcher::sample()`] every `interval` and pass the result to `cb`,
+    /// until `cb` returns `false` or sampling fails.
+    pub fn run<F>(&mut self, interval: Duration, mut cb: F) -> Result<()>
+    where
+        F: FnMut(&[ProgramStatsDelta]) -> bool,
+    {
+        loop {
+            thread::sleep(interval);
+            if !cb(&self.sample()?) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn prog_info(fd: &OwnedFd) -> Result<libbpf_sys::bpf_prog_info> {
+    // Zeroed for the same reason as in gen_info_impl!()
+    let mut info: libbpf_sys::bpf_prog_info = unsafe { std::mem::zeroed() };
+    let mut len = size_of::<libbpf_sys::bpf_prog_info>() as u32;
+
+    let ret = unsafe {
+        libbpf_sys::bpf_obj_get_info_by_fd(
+            fd.as_raw_fd(),
+            &mut info as *mut libbpf_sys::bpf_prog_info as *mut c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::System(errno::errno()));
+    }
+
+    Ok(info)
+}
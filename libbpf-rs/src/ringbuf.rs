@@ -1,24 +1,57 @@
 use core::ffi::c_void;
 use std::boxed::Box;
+use std::cell::Cell;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
 use std::ptr;
 use std::slice;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use nix::sys::epoll;
+use nix::{errno, libc, unistd};
 
 use crate::*;
 
+/// Alignment of every sample in a `ringbuf`, guaranteed by the kernel.
+const RINGBUF_SAMPLE_ALIGN: usize = 8;
+
+/// How long [`RingBuffer::drain()`] waits for another sample before it considers the ring buffers
+/// drained.
+const DRAIN_QUIET_PERIOD: Duration = Duration::from_millis(50);
+
 struct RingBufferCallback {
-    cb: Box<dyn FnMut(&[u8]) -> i32>,
+    cb: Box<dyn FnMut(&[u8]) -> Result<i32>>,
+    stats: RingBufferStats,
+    /// Error returned by a fallible callback, surfaced by the next `RingBuffer` method
+    error: Cell<Option<Error>>,
 }
 
 impl RingBufferCallback {
     fn new<F>(cb: F) -> Self
     where
-        F: FnMut(&[u8]) -> i32 + 'static,
+        F: FnMut(&[u8]) -> Result<i32> + 'static,
     {
-        RingBufferCallback { cb: Box::new(cb) }
+        RingBufferCallback {
+            cb: Box::new(cb),
+            stats: RingBufferStats::default(),
+            error: Cell::new(None),
+        }
     }
 }
 
+/// Counters kept by a [`RingBuffer`] since it was built, across all its ringbufs.
+///
+/// Counters only increase, so they can be exported as is, eg. as Prometheus counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RingBufferStats {
+    /// Samples passed to callbacks.
+    pub events: u64,
+    /// Bytes passed to callbacks, excluding the kernel's sample headers.
+    pub bytes: u64,
+    /// Callback invocations that returned a negative value.
+    pub callback_errors: u64,
+}
+
 /// Builds [`RingBuffer`] instances.
 ///
 /// `ringbuf`s are a special kind of [`Map`], used to transfer data between
@@ -44,21 +77,69 @@ impl RingBufferBuilder {
     ///
     /// The callback provides a raw byte slice. You may find libraries such as
     /// [`plain`](https://crates.io/crates/plain) helpful.
-    pub fn add<NewF>(&mut self, map: &Map, callback: NewF) -> Result<&mut Self>
+    pub fn add<NewF>(&mut self, map: &Map, mut callback: NewF) -> Result<&mut Self>
     where
         NewF: FnMut(&[u8]) -> i32 + 'static,
     {
+        self.add_callback(map, RingBufferCallback::new(move |data| Ok(callback(data))))
+    }
+
+    /// Same as [`RingBufferBuilder::add()`], but the callback returns a [`Result`]. An error
+    /// stops ring buffer consumption and is returned by the [`RingBuffer`] method that invoked
+    /// the callback, eg. [`RingBuffer::poll()`].
+    pub fn add_fallible<NewF>(&mut self, map: &Map, mut callback: NewF) -> Result<&mut Self>
+    where
+        NewF: FnMut(&[u8]) -> Result<()> + 'static,
+    {
+        self.add_callback(
+            map,
+            RingBufferCallback::new(move |data| callback(data).map(|_| 0)),
+        )
+    }
+
+    fn add_callback(&mut self, map: &Map, callback: RingBufferCallback) -> Result<&mut Self> {
         if map.map_type() != MapType::RingBuf {
             return Err(Error::InvalidInput("Must use a RingBuf map".into()));
         }
-        self.fd_callbacks
-            .push((map.fd(), RingBufferCallback::new(callback)));
+        self.fd_callbacks.push((map.fd(), callback));
         Ok(self)
     }
 
+    /// Add a new ringbuf `map` whose samples are all of type `T`, and an associated `callback`
+    /// which receives a reference to each sample.
+    ///
+    /// Samples are not copied. A sample smaller than `T` stops ring buffer consumption with
+    /// `EINVAL`.
+    ///
+    /// # Safety
+    ///
+    /// `T` must be valid for any bit pattern, such as the plain C types generated by `cargo
+    /// libbpf gen`.
+    pub unsafe fn add_typed<T, NewF>(&mut self, map: &Map, mut callback: NewF) -> Result<&mut Self>
+    where
+        T: 'static,
+        NewF: FnMut(&T) -> i32 + 'static,
+    {
+        if mem::align_of::<T>() > RINGBUF_SAMPLE_ALIGN {
+            return Err(Error::InvalidInput(format!(
+                "ringbuf samples are only {} byte aligned",
+                RINGBUF_SAMPLE_ALIGN
+            )));
+        }
+
+        self.add(map, move |data: &[u8]| {
+            if data.len() < mem::size_of::<T>() {
+                return -(errno::Errno::EINVAL as i32);
+            }
+
+            callback(&*(data.as_ptr() as *const T))
+        })
+    }
+
     /// Build a new [`RingBuffer`]. Must have added at least one ringbuf.
     pub fn build(self) -> Result<RingBuffer> {
         let mut cbs = vec![];
+        let fds: Vec<i32> = self.fd_callbacks.iter().map(|(fd, _)| *fd).collect();
         let mut ptr: *mut libbpf_sys::ring_buffer = ptr::null_mut();
         let c_sample_cb: libbpf_sys::ring_buffer_sample_fn = Some(Self::call_sample_cb);
 
@@ -101,14 +182,50 @@ impl RingBufferBuilder {
             ));
         }
 
-        Ok(RingBuffer { ptr, _cbs: cbs })
+        let mut ringbuf = RingBuffer {
+            ptr,
+            epoll_fd: -1,
+            _cbs: cbs,
+        };
+
+        // libbpf keeps its epoll fd to itself, so set up our own for event loop integration.
+        // Ringbuf map fds become readable whenever they have data.
+        ringbuf.epoll_fd = epoll::epoll_create1(epoll::EpollCreateFlags::EPOLL_CLOEXEC)
+            .map_err(|_| Error::System(errno::errno()))?;
+        for fd in fds {
+            let mut event = epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, fd as u64);
+            epoll::epoll_ctl(
+                ringbuf.epoll_fd,
+                epoll::EpollOp::EpollCtlAdd,
+                fd,
+                &mut event,
+            )
+            .map_err(|_| Error::System(errno::errno()))?;
+        }
+
+        Ok(ringbuf)
     }
 
     unsafe extern "C" fn call_sample_cb(ctx: *mut c_void, data: *mut c_void, size: u64) -> i32 {
         let callback_struct = ctx as *mut RingBufferCallback;
         let callback = (*callback_struct).cb.as_mut();
 
-        callback(slice::from_raw_parts(data as *const u8, size as usize))
+        let ret = match callback(slice::from_raw_parts(data as *const u8, size as usize)) {
+            Ok(ret) => ret,
+            Err(e) => {
+                (*callback_struct).error.set(Some(e));
+                -(errno::Errno::ECANCELED as i32)
+            }
+        };
+
+        let stats = &mut (*callback_struct).stats;
+        stats.events += 1;
+        stats.bytes += size;
+        if ret < 0 {
+            stats.callback_errors += 1;
+        }
+
+        ret
     }
 }
 
@@ -119,6 +236,7 @@ impl RingBufferBuilder {
 /// preferred over the `perf buffer`.
 pub struct RingBuffer {
     ptr: *mut libbpf_sys::ring_buffer,
+    epoll_fd: i32,
     #[allow(clippy::vec_box)]
     _cbs: Vec<Box<RingBufferCallback>>,
 }
@@ -131,6 +249,7 @@ impl RingBuffer {
         assert!(!self.ptr.is_null());
 
         let ret = unsafe { libbpf_sys::ring_buffer__poll(self.ptr, timeout.as_millis() as i32) };
+        self.check_callback_error()?;
 
         if ret < 0 {
             Err(Error::System(-ret))
@@ -146,6 +265,7 @@ impl RingBuffer {
         assert!(!self.ptr.is_null());
 
         let ret = unsafe { libbpf_sys::ring_buffer__consume(self.ptr) };
+        self.check_callback_error()?;
 
         if ret < 0 {
             Err(Error::System(-ret))
@@ -153,10 +273,70 @@ impl RingBuffer {
             Ok(())
         }
     }
+
+    /// Consume samples until none arrives for a short while, eg. to collect what programs
+    /// submitted before they were detached. Fails with `ETIMEDOUT` if samples keep arriving for
+    /// longer than `timeout`.
+    pub fn drain(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let events = self.stats().events;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            self.poll(DRAIN_QUIET_PERIOD.min(remaining))?;
+            if self.stats().events == events {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::System(libc::ETIMEDOUT));
+            }
+        }
+    }
+
+    /// Returns the counters accumulated since this `RingBuffer` was built.
+    pub fn stats(&self) -> RingBufferStats {
+        self._cbs
+            .iter()
+            .fold(RingBufferStats::default(), |total, cb| RingBufferStats {
+                events: total.events + cb.stats.events,
+                bytes: total.bytes + cb.stats.bytes,
+                callback_errors: total.callback_errors + cb.stats.callback_errors,
+            })
+    }
+
+    /// Returns the error a fallible callback stopped consumption with, if any.
+    fn check_callback_error(&self) -> Result<()> {
+        match self._cbs.iter().find_map(|cb| cb.error.take()) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns an epoll fd that becomes readable when any of the ring buffers has data, so a
+/// `RingBuffer` can be driven from an existing event loop: wait for readiness, then call
+/// [`RingBuffer::consume()`].
+impl AsFd for RingBuffer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // The fd is owned by `self`
+        unsafe { BorrowedFd::borrow_raw(self.epoll_fd) }
+    }
+}
+
+/// See the [`AsFd`] implementation.
+impl AsRawFd for RingBuffer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll_fd
+    }
 }
 
 impl Drop for RingBuffer {
     fn drop(&mut self) {
+        if self.epoll_fd >= 0 {
+            let _ = unistd::close(self.epoll_fd);
+        }
+
         unsafe {
             if !self.ptr.is_null() {
                 libbpf_sys::ring_buffer__free(self.ptr);
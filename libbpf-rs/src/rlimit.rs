@@ -0,0 +1,50 @@
+use nix::{errno, libc, sys::utsname};
+
+use crate::*;
+
+/// Set the `RLIMIT_MEMLOCK` soft and hard limits of the current process to `limit` bytes.
+///
+/// Before Linux 5.11, memory used by maps and programs is charged against `RLIMIT_MEMLOCK`,
+/// whose default is usually too low to load anything but trivial objects. Pass `u64::MAX` to
+/// remove the limit. Raising the hard limit requires `CAP_SYS_RESOURCE`.
+pub fn set_memlock_rlimit(limit: u64) -> Result<()> {
+    let rlimit = libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+
+    let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlimit) };
+    if ret != 0 {
+        return Err(Error::System(errno::errno()));
+    }
+
+    Ok(())
+}
+
+/// Returns true if the running kernel charges BPF memory against `RLIMIT_MEMLOCK`, ie. if
+/// [`set_memlock_rlimit()`] is needed. Kernels since 5.11 use memcg accounting instead.
+pub fn memlock_rlimit_needed() -> bool {
+    !uses_memcg_accounting(utsname::uname().release())
+}
+
+pub(crate) fn uses_memcg_accounting(release: &str) -> bool {
+    match util::kernel_version(release) {
+        Some(version) => version >= (5, 11),
+        // Assume the worst if the version can't be determined
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uses_memcg_accounting() {
+        assert!(!uses_memcg_accounting("4.18.0-305.el8.x86_64"));
+        assert!(!uses_memcg_accounting("5.10.0"));
+        assert!(uses_memcg_accounting("5.11.0-rc1"));
+        assert!(uses_memcg_accounting("6.1.0-13-amd64"));
+        assert!(!uses_memcg_accounting("garbage"));
+    }
+}
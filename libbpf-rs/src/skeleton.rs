@@ -2,9 +2,14 @@ use core::ffi::c_void;
 use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::boxed::Box;
 use std::ffi::CString;
-use std::mem::size_of;
+use std::fs;
+use std::mem::{align_of, size_of};
 use std::os::raw::c_char;
+use std::path::Path;
 use std::ptr;
+use std::time::Duration;
+
+use nix::{errno, libc};
 
 use libbpf_sys::{
     bpf_link, bpf_map, bpf_map_skeleton, bpf_object, bpf_object_skeleton, bpf_prog_skeleton,
@@ -18,6 +23,8 @@ struct MapSkelConfig {
     name: String,
     p: Box<*mut bpf_map>,
     mmaped: Option<Box<*mut c_void>>,
+    /// Copy of the map's contents if the kernel can't mmap it. `u64`s to keep it aligned.
+    shadow: Vec<u64>,
 }
 
 struct ProgSkelConfig {
@@ -34,6 +41,115 @@ pub struct ObjectSkeletonConfigBuilder<'a> {
     progs: Vec<ProgSkelConfig>,
 }
 
+/// How long the steps of bringing up a skeleton took, recorded by skeletons generated with
+/// libbpf-cargo's `timings` option. Steps not taken yet are zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SkelTimings {
+    /// Opening the object, including parsing its ELF and BTF
+    pub open: Duration,
+    /// Loading the object, including creating maps and verifying programs
+    pub load: Duration,
+    /// The last call to `attach()`
+    pub attach: Duration,
+}
+
+/// Name of the map [`check_pinned_layout()`] pins in a skeleton's pin directory
+const LAYOUT_MAP_NAME: &str = "libbpf_rs_layout";
+
+/// Check that maps pinned under `pin_dir` by an earlier run have the layout `layout_hash`
+/// describes, eg. the `LAYOUT_HASH` of a skeleton generated by libbpf-cargo, before loading an
+/// object that reuses them.
+///
+/// The hash is kept in a single-entry array map pinned as `libbpf_rs_layout` under `pin_dir`.
+/// The first call creates it, later ones fail with [`Error::InvalidInput`] if the hashes differ,
+/// rather than have the new object silently read and write maps of another layout. Maps pinned
+/// by versions predating the check can't be told apart from matching ones.
+pub fn check_pinned_layout<P: AsRef<Path>>(pin_dir: P, layout_hash: u64) -> Result<()> {
+    let pin_dir = pin_dir.as_ref();
+    let path = pin_dir.join(LAYOUT_MAP_NAME);
+    let cpath = util::path_to_cstring(&path)?;
+    let key = 0u32.to_ne_bytes();
+
+    let fd = unsafe { libbpf_sys::bpf_obj_get(cpath.as_ptr()) };
+    if fd < 0 {
+        let errno = errno::errno();
+        if errno != libc::ENOENT {
+            return Err(Error::System(errno));
+        }
+
+        fs::create_dir_all(pin_dir).map_err(|e| Error::Internal(e.to_string()))?;
+        let mut map = MapBuilder::new(MapType::Array)
+            .name("skel_layout")
+            .key_size(4)
+            .value_size(8)
+            .max_entries(1)
+            .build()?;
+        map.update(&key, &layout_hash.to_ne_bytes(), MapFlags::ANY)?;
+        return map.pin(&path);
+    }
+
+    let map = Map::new(
+        fd,
+        LAYOUT_MAP_NAME.to_string(),
+        libbpf_sys::BPF_MAP_TYPE_ARRAY,
+        4,
+        8,
+        ptr::null_mut(),
+    );
+    // Make sure lookups won't write past the value buffer if something else is pinned there
+    let info = map.info()?;
+    if info.type_ != libbpf_sys::BPF_MAP_TYPE_ARRAY || info.key_size != 4 || info.value_size != 8 {
+        return Err(Error::InvalidInput(format!(
+            "{} is not a layout map",
+            path.display()
+        )));
+    }
+
+    let pinned = match map.lookup(&key, MapFlags::ANY)? {
+        Some(value) => {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(&value);
+            u64::from_ne_bytes(buf)
+        }
+        None => return Err(Error::Internal(format!("{} is empty", path.display()))),
+    };
+
+    if pinned != layout_hash {
+        return Err(Error::InvalidInput(format!(
+            "maps pinned under {} have layout {:#x}, expected {:#x}; remove them to start over",
+            pin_dir.display(),
+            pinned,
+            layout_hash
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the name libbpf gives the map backing the datasec `section`, eg. `.bss`, of an object
+/// named `obj_name`: the section name, prefixed with as much of the object name as fits.
+pub fn internal_map_name(obj_name: &str, section: &str) -> String {
+    // Kernel map names are at most BPF_OBJ_NAME_LEN - 1 characters, and libbpf always leaves
+    // room for at least 7 characters of section name
+    let prefix_len = (libbpf_sys::BPF_OBJ_NAME_LEN as usize - 1)
+        .saturating_sub(section.len().max(7))
+        .min(obj_name.len());
+
+    // libbpf replaces characters the kernel rejects
+    obj_name
+        .bytes()
+        .take(prefix_len)
+        .chain(section.bytes())
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b == b'_' || b == b'.' {
+                b as char
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
 fn str_to_cstring_and_pool(s: &str, pool: &mut Vec<CString>) -> Result<*const c_char> {
     let cname = util::str_to_cstring(s)?;
     let p = cname.as_ptr();
@@ -78,6 +194,7 @@ impl<'a> ObjectSkeletonConfigBuilder<'a> {
             name: name.as_ref().to_string(),
             p: Box::new(ptr::null_mut()),
             mmaped: m,
+            shadow: Vec::new(),
         });
 
         self
@@ -245,6 +362,114 @@ impl<'a> ObjectSkeletonConfig<'a> {
         )
     }
 
+    /// Returns a pointer to the contents of the datasec map (`.data`, `.bss`, ...) at the
+    /// specified `index`.
+    ///
+    /// This is the `mmaped` pointer, unless the object is loaded and the kernel can't mmap
+    /// array maps (before 5.5). In that case the contents are copied into a buffer owned by
+    /// `self` with `bpf_map_lookup_elem()` on every call, and writes to the buffer only reach
+    /// the map with [`ObjectSkeletonConfig::map_datasec_flush`].
+    ///
+    /// Warning: the returned pointer is only valid while the `ObjectSkeletonConfig` is alive.
+    pub fn map_datasec_ptr(&mut self, index: usize) -> Result<*mut c_void> {
+        let mmaped = self.map_mmap_ptr(index)?;
+        if !mmaped.is_null() {
+            return Ok(mmaped);
+        }
+
+        let map = &mut self.maps[index];
+        let fd = unsafe { libbpf_sys::bpf_map__fd(*map.p) };
+        if fd < 0 {
+            return Err(Error::Internal(format!("Map {} is not loaded", map.name)));
+        }
+
+        if map.shadow.is_empty() {
+            // bpf_map__def can only return null if passed null
+            let value_size = unsafe { (*libbpf_sys::bpf_map__def(*map.p)).value_size } as usize;
+            map.shadow = vec![0; (value_size + size_of::<u64>() - 1) / size_of::<u64>()];
+        }
+
+        // Datasec maps are single element arrays
+        let key = 0u32;
+        let ret = unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                fd,
+                &key as *const u32 as *const c_void,
+                map.shadow.as_mut_ptr() as *mut c_void,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::System(errno::errno()));
+        }
+
+        Ok(map.shadow.as_mut_ptr() as *mut c_void)
+    }
+
+    /// Checks that `ptr`, as returned by [`ObjectSkeletonConfig::map_mmap_ptr`] or
+    /// [`ObjectSkeletonConfig::map_datasec_ptr`] for the map at the specified `index`, is non-null,
+    /// aligned for `T` and points to at least `size_of::<T>()` bytes, and casts it to `T`.
+    pub fn check_map_ptr<T>(&self, index: usize, ptr: *mut c_void) -> Result<*mut T> {
+        if index >= self.maps.len() {
+            return Err(Error::Internal(format!("Invalid map index: {}", index)));
+        }
+
+        let map = &self.maps[index];
+        if ptr.is_null() {
+            return Err(Error::Internal(format!("Map {} is not mapped", map.name)));
+        }
+        if ptr as usize % align_of::<T>() != 0 {
+            return Err(Error::Internal(format!(
+                "Map {} is not aligned to {} bytes",
+                map.name,
+                align_of::<T>()
+            )));
+        }
+
+        // bpf_map__def can only return null if passed null
+        let value_size = unsafe { (*libbpf_sys::bpf_map__def(*map.p)).value_size } as usize;
+        if value_size < size_of::<T>() {
+            return Err(Error::Internal(format!(
+                "Map {} is {} bytes, expected at least {}",
+                map.name,
+                value_size,
+                size_of::<T>()
+            )));
+        }
+
+        Ok(ptr as *mut T)
+    }
+
+    /// Write the buffer returned by [`ObjectSkeletonConfig::map_datasec_ptr`] for the map at
+    /// the specified `index` back to the map. No-op if the map is mmaped, as writes then go to
+    /// the map directly.
+    pub fn map_datasec_flush(&mut self, index: usize) -> Result<()> {
+        if !self.map_mmap_ptr(index)?.is_null() {
+            return Ok(());
+        }
+
+        let map = &self.maps[index];
+        // Never read, so never changed
+        if map.shadow.is_empty() {
+            return Ok(());
+        }
+
+        let fd = unsafe { libbpf_sys::bpf_map__fd(*map.p) };
+        let key = 0u32;
+        let ret = unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                fd,
+                &key as *const u32 as *const c_void,
+                map.shadow.as_ptr() as *const c_void,
+                libbpf_sys::BPF_ANY as u64,
+            )
+        };
+        if ret != 0 {
+            Err(Error::System(errno::errno()))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Returns the link pointer for a prog at the specified `index`.
     ///
     /// The index is determined by the order in which the prog was passed to
@@ -289,3 +514,22 @@ impl<'a> Drop for ObjectSkeletonConfig<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_map_name() {
+        assert_eq!(internal_map_name("prog_bpf", ".bss"), "prog_bpf.bss");
+        assert_eq!(
+            internal_map_name("runqslower_bpf", ".rodata"),
+            "runqslow.rodata"
+        );
+        assert_eq!(
+            internal_map_name("runqslower_bpf", ".kconfig"),
+            "runqslo.kconfig"
+        );
+        assert_eq!(internal_map_name("a-b", ".data"), "a_b.data");
+    }
+}
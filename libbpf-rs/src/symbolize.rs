@@ -0,0 +1,386 @@
+//! Turn stack trace addresses into human readable frames
+//!
+//! Kernel addresses are resolved with [`Ksyms`]. User addresses are resolved by finding the
+//! file mapped at the address in `/proc/<pid>/maps` and looking the address up in that file's
+//! ELF symbol tables.
+//!
+//! ```no_run
+//! use libbpf_rs::symbolize::{KernelSymbolizer, UserSymbolizer};
+//!
+//! # let (kstack, ustack, pid): (Vec<u64>, Vec<u64>, u32) = (vec![], vec![], 0);
+//! let kernel = KernelSymbolizer::new().expect("failed to load kallsyms");
+//! for addr in kstack {
+//!     println!("{}", kernel.symbolize(addr));
+//! }
+//!
+//! let mut user = UserSymbolizer::new(pid).expect("failed to read process maps");
+//! for addr in ustack {
+//!     println!("{}", user.symbolize(addr));
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ksyms::Ksyms;
+use crate::*;
+
+// From `include/uapi/linux/elf.h`
+const ELFCLASS64: u8 = 2;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+const STT_FUNC: u8 = 2;
+const SYM64_SIZE: usize = 24;
+
+/// A single symbolized stack frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub addr: u64,
+    /// Name of the symbol containing `addr`, if one was found
+    pub symbol: Option<String>,
+    /// Offset of `addr` from the start of `symbol`
+    pub offset: u64,
+    /// Kernel module or path of the mapped file `addr` belongs to
+    pub module: Option<String>,
+}
+
+impl Frame {
+    fn unknown(addr: u64) -> Self {
+        Frame {
+            addr,
+            symbol: None,
+            offset: 0,
+            module: None,
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some(symbol) => write!(f, "{}+{:#x}", symbol, self.offset)?,
+            None => write!(f, "{:#x}", self.addr)?,
+        }
+
+        if let Some(module) = &self.module {
+            write!(f, " [{}]", module)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Symbolizes kernel addresses.
+pub struct KernelSymbolizer {
+    ksyms: Ksyms,
+}
+
+impl KernelSymbolizer {
+    /// Create a symbolizer from `/proc/kallsyms`.
+    pub fn new() -> Result<Self> {
+        Ok(Self::from_ksyms(Ksyms::load()?))
+    }
+
+    pub fn from_ksyms(ksyms: Ksyms) -> Self {
+        KernelSymbolizer { ksyms }
+    }
+
+    pub fn symbolize(&self, addr: u64) -> Frame {
+        match self.ksyms.find_by_addr(addr) {
+            Some(sym) => Frame {
+                addr,
+                symbol: Some(sym.name.clone()),
+                offset: addr - sym.addr,
+                module: sym.module.clone(),
+            },
+            None => Frame::unknown(addr),
+        }
+    }
+}
+
+/// A file backed entry of `/proc/<pid>/maps`.
+#[derive(Clone, Debug, PartialEq)]
+struct Mapping {
+    start: u64,
+    end: u64,
+    /// Offset into `path` that `start` maps
+    offset: u64,
+    path: PathBuf,
+}
+
+/// Symbols of an ELF file and the layout needed to translate file offsets into them.
+#[derive(Debug, Default)]
+struct ElfSymbols {
+    /// `(p_offset, p_filesz, p_vaddr)` of each `PT_LOAD` segment
+    segments: Vec<(u64, u64, u64)>,
+    /// `(st_value, st_size, name)` of each function, sorted by address
+    syms: Vec<(u64, u64, String)>,
+}
+
+/// Symbolizes addresses in the address space of a process.
+///
+/// The process' mappings are read once, on creation. ELF files are parsed the first time an
+/// address inside them is symbolized and cached afterwards.
+pub struct UserSymbolizer {
+    mappings: Vec<Mapping>,
+    /// `None` if the file could not be parsed
+    files: HashMap<PathBuf, Option<ElfSymbols>>,
+}
+
+impl UserSymbolizer {
+    pub fn new(pid: u32) -> Result<Self> {
+        let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).map_err(|e| {
+            match e.raw_os_error() {
+                Some(errno) => Error::System(errno),
+                None => Error::Internal(e.to_string()),
+            }
+        })?;
+
+        Ok(UserSymbolizer {
+            mappings: parse_maps(&maps),
+            files: HashMap::new(),
+        })
+    }
+
+    pub fn symbolize(&mut self, addr: u64) -> Frame {
+        let mapping = match self
+            .mappings
+            .iter()
+            .find(|m| m.start <= addr && addr < m.end)
+        {
+            Some(m) => m,
+            None => return Frame::unknown(addr),
+        };
+
+        let module = Some(mapping.path.to_string_lossy().into_owned());
+        let file_offset = addr - mapping.start + mapping.offset;
+
+        let elf = self
+            .files
+            .entry(mapping.path.clone())
+            .or_insert_with(|| ElfSymbols::load(&mapping.path).ok());
+
+        let sym = elf.as_ref().and_then(|elf| {
+            let vaddr = elf.file_offset_to_vaddr(file_offset)?;
+            let (start, _, name) = elf.find(vaddr)?;
+            Some((name.clone(), vaddr - start))
+        });
+
+        match sym {
+            Some((symbol, offset)) => Frame {
+                addr,
+                symbol: Some(symbol),
+                offset,
+                module,
+            },
+            None => Frame {
+                module,
+                ..Frame::unknown(addr)
+            },
+        }
+    }
+}
+
+/// Returns the file backed entries of a `/proc/<pid>/maps` file.
+fn parse_maps(contents: &str) -> Vec<Mapping> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            // eg. `7f0000000000-7f0000021000 r-xp 00001000 fd:01 1234    /usr/lib/libc.so.6`
+            let mut fields = line.splitn(6, ' ');
+            let range = fields.next()?;
+            let _perms = fields.next()?;
+            let offset = fields.next()?;
+            let _dev = fields.next()?;
+            let _inode = fields.next()?;
+            let path = fields.next()?.trim_start();
+
+            // Skip anonymous and special mappings, eg. `[stack]`
+            if !path.starts_with('/') {
+                return None;
+            }
+
+            let mut range = range.splitn(2, '-');
+            let start = u64::from_str_radix(range.next()?, 16).ok()?;
+            let end = u64::from_str_radix(range.next()?, 16).ok()?;
+
+            Some(Mapping {
+                start,
+                end,
+                offset: u64::from_str_radix(offset, 16).ok()?,
+                path: PathBuf::from(path),
+            })
+        })
+        .collect()
+}
+
+fn read_u16(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(data.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(data.get(off..off + 8)?.try_into().ok()?))
+}
+
+impl ElfSymbols {
+    fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).map_err(|e| match e.raw_os_error() {
+            Some(errno) => Error::System(errno),
+            None => Error::Internal(e.to_string()),
+        })?;
+
+        Self::parse(&data)
+            .ok_or_else(|| Error::InvalidInput(format!("invalid ELF file: {}", path.display())))
+    }
+
+    /// Only native endian ELF64 files are supported, which is all a 64-bit process can map.
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.get(0..4)? != b"\x7fELF" || *data.get(4)? != ELFCLASS64 {
+            return None;
+        }
+
+        let phoff = read_u64(data, 0x20)? as usize;
+        let shoff = read_u64(data, 0x28)? as usize;
+        let phentsize = read_u16(data, 0x36)? as usize;
+        let phnum = read_u16(data, 0x38)? as usize;
+        let shentsize = read_u16(data, 0x3a)? as usize;
+        let shnum = read_u16(data, 0x3c)? as usize;
+
+        let mut elf = ElfSymbols::default();
+
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if read_u32(data, ph)? == PT_LOAD {
+                elf.segments.push((
+                    read_u64(data, ph + 8)?,
+                    read_u64(data, ph + 32)?,
+                    read_u64(data, ph + 16)?,
+                ));
+            }
+        }
+
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            let sh_type = read_u32(data, sh + 4)?;
+            if sh_type != SHT_SYMTAB && sh_type != SHT_DYNSYM {
+                continue;
+            }
+
+            let sym_off = read_u64(data, sh + 24)? as usize;
+            let sym_size = read_u64(data, sh + 32)? as usize;
+            let strtab = shoff + read_u32(data, sh + 40)? as usize * shentsize;
+            let str_off = read_u64(data, strtab + 24)? as usize;
+            let str_size = read_u64(data, strtab + 32)? as usize;
+            let strs = data.get(str_off..str_off + str_size)?;
+
+            for sym in (sym_off..sym_off + sym_size).step_by(SYM64_SIZE) {
+                let info = *data.get(sym + 4)?;
+                let value = read_u64(data, sym + 8)?;
+                if info & 0xf != STT_FUNC || value == 0 {
+                    continue;
+                }
+
+                let name_off = read_u32(data, sym)? as usize;
+                let name = strs.get(name_off..)?;
+                let name = &name[..name.iter().position(|c| *c == 0)?];
+
+                elf.syms.push((
+                    value,
+                    read_u64(data, sym + 16)?,
+                    String::from_utf8_lossy(name).into_owned(),
+                ));
+            }
+        }
+
+        // .symtab and .dynsym overlap, so drop duplicates
+        elf.syms.sort();
+        elf.syms.dedup_by_key(|sym| sym.0);
+
+        Some(elf)
+    }
+
+    fn file_offset_to_vaddr(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(p_offset, p_filesz, _)| *p_offset <= offset && offset < p_offset + p_filesz)
+            .map(|(p_offset, _, p_vaddr)| offset - p_offset + p_vaddr)
+    }
+
+    fn find(&self, vaddr: u64) -> Option<&(u64, u64, String)> {
+        let idx = match self.syms.binary_search_by_key(&vaddr, |sym| sym.0) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+
+        let sym = &self.syms[idx];
+        // Symbols without a size are assumed to extend to the next one
+        if sym.1 == 0 || vaddr < sym.0 + sym.1 {
+            Some(sym)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = "\
+55d0c0a00000-55d0c0a21000 r-xp 00002000 fd:01 1234                       /usr/bin/my prog
+7ffd1c2a1000-7ffd1c2c2000 rw-p 00000000 00:00 0                          [stack]
+7f0000000000-7f0000001000 rw-p 00000000 00:00 0
+";
+        assert_eq!(
+            parse_maps(maps),
+            vec![Mapping {
+                start: 0x55d0c0a00000,
+                end: 0x55d0c0a21000,
+                offset: 0x2000,
+                path: PathBuf::from("/usr/bin/my prog"),
+            }]
+        );
+    }
+
+    #[inline(never)]
+    #[no_mangle]
+    extern "C" fn libbpf_rs_symbolize_test_marker() {}
+
+    #[test]
+    fn test_symbolize_self() {
+        let addr = libbpf_rs_symbolize_test_marker as *const () as u64;
+
+        let mut symbolizer = UserSymbolizer::new(std::process::id()).expect("failed to read maps");
+        let frame = symbolizer.symbolize(addr);
+        assert_eq!(
+            frame.symbol.as_deref(),
+            Some("libbpf_rs_symbolize_test_marker")
+        );
+        assert_eq!(frame.offset, 0);
+        assert!(frame.module.is_some());
+
+        assert_eq!(symbolizer.symbolize(0), Frame::unknown(0));
+    }
+
+    #[test]
+    fn test_kernel_symbolize() {
+        let ksyms = Ksyms::parse("ffffffff81000000 T _text\nffffffffc0000000 t f\t[mod]\n")
+            .expect("failed to parse");
+        let symbolizer = KernelSymbolizer::from_ksyms(ksyms);
+
+        let frame = symbolizer.symbolize(0xffffffffc0000010);
+        assert_eq!(frame.to_string(), "f+0x10 [mod]");
+        assert_eq!(symbolizer.symbolize(0x10).to_string(), "0x10");
+    }
+}
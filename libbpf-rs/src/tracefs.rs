@@ -0,0 +1,137 @@
+//! Query tracefs, where the kernel lists its tracepoints
+//!
+//! For example, to check that a tracepoint exists before attaching to it:
+//! ```no_run
+//! use libbpf_rs::tracefs;
+//!
+//! if !tracefs::tracepoint_exists("sched", "sched_switch").expect("failed to read tracefs") {
+//!     eprintln!("sched:sched_switch is not available on this kernel");
+//! }
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use nix::libc;
+
+use crate::*;
+
+/// Where tracefs is usually found, on its own or under debugfs, if `/proc/self/mounts` does not
+/// list it
+const TRACEFS_PATHS: &[&str] = &["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// A kernel tracepoint, as passed to [`Program::attach_tracepoint()`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tracepoint {
+    /// eg. `sched`
+    pub category: String,
+    /// eg. `sched_switch`
+    pub name: String,
+}
+
+fn io_error(e: io::Error) -> Error {
+    match e.raw_os_error() {
+        Some(errno) => Error::System(errno),
+        None => Error::Internal(e.to_string()),
+    }
+}
+
+/// Returns the first tracefs mount point listed in `mounts`, in `/proc/self/mounts` format
+fn parse_mounts(mounts: &str) -> Option<PathBuf> {
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(_), Some(dir), Some("tracefs")) => Some(PathBuf::from(dir)),
+            _ => None,
+        }
+    })
+}
+
+/// Returns where tracefs is mounted.
+///
+/// Mounts listed in `/proc/self/mounts` take precedence over the usual locations. Fails with
+/// `ENOENT` if tracefs can't be found.
+pub fn root() -> Result<PathBuf> {
+    if let Ok(mounts) = fs::read_to_string("/proc/self/mounts") {
+        if let Some(dir) = parse_mounts(&mounts) {
+            return Ok(dir);
+        }
+    }
+
+    // debugfs mounts tracefs under `tracing` on first access, so it may not be listed yet
+    TRACEFS_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|dir| dir.join("events").is_dir())
+        .ok_or(Error::System(libc::ENOENT))
+}
+
+/// Returns every tracepoint of the running kernel, sorted by category and name.
+pub fn tracepoints() -> Result<Vec<Tracepoint>> {
+    let events = root()?.join("events");
+    let mut tracepoints = Vec::new();
+
+    for category in fs::read_dir(&events).map_err(io_error)? {
+        let category = category.map_err(io_error)?;
+        if !category.file_type().map_err(io_error)?.is_dir() {
+            continue;
+        }
+
+        for event in fs::read_dir(category.path()).map_err(io_error)? {
+            let event = event.map_err(io_error)?;
+            // Categories also hold files like `enable`, events are the directories with a format
+            if !event.path().join("format").is_file() {
+                continue;
+            }
+
+            tracepoints.push(Tracepoint {
+                category: category.file_name().to_string_lossy().into_owned(),
+                name: event.file_name().to_string_lossy().into_owned(),
+            });
+        }
+    }
+
+    tracepoints.sort();
+    Ok(tracepoints)
+}
+
+/// Returns whether the running kernel has the tracepoint `category:name`.
+pub fn tracepoint_exists<T: AsRef<str>>(category: T, name: T) -> Result<bool> {
+    let (category, name) = (category.as_ref(), name.as_ref());
+    for part in &[category, name] {
+        if part.is_empty() || part.contains('/') || *part == "." || *part == ".." {
+            return Err(Error::InvalidInput(format!(
+                "invalid tracepoint {}:{}",
+                category, name
+            )));
+        }
+    }
+
+    let path = root()?.join("events").join(category).join(name);
+    Ok(path.join("format").is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = "sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0
+debugfs /sys/kernel/debug debugfs rw,nosuid,nodev,noexec,relatime 0 0
+tracefs /sys/kernel/tracing tracefs rw,nosuid,nodev,noexec,relatime 0 0
+";
+        assert_eq!(
+            parse_mounts(mounts),
+            Some(PathBuf::from("/sys/kernel/tracing"))
+        );
+        assert_eq!(parse_mounts("proc /proc proc rw 0 0\n"), None);
+    }
+
+    #[test]
+    fn test_tracepoint_exists_invalid() {
+        assert!(tracepoint_exists("sched", "../sched").is_err());
+        assert!(tracepoint_exists("", "sched_switch").is_err());
+    }
+}
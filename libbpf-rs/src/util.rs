@@ -1,4 +1,5 @@
 use std::ffi::{CStr, CString};
+use std::fs;
 use std::os::raw::c_char;
 use std::path::Path;
 
@@ -35,3 +36,186 @@ pub fn ptr_to_option<T>(p: *mut T) -> Option<*mut T> {
         Some(p)
     }
 }
+
+/// Match `s` against a shell style `pattern`, where `*` matches any run of characters and `?`
+/// any single one. Like libbpf, there are no character classes or escapes.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    // Position after the last `*` seen, and where in `s` it started matching
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, i));
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character and retry from there
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    backtrack = Some((star_p, star_i + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns the number of possible CPUs, which per-CPU maps hold a value for each of
+pub fn num_possible_cpus() -> Result<usize> {
+    let ret = unsafe { libbpf_sys::libbpf_num_possible_cpus() };
+    if ret < 0 {
+        // Error code is returned negative, flip to positive to match errno
+        Err(Error::System(-ret))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Returns the CPUs that are currently online
+pub fn online_cpus() -> Result<Vec<u32>> {
+    let list = fs::read_to_string("/sys/devices/system/cpu/online").map_err(|e| {
+        match e.raw_os_error() {
+            Some(errno) => Error::System(errno),
+            None => Error::Internal(e.to_string()),
+        }
+    })?;
+
+    parse_cpu_list(&list)
+}
+
+/// Parse a kernel CPU list, eg. `0-3,8,10-11`
+pub fn parse_cpu_list(list: &str) -> Result<Vec<u32>> {
+    let invalid = || Error::InvalidInput(format!("invalid CPU list: {}", list));
+    let mut cpus = Vec::new();
+
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let start: u32 = bounds.next().unwrap().parse().map_err(|_| invalid())?;
+        let end: u32 = match bounds.next() {
+            Some(end) => end.parse().map_err(|_| invalid())?,
+            None => start,
+        };
+        if end < start {
+            return Err(invalid());
+        }
+        cpus.extend(start..=end);
+    }
+
+    Ok(cpus)
+}
+
+/// Returns the `(major, minor)` version of a kernel release, eg. `(5, 15)` for `5.15.0-91-generic`
+pub fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut version = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>());
+
+    match (version.next(), version.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Some((major, minor)),
+        _ => None,
+    }
+}
+
+/// Levenshtein distance between `a` and `b`, in characters
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the prefix of `a` seen so far to each prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let subst = diag + if ca == cb { 0 } else { 1 };
+            diag = row[j + 1];
+            row[j + 1] = subst.min(row[j] + 1).min(diag + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Returns up to `max` of `candidates` closest to `name`, closest first, leaving out those too
+/// different to plausibly be a typo or rename of it
+pub fn closest_matches<'a, I>(name: &str, candidates: I, max: usize) -> Vec<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let threshold = (name.chars().count() / 3).max(2);
+    let mut matches: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(dist, _)| *dist <= threshold)
+        .collect();
+    matches.sort_unstable();
+    matches.dedup();
+
+    matches.into_iter().take(max).map(|(_, c)| c).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("tcp_*", "tcp_sendmsg"));
+        assert!(glob_match("tcp_*", "tcp_"));
+        assert!(!glob_match("tcp_*", "udp_sendmsg"));
+        assert!(glob_match("*_send*", "tcp_sendmsg"));
+        assert!(glob_match("tcp_?endmsg", "tcp_sendmsg"));
+        assert!(!glob_match("tcp_?endmsg", "tcp_endmsg"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0\n").unwrap(), vec![0]);
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert!(parse_cpu_list("").unwrap().is_empty());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a-b").is_err());
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("tcp_sendmsg", "tcp_sendmsg"), 0);
+        assert_eq!(edit_distance("tcp_sendmsg", "tcp_sendmesg"), 1);
+    }
+
+    #[test]
+    fn test_closest_matches() {
+        let funcs = ["tcp_sendmsg", "tcp_sendpage", "udp_sendmsg", "do_exit"];
+        assert_eq!(
+            closest_matches("tcp_sndmsg", funcs.iter().copied(), 3),
+            vec!["tcp_sendmsg", "udp_sendmsg"]
+        );
+        assert_eq!(
+            closest_matches("tcp_sndmsg", funcs.iter().copied(), 1),
+            vec!["tcp_sendmsg"]
+        );
+        assert!(closest_matches("vfs_read", funcs.iter().copied(), 3).is_empty());
+    }
+}
@@ -1,15 +1,30 @@
 use std::collections::HashSet;
 use std::fs;
 use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsFd, AsRawFd};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
+use std::sync::Arc;
 use std::time::Duration;
 
 use nix::errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd;
 use plain::Plain;
 use scopeguard::defer;
 
-use libbpf_rs::{Iter, MapFlags, Object, ObjectBuilder};
+use libbpf_rs::btf::{Btf, BtfKind};
+use libbpf_rs::query::{enable_stats, query_attached, ProgramStatsWatcher};
+use libbpf_rs::{cgroups, tracefs};
+use libbpf_rs::{
+    check_pinned_layout, memlock_rlimit_needed, set_memlock_rlimit, AttachFilter, AttachSpec,
+    Error, EventSource, EventSourceKind, Iter, KernelCaps, Link, LpmKey, Map, MapBuilder, MapFlags,
+    MapType, Object, ObjectBuilder, PerfBufferBuilder, ProgramAttachFlags, ProgramAttachType,
+    ProgramType, RetryPolicy, SectionHandler, TestRunOptions,
+};
 
 fn get_test_object_path(filename: &str) -> PathBuf {
     let mut path = PathBuf::new();
@@ -51,6 +66,32 @@ fn bump_rlimit_mlock() {
     );
 }
 
+#[test]
+fn test_object_bump_memlock_rlimit() {
+    set_memlock_rlimit(128 << 20).expect("failed to set RLIMIT_MEMLOCK");
+
+    let obj_path = get_test_object_path("runqslower.bpf.o");
+    let mut builder = ObjectBuilder::default();
+    builder
+        .bump_memlock_rlimit(true)
+        .open_file(obj_path)
+        .expect("failed to open object")
+        .load()
+        .expect("failed to load object");
+
+    if memlock_rlimit_needed() {
+        let mut rlimit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlimit) },
+            0
+        );
+        assert_eq!(rlimit.rlim_cur, libc::RLIM_INFINITY);
+    }
+}
+
 #[test]
 fn test_object_build_and_load() {
     bump_rlimit_mlock();
@@ -70,6 +111,26 @@ fn test_object_build_from_memory() {
     assert!(name == "memory name");
 }
 
+#[test]
+fn test_object_build_with_opts() {
+    bump_rlimit_mlock();
+
+    let obj_path = get_test_object_path("runqslower.bpf.o");
+    let mut builder = ObjectBuilder::default();
+    builder
+        .relaxed_core_relocs(true)
+        .kernel_log_level(1)
+        .pin_root_path("/sys/fs/bpf")
+        .expect("failed to set pin root path")
+        .btf_custom_path("/sys/kernel/btf/vmlinux")
+        .expect("failed to set custom BTF path");
+    builder
+        .open_file(obj_path)
+        .expect("failed to open object")
+        .load()
+        .expect("failed to load object");
+}
+
 #[test]
 fn test_object_name() {
     let obj_path = get_test_object_path("runqslower.bpf.o");
@@ -80,6 +141,86 @@ fn test_object_name() {
     assert!(obj_name == "test name");
 }
 
+#[test]
+fn test_object_btf() {
+    bump_rlimit_mlock();
+
+    let obj = get_test_object("runqslower.bpf.o");
+    let btf = obj.btf().expect("object has no BTF");
+
+    let event = btf
+        .find_by_name_kind("event", BtfKind::Struct)
+        .expect("failed to find struct event");
+    assert_eq!(btf.type_kind(event).unwrap(), BtfKind::Struct);
+    assert_eq!(btf.type_name(event).unwrap().as_deref(), Some("event"));
+    assert_eq!(btf.size_of(event).unwrap(), 32);
+    assert_eq!(btf.align_of(event).unwrap(), 8);
+
+    let members = btf.members(event).expect("failed to get members");
+    let names: Vec<_> = members.iter().map(|m| m.name.as_deref()).collect();
+    assert_eq!(names, vec![Some("task"), Some("delta_us"), Some("pid")]);
+    assert_eq!(members[1].bit_offset, 128);
+    assert_eq!(btf.size_of(members[1].type_id).unwrap(), 8);
+
+    assert!(btf.find_by_name("asdf").is_none());
+    assert!(btf.members(members[2].type_id).is_err());
+    assert!(btf.type_ids().count() > 0);
+}
+
+#[test]
+fn test_btf_vmlinux() {
+    let btf = Btf::from_vmlinux().expect("failed to load kernel BTF");
+    let task = btf
+        .find_by_name_kind("task_struct", BtfKind::Struct)
+        .expect("failed to find task_struct");
+    assert!(btf
+        .members(task)
+        .expect("failed to get members")
+        .iter()
+        .any(|m| m.name.as_deref() == Some("pid")));
+}
+
+#[test]
+fn test_kernel_caps() {
+    bump_rlimit_mlock();
+
+    // The tests need a kernel with ringbufs and BTF anyway
+    let caps = KernelCaps::detect();
+    assert!(caps.kernel_version.expect("failed to parse kernel version") >= (5, 8));
+    assert!(caps.ringbuf);
+    assert!(caps.btf);
+    assert!(caps.core);
+    assert_eq!(caps.memcg_accounting, !memlock_rlimit_needed());
+    // The bundled libbpf can't attach with cookies
+    assert!(!caps.bpf_cookie);
+}
+
+#[test]
+fn test_object_verify_attach_targets() {
+    let obj_path = get_test_object_path("runqslower.bpf.o");
+    let mut obj = ObjectBuilder::default()
+        .open_file(&obj_path)
+        .expect("failed to open object");
+
+    let vmlinux = Btf::from_vmlinux().expect("failed to load kernel BTF");
+    obj.verify_attach_targets(&vmlinux)
+        .expect("failed to verify attach targets");
+
+    // The object's own BTF has none of the kernel's tracepoints
+    let btf = Btf::from_path(&obj_path).expect("failed to load object BTF");
+    let err = obj.verify_attach_targets(&btf).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("handle__sched_switch (tp_btf/sched_switch"));
+
+    // Programs that won't be loaded are not checked
+    for prog in obj.progs().expect("failed to get progs") {
+        prog.set_autoload(false).expect("failed to set autoload");
+    }
+    obj.verify_attach_targets(&btf)
+        .expect("failed to verify attach targets");
+}
+
 #[test]
 fn test_object_maps() {
     bump_rlimit_mlock();
@@ -95,7 +236,972 @@ fn test_object_maps() {
 }
 
 #[test]
-fn test_object_map_key_value_size() {
+fn test_object_map_key_value_size() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    assert!(start.lookup(&[1, 2, 3, 4, 5], MapFlags::empty()).is_err());
+    assert!(start.delete(&[1]).is_err());
+    assert!(start.lookup_and_delete(&[1, 2, 3, 4, 5]).is_err());
+    assert!(start
+        .update(&[1, 2, 3, 4, 5], &[1], MapFlags::empty())
+        .is_err());
+}
+
+#[test]
+fn test_object_map_extra() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj.map_unwrap("start");
+    assert_eq!(start.map_extra().expect("failed to get map_extra"), 0);
+}
+
+#[test]
+fn test_object_map_locked() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    // `start` has no spin lock in its values, which the kernel rejects locked access to
+    assert!(matches!(
+        start.update_locked(&1u32, &2u64, MapFlags::ANY),
+        Err(Error::System(e)) if e == nix::libc::EINVAL
+    ));
+    assert!(matches!(
+        start.lookup_locked::<u32, u64>(&1),
+        Err(Error::System(e)) if e == nix::libc::EINVAL
+    ));
+}
+
+#[test]
+fn test_object_map_empty_lookup() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    assert!(start
+        .lookup(&[1, 2, 3, 4], MapFlags::empty())
+        .expect("err in map lookup")
+        .is_none());
+}
+
+#[test]
+fn test_object_map_mutation() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    start
+        .update(&[1, 2, 3, 4], &[1, 2, 3, 4, 5, 6, 7, 8], MapFlags::empty())
+        .expect("failed to write");
+    let val = start
+        .lookup(&[1, 2, 3, 4], MapFlags::empty())
+        .expect("failed to read map")
+        .expect("failed to find key");
+    assert_eq!(val.len(), 8);
+    assert_eq!(val, &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+    start.delete(&[1, 2, 3, 4]).expect("failed to delete key");
+
+    assert!(start
+        .lookup(&[1, 2, 3, 4], MapFlags::empty())
+        .expect("failed to read map")
+        .is_none());
+}
+
+#[test]
+fn test_object_map_shared_between_threads() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start: &Map = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    std::thread::scope(|s| {
+        for i in 0..4u8 {
+            s.spawn(move || {
+                start
+                    .update(&[i, 0, 0, 0], &[i; 8], MapFlags::empty())
+                    .expect("failed to write");
+            });
+        }
+    });
+
+    for i in 0..4u8 {
+        let val = start
+            .lookup(&[i, 0, 0, 0], MapFlags::empty())
+            .expect("failed to read map")
+            .expect("failed to find key");
+        assert_eq!(val, &[i; 8]);
+    }
+}
+
+#[test]
+fn test_object_map_lookup_and_delete_batch() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    for i in 0..10u8 {
+        start
+            .update(&[i, 0, 0, 0], &[i; 8], MapFlags::empty())
+            .expect("failed to write");
+    }
+
+    assert!(start
+        .lookup_and_delete_batch(0, MapFlags::empty(), MapFlags::empty())
+        .is_err());
+
+    let mut elems = start
+        .lookup_and_delete_batch(4, MapFlags::empty(), MapFlags::empty())
+        .expect("failed to drain map");
+    elems.sort();
+    assert_eq!(elems.len(), 10);
+    for (i, (key, value)) in elems.into_iter().enumerate() {
+        assert_eq!(key, &[i as u8, 0, 0, 0]);
+        assert_eq!(value, &[i as u8; 8]);
+    }
+
+    assert!(start.keys().next().is_none());
+
+    let percpu = MapBuilder::new(MapType::PercpuHash)
+        .key_size(4)
+        .value_size(8)
+        .max_entries(2)
+        .build()
+        .expect("failed to create map");
+    assert!(matches!(
+        percpu.lookup_and_delete_batch(4, MapFlags::empty(), MapFlags::empty()),
+        Err(Error::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_object_map_freeze() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    start
+        .update(&[1, 2, 3, 4], &[1, 2, 3, 4, 5, 6, 7, 8], MapFlags::empty())
+        .expect("failed to write");
+    start.freeze().expect("failed to freeze map");
+
+    // Frozen maps may still be read, but not written
+    assert!(start
+        .lookup(&[1, 2, 3, 4], MapFlags::empty())
+        .expect("failed to read map")
+        .is_some());
+    assert!(start
+        .update(&[1, 2, 3, 4], &[0; 8], MapFlags::empty())
+        .is_err());
+}
+
+#[test]
+fn test_object_map_lookup_pretty() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    let key = 1u32.to_ne_bytes();
+    start
+        .update(&key, &42u64.to_ne_bytes(), MapFlags::empty())
+        .expect("failed to write");
+    assert_eq!(
+        start
+            .lookup_pretty(&key, MapFlags::empty())
+            .expect("failed to read map")
+            .as_deref(),
+        Some("42")
+    );
+    assert!(start
+        .lookup_pretty(&2u32.to_ne_bytes(), MapFlags::empty())
+        .expect("failed to read map")
+        .is_none());
+
+    // Maps without BTF cannot be formatted
+    let map = MapBuilder::new(MapType::Hash)
+        .key_size(4)
+        .value_size(8)
+        .max_entries(1)
+        .build()
+        .expect("failed to create map");
+    assert!(map.lookup_pretty(&key, MapFlags::empty()).is_err());
+}
+
+#[test]
+fn test_object_map_snapshot() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    for i in 1..=3u32 {
+        start
+            .update(
+                &i.to_ne_bytes(),
+                &(i as u64 * 10).to_ne_bytes(),
+                MapFlags::empty(),
+            )
+            .expect("failed to write");
+    }
+
+    let snapshot = start.snapshot().expect("failed to snapshot map");
+    assert_eq!(snapshot.name(), "start");
+    assert_eq!(snapshot.entries().len(), 3);
+
+    start.delete(&1u32.to_ne_bytes()).expect("failed to delete");
+    start
+        .update(&2u32.to_ne_bytes(), &0u64.to_ne_bytes(), MapFlags::empty())
+        .expect("failed to write");
+    start.restore(&snapshot).expect("failed to restore map");
+
+    for i in 1..=3u32 {
+        assert_eq!(
+            start
+                .lookup(&i.to_ne_bytes(), MapFlags::empty())
+                .expect("failed to read map"),
+            Some((i as u64 * 10).to_ne_bytes().to_vec())
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&snapshot).expect("failed to serialize");
+        let restored: libbpf_rs::MapSnapshot =
+            serde_json::from_str(&json).expect("failed to deserialize");
+        assert_eq!(restored, snapshot);
+    }
+
+    // Snapshots only restore into compatible maps
+    let map = MapBuilder::new(MapType::Hash)
+        .key_size(4)
+        .value_size(4)
+        .max_entries(1)
+        .build()
+        .expect("failed to create map");
+    assert!(map.restore(&snapshot).is_err());
+
+    let events = obj
+        .map("events")
+        .expect("error finding map")
+        .expect("failed to find map");
+    assert!(events.snapshot().is_err());
+}
+
+#[test]
+fn test_object_map_dump_via_iter() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    for i in 1..=3u32 {
+        start
+            .update(
+                &i.to_ne_bytes(),
+                &(i as u64 * 10).to_ne_bytes(),
+                MapFlags::empty(),
+            )
+            .expect("failed to write");
+    }
+
+    let snapshot = start.dump_via_iter().expect("failed to dump map");
+    assert_eq!(snapshot.name(), "start");
+    let mut entries = snapshot.entries().to_vec();
+    entries.sort();
+    assert_eq!(
+        entries,
+        (1..=3u32)
+            .map(|i| (
+                i.to_ne_bytes().to_vec(),
+                (i as u64 * 10).to_ne_bytes().to_vec()
+            ))
+            .collect::<Vec<_>>()
+    );
+
+    let events = obj
+        .map("events")
+        .expect("error finding map")
+        .expect("failed to find map");
+    assert!(events.dump_via_iter().is_err());
+}
+
+#[test]
+fn test_object_replace_with() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let mut links = Vec::new();
+    for name in &[
+        "handle__sched_wakeup",
+        "handle__sched_wakeup_new",
+        "handle__sched_switch",
+    ] {
+        links.push(obj.prog_unwrap(name).attach().expect("failed to attach"));
+    }
+
+    // Unlikely to collide with a real pid the programs write
+    let key = u32::MAX.to_ne_bytes();
+    let value = 1234u64.to_ne_bytes();
+    obj.map_unwrap("start")
+        .update(&key, &value, MapFlags::empty())
+        .expect("failed to write");
+
+    let new = ObjectBuilder::default()
+        .open_file(get_test_object_path("runqslower.bpf.o"))
+        .expect("failed to open object");
+    obj.replace_with(&mut links, new)
+        .expect("failed to replace object");
+
+    assert_eq!(links.len(), 3);
+    assert_eq!(
+        obj.map_unwrap("start")
+            .lookup(&key, MapFlags::empty())
+            .expect("failed to read map"),
+        Some(value.to_vec())
+    );
+}
+
+#[test]
+fn test_object_section_handler() {
+    bump_rlimit_mlock();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handler_calls = calls.clone();
+    let handler = SectionHandler::new(ProgramType::Tracing)
+        .attach_type(ProgramAttachType::TraceRawTp)
+        .attach_fn(move |prog| {
+            handler_calls.fetch_add(1, Ordering::SeqCst);
+            prog.attach_trace()
+        });
+
+    let mut obj = ObjectBuilder::default()
+        .section_handler("tp_btf/sched_switch", handler)
+        .open_file(get_test_object_path("runqslower.bpf.o"))
+        .expect("failed to open object")
+        .load()
+        .expect("failed to load object");
+
+    let prog = obj.prog_unwrap("handle__sched_switch");
+    assert!(matches!(prog.prog_type(), ProgramType::Tracing));
+    assert!(matches!(prog.attach_type(), ProgramAttachType::TraceRawTp));
+    let _link = prog.attach().expect("failed to attach");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Programs in other sections still go through libbpf
+    let _link = obj
+        .prog_unwrap("handle__sched_wakeup")
+        .attach()
+        .expect("failed to attach");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_object_attach_with_fallback() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let prog = obj.prog_unwrap("handle__sched_switch");
+
+    assert!(prog.attach_with_fallback(&[]).is_err());
+
+    // A tp_btf program can't be attached as a kprobe
+    let (idx, _link) = prog
+        .attach_with_fallback(&[
+            AttachSpec::Kprobe {
+                retprobe: false,
+                func_name: "finish_task_switch".to_string(),
+            },
+            AttachSpec::Trace,
+        ])
+        .expect("failed to attach");
+    assert_eq!(idx, 1);
+}
+
+#[test]
+fn test_object_attach_with_retry() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let prog = obj.prog_unwrap("handle__sched_switch");
+
+    let _link = prog
+        .attach_with_retry(&AttachSpec::Auto, &RetryPolicy::default())
+        .expect("failed to attach");
+
+    let mut filter = AttachFilter::default();
+    filter.include("handle__sched_switch");
+    let links = obj
+        .attach_all_with_retry(&filter, &RetryPolicy::default())
+        .expect("failed to attach");
+    assert_eq!(links.len(), 1);
+}
+
+#[test]
+fn test_retry_policy() {
+    let mut policy = RetryPolicy::default();
+    policy.attempts(3).backoff(Duration::from_millis(1));
+
+    // Transient errors are retried until attempts run out
+    let mut calls = 0;
+    let res: libbpf_rs::Result<()> = policy.run(|| {
+        calls += 1;
+        Err(Error::System(nix::libc::EBUSY))
+    });
+    assert!(matches!(res, Err(Error::System(e)) if e == nix::libc::EBUSY));
+    assert_eq!(calls, 3);
+
+    calls = 0;
+    let res = policy.run(|| {
+        calls += 1;
+        if calls < 2 {
+            Err(Error::System(nix::libc::EAGAIN))
+        } else {
+            Ok(calls)
+        }
+    });
+    assert_eq!(res.expect("failed to retry"), 2);
+
+    // Other errors are not
+    calls = 0;
+    let res: libbpf_rs::Result<()> = policy.run(|| {
+        calls += 1;
+        Err(Error::System(nix::libc::ENOENT))
+    });
+    assert!(res.is_err());
+    assert_eq!(calls, 1);
+
+    policy.retry_on(nix::libc::ENOENT);
+    calls = 0;
+    let _ = policy.run(|| -> libbpf_rs::Result<()> {
+        calls += 1;
+        Err(Error::System(nix::libc::ENOENT))
+    });
+    assert_eq!(calls, 3);
+}
+
+#[test]
+fn test_tracefs_tracepoints() {
+    let tracepoints = tracefs::tracepoints().expect("failed to list tracepoints");
+    assert!(tracepoints
+        .iter()
+        .any(|tp| tp.category == "sched" && tp.name == "sched_switch"));
+
+    assert!(tracefs::tracepoint_exists("sched", "sched_switch").expect("failed to read tracefs"));
+    assert!(
+        !tracefs::tracepoint_exists("sched", "libbpf_rs_no_such_tracepoint")
+            .expect("failed to read tracefs")
+    );
+}
+
+#[test]
+fn test_cgroups() {
+    let root = cgroups::root().expect("failed to find cgroup2 mount");
+    let parent = root.join("libbpf_rs_test");
+    let child = parent.join("child");
+    fs::create_dir_all(&child).expect("failed to create cgroups");
+    defer! {
+        let _ = fs::remove_dir(&child);
+        let _ = fs::remove_dir(&parent);
+    }
+
+    let descendants = cgroups::descendants("libbpf_rs_test").expect("failed to list cgroups");
+    assert_eq!(descendants, vec![PathBuf::from("libbpf_rs_test/child")]);
+
+    let cgroup = cgroups::Cgroup::open("libbpf_rs_test/child").expect("failed to open cgroup");
+    assert_eq!(cgroup.path(), child);
+    assert_eq!(
+        cgroup.id().expect("failed to get cgroup id"),
+        fs::metadata(&child).expect("failed to stat cgroup").ino()
+    );
+
+    assert!(matches!(
+        cgroups::Cgroup::open("libbpf_rs_test/child/cgroup.procs"),
+        Err(Error::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_query_attached() {
+    let root = cgroups::root().expect("failed to find cgroup2 mount");
+    let path = root.join("libbpf_rs_query_test");
+    fs::create_dir_all(&path).expect("failed to create cgroup");
+    defer! {
+        let _ = fs::remove_dir(&path);
+    }
+
+    let cgroup = cgroups::Cgroup::open(&path).expect("failed to open cgroup");
+    let attached = query_attached(cgroup.as_raw_fd(), ProgramAttachType::CgroupInetIngress)
+        .expect("failed to query cgroup");
+    assert!(attached.prog_ids.is_empty());
+
+    assert!(query_attached(-1, ProgramAttachType::CgroupInetIngress).is_err());
+}
+
+#[test]
+fn test_object_attach_all() {
+    bump_rlimit_mlock();
+
+    let mut filter = AttachFilter::default();
+    filter.include("handle__*").exclude("*wakeup*");
+    assert!(filter.matches("handle__sched_switch"));
+    assert!(!filter.matches("handle__sched_wakeup_new"));
+    assert!(!filter.matches("other"));
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let links = obj.attach_all(&filter).expect("failed to attach");
+    let names: Vec<&str> = links.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["handle__sched_switch"]);
+}
+
+#[test]
+fn test_object_prog_stats_watcher() {
+    bump_rlimit_mlock();
+
+    let _stats = enable_stats().expect("failed to enable stats");
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let prog = obj.prog_unwrap("handle__sched_switch");
+    let mut watcher = ProgramStatsWatcher::default();
+    watcher.add(prog).expect("failed to watch prog");
+    let _link = prog.attach().expect("failed to attach prog");
+
+    // Sleeping switches tasks, so the program runs
+    std::thread::sleep(Duration::from_millis(100));
+    let deltas = watcher.sample().expect("failed to sample stats");
+    assert_eq!(deltas.len(), 1);
+    assert_eq!(deltas[0].name, "handle__sched_s");
+    assert!(deltas[0].run_cnt > 0);
+    assert!(deltas[0].avg_run_time().is_some());
+    assert!(deltas[0].elapsed >= Duration::from_millis(100));
+}
+
+#[test]
+fn test_object_prog_set_attach_target() {
+    bump_rlimit_mlock();
+
+    let mut target = get_test_object("runqslower.bpf.o");
+    let target_fd = target.prog_unwrap("handle__sched_switch").fd();
+
+    let mut open_obj = ObjectBuilder::default()
+        .open_file(get_test_object_path("runqslower.bpf.o"))
+        .expect("failed to open object");
+    let prog = open_obj.prog_unwrap("handle__sched_switch");
+    prog.set_prog_type(ProgramType::Tracing);
+    prog.set_attach_type(ProgramAttachType::TraceFentry);
+
+    prog.set_attach_target(target_fd, "handle__sched_switch")
+        .expect("failed to set attach target");
+    assert!(prog.set_attach_target(target_fd, "does_not_exist").is_err());
+}
+
+#[test]
+fn test_object_prog_attach_fd() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let prog = obj.prog_unwrap("handle__sched_switch");
+    let cgroup = fs::File::open("/sys/fs/cgroup").expect("failed to open cgroup");
+
+    // Tracing programs can't be attached to cgroups
+    assert!(prog
+        .attach_fd(
+            cgroup.as_raw_fd(),
+            ProgramAttachType::CgroupDevice,
+            ProgramAttachFlags::ALLOW_MULTI,
+        )
+        .is_err());
+    assert!(prog
+        .detach_fd(cgroup.as_raw_fd(), ProgramAttachType::CgroupDevice)
+        .is_err());
+}
+
+#[test]
+fn test_object_maps_progs() {
+    bump_rlimit_mlock();
+
+    let mut open_obj = ObjectBuilder::default()
+        .open_file(get_test_object_path("runqslower.bpf.o"))
+        .expect("failed to open object");
+    let prog_names: Vec<String> = open_obj
+        .progs()
+        .expect("failed to get progs")
+        .iter()
+        .map(|prog| prog.name().expect("failed to get name").to_string())
+        .collect();
+    assert_eq!(
+        prog_names,
+        vec![
+            "handle__sched_wakeup",
+            "handle__sched_wakeup_new",
+            "handle__sched_switch"
+        ]
+    );
+
+    let mut obj = open_obj.load().expect("failed to load object");
+    let map_names: Vec<String> = obj
+        .maps()
+        .expect("failed to get maps")
+        .iter()
+        .map(|map| map.name().to_string())
+        .collect();
+    assert!(map_names.contains(&"start".to_string()));
+    assert!(map_names.contains(&"events".to_string()));
+    // Internal maps are included
+    assert!(map_names.iter().any(|name| name.ends_with(".rodata")));
+
+    assert_eq!(obj.progs().expect("failed to get progs").len(), 3);
+}
+
+#[test]
+fn test_object_map_pin_path() {
+    bump_rlimit_mlock();
+
+    let path = "/sys/fs/bpf/mymap_pin_path_test";
+    let open = || {
+        let mut open_obj = ObjectBuilder::default()
+            .open_file(get_test_object_path("runqslower.bpf.o"))
+            .expect("failed to open object");
+        let start = open_obj.map_unwrap("start");
+        assert_eq!(start.pin_path(), None);
+        start.set_pin_path(path).expect("failed to set pin path");
+        assert_eq!(start.pin_path(), Some(PathBuf::from(path)));
+        open_obj.load().expect("failed to load object")
+    };
+
+    let mut obj = open();
+    assert_eq!(
+        obj.map_unwrap("start").pin_path(),
+        Some(PathBuf::from(path))
+    );
+    assert!(Path::new(path).exists());
+
+    let key = u32::MAX.to_ne_bytes();
+    let value = 42u64.to_ne_bytes();
+    obj.map_unwrap("start")
+        .update(&key, &value, MapFlags::empty())
+        .expect("failed to write");
+
+    // A second instance reuses the pinned map
+    let mut obj2 = open();
+    assert_eq!(
+        obj2.map_unwrap("start")
+            .lookup(&key, MapFlags::empty())
+            .expect("failed to read map"),
+        Some(value.to_vec())
+    );
+
+    obj.map_unwrap("start")
+        .unpin(path)
+        .expect("failed to unpin");
+    assert_eq!(obj.map_unwrap("start").pin_path(), None);
+}
+
+#[test]
+fn test_object_map_typed() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj.map_unwrap("start");
+
+    start
+        .update_typed(&u32::MAX, &42u64, MapFlags::empty())
+        .expect("failed to write");
+    assert_eq!(
+        start
+            .lookup_typed::<u32, u64>(&u32::MAX, MapFlags::empty())
+            .expect("failed to read map"),
+        Some(42)
+    );
+    assert_eq!(
+        start
+            .lookup_typed::<u32, u64>(&0, MapFlags::empty())
+            .expect("failed to read map"),
+        None
+    );
+
+    // Sizes are checked against the map definition
+    assert!(start
+        .update_typed(&u64::MAX, &42u64, MapFlags::empty())
+        .is_err());
+    assert!(start
+        .lookup_typed::<u32, u32>(&u32::MAX, MapFlags::empty())
+        .is_err());
+}
+
+#[test]
+fn test_object_map_try_clone() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map_unwrap("start")
+        .try_clone()
+        .expect("failed to clone map");
+    assert_eq!(start.name(), "start");
+
+    let key = u32::MAX.to_ne_bytes();
+    let value = 42u64.to_ne_bytes();
+    start
+        .update(&key, &value, MapFlags::empty())
+        .expect("failed to write");
+    assert_eq!(
+        obj.map_unwrap("start")
+            .lookup(&key, MapFlags::empty())
+            .expect("failed to read map"),
+        Some(value.to_vec())
+    );
+
+    // The clone keeps the map alive
+    drop(obj);
+    assert_eq!(
+        start
+            .lookup(&key, MapFlags::empty())
+            .expect("failed to read map"),
+        Some(value.to_vec())
+    );
+}
+
+#[test]
+fn test_object_open_pinned() {
+    bump_rlimit_mlock();
+
+    let dir = "/sys/fs/bpf/open_pinned_test";
+    let mut obj = get_test_object("runqslower.bpf.o");
+    obj.pin_maps(dir).expect("failed to pin maps");
+
+    let key = u32::MAX.to_ne_bytes();
+    let value = 42u64.to_ne_bytes();
+    obj.map_unwrap("start")
+        .update(&key, &value, MapFlags::empty())
+        .expect("failed to write");
+
+    let mut view = ObjectBuilder::default()
+        .open_pinned(get_test_object_path("runqslower.bpf.o"), dir)
+        .expect("failed to open pinned object");
+    assert_eq!(
+        view.map_unwrap("start")
+            .lookup(&key, MapFlags::empty())
+            .expect("failed to read map"),
+        Some(value.to_vec())
+    );
+    assert!(view
+        .progs()
+        .expect("failed to get progs")
+        .iter()
+        .all(|prog| prog.fd() < 0));
+
+    obj.unpin_maps(dir).expect("failed to unpin maps");
+    assert!(ObjectBuilder::default()
+        .open_pinned(get_test_object_path("runqslower.bpf.o"), dir)
+        .is_err());
+}
+
+#[test]
+fn test_check_pinned_layout() {
+    bump_rlimit_mlock();
+
+    let dir = Path::new("/sys/fs/bpf/layout_test");
+    defer! {
+        let _ = fs::remove_file(dir.join("libbpf_rs_layout"));
+        let _ = fs::remove_dir(dir);
+    }
+
+    // The first check records the layout, later ones compare against it
+    check_pinned_layout(dir, 0x1234).expect("failed to record layout");
+    assert!(dir.join("libbpf_rs_layout").exists());
+    check_pinned_layout(dir, 0x1234).expect("same layout rejected");
+    assert!(matches!(
+        check_pinned_layout(dir, 0x5678),
+        Err(Error::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_object_map_as_queue() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let start = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+
+    // Only queue and stack maps support keyless operations
+    assert!(start.as_queue().is_err());
+}
+
+#[test]
+fn test_map_read_write_only() {
+    bump_rlimit_mlock();
+
+    let key = 1u32.to_ne_bytes();
+    let value = 2u64.to_ne_bytes();
+
+    let mut wronly = MapBuilder::new(MapType::Hash)
+        .key_size(4)
+        .value_size(8)
+        .max_entries(1)
+        .map_flags(libbpf_rs::libbpf_sys::BPF_F_WRONLY)
+        .build()
+        .expect("failed to create map");
+    let fd = wronly.fd();
+    let view = wronly.as_write_only();
+    view.update(&key, &value, MapFlags::ANY)
+        .expect("failed to update");
+    view.delete(&key).expect("failed to delete");
+    // The fd stays available, eg. to share the map
+    assert_eq!(view.as_fd().as_raw_fd(), fd);
+    assert_eq!(view.as_map().fd(), fd);
+    // The kernel enforces what the view leaves out
+    assert!(matches!(
+        wronly.lookup(&key, MapFlags::ANY),
+        Err(Error::System(libc::EPERM))
+    ));
+
+    let mut rdonly = MapBuilder::new(MapType::Hash)
+        .key_size(4)
+        .value_size(8)
+        .max_entries(1)
+        .map_flags(libbpf_rs::libbpf_sys::BPF_F_RDONLY)
+        .build()
+        .expect("failed to create map");
+    let view = rdonly.as_read_only();
+    assert_eq!(
+        view.lookup(&key, MapFlags::ANY).expect("failed to lookup"),
+        None
+    );
+    assert_eq!(view.keys().count(), 0);
+    assert!(matches!(
+        rdonly.update(&key, &value, MapFlags::ANY),
+        Err(Error::System(libc::EPERM))
+    ));
+}
+
+#[test]
+fn test_map_percpu_counter() {
+    bump_rlimit_mlock();
+
+    let map = MapBuilder::new(MapType::PercpuArray)
+        .key_size(4)
+        .value_size(8)
+        .max_entries(2)
+        .build()
+        .expect("failed to create map");
+    let counter = map.as_percpu_counter(1).expect("failed to get counter");
+    assert_eq!(counter.sum().expect("failed to sum"), 0);
+
+    // Give CPU n a count of n + 1
+    let ncpus = counter.per_cpu().expect("failed to read counter").len();
+    assert!(ncpus > 0);
+    let values: Vec<u64> = (1..=ncpus as u64).collect();
+    let key = 1u32;
+    let ret = unsafe {
+        libbpf_rs::libbpf_sys::bpf_map_update_elem(
+            map.fd(),
+            &key as *const u32 as *const _,
+            values.as_ptr() as *const _,
+            0,
+        )
+    };
+    assert_eq!(ret, 0);
+
+    assert_eq!(counter.per_cpu().expect("failed to read counter"), values);
+    assert_eq!(
+        counter.sum().expect("failed to sum"),
+        values.iter().sum::<u64>()
+    );
+    for (cpu, value) in counter
+        .per_online_cpu()
+        .expect("failed to read online counters")
+    {
+        assert_eq!(value, u64::from(cpu) + 1);
+    }
+    assert_eq!(
+        map.as_percpu_counter(0)
+            .expect("failed to get counter")
+            .sum()
+            .expect("failed to sum"),
+        0
+    );
+
+    counter.reset().expect("failed to reset counter");
+    assert_eq!(counter.sum().expect("failed to sum"), 0);
+
+    // Only per-CPU arrays of u32 or u64 are counters
+    let map = MapBuilder::new(MapType::PercpuArray)
+        .key_size(4)
+        .value_size(16)
+        .max_entries(1)
+        .build()
+        .expect("failed to create map");
+    assert!(map.as_percpu_counter(0).is_err());
+    let map = MapBuilder::new(MapType::Array)
+        .key_size(4)
+        .value_size(8)
+        .max_entries(1)
+        .build()
+        .expect("failed to create map");
+    assert!(map.as_percpu_counter(0).is_err());
+}
+
+#[test]
+fn test_lpm_key() {
+    let key = LpmKey::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 0)), 24).expect("invalid key");
+    assert_eq!(key.prefix_len(), 24);
+    assert_eq!(key.data(), &[192, 168, 1, 0]);
+    let mut expected = 24u32.to_ne_bytes().to_vec();
+    expected.extend_from_slice(&[192, 168, 1, 0]);
+    assert_eq!(key.as_bytes(), expected.as_slice());
+
+    let key = LpmKey::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 128).expect("invalid key");
+    assert_eq!(key.as_bytes().len(), 20);
+    assert_eq!(key.data()[15], 1);
+
+    assert!(LpmKey::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 33).is_err());
+    assert!(LpmKey::from_data(17, &[0, 0]).is_err());
+}
+
+#[test]
+fn test_object_map_lpm_wrong_type() {
     bump_rlimit_mlock();
 
     let mut obj = get_test_object("runqslower.bpf.o");
@@ -104,56 +1210,127 @@ fn test_object_map_key_value_size() {
         .expect("error finding map")
         .expect("failed to find map");
 
-    assert!(start.lookup(&[1, 2, 3, 4, 5], MapFlags::empty()).is_err());
-    assert!(start.delete(&[1]).is_err());
-    assert!(start.lookup_and_delete(&[1, 2, 3, 4, 5]).is_err());
-    assert!(start
-        .update(&[1, 2, 3, 4, 5], &[1], MapFlags::empty())
+    let key = LpmKey::from_data(0, &[]).expect("invalid key");
+    assert!(start.lookup_lpm(&key, MapFlags::empty()).is_err());
+}
+
+#[test]
+fn test_map_builder() {
+    bump_rlimit_mlock();
+
+    let mut map = MapBuilder::new(MapType::Hash)
+        .name("scratch")
+        .key_size(4)
+        .value_size(8)
+        .max_entries(16)
+        .build()
+        .expect("failed to create map");
+    assert_eq!(map.name(), "scratch");
+    assert!(map.map_type() == MapType::Hash);
+
+    map.update(&[1, 2, 3, 4], &[1, 2, 3, 4, 5, 6, 7, 8], MapFlags::empty())
+        .expect("failed to write");
+    let val = map
+        .lookup(&[1, 2, 3, 4], MapFlags::empty())
+        .expect("failed to read map")
+        .expect("failed to find key");
+    assert_eq!(val, &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+    let path = "/sys/fs/bpf/mymap_builder_test";
+    map.pin(path).expect("failed to pin map");
+    assert!(Path::new(path).exists());
+    map.unpin(path).expect("failed to unpin map");
+    assert!(!Path::new(path).exists());
+
+    // Hash maps need a key
+    assert!(MapBuilder::new(MapType::Hash)
+        .value_size(8)
+        .max_entries(16)
+        .build()
         .is_err());
 }
 
 #[test]
-fn test_object_map_empty_lookup() {
+fn test_map_arena() {
     bump_rlimit_mlock();
 
-    let mut obj = get_test_object("runqslower.bpf.o");
-    let start = obj
-        .map("start")
-        .expect("error finding map")
-        .expect("failed to find map");
+    let map = MapBuilder::new(MapType::Arena)
+        .name("arena")
+        .map_flags(libbpf_rs::libbpf_sys::BPF_F_MMAPABLE)
+        .max_entries(4)
+        .build()
+        .expect("failed to create arena");
+    let mut arena = map.arena().expect("failed to map arena");
+    let page_size = unistd::sysconf(unistd::SysconfVar::PAGE_SIZE)
+        .expect("failed to get page size")
+        .expect("no page size") as usize;
+    assert_eq!(arena.len(), 4 * page_size);
+
+    arena
+        .write(8, &[1, 2, 3, 4])
+        .expect("failed to write arena");
+    let mut buf = [0u8; 4];
+    arena.read(8, &mut buf).expect("failed to read arena");
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert!(arena.read(arena.len() - 2, &mut buf).is_err());
+
+    unsafe { *arena.view_mut::<u64>(16).expect("failed to view arena") = 42 };
+    assert_eq!(
+        unsafe { *arena.view::<u64>(16).expect("failed to view arena") },
+        42
+    );
+    assert!(unsafe { arena.view::<u64>(17) }.is_err());
 
-    assert!(start
-        .lookup(&[1, 2, 3, 4], MapFlags::empty())
-        .expect("err in map lookup")
-        .is_none());
+    let addr = arena.as_ptr() as u64 + 16;
+    assert_eq!(arena.offset_of(addr), Some(16));
+    assert_eq!(
+        arena.offset_of(arena.as_ptr() as u64 + arena.len() as u64),
+        None
+    );
+
+    // The kernel keeps the arena at the address it was first mapped at, where it is still
+    // mapped
+    let ptr = arena.as_ptr();
+    assert!(matches!(
+        map.arena(),
+        Err(Error::System(e)) if e == nix::libc::EEXIST
+    ));
+    drop(arena);
+    let arena = map.arena().expect("failed to map arena again");
+    assert_eq!(arena.as_ptr(), ptr);
+
+    // The mapping outlives the map
+    drop(map);
+    arena.read(8, &mut buf).expect("failed to read arena");
+    assert_eq!(buf, [1, 2, 3, 4]);
+
+    let hash = MapBuilder::new(MapType::Hash)
+        .key_size(4)
+        .value_size(8)
+        .max_entries(16)
+        .build()
+        .expect("failed to create map");
+    assert!(hash.arena().is_err());
 }
 
 #[test]
-fn test_object_map_mutation() {
+fn test_map_lookup_stack() {
     bump_rlimit_mlock();
 
+    let map = MapBuilder::new(MapType::StackTrace)
+        .key_size(4)
+        .value_size(8 * 16)
+        .max_entries(16)
+        .build()
+        .expect("failed to create map");
+    assert!(map.lookup_stack(0).expect("failed to read map").is_none());
+
     let mut obj = get_test_object("runqslower.bpf.o");
     let start = obj
         .map("start")
         .expect("error finding map")
         .expect("failed to find map");
-
-    start
-        .update(&[1, 2, 3, 4], &[1, 2, 3, 4, 5, 6, 7, 8], MapFlags::empty())
-        .expect("failed to write");
-    let val = start
-        .lookup(&[1, 2, 3, 4], MapFlags::empty())
-        .expect("failed to read map")
-        .expect("failed to find key");
-    assert_eq!(val.len(), 8);
-    assert_eq!(val, &[1, 2, 3, 4, 5, 6, 7, 8]);
-
-    start.delete(&[1, 2, 3, 4]).expect("failed to delete key");
-
-    assert!(start
-        .lookup(&[1, 2, 3, 4], MapFlags::empty())
-        .expect("failed to read map")
-        .is_none());
+    assert!(start.lookup_stack(0).is_err());
 }
 
 #[test]
@@ -266,6 +1443,139 @@ fn test_object_programs() {
     assert!(obj.prog("asdf").expect("error finding program").is_none());
 }
 
+#[test]
+fn test_object_program_insns() {
+    bump_rlimit_mlock();
+
+    let obj_path = get_test_object_path("runqslower.bpf.o");
+    let mut open_obj = ObjectBuilder::default()
+        .open_file(obj_path)
+        .expect("failed to open object");
+    let insn_cnt = open_obj
+        .prog("handle__sched_switch")
+        .expect("error finding program")
+        .expect("failed to find program")
+        .insn_cnt();
+    assert!(insn_cnt > 0);
+
+    let mut obj = open_obj.load().expect("failed to load object");
+    let prog = obj
+        .prog("handle__sched_switch")
+        .expect("error finding program")
+        .expect("failed to find program");
+
+    let xlated = prog.xlated_insns().expect("failed to get xlated insns");
+    assert!(!xlated.is_empty());
+    // Every program ends with an exit
+    assert_eq!(xlated.last().unwrap().code, 0x95);
+
+    prog.jited_insns().expect("failed to get jited insns");
+}
+
+#[test]
+fn test_object_program_bind_map() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let prog = obj
+        .prog("handle__sched_switch")
+        .expect("error finding program")
+        .expect("failed to find program");
+
+    let map_ids = prog.map_ids().expect("failed to get map ids");
+    assert!(!map_ids.is_empty());
+
+    let map = MapBuilder::new(MapType::Array)
+        .key_size(4)
+        .value_size(8)
+        .max_entries(1)
+        .build()
+        .expect("failed to create map");
+    prog.bind_map(&map).expect("failed to bind map");
+
+    let bound_ids = prog.map_ids().expect("failed to get map ids");
+    assert_eq!(bound_ids.len(), map_ids.len() + 1);
+}
+
+#[test]
+fn test_object_program_autoload() {
+    bump_rlimit_mlock();
+
+    let obj_path = get_test_object_path("runqslower.bpf.o");
+    let mut open_obj = ObjectBuilder::default()
+        .open_file(obj_path)
+        .expect("failed to open object");
+    let open_prog = open_obj
+        .prog("handle__sched_wakeup")
+        .expect("error finding program")
+        .expect("failed to find program");
+    assert!(open_prog.autoload());
+
+    open_prog
+        .set_autoload(false)
+        .expect("failed to disable autoload");
+    assert!(!open_prog.autoload());
+
+    let mut obj = open_obj.load().expect("failed to load object");
+
+    // Program should not have been loaded
+    let prog = obj
+        .prog("handle__sched_wakeup")
+        .expect("error finding program")
+        .expect("failed to find program");
+    assert!(prog.fd() < 0);
+    let cgroup = fs::File::open("/sys/fs/cgroup").expect("failed to open cgroup");
+    assert!(matches!(
+        prog.attach_fd(
+            cgroup.as_raw_fd(),
+            ProgramAttachType::CgroupDevice,
+            ProgramAttachFlags::empty(),
+        ),
+        Err(Error::InvalidInput(_))
+    ));
+
+    // But other programs should be
+    let prog = obj
+        .prog("handle__sched_switch")
+        .expect("error finding program")
+        .expect("failed to find program");
+    assert!(prog.fd() >= 0);
+}
+
+#[test]
+fn test_object_fd_clone() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+
+    let map = obj
+        .map("start")
+        .expect("error finding map")
+        .expect("failed to find map");
+    let map_fd = map
+        .as_fd()
+        .try_clone_to_owned()
+        .expect("failed to dup map fd");
+    assert!(map_fd.as_raw_fd() != map.fd());
+
+    let prog = obj
+        .prog("handle__sched_wakeup")
+        .expect("error finding program")
+        .expect("failed to find program");
+    let prog_fd = prog
+        .as_fd()
+        .try_clone_to_owned()
+        .expect("failed to dup prog fd");
+    assert!(prog_fd.as_raw_fd() != prog.fd());
+
+    let link = prog.attach().expect("failed to attach prog");
+    let link_fd = link
+        .as_fd()
+        .try_clone_to_owned()
+        .expect("failed to dup link fd");
+    assert!(link_fd.as_raw_fd() != link.fd());
+}
+
 #[test]
 fn test_object_program_pin() {
     bump_rlimit_mlock();
@@ -322,6 +1632,11 @@ fn test_object_link_pin() {
         let _ = fs::remove_file(path);
     }
 
+    // Pinned link should be openable
+    let opened = Link::open(path).expect("failed to open pinned link");
+    assert!(opened.fd() >= 0);
+    assert!(opened.fd() != link.fd());
+
     // Unpin should be successful
     link.unpin().expect("failed to unpin prog");
     assert!(!Path::new(path).exists());
@@ -386,6 +1701,307 @@ fn test_object_reuse_pined_map() {
     assert!(!Path::new(path).exists());
 }
 
+#[test]
+fn test_object_perf_buffer() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let mut links = Vec::new();
+    for name in &["handle__sched_wakeup", "handle__sched_switch"] {
+        links.push(obj.prog_unwrap(name).attach().expect("failed to attach"));
+    }
+
+    let (tx, rx) = channel();
+    let events = obj
+        .map("events")
+        .expect("error finding map")
+        .expect("failed to find map");
+    let mut builder = PerfBufferBuilder::new(events).sample_cb(move |_cpu, data: &[u8]| {
+        let _ = tx.send(data.len());
+    });
+    builder.wakeup_events(2);
+    let perf = builder.build().expect("failed to build perf buffer");
+
+    assert!(perf.buffer_cnt() > 0);
+    assert!(perf.buffer_fd(0).expect("failed to get buffer fd") >= 0);
+    assert!(perf.buffer_fd(perf.buffer_cnt()).is_err());
+
+    // Context switches happen all the time, so samples show up quickly
+    for _ in 0..50 {
+        perf.poll(Duration::from_millis(100))
+            .expect("failed to poll");
+        if let Ok(len) = rx.try_recv() {
+            // struct event from runqslower.h
+            assert!(len >= 32);
+
+            let stats = perf.stats();
+            assert!(stats.events >= 1);
+            assert!(stats.bytes >= 32);
+            return;
+        }
+    }
+    panic!("no samples received");
+}
+
+#[test]
+fn test_object_perf_buffer_fallible() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("runqslower.bpf.o");
+    let mut links = Vec::new();
+    for name in &["handle__sched_wakeup", "handle__sched_switch"] {
+        links.push(obj.prog_unwrap(name).attach().expect("failed to attach"));
+    }
+
+    let events = obj.map_unwrap("events");
+    let perf = PerfBufferBuilder::new(events)
+        .sample_cb_fallible(|_cpu, _data: &[u8]| Err(Error::Internal("stop".to_string())))
+        .build()
+        .expect("failed to build perf buffer");
+
+    // Context switches happen all the time, so samples show up quickly
+    for _ in 0..50 {
+        match perf.poll(Duration::from_millis(100)) {
+            Ok(()) => continue,
+            Err(Error::Internal(msg)) => {
+                assert_eq!(msg, "stop");
+                return;
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+    panic!("callback error not returned");
+}
+
+#[test]
+fn test_object_ringbuf_epoll() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("ringbuf.bpf.o");
+    let _link = obj
+        .prog_unwrap("handle__sys_enter_getpid")
+        .attach()
+        .expect("failed to attach prog");
+
+    let (tx, rx) = channel();
+    let mut builder = libbpf_rs::RingBufferBuilder::new();
+    builder
+        .add(obj.map_unwrap("ringbuf1"), move |data: &[u8]| {
+            let _ = tx.send(data.to_vec());
+            0
+        })
+        .expect("failed to add ringbuf");
+    let mgr = builder.build().expect("failed to build");
+
+    unsafe { libc::getpid() };
+
+    // Wait for readiness like an external event loop would, then consume without blocking
+    let mut fds = [PollFd::new(mgr.as_raw_fd(), PollFlags::POLLIN)];
+    assert_eq!(poll(&mut fds, 1000).expect("failed to poll"), 1);
+    mgr.consume().expect("failed to consume ringbuf");
+    assert_eq!(
+        rx.try_recv().expect("no sample received"),
+        1i32.to_ne_bytes()
+    );
+
+    let stats = mgr.stats();
+    assert!(stats.events >= 1);
+    assert!(stats.bytes >= 4);
+    assert_eq!(stats.callback_errors, 0);
+}
+
+#[test]
+fn test_event_source() {
+    bump_rlimit_mlock();
+
+    let obj_path = get_test_object_path("ringbuf.bpf.o");
+    let mut open_obj = ObjectBuilder::default()
+        .open_file(obj_path)
+        .expect("failed to open object");
+    let kind = EventSource::prepare(open_obj.map_unwrap("ringbuf1")).expect("failed to prepare");
+    // Tests run on kernels with ringbufs
+    assert_eq!(kind, EventSourceKind::RingBuf);
+    let mut obj = open_obj.load().expect("failed to load object");
+    let _link = obj
+        .prog_unwrap("handle__sys_enter_getpid")
+        .attach()
+        .expect("failed to attach prog");
+
+    let (tx, rx) = channel();
+    let events = EventSource::new(obj.map_unwrap("ringbuf1"), move |data: &[u8]| {
+        let _ = tx.send(data.to_vec());
+    })
+    .expect("failed to create event source");
+    assert_eq!(events.kind(), EventSourceKind::RingBuf);
+
+    unsafe { libc::getpid() };
+    events
+        .poll(Duration::from_secs(1))
+        .expect("failed to poll events");
+    assert_eq!(
+        rx.try_recv().expect("no sample received"),
+        1i32.to_ne_bytes()
+    );
+    assert_eq!(events.lost(), 0);
+
+    // What the ringbuf turns into on older kernels
+    let perf = MapBuilder::new(MapType::PerfEventArray)
+        .key_size(4)
+        .value_size(4)
+        .max_entries(1)
+        .build()
+        .expect("failed to create map");
+    let events = EventSource::new(&perf, |_: &[u8]| ()).expect("failed to create event source");
+    assert_eq!(events.kind(), EventSourceKind::PerfBuffer);
+    events.consume().expect("failed to consume events");
+
+    let hash = MapBuilder::new(MapType::Hash)
+        .key_size(4)
+        .value_size(4)
+        .max_entries(1)
+        .build()
+        .expect("failed to create map");
+    assert!(EventSource::new(&hash, |_: &[u8]| ()).is_err());
+
+    // Only ringbufs can be prepared
+    let mut open_obj = ObjectBuilder::default()
+        .open_file(get_test_object_path("runqslower.bpf.o"))
+        .expect("failed to open object");
+    assert!(
+        EventSource::prepare_as(open_obj.map_unwrap("events"), EventSourceKind::PerfBuffer)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_object_detach() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("ringbuf.bpf.o");
+    let link = obj
+        .prog_unwrap("handle__sys_enter_getpid")
+        .attach()
+        .expect("failed to attach prog");
+
+    let (tx, rx) = channel();
+    let mut builder = libbpf_rs::RingBufferBuilder::new();
+    builder
+        .add(obj.map_unwrap("ringbuf1"), move |data: &[u8]| {
+            let _ = tx.send(data.to_vec());
+            0
+        })
+        .expect("failed to add ringbuf");
+    let mgr = builder.build().expect("failed to build");
+
+    unsafe { libc::getpid() };
+
+    let mut opts = libbpf_rs::DetachOptions::default();
+    opts.drain(&mgr).drain_timeout(Duration::from_secs(5));
+    obj.detach(vec![link], &opts).expect("failed to detach");
+
+    // Draining consumed the sample submitted before the program was detached
+    assert_eq!(
+        rx.try_recv().expect("no sample received"),
+        1i32.to_ne_bytes()
+    );
+}
+
+#[test]
+fn test_object_program_test_run() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("ringbuf.bpf.o");
+    let prog = obj.prog_unwrap("handle__sys_enter_getpid");
+
+    // Tracepoint programs can't be test run
+    let data = [0u8; 64];
+    let mut opts = TestRunOptions::default();
+    opts.data_in(&data).repeat(10);
+    assert!(prog.test_run(&opts).is_err());
+}
+
+#[test]
+fn test_object_program_attach_socket() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("ringbuf.bpf.o");
+    let prog = obj.prog_unwrap("handle__sys_enter_getpid");
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("failed to bind socket");
+
+    // Only socket filter programs can filter sockets
+    assert!(matches!(
+        prog.attach_socket(&socket),
+        Err(Error::System(e)) if e == nix::libc::EINVAL
+    ));
+}
+
+#[test]
+fn test_object_ringbuf_fallible() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("ringbuf.bpf.o");
+    let _link = obj
+        .prog_unwrap("handle__sys_enter_getpid")
+        .attach()
+        .expect("failed to attach prog");
+
+    let mut builder = libbpf_rs::RingBufferBuilder::new();
+    builder
+        .add_fallible(obj.map_unwrap("ringbuf1"), |_| {
+            Err(Error::Internal("stop".to_string()))
+        })
+        .expect("failed to add ringbuf");
+    let mgr = builder.build().expect("failed to build");
+
+    unsafe { libc::getpid() };
+
+    match mgr.consume() {
+        Err(Error::Internal(msg)) => assert_eq!(msg, "stop"),
+        _ => panic!("callback error not returned"),
+    }
+    assert!(mgr.stats().callback_errors >= 1);
+}
+
+#[test]
+fn test_object_ringbuf_size_positions() {
+    bump_rlimit_mlock();
+
+    let mut open_obj = ObjectBuilder::default()
+        .open_file(get_test_object_path("ringbuf.bpf.o"))
+        .expect("failed to open object");
+    let size = open_obj
+        .map_unwrap("ringbuf1")
+        .set_ringbuf_size(1000, 20)
+        .expect("failed to size ringbuf");
+    assert_eq!(size, 32768);
+    let mut obj = open_obj.load().expect("failed to load object");
+
+    let _link = obj
+        .prog_unwrap("handle__sys_enter_getpid")
+        .attach()
+        .expect("failed to attach prog");
+
+    let mut builder = libbpf_rs::RingBufferBuilder::new();
+    builder
+        .add(obj.map_unwrap("ringbuf1"), |_| 0)
+        .expect("failed to add ringbuf");
+    let mgr = builder.build().expect("failed to build");
+
+    let map = obj.map_unwrap("ringbuf1");
+    let before = map.ringbuf_positions().expect("failed to get positions");
+
+    unsafe { libc::getpid() };
+
+    let after = map.ringbuf_positions().expect("failed to get positions");
+    assert!(after.producer_pos > before.producer_pos);
+    assert!(after.avail_data() > 0);
+
+    mgr.consume().expect("failed to consume ringbuf");
+    // Other processes may call getpid concurrently, so only check our sample was consumed
+    let consumed = map.ringbuf_positions().expect("failed to get positions");
+    assert!(consumed.consumer_pos >= after.producer_pos);
+}
+
 #[test]
 fn test_object_ringbuf() {
     bump_rlimit_mlock();
@@ -550,6 +2166,56 @@ fn test_object_ringbuf_closure() {
     assert_eq!(v2, 2);
 }
 
+#[test]
+fn test_object_ringbuf_typed() {
+    bump_rlimit_mlock();
+
+    let mut obj = get_test_object("ringbuf.bpf.o");
+    let prog = obj
+        .prog("handle__sys_enter_getpid")
+        .expect("error finding program")
+        .expect("failed to find program");
+    let _link = prog.attach().expect("failed to attach prog");
+
+    let (sender1, receiver1) = channel();
+    let (sender2, receiver2) = channel();
+
+    let mut builder = libbpf_rs::RingBufferBuilder::new();
+    let map1 = obj
+        .map("ringbuf1")
+        .expect("Error getting ringbuf1 map")
+        .expect("Failed to get ringbuf1 map");
+    unsafe {
+        builder.add_typed(map1, move |value: &i32| -> i32 {
+            sender1.send(*value).expect("Failed to send value");
+            0
+        })
+    }
+    .expect("Failed to add ringbuf");
+
+    // Samples are only 4 bytes, too small for a u64
+    let map2 = obj
+        .map("ringbuf2")
+        .expect("Error getting ringbuf2 map")
+        .expect("Failed to get ringbuf2 map");
+    unsafe {
+        builder.add_typed(map2, move |value: &u64| -> i32 {
+            sender2.send(*value).expect("Failed to send value");
+            0
+        })
+    }
+    .expect("Failed to add ringbuf");
+
+    let mgr = builder.build().expect("Failed to build");
+
+    // Call getpid to ensure the BPF program runs
+    unsafe { libc::getpid() };
+
+    assert!(mgr.consume().is_err());
+    assert_eq!(receiver1.recv().expect("Failed to receive value"), 1);
+    assert!(receiver2.try_recv().is_err());
+}
+
 #[test]
 fn test_object_task_iter() {
     bump_rlimit_mlock();